@@ -1,11 +1,13 @@
-use std::{env::home_dir, fs::{File, create_dir, create_dir_all}, io::copy, path::{Path, PathBuf}, str::FromStr};
+use std::{env::home_dir, fs::{File, create_dir_all}, io::copy, path::PathBuf};
 
 use actix_cors::Cors;
 use actix_multipart::form::{MultipartForm, tempfile::TempFile, text::Text};
-use actix_web::{App, HttpResponse, HttpServer, Responder, get, post, web};
-use p_hash::{core::app, db};
+use actix_web::{App, HttpResponse, HttpServer, Responder, delete, get, post, put, web};
+use futures::stream;
+use p_hash::{core::{self, app}, db::{DB, DbConfig, Repository, Run}, events::Event};
 use serde::{Deserialize, Serialize};
-use sqlx::{SqlitePool, prelude::FromRow, sqlite::SqliteConnectOptions};
+use sqlx::SqlitePool;
+use tokio::sync::broadcast::error::RecvError;
 use tracing_subscriber::{EnvFilter, fmt, layer::SubscriberExt, util::SubscriberInitExt};
 
 type DynError = Box<dyn std::error::Error>;
@@ -21,11 +23,22 @@ async fn main() -> Result<(), DynError> {
         App::new()
             .service(health)
             .service(runs)
+            .service(run_summary)
+            .service(delete_run)
+            .service(prune_runs)
+            .service(compact_db)
+            .service(check_db)
+            .service(repair_db)
             .service(get_hashing_methods)
             .service(get_modifications)
             .service(get_run_hashing_methods)
             .service(get_run_modifications)
-            .service(get_modifications)
+            .service(set_run_hashing_methods)
+            .service(set_run_modifications)
+            .service(start_run)
+            .service(cancel_run)
+            .service(run_state)
+            .service(run_events)
             .service(submit_image)
             .app_data(state.clone())
             .wrap(cors)
@@ -50,16 +63,114 @@ async fn runs(data: web::Data<State>) -> impl Responder {
     HttpResponse::Ok().json(runs)
 }
 
+/// Counts of what is stored for a run
+#[get("/runs/{id}/summary")]
+async fn run_summary(data: web::Data<State>, id: web::Path<i64>) -> impl Responder {
+    match data.repository.run_summary(id.into_inner()).await {
+        Ok(Some(summary)) => HttpResponse::Ok().json(summary),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Deletes a run and the results only it uses
+#[delete("/runs/{id}")]
+async fn delete_run(data: web::Data<State>, id: web::Path<i64>) -> impl Responder {
+    match DB::delete_run(&data.db, id.into_inner()).await {
+        Ok(Some(deleted)) => HttpResponse::Ok().json(deleted),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct PruneQuery {
+    keep: usize,
+}
+
+/// Deletes all but the newest runs, e.g. `/runs/prune?keep=5`
+#[post("/runs/prune")]
+async fn prune_runs(data: web::Data<State>, query: web::Query<PruneQuery>) -> impl Responder {
+    match DB::prune_runs(&data.db, query.keep).await {
+        Ok(deleted) => HttpResponse::Ok().json(deleted),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Releases the space of deleted results
+#[post("/db/compact")]
+async fn compact_db(data: web::Data<State>) -> impl Responder {
+    match DB::compact(&data.db).await {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Reports rows that break the rules of the schema
+#[get("/db/check")]
+async fn check_db(data: web::Data<State>) -> impl Responder {
+    match DB::check(&data.db, false).await {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Deletes the rows the check reports that can not be used
+#[post("/db/repair")]
+async fn repair_db(data: web::Data<State>) -> impl Responder {
+    match DB::check(&data.db, true).await {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
 /// Hashing methods for the current run setup
 #[get("/run/hashing_methods")]
 async fn get_run_hashing_methods(data: web::Data<State>) -> impl Responder {
-    let hashing_methods = data.app.state().get_run_hashes();
+    let hashing_methods = data.app.state().get_run_hashes().await;
     HttpResponse::Ok().json(hashing_methods)
 }
 #[get("/run/modifications")]
 async fn get_run_modifications(data: web::Data<State>) -> impl Responder {
-    let hashing_methods = data.app.state().get_run_modifications();
-    HttpResponse::Ok().json(hashing_methods)
+    let modifications = data.app.state().get_run_modifications().await;
+    HttpResponse::Ok().json(modifications)
+}
+
+/// Selects the hashing methods of the next run by their index in `/hashing_methods`
+#[put("/run/hashing_methods")]
+async fn set_run_hashing_methods(
+    data: web::Data<State>,
+    ids: web::Json<Vec<usize>>,
+) -> impl Responder {
+    match data
+        .app
+        .set_selected_hashing_methods(ids.into_inner())
+        .await
+    {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(e) => selection_error(e),
+    }
+}
+/// Selects the modifications of the next run by their index in `/modifications`
+#[put("/run/modifications")]
+async fn set_run_modifications(
+    data: web::Data<State>,
+    ids: web::Json<Vec<usize>>,
+) -> impl Responder {
+    match data.app.set_selected_modifications(ids.into_inner()).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(e) => selection_error(e),
+    }
+}
+
+fn selection_error(e: core::Error) -> HttpResponse {
+    match e {
+        core::Error::AppAlreadyRunning => HttpResponse::Conflict().body(e.to_string()),
+        core::Error::HashingMethodNotFound { .. }
+        | core::Error::ModificationNotFound { .. }
+        | core::Error::InvalidImageRoot { .. } => HttpResponse::BadRequest().body(e.to_string()),
+        e => HttpResponse::InternalServerError().body(e.to_string()),
+    }
 }
 
 #[get("/hashing_methods")]
@@ -86,21 +197,96 @@ async fn get_modifications(data: web::Data<State>) -> impl Responder {
     HttpResponse::Ok().json(methods)
 }
 
+/// Starts a run in the background, responds with the id of the run once it is stored
 #[post("/run/start")]
 async fn start_run(data: web::Data<State>, config: web::Json<AppConfig>) -> impl Responder {
     let mod_ids = config.modifications.iter().map(|i| *i as usize).collect();
-    data.app.set_selected_modifications(mod_ids);
+    if let Err(e) = data.app.set_selected_modifications(mod_ids).await {
+        return selection_error(e);
+    }
 
     let hash_ids = config.hashing_methods.iter().map(|i| *i as usize).collect();
-    data.app.set_selected_hashing_methods(hash_ids);
+    if let Err(e) = data.app.set_selected_hashing_methods(hash_ids).await {
+        return selection_error(e);
+    }
+
+    if let Some(path) = &config.path
+        && let Err(e) = data.app.set_path(path).await
+    {
+        return selection_error(e);
+    }
 
-    data.app.set_path(config.path.clone());
+    if let Some(batch_size) = config.batch_size
+        && let Err(e) = data.app.set_batch_size(batch_size).await
+    {
+        return selection_error(e);
+    }
 
-    if let Err(e) = data.app.run().await {
-        return HttpResponse::InternalServerError().json(e.to_string());
-    };
+    // The run is not tied to the request, a client that disconnects does not cancel it.
+    let mut events = data.app.events().subscribe();
+    let app = data.clone();
+    let mut run = actix_web::rt::spawn(async move { app.app.run().await });
+
+    loop {
+        tokio::select! {
+            // Events first, the run can have finished after it sent its id.
+            biased;
+            event = events.recv() => match event {
+                Ok(Event::RunStarted { run_id }) => return HttpResponse::Accepted().json(run_id),
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => {
+                    return HttpResponse::InternalServerError().json("Run events closed")
+                }
+            },
+            // Failed before it was stored, e.g. another run is in progress.
+            res = &mut run => {
+                return match res {
+                    Ok(Err(e)) => HttpResponse::InternalServerError().json(e.to_string()),
+                    Ok(Ok(())) => HttpResponse::InternalServerError().json("Run did not start"),
+                    Err(e) => HttpResponse::InternalServerError().json(e.to_string()),
+                };
+            }
+        }
+    }
+}
+
+/// Stops the current run at its next stop point
+#[post("/run/cancel")]
+async fn cancel_run(data: web::Data<State>) -> impl Responder {
+    match data.app.cancel().await {
+        true => HttpResponse::Accepted().finish(),
+        false => HttpResponse::Conflict().body("No run in progress"),
+    }
+}
 
-    HttpResponse::Ok().into()
+/// Whether a run is in progress, and how the last one ended
+#[get("/run/state")]
+async fn run_state(data: web::Data<State>) -> impl Responder {
+    HttpResponse::Ok().json(data.app.state().get_running_state().await)
+}
+
+/// Events of runs as server-sent events, one JSON object per event
+#[get("/run/events")]
+async fn run_events(data: web::Data<State>) -> impl Responder {
+    let rx = data.app.events().subscribe();
+    let events = stream::unfold(rx, |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) => {
+                    let json = serde_json::to_string(&event).ok()?;
+                    let data = web::Bytes::from(format!("data: {}\n\n", json));
+                    return Some((Ok::<_, actix_web::Error>(data), rx));
+                }
+                // A client that falls behind misses progress, later events still tell where the
+                // run is.
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .streaming(events)
 }
 
 #[post("/images/submit")]
@@ -140,11 +326,11 @@ async fn submit_image(data: web::Data<State>, MultipartForm(form): MultipartForm
 
         let res = sqlx::query(
             "
-        INSERT INTO images (path, user) VALUES (?, ?) ON CONFLICT(id) DO NOTHING;
+        INSERT INTO images (path, user) VALUES (?, ?) ON CONFLICT DO NOTHING;
         ",
         )
         .bind(save_path.to_str().unwrap())
-        .bind(&form.username.to_string())
+        .bind(form.username.to_string())
         .execute(&mut *tx)
         .await;
         if let Err(e) = res { return HttpResponse::InternalServerError().json(e.to_string())}
@@ -165,7 +351,10 @@ struct UploadForm{
 /// Submit form to initialize app
 #[derive(Serialize, Deserialize)]
 struct AppConfig {
-    path: PathBuf,
+    /// Image root of the run, the previous one is used if it is not given.
+    path: Option<PathBuf>,
+    /// Images hashed and stored together, the previous one is used if it is not given.
+    batch_size: Option<usize>,
     hashing_methods: Vec<u32>,
     modifications: Vec<u32>,
 }
@@ -173,13 +362,12 @@ struct AppConfig {
 struct State {
     image_save_path: PathBuf,
     db: SqlitePool,
+    repository: Repository,
     app: app::App,
 }
 impl State {
     async fn get_runs(&self) -> Result<Runs, DynError> {
-        let r: Vec<Run> = sqlx::query_as("SELECT * FROM runs")
-            .fetch_all(&self.db)
-            .await?;
+        let r = self.repository.runs().await?;
 
         Ok(Runs { runs: r })
     }
@@ -187,28 +375,22 @@ impl State {
 
 impl State {
     pub async fn try_new() -> Result<Self, DynError> {
-        let pool = SqlitePool::connect_with(
-            SqliteConnectOptions::from_str("sqlite:data.db")?
-                .create_if_missing(true)
-                .pragma("cache_size", "200000"),
-        )
-        .await?;
+        // The database is taken from P_HASH_DB, defaulting to data.db.
+        let db = DbConfig::from_env();
+        let pool = db.connect().await?;
 
-        let app = app::App::try_default().await?;
+        // One pool shared by the app, the repository and the maintenance routes.
+        let app = app::App::try_with_pool(pool.clone()).await?;
         let mut image_save_path = home_dir().unwrap();
         image_save_path.push(".local/share/p-hash/images");
 
         create_dir_all(&image_save_path)?;
 
-        Ok(State { db: pool, app , image_save_path: image_save_path.to_path_buf()})
+        let repository = Repository::new(pool.clone());
+        Ok(State { db: pool, repository, app , image_save_path: image_save_path.to_path_buf()})
     }
 }
 
-#[derive(Serialize, Deserialize, FromRow)]
-struct Run {
-    id: u32,
-    timestamp: u64,
-}
 #[derive(Serialize, Deserialize)]
 struct Runs {
     runs: Vec<Run>,
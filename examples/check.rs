@@ -0,0 +1,22 @@
+use std::env::args;
+
+use p_hash::db::{DB, DbConfig};
+
+/// Checks the stored results for rows that break the rules of the schema and prints the report
+/// as JSON. Exits with 1 if anything was found.
+///
+/// Usage: check [--repair]
+#[tokio::main]
+pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let repair = args().skip(1).any(|a| a == "--repair");
+
+    // The database is taken from P_HASH_DB, defaulting to data.db.
+    let pool = DbConfig::from_env().connect().await?;
+    let report = DB::check(&pool, repair).await?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+
+    if !report.is_ok() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,93 @@
+use std::{env::args, path::PathBuf};
+
+use p_hash::{
+    core::{app::App, images_processor::RayonImagesProcessor, result_parser::StoreResultParser},
+    events, hashing_methods,
+    image_hash::{self, HashingMethods},
+    image_modify::{self, Modifications},
+    matching::match_process::StoreRunner,
+    modifications,
+    store::ResultStore,
+};
+use tracing_subscriber::{EnvFilter, fmt, layer::SubscriberExt, util::SubscriberInitExt};
+
+/// Same run as the sqlite-rayon example, without a database. The results are written as one
+/// JSON-lines file per table.
+///
+/// Usage: jsonl [image dir] [results dir], `:memory:` as results dir keeps them in memory.
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    init_logger()?;
+
+    let processor = Box::new(RayonImagesProcessor::default());
+
+    let args: Vec<String> = args().collect();
+    let example_dir = match args.get(1) {
+        Some(s) => PathBuf::from(s),
+        None => PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("images"),
+    };
+    let store = match args.get(2).map(|s| s.as_str()) {
+        Some(":memory:") => ResultStore::in_memory(),
+        Some(dir) => ResultStore::open(dir)?,
+        None => ResultStore::open("results")?,
+    };
+
+    let parser = Box::new(StoreResultParser::new(store.clone()));
+    let match_process = Box::new(StoreRunner::new(store.clone()));
+
+    let modifications = modifications![
+        image_modify::Angle::Rot180,
+        image_modify::Angle::Rot90,
+        image_modify::Angle::Rot270,
+        image_modify::Blur::new(0.5),
+        image_modify::Blur::new(0.9),
+    ];
+    let hashing_methods = hashing_methods![
+        image_hash::AverageHash::new(8),
+        image_hash::AverageHash::new(16),
+        image_hash::AverageHash::new(64),
+        image_hash::AverageHash::new(256),
+        image_hash::VertGradient::new(8),
+        image_hash::VertGradient::new(16),
+        image_hash::VertGradient::new(64),
+        image_hash::VertGradient::new(256)
+    ];
+
+    let app = App::builder()
+        .imgs_path(&example_dir)
+        .images_processor(processor)
+        .results_parser(parser)
+        .match_process(match_process)
+        .modifications(modifications)
+        .hashing_methods(hashing_methods)
+        .finish();
+
+    events::draw_progress(app.events());
+
+    app.set_selected_hashing_methods(vec![1, 2, 3]).await?;
+    app.set_selected_modifications(vec![1, 2, 3]).await?;
+
+    app.run().await?;
+
+    println!(
+        "{} runs, {} images, {} hashes, {} matches",
+        store.runs()?.len(),
+        store.images()?.len(),
+        store.hashes()?.len(),
+        store.matches()?.len()
+    );
+    if let Some(dir) = store.get_dir() {
+        println!("Results written to {:?}", dir);
+    }
+    Ok(())
+}
+
+fn init_logger() -> Result<(), tracing::subscriber::SetGlobalDefaultError> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn"));
+    tracing_subscriber::registry()
+        .with(fmt::layer())
+        .with(filter)
+        .init();
+
+    Ok(())
+}
@@ -0,0 +1,62 @@
+use std::{env::args, path::Path};
+
+use p_hash::db::{DB, DbConfig, MIGRATIONS, latest_version};
+use sqlx::SqlitePool;
+
+/// Upgrades the schema of a database to the latest version.
+///
+/// Usage: migrate [--dry-run] [database, default data.db]
+#[tokio::main]
+pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = args().skip(1).collect::<Vec<String>>();
+    let dry_run = args.iter().any(|a| a == "--dry-run");
+    let path = args
+        .iter()
+        .find(|a| !a.starts_with("--"))
+        .map(String::as_str)
+        .unwrap_or("data.db");
+
+    if dry_run && !Path::new(path).exists() {
+        println!("{} does not exist, all migrations are pending", path);
+        for migration in MIGRATIONS {
+            println!(
+                "Pending {}: {}",
+                migration.version(),
+                migration.description()
+            );
+        }
+        return Ok(());
+    }
+
+    let pool = SqlitePool::connect_with(
+        DbConfig::file(path)
+            .connect_options()
+            .create_if_missing(!dry_run),
+    )
+    .await?;
+
+    println!(
+        "{} is at schema version {}, the latest version is {}",
+        path,
+        DB::schema_version(&pool).await?,
+        latest_version()
+    );
+
+    let migrations = match dry_run {
+        true => DB::pending_migrations(&pool).await?,
+        false => DB::migrate(&pool).await?,
+    };
+    if migrations.is_empty() {
+        println!("No pending migrations");
+    }
+    for migration in migrations {
+        let action = if dry_run { "Pending" } else { "Applied" };
+        println!(
+            "{} {}: {}",
+            action,
+            migration.version(),
+            migration.description()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,44 @@
+use std::env::args;
+
+use p_hash::db::{DB, DbConfig, MatchFilter, Repository};
+
+/// Prints how the match and hash queries of a run are executed and how long they take, for every
+/// modification and hashing method the run was stored with.
+///
+/// Usage: plans [run id], the active run is used if no id is given.
+#[tokio::main]
+pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // The database is taken from P_HASH_DB, defaulting to data.db.
+    let pool = DbConfig::from_env().connect().await?;
+    let repository = Repository::new(pool.clone());
+    let run_id = match args().nth(1) {
+        Some(run_id) => run_id.parse::<i64>()?,
+        None => repository.active_run().await?.ok_or("No active run")?,
+    };
+
+    let modifications = repository.run_modifications(run_id).await?;
+    let hashing_methods = repository.run_hashing_methods(run_id).await?;
+    for modification in &modifications {
+        for hashing_method in &hashing_methods {
+            println!(
+                "{} / {}",
+                modification.get_name(),
+                hashing_method.get_name()
+            );
+            let filter =
+                MatchFilter::new(modification.get_id(), hashing_method.get_id()).run(Some(run_id));
+            for path in DB::benchmark(&pool, &filter).await? {
+                println!(
+                    "  {}: {} rows in {} ms",
+                    path.get_name(),
+                    path.get_rows(),
+                    path.get_duration_ms()
+                );
+                for line in path.get_plan() {
+                    println!("    {}", line);
+                }
+            }
+        }
+    }
+    Ok(())
+}
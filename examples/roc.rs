@@ -1,14 +1,15 @@
-use std::{env::args, path::Path, str::FromStr};
+use std::{env::args, path::Path};
 use tracing_subscriber::{EnvFilter, fmt, layer::SubscriberExt, util::SubscriberInitExt};
 
 use p_hash::{
+    db::DbConfig,
+    events::{self, EventBus},
     hashing_methods,
     image_hash::{AverageHash, HashingMethods},
     image_modify::{Blur, Modifications},
     modifications,
     result_calc::{RocProcess, plot_roc},
 };
-use sqlx::{SqlitePool, sqlite::SqliteConnectOptions};
 
 #[tokio::main]
 pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -16,17 +17,19 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
     init_logger()?;
     let thresholds = (1..100).map(|i| (i as f32) / 100.).collect();
 
-    let pool = SqlitePool::connect_with(
-        SqliteConnectOptions::from_str("sqlite:data.db")?
-            .create_if_missing(false)
-            .pragma("cache_size", "200000"),
-    )
-    .await?;
+    // The database is taken from P_HASH_DB, defaulting to data.db.
+    let pool = DbConfig::from_env().connect().await?;
     let modifications = modifications![Blur::new(0.9)];
     let hashing_methods = hashing_methods![AverageHash::new(8)];
-    let res = RocProcess::new(thresholds, pool, modifications, hashing_methods)
-        .run()
-        .await?;
+    let events = EventBus::new();
+    events::draw_progress(&events);
+    let mut roc = RocProcess::new(thresholds, pool, modifications, hashing_methods)
+        .with_events(events.clone());
+    // Any stored run can be evaluated, the active run is used if none is given.
+    if let Some(run_id) = args.get(2) {
+        roc = roc.with_run(run_id.parse()?);
+    }
+    let res = roc.run().await?;
 
     plot_roc(res, Path::new(&args[1]))?;
     Ok(())
@@ -0,0 +1,83 @@
+use std::env::args;
+
+use p_hash::{
+    core::snapshot::RunSnapshot,
+    db::{DbConfig, Repository},
+};
+
+/// Prints the configuration, stage timings and stored counts of runs side by side.
+///
+/// Usage: runs [run id...], all runs with a snapshot are printed if no ids are given.
+#[tokio::main]
+pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let run_ids = args()
+        .skip(1)
+        .map(|a| a.parse::<i64>())
+        .collect::<Result<Vec<i64>, _>>()?;
+
+    // The database is taken from P_HASH_DB, defaulting to data.db.
+    let pool = DbConfig::from_env().connect().await?;
+    let repository = Repository::new(pool.clone());
+
+    let snapshots = match run_ids.is_empty() {
+        true => RunSnapshot::fetch_all(&pool).await?,
+        false => {
+            let mut snapshots = Vec::new();
+            for run_id in run_ids {
+                match RunSnapshot::fetch(&pool, run_id).await? {
+                    Some(s) => snapshots.push(s),
+                    None => println!("Run {} has no snapshot", run_id),
+                }
+            }
+            snapshots
+        }
+    };
+
+    for snapshot in snapshots {
+        println!("Run {}", snapshot.get_run_id().unwrap_or_default());
+        println!("  image root:      {:?}", snapshot.get_image_root());
+        println!("  version:         {}", snapshot.get_crate_version());
+        println!("  threads:         {}", snapshot.get_threads());
+        println!("  sampling seed:   {:?}", snapshot.get_sampling_seed());
+        println!("  split seed:      {:?}", snapshot.get_split_seed());
+        println!("  modifications:   {}", join(snapshot.get_modifications()));
+        println!(
+            "  hashing methods: {}",
+            join(snapshot.get_hashing_methods())
+        );
+        // Stages overlap, the total is the wall-clock time of the run and not their sum.
+        println!(
+            "  {:<16} {:.3}s",
+            "total:",
+            snapshot.total_duration().as_secs_f64()
+        );
+        for stage in snapshot.get_stages() {
+            println!(
+                "    {:<14} {:.3}s",
+                format!("{}:", stage.get_stage()),
+                stage.get_duration().as_secs_f64()
+            );
+        }
+        let run_id = snapshot.get_run_id().unwrap_or_default();
+        if let Some(summary) = repository.run_summary(run_id).await? {
+            println!(
+                "  stored:          {} images ({} skipped, {} duplicates), {} modified images, {} hashes, {} matches",
+                summary.get_images(),
+                summary.get_skipped_images(),
+                summary.get_duplicate_images(),
+                summary.get_modified_images(),
+                summary.get_hashes(),
+                summary.get_matches()
+            );
+        }
+    }
+    Ok(())
+}
+
+fn join(items: &[impl ToString]) -> String {
+    items
+        .iter()
+        .map(|i| i.to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
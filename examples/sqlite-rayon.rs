@@ -1,14 +1,14 @@
-use std::{env::args, path::PathBuf, str::FromStr};
+use std::{env::args, path::PathBuf};
 
 use p_hash::{
     core::{app::App, images_processor::RayonImagesProcessor, result_parser::SqliteResultParser},
-    hashing_methods,
+    db::DbConfig,
+    events, hashing_methods,
     image_hash::{self, HashingMethods},
     image_modify::{self, Modifications},
     matching::match_process::SqliteRunner,
     modifications,
 };
-use sqlx::{SqlitePool, sqlite::SqliteConnectOptions};
 use tracing_subscriber::{EnvFilter, fmt, layer::SubscriberExt, util::SubscriberInitExt};
 
 #[tokio::main]
@@ -20,12 +20,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Choosing what method to process images with.
     let processor = Box::new(RayonImagesProcessor::default());
 
-    let pool = SqlitePool::connect_with(
-        SqliteConnectOptions::from_str("sqlite:data.db")?
-            .create_if_missing(true)
-            .pragma("cache_size", "200000"),
-    )
-    .await?;
+    // The database is taken from P_HASH_DB, defaulting to data.db.
+    let pool = DbConfig::from_env().connect().await?;
 
     let parser = Box::new(SqliteResultParser::new(pool.clone()));
     let match_process = Box::new(SqliteRunner::new(pool.clone()));
@@ -68,9 +64,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .hashing_methods(hashing_methods)
         .finish();
 
+    events::draw_progress(app.events());
+
     // Set what methods that should be used in this run
-    app.set_selected_hashing_methods(vec![1, 2, 3]);
-    app.set_selected_modifications(vec![1, 2, 3]);
+    app.set_selected_hashing_methods(vec![1, 2, 3]).await?;
+    app.set_selected_modifications(vec![1, 2, 3]).await?;
+
+    // Optional second argument, how many images are hashed and stored together.
+    if let Some(batch_size) = args.get(2) {
+        app.set_batch_size(batch_size.parse()?).await?;
+    }
 
     if let Err(e) = app.run().await {
         tracing::error!("{e}");
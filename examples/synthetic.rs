@@ -0,0 +1,22 @@
+use std::{env::args, path::PathBuf};
+
+use p_hash::synthetic::SyntheticDataset;
+
+/// Writes a synthetic dataset that can be used as the image root of the other examples.
+///
+/// Usage: synthetic <output dir> [identities] [images per identity] [seed]
+pub fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = args().collect::<Vec<String>>();
+    let root = PathBuf::from(args.get(1).ok_or("missing output directory")?);
+    let identities = args.get(2).map(|s| s.parse()).transpose()?.unwrap_or(10);
+    let images_per_identity = args.get(3).map(|s| s.parse()).transpose()?.unwrap_or(5);
+    let seed = args.get(4).map(|s| s.parse()).transpose()?.unwrap_or(0);
+
+    let paths = SyntheticDataset::new(seed)
+        .identities(identities)
+        .images_per_identity(images_per_identity)
+        .generate(&root)?;
+
+    println!("Wrote {} images to {:?}", paths.len(), root);
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use std::{env::args, path::Path};
+
+use p_hash::{
+    db::{DbConfig, Repository},
+    export::{self, ExportOptions, MatchExport},
+};
+
+/// Moves runs between databases as JSON-lines archives, gzip compressed if the file ends with
+/// `.gz`.
+///
+/// Usage:
+///   transfer export <run id> <file> [--aggregate | --no-matches]
+///   transfer import <file>
+#[tokio::main]
+pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = args().collect::<Vec<String>>();
+    // The database is taken from P_HASH_DB, defaulting to data.db.
+    let pool = DbConfig::from_env().connect().await?;
+
+    match args.get(1).map(|a| a.as_str()) {
+        Some("export") if args.len() >= 4 => {
+            let run_id = args[2].parse::<i64>()?;
+            let matches = match args.get(4).map(|a| a.as_str()) {
+                Some("--aggregate") => MatchExport::Aggregated,
+                Some("--no-matches") => MatchExport::None,
+                _ => MatchExport::Full,
+            };
+            let options = ExportOptions::default().matches(matches);
+            let summary = export::export_run_to_path(
+                &Repository::new(pool),
+                run_id,
+                Path::new(&args[3]),
+                &options,
+            )
+            .await?;
+            println!(
+                "Exported run {}: {} images, {} hashes, {} matches in {} records",
+                summary.get_run_id(),
+                summary.get_images(),
+                summary.get_hashes(),
+                summary.get_matches(),
+                summary.get_records()
+            );
+        }
+        Some("import") if args.len() >= 3 => {
+            let summary = export::import_run_from_path(&pool, Path::new(&args[2])).await?;
+            println!(
+                "Imported run {} as run {}: {} images ({} new), {} hashes, {} matches",
+                summary.get_source_run_id(),
+                summary.get_run_id(),
+                summary.get_images(),
+                summary.get_new_images(),
+                summary.get_hashes(),
+                summary.get_matches()
+            );
+        }
+        _ => {
+            println!("Usage: transfer export <run id> <file> [--aggregate | --no-matches]");
+            println!("       transfer import <file>");
+        }
+    }
+    Ok(())
+}
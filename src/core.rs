@@ -3,8 +3,10 @@ pub mod app_builder;
 pub mod app_proc;
 pub mod error;
 pub mod image_parser;
+pub mod ingest;
 pub mod images_processor;
 pub mod result_parser;
+pub mod snapshot;
 pub mod state;
 
 pub use error::Error;
@@ -1,30 +1,35 @@
 use std::{
     path::{Path, PathBuf},
-    str::FromStr,
+    sync::Arc,
 };
 
-use sqlx::{SqlitePool, sqlite::SqliteConnectOptions};
+use sqlx::SqlitePool;
+use tokio::sync::mpsc;
 
 use crate::{
+    cancel::CancellationToken,
     core::{
         app_builder::{AppBuilder, Missing},
         error::Error,
         images_processor::{ImagesProcessor, RayonImagesProcessor},
-        result_parser::{ResultParser, SqliteResultParser},
-        state::AppState,
+        ingest::Ingest,
+        result_parser::{ResultParser, SqliteResultParser, StoreResultParser},
+        snapshot::{RunSnapshot, Stage, StageTimer, StageTiming},
+        state::{AppState, ImageBatch, RunIds, check_image_source},
     },
+    db::{DB, DbConfig},
+    events::{Event, EventBus},
     hashing_methods,
     image_hash::{self, HashingMethods},
     image_modify::{self, Modifications},
-    image_parse::Images,
-    matching::match_process::{PipelineRunner, SqliteRunner},
+    image_parse::{DuplicatePolicy, ImageFilter, ImageSource, Sampling, Split},
+    matching::match_process::{PipelineRunner, SqliteRunner, StoreRunner},
     modifications,
+    store::ResultStore,
 };
 
 pub struct App {
-    imgs_path: PathBuf,
-
-    images_processor: Box<dyn ImagesProcessor>,
+    images_processor: Arc<dyn ImagesProcessor>,
     results_parser: Box<dyn ResultParser>,
     match_process: Box<dyn PipelineRunner>,
 
@@ -39,10 +44,9 @@ impl App {
         modifications: Modifications,
         hashing_methods: HashingMethods,
     ) -> Self {
-        let state = AppState::new(hashing_methods, modifications);
+        let state = AppState::new(path, hashing_methods, modifications);
         Self {
-            imgs_path: path.to_path_buf(),
-            images_processor,
+            images_processor: Arc::from(images_processor),
             results_parser,
             match_process,
             state,
@@ -54,71 +58,284 @@ impl App {
     pub fn state(&self) -> &AppState {
         &self.state
     }
-    pub fn set_selected_modifications(&self, ids: Vec<usize>) {
-        self.state.set_run_modifications(ids);
+    /// Bus the progress and outcome of runs are sent to, nothing is drawn or printed unless
+    /// someone subscribes, see `events::draw_progress`.
+    pub fn events(&self) -> &EventBus {
+        self.state.events()
+    }
+    pub async fn set_selected_modifications(&self, ids: Vec<usize>) -> Result<(), Error> {
+        self.state.set_run_modifications(ids).await
+    }
+    pub async fn set_selected_hashing_methods(&self, ids: Vec<usize>) -> Result<(), Error> {
+        self.state.set_run_hashes(ids).await
+    }
+    /// Reads the images of the next run from a directory, it must be a readable directory.
+    pub async fn set_path(&self, path: impl Into<PathBuf>) -> Result<(), Error> {
+        self.state.set_path(path).await
+    }
+    /// Image root of the next run, the one the app was built with until `set_path` or
+    /// `set_image_source` changes it.
+    pub async fn get_path(&self) -> PathBuf {
+        self.state.get_path().await
+    }
+    /// Reads the images of the next run from a directory, a manifest or an archive.
+    pub async fn set_image_source(&self, source: ImageSource) -> Result<(), Error> {
+        self.state.set_image_source(source).await
+    }
+    pub async fn get_image_source(&self) -> ImageSource {
+        self.state.get_image_source().await
     }
-    pub fn set_selected_hashing_methods(&self, ids: Vec<usize>) {
-        self.state.set_run_hashes(ids);
+    /// Sets what files under the image root that are accepted as images.
+    pub async fn set_image_filter(&self, filter: ImageFilter) -> Result<(), Error> {
+        self.state.set_image_filter(filter).await
     }
-    pub fn set_path(&self, path: impl Into<PathBuf>){
-        self.state.set_path(path);
+    /// Sets what to do with images that has the same content as another image in the run.
+    pub async fn set_duplicate_policy(&self, policy: DuplicatePolicy) -> Result<(), Error> {
+        self.state.set_duplicate_policy(policy).await
     }
-    pub fn get_path(&self){
-        self.state.get_path();
+    /// Only runs on a seeded sample of the images, `None` runs on all of them.
+    pub async fn set_sampling(&self, sampling: Option<Sampling>) -> Result<(), Error> {
+        self.state.set_sampling(sampling).await
     }
+    /// Splits the images of the run into a calibration and an evaluation partition.
+    pub async fn set_split(&self, split: Option<Split>) -> Result<(), Error> {
+        self.state.set_split(split).await
+    }
+    /// Sets how many images are hashed and stored together, fewer images are then held in
+    /// memory at once with smaller batches.
+    pub async fn set_batch_size(&self, batch_size: usize) -> Result<(), Error> {
+        self.state.set_batch_size(batch_size).await
+    }
+    /// Runs with the selected modifications and hashing methods. Fails if a run is already in
+    /// progress, the settings can not be changed until it has finished.
     pub async fn run(&self) -> Result<(), Error> {
-        if let crate::core::state::RunningState::Running = self.state.get_running_state() {
-            return Err(Error::AppAlreadyRunning);
-        }
-        self.state
-            .set_running_state(crate::core::state::RunningState::Running);
-
-        let images = Images::from_path(self.imgs_path.to_path_buf());
-        let images = images
-            .filter_map(|r| {
-                if let Err(e) = r.as_ref() {
-                    tracing::warn!("An image failed to process: {}", e);
-                }
-                r.ok()
-            })
-            .collect();
+        let run = self.state.start_run().await?;
+        let res = self.run_selected(run.cancellation()).await;
+        run.finish(&res).await;
+        res
+    }
+    /// Asks the current run to stop at its next stop point, it then fails with
+    /// `Error::Cancelled`. Returns false if nothing is running.
+    pub async fn cancel(&self) -> bool {
+        self.state.cancel().await
+    }
+    async fn run_selected(&self, cancel: &CancellationToken) -> Result<(), Error> {
+        // Checked again, the files can have been removed since they were set.
+        let source = self.state.get_image_source().await;
+        check_image_source(&source).await?;
+        let imgs_path = source.get_root().to_path_buf();
 
-        tracing::info!("starting image hashing");
+        let sampling = self.state.get_sampling().await;
+        let split = self.state.get_split().await;
 
         let modifications = self.state.modifications();
-
-        let modifications_selected = modifications.select(&self.state.get_run_modifications());
+        let run_modifications = self.state.get_run_modifications().await;
+        let modifications_selected = modifications.select(&run_modifications);
 
         let hashing_methods = self.state.hashing_methods();
+        let run_hashes = self.state.get_run_hashes().await;
+        let hashing_methods_selected = hashing_methods.select(&run_hashes);
 
-        let hashing_methods_selected = hashing_methods.select(&self.state.get_run_hashes());
+        let mut snapshot = RunSnapshot::new(&imgs_path)
+            .with_sampling_seed(sampling.as_ref().map(|s| s.get_seed()))
+            .with_split_seed(split.as_ref().map(|s| s.get_seed()))
+            .with_modifications(&modifications_selected)
+            .with_hashing_methods(&hashing_methods_selected);
 
-        let res =
-            self.images_processor
-                .run(images, &modifications_selected, &hashing_methods_selected);
+        let filter = self.state.get_image_filter().await;
+        let policy = self.state.get_duplicate_policy().await;
+        // Both need every image, they are read before anything is hashed. Manifests are parsed
+        // on the same thread.
+        let ingest = tokio::task::spawn_blocking(move || {
+            let ingest = Ingest::new(source.images(filter)?, policy);
+            Ok::<_, Error>(ingest.sample(sampling.as_ref(), split.as_ref()))
+        })
+        .await??;
 
-        tracing::info!("sending results to db");
-        self.results_parser
-            .parse(res, &modifications_selected, &hashing_methods_selected)
+        let ids = self
+            .results_parser
+            .begin(&modifications_selected, &hashing_methods_selected)
             .await?;
-        self.match_process.run(&hashing_methods_selected).await?;
+        self.events().send(Event::RunStarted {
+            run_id: ids.run_id(),
+        });
 
-        Ok(())
+        tracing::info!("starting image hashing");
+        let stored = self
+            .hash_and_store(ingest, &ids, cancel, &mut snapshot)
+            .await;
+
+        let matched = match stored {
+            Ok(()) => {
+                let timer = start_stage(self.events(), Stage::Matching);
+                let matched = self
+                    .match_process
+                    .run(
+                        ids.hashing_method_ids(),
+                        cancel.clone(),
+                        self.events().clone(),
+                    )
+                    .await;
+                snapshot.push_stage(finish_stage(self.events(), timer));
+                matched.map_err(Error::from)
+            }
+            Err(e) => Err(e),
+        };
+
+        self.results_parser.save_snapshot(&ids, &snapshot).await?;
+        matched
     }
+    /// Hashes the images in batches and stores every batch as soon as it is hashed. Reading,
+    /// hashing and storing run at the same time and each waits while the next one already has a
+    /// batch waiting, so only a few batches are held in memory however many images the run has.
+    async fn hash_and_store(
+        &self,
+        ingest: Ingest,
+        ids: &RunIds,
+        cancel: &CancellationToken,
+        snapshot: &mut RunSnapshot,
+    ) -> Result<(), Error> {
+        let batch_size = self.state.get_batch_size().await;
+        let modifications = self.state.modifications();
+        let run_modifications = self.state.get_run_modifications().await;
+        let hashing_methods = self.state.hashing_methods();
+        let run_hashes = self.state.get_run_hashes().await;
+
+        let (batch_tx, batch_rx) = mpsc::channel(1);
+        let (result_tx, result_rx) = mpsc::channel(1);
+
+        let hashing = {
+            let processor = Arc::clone(&self.images_processor);
+            let modifications = Arc::clone(&modifications);
+            let hashing_methods = Arc::clone(&hashing_methods);
+            let (run_modifications, run_hashes) = (run_modifications.clone(), run_hashes.clone());
+            let cancel = cancel.clone();
+            let events = self.events().clone();
+            tokio::task::spawn_blocking(move || {
+                let timer = start_stage(&events, Stage::Hashing);
+                processor.run(
+                    batch_rx,
+                    result_tx,
+                    &modifications.select(&run_modifications),
+                    &hashing_methods.select(&run_hashes),
+                    &cancel,
+                    &events,
+                );
+                finish_stage(&events, timer)
+            })
+        };
+
+        let modifications_selected = modifications.select(&run_modifications);
+        let hashing_methods_selected = hashing_methods.select(&run_hashes);
+        let read = async {
+            // Owned by the future, the processor stops once it is dropped.
+            let batch_tx = batch_tx;
+            let timer = start_stage(self.events(), Stage::Ingest);
+            let mut ingest = ingest;
+            while !cancel.is_cancelled() {
+                // Reading an image computes its digest, which blocks.
+                let (rest, images) = tokio::task::spawn_blocking(move || {
+                    let images = ingest.take_batch(batch_size);
+                    (ingest, images)
+                })
+                .await?;
+                ingest = rest;
 
+                let existing = self
+                    .results_parser
+                    .existing_hashes(
+                        &images,
+                        &modifications_selected,
+                        &hashing_methods_selected,
+                        self.images_processor.load_options(),
+                    )
+                    .await?;
+                if !existing.is_empty() {
+                    tracing::debug!(
+                        "{} hashes are already stored and will be reused",
+                        existing.len()
+                    );
+                }
+                let batch = ImageBatch::new(images, existing);
+                if batch.images().len() == batch_size {
+                    if batch_tx.send(batch).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+
+                // The images are exhausted, the last batch carries the ingest report.
+                let report = ingest.into_report();
+                if !report.is_empty() {
+                    let msg = format!(
+                        "{} files were skipped and {} duplicates found, see the ingest report",
+                        report.skipped().len(),
+                        report.duplicates().len()
+                    );
+                    tracing::warn!("{}", msg);
+                    self.events().warn(Stage::Ingest, msg);
+                }
+                let _ = batch_tx.send(batch.with_ingest_report(report)).await;
+                break;
+            }
+            Ok::<_, Error>(finish_stage(self.events(), timer))
+        };
+        let store = async {
+            let mut result_rx = result_rx;
+            let timer = start_stage(self.events(), Stage::Storing);
+            let progress = self.events().progress(Stage::Storing, None);
+            while let Some(results) = result_rx.recv().await {
+                // The batch hashed when the run was cancelled is incomplete and not stored.
+                if cancel.is_cancelled() {
+                    break;
+                }
+                let images = results.images().len();
+                self.results_parser.parse(ids, results).await?;
+                progress.inc(images as u64);
+            }
+            Ok::<_, Error>(finish_stage(self.events(), timer))
+        };
+
+        let stored = tokio::try_join!(read, store);
+        // The processor returns once reading and storing are done or have failed.
+        let hashed = hashing.await?;
+        let (read, stored) = stored?;
+        snapshot.push_stage(read);
+        snapshot.push_stage(hashed);
+        snapshot.push_stage(stored);
+        check_cancelled(cancel)
+    }
+
+    /// Default setup using the database given by `P_HASH_DB`, `data.db` if it is not set.
     pub async fn try_default() -> Result<Self, Error> {
-        // Choosing what method to process images with.
-        let processor = Box::new(RayonImagesProcessor::default());
+        Self::try_with_db(&DbConfig::from_env()).await
+    }
 
-        let pool = SqlitePool::connect_with(
-            SqliteConnectOptions::from_str("sqlite:data.db")?
-                .create_if_missing(true)
-                .pragma("cache_size", "200000"),
-        )
-        .await?;
+    /// Default setup using the given database.
+    pub async fn try_with_db(db: &DbConfig) -> Result<Self, Error> {
+        Self::try_with_pool(db.connect_without_migrating().await?).await
+    }
+
+    /// Default setup using an open pool, for sharing the pool with other readers and writers of
+    /// the database. The database is migrated to the latest schema if it is not already.
+    pub async fn try_with_pool(pool: SqlitePool) -> Result<Self, Error> {
+        DB::migrate(&pool).await?;
 
         let parser = Box::new(SqliteResultParser::new(pool.clone()));
         let match_process = Box::new(SqliteRunner::new(pool.clone()));
+        Ok(Self::default_with(parser, match_process))
+    }
+
+    /// Default setup storing the results in a `ResultStore` instead of a database.
+    pub fn with_store(store: ResultStore) -> Self {
+        let parser = Box::new(StoreResultParser::new(store.clone()));
+        let match_process = Box::new(StoreRunner::new(store));
+        Self::default_with(parser, match_process)
+    }
+
+    fn default_with(parser: Box<dyn ResultParser>, match_process: Box<dyn PipelineRunner>) -> Self {
+        // Choosing what method to process images with.
+        let processor = Box::new(RayonImagesProcessor::default());
 
         let example_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("images");
 
@@ -140,14 +357,35 @@ impl App {
             image_hash::VertGradient::new(256)
         ];
 
-        let app = App::builder()
+        App::builder()
             .imgs_path(&example_dir)
             .images_processor(processor)
             .results_parser(parser)
             .match_process(match_process)
             .modifications(modifications)
             .hashing_methods(hashing_methods)
-            .finish();
-        Ok(app)
+            .finish()
+    }
+}
+
+/// Starts timing the stage and sends `Event::StageStarted`.
+fn start_stage(events: &EventBus, stage: Stage) -> StageTimer {
+    events.send(Event::StageStarted { stage });
+    StageTimer::start(stage)
+}
+
+fn finish_stage(events: &EventBus, timer: StageTimer) -> StageTiming {
+    let timing = timer.finish();
+    events.send(Event::StageFinished {
+        stage: timing.get_stage(),
+        duration_ms: timing.get_duration().as_millis() as u64,
+    });
+    timing
+}
+
+fn check_cancelled(cancel: &CancellationToken) -> Result<(), Error> {
+    match cancel.is_cancelled() {
+        true => Err(Error::Cancelled),
+        false => Ok(()),
     }
 }
@@ -1,7 +1,7 @@
 use std::{
     env::home_dir,
     marker::PhantomData,
-    path::{Path, PathBuf},
+    path::PathBuf,
 };
 
 use crate::{
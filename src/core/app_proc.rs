@@ -1,78 +1,103 @@
-use std::path::Path;
+use image::DynamicImage;
 
 use crate::{
     core::{
         error::Error,
         images_processor::PHashResult,
-        state::{self, Hash, ModifiedImage},
+        state::{self, ExistingHashes, Hash, ModifiedImage},
     },
-    image_hash::{HashingMethods, SelectedHashingMethods, hash_images},
-    image_modify::{Modifications, ModifiedImages, SelectedModifications, modify_image},
+    image_hash::{SelectedHashingMethods, hash_images_where},
+    image_modify::{ModifiedImages, SelectedModifications, modify_image_where},
+    image_parse::{Image, LoadOptions},
 };
 
 #[derive(Default)]
 pub struct AppProcess {
     result: PHashResult,
+    load_options: LoadOptions,
 }
 impl AppProcess {
     pub fn new() -> Self {
         Self::default()
     }
+    pub fn with_load_options(mut self, load_options: LoadOptions) -> Self {
+        self.load_options = load_options;
+        self
+    }
     pub fn finish(self) -> PHashResult {
         self.result
     }
     pub fn run(
         &mut self,
-        img_path: &Path,
+        image: &Image,
         img_id: u32,
         modifications: &SelectedModifications,
         hashing_methods: &SelectedHashingMethods,
+        existing: &ExistingHashes,
     ) -> Result<(), Error> {
-        let modified_images = Self::modify_image(img_path, img_id, modifications)?;
+        if existing.has_image(img_id, modifications.len(), hashing_methods.len()) {
+            tracing::debug!("{:?} is already hashed, skipping it", image.get_path());
+            return Ok(());
+        }
+        let (img, metadata) = image.load_with(self.load_options)?;
+        self.result.set_metadata(metadata);
+
+        let modified_images =
+            Self::modify_image(&img, img_id, modifications, hashing_methods.len(), existing);
         self.result.set_mod_imgs(modified_images);
 
         let ids = 0..self.result.mod_imgs().len();
 
         for id in ids {
-            if let Err(e) = self.hash_image(id as u32, &hashing_methods) {
+            if let Err(e) = self.hash_image(id as u32, img_id, hashing_methods, existing) {
                 tracing::error!("failed to hash an image {e}")
             }
         }
         Ok(())
     }
+    /// Applies the modifications that are missing hashes for some of the hashing methods.
     fn modify_image(
-        img_path: &Path,
+        img: &DynamicImage,
         img_id: u32,
         modifications: &SelectedModifications,
-    ) -> Result<ModifiedImages, Error> {
-        let modified_images = modify_image(img_path, modifications)?;
+        hashing_methods: usize,
+        existing: &ExistingHashes,
+    ) -> ModifiedImages {
+        let modified_images = modify_image_where(img, modifications, |mod_id| {
+            !existing.has_modification(img_id, mod_id, hashing_methods)
+        });
 
         let mod_imgs_state = modified_images
             .into_iter()
             .map(|i| ModifiedImage::new(img_id, i));
 
-        Ok(ModifiedImages::from(
-            mod_imgs_state.collect::<Vec<state::ModifiedImage>>(),
-        ))
+        ModifiedImages::from(mod_imgs_state.collect::<Vec<state::ModifiedImage>>())
     }
     fn hash_image(
         &mut self,
         mod_img_id: u32,
+        img_id: u32,
         hashing_methods: &SelectedHashingMethods,
+        existing: &ExistingHashes,
     ) -> Result<(), Error> {
-        let img = {
+        let (img, mod_id) = {
             let modified_image = self.result.mod_imgs().get_img(mod_img_id)?;
 
-            modified_image.get_img().ok_or(Error::ImageHandleClosed)?
+            (
+                modified_image.get_img().ok_or(Error::ImageHandleClosed)?,
+                modified_image.get_mod_id(),
+            )
         };
 
-        hash_images(img.clone(), hashing_methods)
-            .into_iter()
-            .for_each(|r| {
-                self.result
-                    .hashes_mut()
-                    .insert_hash(Hash::new(mod_img_id, r));
-            });
+        hash_images_where(img.clone(), hashing_methods, |h| {
+            !existing.contains(img_id, mod_id, h)
+        })
+        .into_iter()
+        .for_each(|r| {
+            self.result
+                .hashes_mut()
+                .insert_hash(Hash::new(mod_img_id, r));
+        });
 
         self.result
             .mod_imgs_mut()
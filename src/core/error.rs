@@ -1,23 +1,34 @@
-use std::fmt::Display;
+use std::{fmt::Display, path::PathBuf};
 
-use crate::{image_modify, matching};
+use crate::{db, image_modify, matching, store};
 
 #[derive(Debug)]
 pub enum Error {
     ImageProc { err: crate::image_parse::Error },
     ImageMod { err: crate::image_modify::Error },
+    ImageLoad { err: image::ImageError },
     ModificationNotFound { id: usize },
     HashingMethodNotFound { id: usize },
     ImageNotFound { id: usize },
     ImageHandleClosed,
     Sqlx { err: sqlx::Error },
+    Db { err: db::Error },
+    Store { err: store::Error },
     HomeDirNotFound,
     MatchError { err: matching::error::Error },
     AppAlreadyRunning,
+    InvalidImageRoot { path: PathBuf, err: std::io::Error },
+    InvalidImageSource { path: PathBuf, err: std::io::Error },
+    Cancelled,
+    RunNotStarted,
+    Task { err: tokio::task::JoinError },
 }
 impl From<matching::error::Error> for Error {
     fn from(value: matching::error::Error) -> Self {
-        Self::MatchError { err: value }
+        match value {
+            matching::error::Error::Cancelled => Self::Cancelled,
+            err => Self::MatchError { err },
+        }
     }
 }
 impl From<image_modify::Error> for Error {
@@ -25,11 +36,31 @@ impl From<image_modify::Error> for Error {
         Self::ImageMod { err: value }
     }
 }
+impl From<image::ImageError> for Error {
+    fn from(value: image::ImageError) -> Self {
+        Self::ImageLoad { err: value }
+    }
+}
 impl From<crate::image_parse::Error> for Error {
     fn from(value: crate::image_parse::Error) -> Self {
         Self::ImageProc { err: value }
     }
 }
+impl From<db::Error> for Error {
+    fn from(value: db::Error) -> Self {
+        Self::Db { err: value }
+    }
+}
+impl From<store::Error> for Error {
+    fn from(value: store::Error) -> Self {
+        Self::Store { err: value }
+    }
+}
+impl From<tokio::task::JoinError> for Error {
+    fn from(value: tokio::task::JoinError) -> Self {
+        Self::Task { err: value }
+    }
+}
 impl From<sqlx::Error> for Error {
     fn from(value: sqlx::Error) -> Self {
         Self::Sqlx { err: value }
@@ -41,6 +72,7 @@ impl Display for Error {
         match self {
             Self::ImageProc { err } => write!(f, "Image proc error: {}", err),
             Self::ImageMod { err } => write!(f, "Image modification error: {}", err),
+            Self::ImageLoad { err } => write!(f, "Image could not be loaded: {}", err),
             Self::ModificationNotFound { id } => write!(f, "Modification with id {} not found", id),
             Self::HashingMethodNotFound { id } => {
                 write!(f, "Hashing method with id {} not found", id)
@@ -48,9 +80,20 @@ impl Display for Error {
             Self::ImageNotFound { id } => write!(f, "Image with id {} not found", id),
             Self::ImageHandleClosed => write!(f, "Image handle closed before expected"),
             Self::Sqlx { err } => write!(f, "Sqlx Error: {}", err),
+            Self::Db { err } => write!(f, "Database error: {}", err),
+            Self::Store { err } => write!(f, "Result store error: {}", err),
             Self::HomeDirNotFound => write!(f, "Home dir not found"),
             Self::MatchError { err } => write!(f, "Error when matching: {}", err),
             Self::AppAlreadyRunning => write!(f, "App is already running"),
+            Self::InvalidImageRoot { path, err } => {
+                write!(f, "Image root {:?} can not be used: {}", path, err)
+            }
+            Self::InvalidImageSource { path, err } => {
+                write!(f, "Image source {:?} can not be used: {}", path, err)
+            }
+            Self::Cancelled => write!(f, "Run was cancelled"),
+            Self::RunNotStarted => write!(f, "Results can not be stored before the run is started"),
+            Self::Task { err } => write!(f, "Background task failed: {}", err),
         }
     }
 }
@@ -1,11 +1,11 @@
 use crate::{
-    core::{Error, app_proc::AppProcess, images_processor::PHashResult},
-    image_hash::{HashingMethods, SelectedHashingMethods},
-    image_modify::{Modifications, SelectedModifications},
-    image_parse::Image,
+    core::{Error, app_proc::AppProcess, images_processor::PHashResult, state::ExistingHashes},
+    image_hash::SelectedHashingMethods,
+    image_modify::SelectedModifications,
+    image_parse::{Image, LoadOptions},
 };
 /// Parses one image and returns a PHashResult which reperensents the modified images and hashes
-/// for the input image.
+/// for the input image. Hashes in `existing` are already stored and are not computed again.
 pub trait ImageParser: Sync + Send {
     fn run(
         &self,
@@ -13,10 +13,24 @@ pub trait ImageParser: Sync + Send {
         id: u32,
         modifications: &SelectedModifications,
         hashing_methods: &SelectedHashingMethods,
+        existing: &ExistingHashes,
     ) -> Result<PHashResult, Error>;
+    /// Options the images are decoded with, stored hashes are only reused if they were made
+    /// with the same.
+    fn load_options(&self) -> LoadOptions {
+        LoadOptions::default()
+    }
 }
 #[derive(Debug, Default)]
-pub struct AppProcParser {}
+pub struct AppProcParser {
+    load_options: LoadOptions,
+}
+impl AppProcParser {
+    /// Parser that decodes images with the given options, e.g. to turn off EXIF orientation.
+    pub fn new(load_options: LoadOptions) -> Self {
+        Self { load_options }
+    }
+}
 impl ImageParser for AppProcParser {
     fn run(
         &self,
@@ -24,11 +38,15 @@ impl ImageParser for AppProcParser {
         id: u32,
         modifications: &SelectedModifications,
         hashing_methods: &SelectedHashingMethods,
+        existing: &ExistingHashes,
     ) -> Result<PHashResult, Error> {
-        let mut app_proc = AppProcess::new();
-        app_proc.run(image.get_path(), id, modifications, hashing_methods)?;
+        let mut app_proc = AppProcess::new().with_load_options(self.load_options);
+        app_proc.run(image, id, modifications, hashing_methods, existing)?;
 
         let proc_res = app_proc.finish();
         Ok(proc_res)
     }
+    fn load_options(&self) -> LoadOptions {
+        self.load_options
+    }
 }
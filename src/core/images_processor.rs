@@ -4,79 +4,121 @@ use std::{
 };
 
 use crossbeam::channel::unbounded;
-use indicatif::{ParallelProgressIterator, ProgressBar, ProgressStyle};
 use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
-use sqlx::SqlitePool;
+use sqlx::SqliteConnection;
+use tokio::sync::mpsc::{Receiver, Sender};
 
 use crate::{
+    cancel::CancellationToken,
     core::{
         error::Error,
         image_parser::{AppProcParser, ImageParser},
-        state::{AppProcessResult, Hashes, Images},
+        snapshot::Stage,
+        state::{AppProcessResult, Hashes, ImageBatch, Images, RunIds},
     },
-    image_hash::{HashingMethods, SelectedHashingMethods},
-    image_modify::{Modifications, ModifiedImages, SelectedModifications},
-    image_parse::Image,
+    events::{EventBus, Progress},
+    image_hash::{self, SelectedHashingMethods},
+    image_modify::{ModifiedImages, SelectedModifications},
+    image_parse::{ImageMetadata, LoadOptions},
+    store::ResultStore,
 };
 
-/// Parses input images given by img_proc::Image data struct.
+/// Hashes the images of a run batch by batch.
 pub trait ImagesProcessor: Send + Sync {
-    fn run(
+    /// Hashes the images of one batch. Images not started before `cancel` is cancelled are left
+    /// out of the result. Images are counted in `progress` as they are parsed, the ones that
+    /// could not be parsed are sent as warnings.
+    fn process(
         &self,
-        images: Vec<Image>,
+        batch: ImageBatch,
         modifications: &SelectedModifications,
         hashing_methods: &SelectedHashingMethods,
+        cancel: &CancellationToken,
+        progress: &Progress,
     ) -> AppProcessResult;
+    /// Hashes the batches from `batches` one at a time and sends the results to `results` in the
+    /// same order. Waits while `results` is full, so hashing never gets further ahead of
+    /// storing than `results` has room for. Returns when `batches` is closed, `results` is
+    /// dropped or the run is cancelled.
+    fn run(
+        &self,
+        mut batches: Receiver<ImageBatch>,
+        results: Sender<AppProcessResult>,
+        modifications: &SelectedModifications,
+        hashing_methods: &SelectedHashingMethods,
+        cancel: &CancellationToken,
+        events: &EventBus,
+    ) {
+        let progress = events.progress(Stage::Hashing, None);
+        while let Some(batch) = batches.blocking_recv() {
+            if cancel.is_cancelled() {
+                break;
+            }
+            let res = self.process(batch, modifications, hashing_methods, cancel, &progress);
+            if results.blocking_send(res).is_err() {
+                break;
+            }
+        }
+    }
+    /// Options the images are decoded with, see `ImageParser::load_options`.
+    fn load_options(&self) -> LoadOptions {
+        LoadOptions::default()
+    }
 }
 pub struct RayonImagesProcessor {
     image_parser: Box<dyn ImageParser>,
 }
+impl RayonImagesProcessor {
+    pub fn new(image_parser: Box<dyn ImageParser>) -> Self {
+        Self { image_parser }
+    }
+}
 impl ImagesProcessor for RayonImagesProcessor {
-    fn run(
+    fn process(
         &self,
-        images: Vec<Image>,
+        batch: ImageBatch,
         modifications: &SelectedModifications,
         hashing_methods: &SelectedHashingMethods,
+        cancel: &CancellationToken,
+        progress: &Progress,
     ) -> AppProcessResult {
+        let (images, existing, ingest_report) = batch.into_parts();
         let (s, r) = unbounded();
-        let style = ProgressStyle::with_template(
-            "[{elapsed_precise} | {eta_precise}] Processing images: {pos:>7}/{len:7} {percent}%",
-        )
-        .unwrap()
-        .progress_chars("##-");
-
-        images
-            .par_iter()
-            .progress_with(ProgressBar::new(images.len() as u64).with_style(style))
-            .enumerate()
-            .for_each(move |(id, image)| {
-                let res = self
-                    .image_parser
-                    .run(image, id as u32, modifications, hashing_methods);
-
-                let phash_res = match res {
-                    Ok(r) => r,
-                    Err(e) => {
-                        tracing::warn!(
-                            "Could not parse image {:?}. Error: {}",
-                            image.get_path(),
-                            e
-                        );
-                        return;
-                    }
-                };
-
-                if let Err(e) = s.send((id, phash_res)) {
-                    tracing::warn!("failed to send result through channel, err: {}", e);
+
+        images.par_iter().enumerate().for_each(move |(id, image)| {
+            if cancel.is_cancelled() {
+                return;
+            }
+            let res =
+                self.image_parser
+                    .run(image, id as u32, modifications, hashing_methods, &existing);
+
+            progress.inc(1);
+
+            let phash_res = match res {
+                Ok(r) => r,
+                Err(e) => {
+                    let msg = format!("Could not parse image {:?}. Error: {}", image.get_path(), e);
+                    tracing::warn!("{}", msg);
+                    progress.warn(msg);
+                    return;
                 }
-            });
+            };
+
+            if let Err(e) = s.send((id, phash_res)) {
+                tracing::warn!("failed to send result through channel, err: {}", e);
+            }
+        });
 
         let mut results = PHashResults::default();
         while let Ok((id, r)) = r.recv() {
             results.insert(id as u32, r);
         }
 
-        AppProcessResult::new(Images::from(images), results)
+        AppProcessResult::new(Images::from(images), results).with_ingest_report(ingest_report)
+    }
+    fn load_options(&self) -> LoadOptions {
+        self.image_parser.load_options()
     }
 }
 impl Default for RayonImagesProcessor {
@@ -102,9 +144,31 @@ impl DerefMut for PHashResults {
     }
 }
 impl PHashResults {
-    pub async fn send_to_db(&self, pool: &SqlitePool) -> Result<(), Error> {
-        let mut tx = pool.begin().await?;
-        for (id, res) in &self.results {
+    /// Stores the modified images and hashes, the ids used while processing are translated to
+    /// database ids with `ids`. Runs on the connection of the caller, so they can be stored in the
+    /// same transaction as the images of the batch.
+    pub async fn send_to_db(&self, conn: &mut SqliteConnection, ids: &RunIds) -> Result<(), Error> {
+        for (img_id, res) in &self.results {
+            let id = ids.image(*img_id)?;
+            let apply_orientation = res
+                .metadata()
+                .map(|m| m.load_options().get_apply_orientation());
+            if let Some(metadata) = res.metadata() {
+                sqlx::query(
+                    "
+                UPDATE images SET width = ?, height = ?, format = ?, color_type = ?, orientation = ?
+                WHERE id = ?;
+                ",
+                )
+                .bind(metadata.width())
+                .bind(metadata.height())
+                .bind(metadata.format().map(|f| format!("{:?}", f)))
+                .bind(format!("{:?}", metadata.color_type()))
+                .bind(metadata.orientation().tag())
+                .bind(id)
+                .execute(&mut *conn)
+                .await?;
+            }
             for hash in res.hashes.into_iter() {
                 let img = res.mod_imgs.get_img(hash.mod_img_id())?;
 
@@ -114,41 +178,133 @@ impl PHashResults {
                     img.get_mod_id()
                 );
 
+                let quality = img.get_quality();
                 let res: (i64,) = sqlx::query_as(
                     "
-                INSERT INTO modified_images ( image_id, modification_id) VALUES (?,?) 
+                INSERT INTO modified_images
+                (image_id, modification_id, psnr, ssim, mean_abs_diff, apply_orientation)
+                VALUES (?,?,?,?,?,?)
                 ON CONFLICT (image_id, modification_id) 
-                DO UPDATE SET modification_id = excluded.modification_id
+                DO UPDATE SET psnr = excluded.psnr,
+                    ssim = excluded.ssim,
+                    mean_abs_diff = excluded.mean_abs_diff,
+                    apply_orientation = excluded.apply_orientation
                 RETURNING id;
                 ",
                 )
                 .bind(id)
-                .bind(img.get_mod_id())
-                .fetch_one(&mut *tx)
+                .bind(ids.modification(img.get_mod_id())?)
+                .bind(quality.psnr())
+                .bind(quality.ssim())
+                .bind(quality.mean_abs_diff())
+                .bind(apply_orientation)
+                .fetch_one(&mut *conn)
                 .await?;
 
                 let mod_img_id = res.0;
 
-                sqlx::query(
-                    "
-                INSERT INTO hashes (hash, mod_image_id, hashing_method_id) VALUES (?,?,?) ON CONFLICT DO NOTHING;
-                ",
+                store_hash(
+                    &mut *conn,
+                    mod_img_id,
+                    ids.hashing_method(*hash.hash().hashing_method_id())?,
+                    hash.hash().hash(),
                 )
-                .bind(hash.hash().hash().to_string())
-                .bind(mod_img_id)
-                .bind(hash.hash().hashing_method_id())
-                .execute(&mut *tx)
                 .await?;
             }
         }
-        tx.commit().await?;
         Ok(())
     }
+    /// Stores the modified images and hashes in a `ResultStore`, the same way `send_to_db` does.
+    pub fn send_to_store(&self, store: &ResultStore, ids: &RunIds) -> Result<(), Error> {
+        store.write(|tables| {
+            for (img_id, res) in &self.results {
+                let id = ids.image(*img_id)?;
+                let apply_orientation = res
+                    .metadata()
+                    .map(|m| m.load_options().get_apply_orientation());
+                if let Some(metadata) = res.metadata() {
+                    tables.set_image_metadata(
+                        id,
+                        (metadata.width(), metadata.height()),
+                        metadata.format().map(|f| format!("{:?}", f)),
+                        format!("{:?}", metadata.color_type()),
+                        metadata.orientation().tag() as u32,
+                    );
+                }
+                for hash in res.hashes.into_iter() {
+                    let img = res.mod_imgs.get_img(hash.mod_img_id())?;
+                    let mod_img_id = tables.upsert_modified_image(
+                        id,
+                        ids.modification(img.get_mod_id())?,
+                        img.get_quality(),
+                        apply_orientation,
+                    );
+                    tables.insert_hash(
+                        mod_img_id,
+                        ids.hashing_method(*hash.hash().hashing_method_id())?,
+                        hash.hash().hash().to_bytes().to_vec(),
+                        hash.hash().hash().bit_len() as u32,
+                    );
+                }
+            }
+            Ok(())
+        })?
+    }
+}
+/// Stores the hash of a modified image. A modified image that is hashed again, because it was
+/// decoded with other load options than the stored hash, gets the new hash. Its matches are
+/// deleted, their distances are of the old hash.
+async fn store_hash(
+    conn: &mut SqliteConnection,
+    mod_img_id: i64,
+    hashing_method_id: i64,
+    hash: &image_hash::Hash,
+) -> Result<(), Error> {
+    let bytes = hash.to_bytes();
+    let stored: Option<(i64, Vec<u8>)> = sqlx::query_as(
+        "SELECT id, hash FROM hashes WHERE mod_image_id = ? AND hashing_method_id = ?;",
+    )
+    .bind(mod_img_id)
+    .bind(hashing_method_id)
+    .fetch_optional(&mut *conn)
+    .await?;
+    match stored {
+        None => {
+            sqlx::query(
+                "
+                INSERT INTO hashes (hash, hash_bits, mod_image_id, hashing_method_id)
+                VALUES (?,?,?,?);
+                ",
+            )
+            .bind(bytes)
+            .bind(hash.bit_len() as u32)
+            .bind(mod_img_id)
+            .bind(hashing_method_id)
+            .execute(&mut *conn)
+            .await?;
+        }
+        Some((_, stored)) if stored.as_slice() == bytes => {}
+        Some((id, _)) => {
+            sqlx::query("UPDATE hashes SET hash = ?, hash_bits = ? WHERE id = ?;")
+                .bind(bytes)
+                .bind(hash.bit_len() as u32)
+                .bind(id)
+                .execute(&mut *conn)
+                .await?;
+            sqlx::query("DELETE FROM matches WHERE hash1_id = ?1 OR hash2_id = ?1;")
+                .bind(id)
+                .execute(&mut *conn)
+                .await?;
+        }
+    }
+    Ok(())
 }
+
 #[derive(Default)]
 pub struct PHashResult {
     mod_imgs: ModifiedImages,
     hashes: Hashes,
+    metadata: Option<ImageMetadata>,
 }
 
 impl PHashResult {
@@ -165,7 +321,18 @@ impl PHashResult {
         &mut self.hashes
     }
     pub fn new(mod_imgs: ModifiedImages, hashes: Hashes) -> Self {
-        Self { mod_imgs, hashes }
+        Self {
+            mod_imgs,
+            hashes,
+            metadata: None,
+        }
+    }
+    /// Properties of the original image.
+    pub fn metadata(&self) -> Option<&ImageMetadata> {
+        self.metadata.as_ref()
+    }
+    pub fn set_metadata(&mut self, metadata: ImageMetadata) {
+        self.metadata = Some(metadata)
     }
     pub fn set_mod_imgs(&mut self, mod_imgs: ModifiedImages) {
         self.mod_imgs = mod_imgs
@@ -0,0 +1,74 @@
+use crate::image_parse::{
+    self, Deduplicator, DuplicatePolicy, Image, Images, IngestReport, Sampling, SkippedImage, Split,
+};
+
+/// The images of a run in the order they are found, read one at a time. Files that are skipped
+/// and duplicates are recorded on the way and can be taken with `into_report` once the images
+/// are exhausted.
+pub struct Ingest {
+    images: Images,
+    deduplicator: Deduplicator,
+    report: IngestReport,
+    sampled: Option<std::vec::IntoIter<Image>>,
+}
+impl Ingest {
+    pub fn new(images: Images, policy: DuplicatePolicy) -> Self {
+        Self {
+            images,
+            deduplicator: Deduplicator::new(policy),
+            report: IngestReport::new(),
+            sampled: None,
+        }
+    }
+    /// Applies the sampling and split. Both need every image of the run, so the images are read
+    /// up front if either is given, only their paths and digests are kept until they are taken.
+    pub fn sample(mut self, sampling: Option<&Sampling>, split: Option<&Split>) -> Self {
+        if sampling.is_none() && split.is_none() {
+            return self;
+        }
+        let images: Vec<Image> = self.by_ref().collect();
+        let images = match sampling {
+            Some(sampling) => sampling.apply(images),
+            None => images,
+        };
+        let images = match split {
+            Some(split) => split.apply(images),
+            None => images,
+        };
+        self.sampled = Some(images.into_iter());
+        self
+    }
+    /// Takes the next `n` images, fewer once the images are exhausted.
+    pub fn take_batch(&mut self, n: usize) -> Vec<Image> {
+        self.by_ref().take(n).collect()
+    }
+    /// Files skipped and duplicates found, complete once the images are exhausted.
+    pub fn into_report(mut self) -> IngestReport {
+        self.report
+            .add_duplicates(self.deduplicator.into_duplicates());
+        self.report
+    }
+}
+impl Iterator for Ingest {
+    type Item = Image;
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(sampled) = &mut self.sampled {
+            return sampled.next();
+        }
+        for r in self.images.by_ref() {
+            match r {
+                Ok(image) => {
+                    if let Some(image) = self.deduplicator.check(image) {
+                        return Some(image);
+                    }
+                }
+                Err(image_parse::Error::Skipped { path, reason }) => {
+                    tracing::info!("Skipping file {:?}: {}", path, reason);
+                    self.report.push(SkippedImage::new(path, reason));
+                }
+                Err(e) => tracing::warn!("An image failed to process: {}", e),
+            }
+        }
+        None
+    }
+}
@@ -1,23 +1,49 @@
 use async_trait::async_trait;
 use chrono::Utc;
-use indicatif::{ProgressBar, ProgressIterator, ProgressStyle};
 use sqlx::SqlitePool;
 
 use crate::{
-    core::{error::Error, state::AppProcessResult},
+    core::{
+        error::Error,
+        snapshot::RunSnapshot,
+        state::{AppProcessResult, ExistingHashes, RunIds},
+    },
     db::DB,
-    image_hash::{HashingMethods, SelectedHashingMethods},
-    image_modify::{Modifications, SelectedModifications},
+    image_hash::SelectedHashingMethods,
+    image_modify::SelectedModifications,
+    image_parse::{Image, LoadOptions},
+    store::ResultStore,
 };
 
 #[async_trait]
 pub trait ResultParser: Send + Sync {
-    async fn parse(
+    /// Starts a run and returns its ids, with the ids the modifications and hashing methods are
+    /// stored with. The images are stored with `parse`.
+    async fn begin(
         &self,
-        results: AppProcessResult,
         modifications: &SelectedModifications,
         hashing_methods: &SelectedHashingMethods,
-    ) -> Result<(), Error>;
+    ) -> Result<RunIds, Error>;
+    /// Stores a batch of images of the run with their results, called as soon as the batch is
+    /// hashed. Batches stored before a run fails or is cancelled are kept.
+    async fn parse(&self, ids: &RunIds, results: AppProcessResult) -> Result<(), Error>;
+    /// Hashes of a batch of images that were stored by earlier runs and does not have to be
+    /// computed again, made from images decoded with `load_options`. Parsers
+    /// that can not look up earlier results makes every run compute all hashes.
+    async fn existing_hashes(
+        &self,
+        _images: &[Image],
+        _modifications: &SelectedModifications,
+        _hashing_methods: &SelectedHashingMethods,
+        _load_options: LoadOptions,
+    ) -> Result<ExistingHashes, Error> {
+        Ok(ExistingHashes::default())
+    }
+    /// Stores what the run was configured with. Called when the run is finished, also when it
+    /// failed after `begin`.
+    async fn save_snapshot(&self, _ids: &RunIds, _snapshot: &RunSnapshot) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
 pub struct SqliteResultParser {
@@ -25,18 +51,17 @@ pub struct SqliteResultParser {
 }
 impl SqliteResultParser {
     pub fn new(pool: SqlitePool) -> Self {
-        Self { pool: pool }
+        Self { pool }
     }
 }
 impl ResultParser for SqliteResultParser {
-    fn parse<'life0, 'life1, 'life2, 'async_trait>(
+    fn begin<'life0, 'life1, 'life2, 'async_trait>(
         &'life0 self,
-        results: AppProcessResult,
         modifications: &'life1 SelectedModifications,
         hashing_methods: &'life2 SelectedHashingMethods,
     ) -> ::core::pin::Pin<
         Box<
-            dyn ::core::future::Future<Output = Result<(), Error>>
+            dyn ::core::future::Future<Output = Result<RunIds, Error>>
                 + ::core::marker::Send
                 + 'async_trait,
         >,
@@ -48,50 +73,143 @@ impl ResultParser for SqliteResultParser {
         Self: 'async_trait,
     {
         Box::pin(async move {
-            DB::create_db().await?;
-            send_hashes_to_db(&self.pool, hashing_methods).await?;
-            send_modifications_to_db(&self.pool, modifications).await?;
+            DB::migrate(&self.pool).await?;
 
             let run_id = create_run(&self.pool).await?;
             create_program(&self.pool, run_id).await?;
 
-            let style = ProgressStyle::with_template(
-                "[{elapsed_precise} | {eta_precise}] Sending results to DB: {pos:>7}/{len:7} {percent}%",
-            )
-            .unwrap()
-            .progress_chars("##-");
-
             let mut tx = self.pool.begin().await?;
-            for (id, img) in results
-                .images()
-                .iter()
-                .enumerate()
-                .progress_with_style(style)
-            {
-                let path_str = img.get_path().to_string_lossy().to_string();
+            let ids = RunIds::get_or_insert(&mut tx, &[], modifications, hashing_methods).await?;
+            tx.commit().await?;
+            Ok(ids.with_run(run_id))
+        })
+    }
+    fn parse<'life0, 'life1, 'async_trait>(
+        &'life0 self,
+        ids: &'life1 RunIds,
+        results: AppProcessResult,
+    ) -> ::core::pin::Pin<
+        Box<
+            dyn ::core::future::Future<Output = Result<(), Error>>
+                + ::core::marker::Send
+                + 'async_trait,
+        >,
+    >
+    where
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(async move {
+            let Some(run_id) = ids.run_id() else {
+                return Err(Error::RunNotStarted);
+            };
+            let mut tx = self.pool.begin().await?;
+            let ids = ids.with_batch(&mut tx, results.images()).await?;
+            for (id, img) in results.images().iter().enumerate() {
                 sqlx::query(
                     "
-                INSERT INTO images (id, path, user) VALUES (?, ?, ?) ON CONFLICT(id) DO NOTHING;
+                    INSERT INTO run_images (run_id, image_id, partition) VALUES (?, ?, ?);
                     ",
                 )
-                .bind(id as u32)
-                .bind(path_str)
-                .bind(img.get_user())
+                .bind(run_id)
+                .bind(ids.image(id as u32)?)
+                .bind(img.get_partition().map(|p| p.to_string()))
                 .execute(&mut *tx)
                 .await?;
-
+            }
+            for skipped in results.ingest_report().skipped() {
+                sqlx::query(
+                    "
+                    INSERT INTO skipped_images (run_id, path, reason) VALUES (?, ?, ?);
+                    ",
+                )
+                .bind(run_id)
+                .bind(skipped.get_path().to_string_lossy().to_string())
+                .bind(skipped.get_reason().to_string())
+                .execute(&mut *tx)
+                .await?;
+            }
+            // Merged duplicates are aliases of the original, which can be in any batch of the
+            // run that is already stored.
+            for duplicate in results.ingest_report().duplicates() {
                 sqlx::query(
                     "
-                    INSERT INTO run_images (run_id, image_id) VALUES (?, ?);
+                    INSERT INTO duplicate_images (run_id, image_id, digest, path, original_path, policy)
+                    VALUES (?1, CASE WHEN ?6 THEN (
+                        SELECT i.id FROM images i
+                        JOIN run_images ri ON ri.image_id = i.id AND ri.run_id = ?1
+                        WHERE i.path = ?4 AND i.digest IS ?2
+                    ) END, ?2, ?3, ?4, ?5);
                     ",
                 )
                 .bind(run_id)
-                .bind(id as u32)
+                .bind(duplicate.get_digest())
+                .bind(duplicate.get_path().to_string_lossy().to_string())
+                .bind(duplicate.get_original().to_string_lossy().to_string())
+                .bind(duplicate.get_policy().to_string())
+                .bind(duplicate.is_merged())
                 .execute(&mut *tx)
                 .await?;
             }
+            results.phash_results().send_to_db(&mut tx, &ids).await?;
+            tx.commit().await?;
+            Ok(())
+        })
+    }
+    fn existing_hashes<'life0, 'life1, 'life2, 'life3, 'async_trait>(
+        &'life0 self,
+        images: &'life1 [Image],
+        modifications: &'life2 SelectedModifications,
+        hashing_methods: &'life3 SelectedHashingMethods,
+        load_options: LoadOptions,
+    ) -> ::core::pin::Pin<
+        Box<
+            dyn ::core::future::Future<Output = Result<ExistingHashes, Error>>
+                + ::core::marker::Send
+                + 'async_trait,
+        >,
+    >
+    where
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+        'life2: 'async_trait,
+        'life3: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(async move {
+            let mut conn = self.pool.acquire().await?;
+            ExistingHashes::fetch(
+                &mut conn,
+                images,
+                modifications,
+                hashing_methods,
+                load_options,
+            )
+            .await
+        })
+    }
+    fn save_snapshot<'life0, 'life1, 'life2, 'async_trait>(
+        &'life0 self,
+        ids: &'life1 RunIds,
+        snapshot: &'life2 RunSnapshot,
+    ) -> ::core::pin::Pin<
+        Box<
+            dyn ::core::future::Future<Output = Result<(), Error>>
+                + ::core::marker::Send
+                + 'async_trait,
+        >,
+    >
+    where
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+        'life2: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(async move {
+            let mut tx = self.pool.begin().await?;
+            snapshot.insert(&mut tx, ids).await?;
             tx.commit().await?;
-            results.phash_results().send_to_db(&self.pool).await?;
             Ok(())
         })
     }
@@ -121,43 +239,165 @@ async fn create_run(pool: &SqlitePool) -> Result<i64, Error> {
     Ok(res.last_insert_rowid())
 }
 
-async fn send_modifications_to_db<'a>(
-    pool: &SqlitePool,
-    modifications: &SelectedModifications<'a>,
-) -> Result<(), Error> {
-    let mut tx = pool.begin().await?;
-    for (i, modification) in modifications.iter().enumerate() {
-        let name = modification.name();
-        sqlx::query(
-            "
-            INSERT INTO modifications (id, name) VALUES (?,?) ON CONFLICT(id) DO NOTHING;
-            ",
-        )
-        .bind(i as u32)
-        .bind(name)
-        .execute(&mut *tx)
-        .await?;
+/// Stores results in a `ResultStore` instead of a database, in memory or as JSON-lines files.
+pub struct StoreResultParser {
+    store: ResultStore,
+}
+impl StoreResultParser {
+    pub fn new(store: ResultStore) -> Self {
+        Self { store }
     }
-    tx.commit().await?;
-    Ok(())
 }
-
-async fn send_hashes_to_db<'a>(
-    pool: &SqlitePool,
-    hashing_methods: &SelectedHashingMethods<'a>,
-) -> Result<(), sqlx::Error> {
-    let mut tx = pool.begin().await?;
-    for (id, obj) in hashing_methods.iter().enumerate() {
-        sqlx::query(
-            "
-                INSERT INTO hashing_methods (id, name) VALUES (?,?) ON CONFLICT(id) DO NOTHING;
-                ",
-        )
-        .bind(id as u32)
-        .bind(obj.name())
-        .execute(&mut *tx)
-        .await?;
+impl ResultParser for StoreResultParser {
+    fn begin<'life0, 'life1, 'life2, 'async_trait>(
+        &'life0 self,
+        modifications: &'life1 SelectedModifications,
+        hashing_methods: &'life2 SelectedHashingMethods,
+    ) -> ::core::pin::Pin<
+        Box<
+            dyn ::core::future::Future<Output = Result<RunIds, Error>>
+                + ::core::marker::Send
+                + 'async_trait,
+        >,
+    >
+    where
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+        'life2: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(async move {
+            let ids =
+                RunIds::get_or_insert_in_store(&self.store, &[], modifications, hashing_methods)?;
+            let run_id = self.store.write(|t| {
+                let run_id = t.insert_run(Utc::now().timestamp_millis());
+                t.set_active_run(run_id);
+                run_id
+            })?;
+            Ok(ids.with_run(run_id))
+        })
+    }
+    fn parse<'life0, 'life1, 'async_trait>(
+        &'life0 self,
+        ids: &'life1 RunIds,
+        results: AppProcessResult,
+    ) -> ::core::pin::Pin<
+        Box<
+            dyn ::core::future::Future<Output = Result<(), Error>>
+                + ::core::marker::Send
+                + 'async_trait,
+        >,
+    >
+    where
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(async move {
+            let Some(run_id) = ids.run_id() else {
+                return Err(Error::RunNotStarted);
+            };
+            let ids = ids.with_batch_in_store(&self.store, results.images())?;
+            self.store.write(|t| -> Result<(), Error> {
+                for (id, img) in results.images().iter().enumerate() {
+                    let id = ids.image(id as u32)?;
+                    t.insert_run_image(run_id, id, img.get_partition().map(|p| p.to_string()));
+                }
+                for skipped in results.ingest_report().skipped() {
+                    t.insert_skipped_image(
+                        run_id,
+                        skipped.get_path().to_string_lossy().to_string(),
+                        skipped.get_reason().to_string(),
+                    );
+                }
+                // Merged duplicates are aliases of the original, which can be in any batch of the
+                // run that is already stored.
+                for duplicate in results.ingest_report().duplicates() {
+                    let original = duplicate
+                        .is_merged()
+                        .then(|| {
+                            t.find_image_id(
+                                &duplicate.get_original().to_string_lossy(),
+                                Some(duplicate.get_digest()),
+                            )
+                        })
+                        .flatten()
+                        .filter(|id| {
+                            t.run_images
+                                .rows()
+                                .iter()
+                                .any(|ri| ri.get_run_id() == run_id && ri.get_image_id() == *id)
+                        });
+                    t.insert_duplicate_image(
+                        run_id,
+                        original,
+                        duplicate.get_digest().to_string(),
+                        duplicate.get_path().to_string_lossy().to_string(),
+                        duplicate.get_original().to_string_lossy().to_string(),
+                        duplicate.get_policy().to_string(),
+                    );
+                }
+                Ok(())
+            })??;
+            results.phash_results().send_to_store(&self.store, &ids)?;
+            Ok(())
+        })
+    }
+    fn existing_hashes<'life0, 'life1, 'life2, 'life3, 'async_trait>(
+        &'life0 self,
+        images: &'life1 [Image],
+        modifications: &'life2 SelectedModifications,
+        hashing_methods: &'life3 SelectedHashingMethods,
+        load_options: LoadOptions,
+    ) -> ::core::pin::Pin<
+        Box<
+            dyn ::core::future::Future<Output = Result<ExistingHashes, Error>>
+                + ::core::marker::Send
+                + 'async_trait,
+        >,
+    >
+    where
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+        'life2: 'async_trait,
+        'life3: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(async move {
+            ExistingHashes::from_store(
+                &self.store,
+                images,
+                modifications,
+                hashing_methods,
+                load_options,
+            )
+        })
+    }
+    fn save_snapshot<'life0, 'life1, 'life2, 'async_trait>(
+        &'life0 self,
+        ids: &'life1 RunIds,
+        snapshot: &'life2 RunSnapshot,
+    ) -> ::core::pin::Pin<
+        Box<
+            dyn ::core::future::Future<Output = Result<(), Error>>
+                + ::core::marker::Send
+                + 'async_trait,
+        >,
+    >
+    where
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+        'life2: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(async move {
+            let Some(run_id) = ids.run_id() else {
+                tracing::warn!("The run was not stored, skipping its snapshot");
+                return Ok(());
+            };
+            let snapshot = snapshot.clone().with_run_id(run_id);
+            self.store.write(|t| t.insert_snapshot(snapshot))?;
+            Ok(())
+        })
     }
-    tx.commit().await?;
-    Ok(())
 }
@@ -0,0 +1,420 @@
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{SqliteConnection, SqlitePool, prelude::FromRow};
+
+use crate::{
+    core::{error::Error, state::RunIds},
+    image_hash::SelectedHashingMethods,
+    image_modify::SelectedModifications,
+};
+
+/// Everything a run was configured with, stored with the run so runs can be reproduced and
+/// compared without making them the active run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunSnapshot {
+    run_id: Option<i64>,
+    image_root: PathBuf,
+    sampling_seed: Option<u64>,
+    split_seed: Option<u64>,
+    crate_version: String,
+    threads: usize,
+    modifications: Vec<MethodSnapshot>,
+    hashing_methods: Vec<MethodSnapshot>,
+    stages: Vec<StageTiming>,
+}
+impl RunSnapshot {
+    /// Snapshot of a run on this host with this version of the crate.
+    pub fn new(image_root: impl Into<PathBuf>) -> Self {
+        Self {
+            run_id: None,
+            image_root: image_root.into(),
+            sampling_seed: None,
+            split_seed: None,
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            threads: rayon::current_num_threads(),
+            modifications: Vec::new(),
+            hashing_methods: Vec::new(),
+            stages: Vec::new(),
+        }
+    }
+    pub fn with_sampling_seed(mut self, seed: Option<u64>) -> Self {
+        self.sampling_seed = seed;
+        self
+    }
+    pub fn with_split_seed(mut self, seed: Option<u64>) -> Self {
+        self.split_seed = seed;
+        self
+    }
+    pub fn with_modifications(mut self, modifications: &SelectedModifications) -> Self {
+        self.modifications = modifications
+            .iter()
+            .map(|m| MethodSnapshot::new(m.name(), m.params()))
+            .collect();
+        self
+    }
+    pub fn with_hashing_methods(mut self, hashing_methods: &SelectedHashingMethods) -> Self {
+        self.hashing_methods = hashing_methods
+            .iter()
+            .map(|m| MethodSnapshot::new(m.name(), m.params()))
+            .collect();
+        self
+    }
+    /// Sets the id of the run the snapshot is stored for.
+    pub(crate) fn with_run_id(mut self, run_id: i64) -> Self {
+        self.run_id = Some(run_id);
+        self
+    }
+    pub fn push_stage(&mut self, timing: StageTiming) {
+        self.stages.push(timing);
+    }
+    /// Id of the run, `None` until the snapshot is stored.
+    pub fn get_run_id(&self) -> Option<i64> {
+        self.run_id
+    }
+    pub fn get_image_root(&self) -> &Path {
+        &self.image_root
+    }
+    pub fn get_sampling_seed(&self) -> Option<u64> {
+        self.sampling_seed
+    }
+    pub fn get_split_seed(&self) -> Option<u64> {
+        self.split_seed
+    }
+    pub fn get_crate_version(&self) -> &str {
+        &self.crate_version
+    }
+    /// Number of threads in the global rayon pool.
+    pub fn get_threads(&self) -> usize {
+        self.threads
+    }
+    pub fn get_modifications(&self) -> &[MethodSnapshot] {
+        &self.modifications
+    }
+    pub fn get_hashing_methods(&self) -> &[MethodSnapshot] {
+        &self.hashing_methods
+    }
+    pub fn get_stages(&self) -> &[StageTiming] {
+        &self.stages
+    }
+    /// Wall-clock time from when the first stage started until the last one finished. Stages
+    /// overlap, so it is less than the sum of their durations.
+    pub fn total_duration(&self) -> Duration {
+        let started_at = self.stages.iter().map(|s| s.started_at).min();
+        let finished_at = self.stages.iter().map(|s| s.get_finished_at()).max();
+        match started_at.zip(finished_at) {
+            Some((started_at, finished_at)) => {
+                (finished_at - started_at).to_std().unwrap_or_default()
+            }
+            None => Duration::ZERO,
+        }
+    }
+
+    /// Stores the snapshot for the run the ids belong to. The modifications and hashing methods
+    /// are stored by the ids they were given in the run.
+    pub(crate) async fn insert(
+        &self,
+        conn: &mut SqliteConnection,
+        ids: &RunIds,
+    ) -> Result<(), Error> {
+        let Some(run_id) = ids.run_id() else {
+            tracing::warn!("The run was not stored, skipping its snapshot");
+            return Ok(());
+        };
+        sqlx::query(
+            "
+            INSERT OR REPLACE INTO run_config
+            (run_id, image_root, sampling_seed, split_seed, crate_version, threads)
+            VALUES (?, ?, ?, ?, ?, ?);
+            ",
+        )
+        .bind(run_id)
+        .bind(self.image_root.to_string_lossy().to_string())
+        .bind(self.sampling_seed.map(|s| s as i64))
+        .bind(self.split_seed.map(|s| s as i64))
+        .bind(&self.crate_version)
+        .bind(self.threads as i64)
+        .execute(&mut *conn)
+        .await?;
+
+        for (position, id) in ids.modification_ids().iter().enumerate() {
+            sqlx::query(
+                "
+                INSERT OR IGNORE INTO run_modifications (run_id, modification_id, position)
+                VALUES (?, ?, ?);
+                ",
+            )
+            .bind(run_id)
+            .bind(id)
+            .bind(position as i64)
+            .execute(&mut *conn)
+            .await?;
+        }
+        for (position, id) in ids.hashing_method_ids().iter().enumerate() {
+            sqlx::query(
+                "
+                INSERT OR IGNORE INTO run_hashing_methods (run_id, hashing_method_id, position)
+                VALUES (?, ?, ?);
+                ",
+            )
+            .bind(run_id)
+            .bind(id)
+            .bind(position as i64)
+            .execute(&mut *conn)
+            .await?;
+        }
+        for stage in &self.stages {
+            sqlx::query(
+                "
+                INSERT OR REPLACE INTO run_stages (run_id, stage, started_at, duration_ms)
+                VALUES (?, ?, ?, ?);
+                ",
+            )
+            .bind(run_id)
+            .bind(stage.stage.to_string())
+            .bind(stage.started_at.timestamp_millis())
+            .bind(stage.duration.as_millis() as i64)
+            .execute(&mut *conn)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Snapshot of the given run, `None` for runs stored without one.
+    pub async fn fetch(pool: &SqlitePool, run_id: i64) -> Result<Option<Self>, Error> {
+        let config: Option<RunConfig> = sqlx::query_as(
+            "
+            SELECT image_root, sampling_seed, split_seed, crate_version, threads
+            FROM run_config WHERE run_id = ?;
+            ",
+        )
+        .bind(run_id)
+        .fetch_optional(pool)
+        .await?;
+        let Some(config) = config else {
+            return Ok(None);
+        };
+
+        let modifications: Vec<(String, String)> = sqlx::query_as(
+            "
+            SELECT m.name, m.params
+            FROM run_modifications rm JOIN modifications m ON m.id = rm.modification_id
+            WHERE rm.run_id = ? ORDER BY rm.position;
+            ",
+        )
+        .bind(run_id)
+        .fetch_all(pool)
+        .await?;
+        let hashing_methods: Vec<(String, String)> = sqlx::query_as(
+            "
+            SELECT hm.name, hm.params
+            FROM run_hashing_methods rh JOIN hashing_methods hm ON hm.id = rh.hashing_method_id
+            WHERE rh.run_id = ? ORDER BY rh.position;
+            ",
+        )
+        .bind(run_id)
+        .fetch_all(pool)
+        .await?;
+        let stages: Vec<(String, i64, i64)> = sqlx::query_as(
+            "
+            SELECT stage, started_at, duration_ms FROM run_stages
+            WHERE run_id = ? ORDER BY started_at;
+            ",
+        )
+        .bind(run_id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(Some(Self {
+            run_id: Some(run_id),
+            image_root: PathBuf::from(config.image_root),
+            sampling_seed: config.sampling_seed.map(|s| s as u64),
+            split_seed: config.split_seed.map(|s| s as u64),
+            crate_version: config.crate_version,
+            threads: config.threads as usize,
+            modifications: modifications
+                .into_iter()
+                .map(|(name, params)| MethodSnapshot::new(name, params))
+                .collect(),
+            hashing_methods: hashing_methods
+                .into_iter()
+                .map(|(name, params)| MethodSnapshot::new(name, params))
+                .collect(),
+            stages: stages
+                .into_iter()
+                .filter_map(|(stage, started_at, duration_ms)| {
+                    Some(StageTiming {
+                        stage: stage.parse().ok()?,
+                        started_at: DateTime::from_timestamp_millis(started_at)?,
+                        duration: Duration::from_millis(duration_ms as u64),
+                    })
+                })
+                .collect(),
+        }))
+    }
+
+    /// Snapshots of all runs that have one, oldest first.
+    pub async fn fetch_all(pool: &SqlitePool) -> Result<Vec<Self>, Error> {
+        let run_ids: Vec<(i64,)> = sqlx::query_as("SELECT run_id FROM run_config ORDER BY run_id;")
+            .fetch_all(pool)
+            .await?;
+        let mut snapshots = Vec::with_capacity(run_ids.len());
+        for (run_id,) in run_ids {
+            if let Some(snapshot) = Self::fetch(pool, run_id).await? {
+                snapshots.push(snapshot);
+            }
+        }
+        Ok(snapshots)
+    }
+}
+
+#[derive(FromRow)]
+struct RunConfig {
+    image_root: String,
+    sampling_seed: Option<i64>,
+    split_seed: Option<i64>,
+    crate_version: String,
+    threads: i64,
+}
+
+/// Name and parameters of a modification or hashing method used in a run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MethodSnapshot {
+    name: String,
+    params: String,
+}
+impl MethodSnapshot {
+    pub fn new(name: impl Into<String>, params: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            params: params.into(),
+        }
+    }
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+    pub fn get_params(&self) -> &str {
+        &self.params
+    }
+}
+impl Display for MethodSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.params.is_empty() {
+            true => write!(f, "{}", self.name),
+            false => write!(f, "{}({})", self.name, self.params),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Stage {
+    /// Finding, loading, deduplicating and sampling the images.
+    Ingest,
+    Hashing,
+    /// Writing the images and hashes to the results.
+    Storing,
+    Matching,
+    /// Classifying stored matches for a ROC, outside of runs.
+    Roc,
+}
+impl Display for Stage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ingest => write!(f, "ingest"),
+            Self::Hashing => write!(f, "hashing"),
+            Self::Storing => write!(f, "storing"),
+            Self::Matching => write!(f, "matching"),
+            Self::Roc => write!(f, "roc"),
+        }
+    }
+}
+impl FromStr for Stage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ingest" => Ok(Self::Ingest),
+            "hashing" => Ok(Self::Hashing),
+            "storing" => Ok(Self::Storing),
+            "matching" => Ok(Self::Matching),
+            "roc" => Ok(Self::Roc),
+            _ => Err(format!("unknown stage {}", s)),
+        }
+    }
+}
+
+/// Wall-clock time spent in a stage of a run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StageTiming {
+    stage: Stage,
+    started_at: DateTime<Utc>,
+    duration: Duration,
+}
+impl StageTiming {
+    pub fn get_stage(&self) -> Stage {
+        self.stage
+    }
+    pub fn get_started_at(&self) -> DateTime<Utc> {
+        self.started_at
+    }
+    pub fn get_duration(&self) -> Duration {
+        self.duration
+    }
+    pub fn get_finished_at(&self) -> DateTime<Utc> {
+        self.started_at + TimeDelta::from_std(self.duration).unwrap_or(TimeDelta::MAX)
+    }
+}
+
+/// Measures a stage from when it is started until it is finished.
+pub struct StageTimer {
+    stage: Stage,
+    started_at: DateTime<Utc>,
+    start: Instant,
+}
+impl StageTimer {
+    pub fn start(stage: Stage) -> Self {
+        Self {
+            stage,
+            started_at: Utc::now(),
+            start: Instant::now(),
+        }
+    }
+    pub fn finish(self) -> StageTiming {
+        StageTiming {
+            stage: self.stage,
+            started_at: self.started_at,
+            duration: self.start.elapsed(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timing(stage: Stage, started_at_ms: i64, duration_ms: u64) -> StageTiming {
+        StageTiming {
+            stage,
+            started_at: DateTime::from_timestamp_millis(started_at_ms).unwrap(),
+            duration: Duration::from_millis(duration_ms),
+        }
+    }
+
+    #[test]
+    fn total_duration_is_the_wall_clock_time_of_the_stages() {
+        let mut snapshot = RunSnapshot::new("images");
+        assert_eq!(snapshot.total_duration(), Duration::ZERO);
+        // Hashing and storing run at the same time as the images are read.
+        snapshot.push_stage(timing(Stage::Ingest, 1_000, 3_000));
+        snapshot.push_stage(timing(Stage::Hashing, 1_500, 3_000));
+        snapshot.push_stage(timing(Stage::Storing, 2_000, 3_000));
+        snapshot.push_stage(timing(Stage::Matching, 5_000, 1_000));
+        assert_eq!(snapshot.total_duration(), Duration::from_millis(5_000));
+    }
+}
@@ -1,86 +1,313 @@
 use std::{
-    ops::{Deref, DerefMut}, path::PathBuf, sync::{Arc, mpsc}
+    collections::{HashMap, HashSet},
+    io,
+    ops::{Deref, DerefMut},
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
-use sqlx::SqlitePool;
+use serde::{Deserialize, Serialize};
+use sqlx::{QueryBuilder, Sqlite, SqliteConnection, SqlitePool};
+use tokio::sync::RwLock;
 
 use crate::{
+    cancel::CancellationToken,
     core::{
         error::Error,
         images_processor::{PHashResult, PHashResults},
     },
-    image_hash::{self, HashingMethods},
-    image_modify::{self, Modifications},
-    image_parse,
+    db,
+    events::{Event, EventBus},
+    image_hash::{self, HashingMethods, SelectedHashingMethods},
+    image_modify::{self, Modifications, SelectedModifications},
+    image_parse::{
+        self, DuplicatePolicy, ImageFilter, ImageSource, IngestReport, LoadOptions, Orientation,
+        Sampling, Split,
+    },
+    store::ResultStore,
 };
+
+/// Images hashed and stored together when `set_batch_size` has not been called.
+pub const DEFAULT_BATCH_SIZE: usize = 64;
+
+/// What the next run uses and whether a run is in progress. Reads and updates wait on an async
+/// lock instead of blocking the thread, so HTTP handlers can use it directly. Clones share the
+/// same state.
+#[derive(Clone)]
 pub struct AppState {
-    handler: mpsc::Sender<Message>,
+    hashes: Arc<HashingMethods>,
+    modifications: Arc<Modifications>,
+    settings: Arc<RwLock<Settings>>,
+    events: EventBus,
 }
 impl AppState {
-    pub fn new(hashes: HashingMethods, modifications: Modifications) -> Self {
-        let handler = AppStateBuilder::new(hashes, modifications).spawn_app_state();
-        Self { handler }
+    /// State with the image root runs use until `set_path` changes it.
+    pub fn new(
+        path: impl Into<PathBuf>,
+        hashes: HashingMethods,
+        modifications: Modifications,
+    ) -> Self {
+        let settings = Settings {
+            image_source: ImageSource::Directory(path.into()),
+            ..Default::default()
+        };
+        Self {
+            hashes: Arc::new(hashes),
+            modifications: Arc::new(modifications),
+            settings: Arc::new(RwLock::new(settings)),
+            events: EventBus::new(),
+        }
+    }
+    /// Bus the progress and outcome of runs are sent to.
+    pub fn events(&self) -> &EventBus {
+        &self.events
     }
     pub fn hashing_methods(&self) -> Arc<HashingMethods> {
-        let (tx, rx) = oneshot::channel();
-        self.handler.send(Message::HashingMethods(tx));
-        let res = rx.recv().unwrap();
-        res
+        Arc::clone(&self.hashes)
     }
     pub fn modifications(&self) -> Arc<Modifications> {
-        let (tx, rx) = oneshot::channel();
-        self.handler.send(Message::Modifications(tx));
-        let res = rx.recv().unwrap();
-        res
+        Arc::clone(&self.modifications)
     }
 
-    pub fn set_run_hashes(&self, ids: Vec<usize>) {
-        self.handler.send(Message::SetRunHashes(ids));
+    /// Selects the hashing methods of the next run by their index in `hashing_methods`.
+    pub async fn set_run_hashes(&self, ids: Vec<usize>) -> Result<(), Error> {
+        if let Some(id) = ids.iter().find(|id| **id >= self.hashes.len()) {
+            return Err(Error::HashingMethodNotFound { id: *id });
+        }
+        self.update(|s| s.run_hashes = ids).await
+    }
+    /// Selects the modifications of the next run by their index in `modifications`.
+    pub async fn set_run_modifications(&self, ids: Vec<usize>) -> Result<(), Error> {
+        if let Some(id) = ids.iter().find(|id| **id >= self.modifications.len()) {
+            return Err(Error::ModificationNotFound { id: *id });
+        }
+        self.update(|s| s.run_modifications = ids).await
+    }
+    pub async fn get_run_hashes(&self) -> Vec<usize> {
+        self.settings.read().await.run_hashes.clone()
+    }
+    pub async fn get_run_modifications(&self) -> Vec<usize> {
+        self.settings.read().await.run_modifications.clone()
+    }
+    pub async fn get_running_state(&self) -> RunningState {
+        self.settings.read().await.state.clone()
+    }
+    pub async fn set_running_state(&self, state: RunningState) {
+        self.settings.write().await.state = state;
     }
-    pub fn set_run_modifications(&self, ids: Vec<usize>) {
-        self.handler.send(Message::SetRunModifications(ids));
+    /// Marks a run as started, fails if one is already running. The run is `Running` until the
+    /// returned guard is finished or dropped.
+    pub async fn start_run(&self) -> Result<RunGuard, Error> {
+        let mut settings = self.settings.write().await;
+        if let RunningState::Running = settings.state {
+            return Err(Error::AppAlreadyRunning);
+        }
+        settings.state = RunningState::Running;
+        settings.cancel = CancellationToken::new();
+        Ok(RunGuard {
+            settings: Arc::clone(&self.settings),
+            cancel: settings.cancel.clone(),
+            events: self.events.clone(),
+            finished: false,
+        })
+    }
+    /// Asks the current run to stop at its next stop point. Returns false if nothing is running.
+    pub async fn cancel(&self) -> bool {
+        let settings = self.settings.read().await;
+        match settings.state {
+            RunningState::Running => {
+                settings.cancel.cancel();
+                true
+            }
+            _ => false,
+        }
+    }
+    /// Reads the images of the next run from a directory, fails unless it is a readable
+    /// directory.
+    pub async fn set_path(&self, path: impl Into<PathBuf>) -> Result<(), Error> {
+        self.set_image_source(ImageSource::Directory(path.into()))
+            .await
+    }
+    /// Image root of the next run, see `ImageSource::get_root`.
+    pub async fn get_path(&self) -> PathBuf {
+        self.settings
+            .read()
+            .await
+            .image_source
+            .get_root()
+            .to_path_buf()
+    }
+    /// Sets where the images of the next run are read from, fails unless the directories and
+    /// files of the source can be read.
+    pub async fn set_image_source(&self, source: ImageSource) -> Result<(), Error> {
+        check_image_source(&source).await?;
+        self.update(|s| s.image_source = source).await
+    }
+    pub async fn get_image_source(&self) -> ImageSource {
+        self.settings.read().await.image_source.clone()
+    }
+    pub async fn set_image_filter(&self, filter: ImageFilter) -> Result<(), Error> {
+        self.update(|s| s.image_filter = filter).await
     }
-    pub fn get_run_hashes(&self) -> Vec<usize> {
-        let (tx, rx) = oneshot::channel();
-        self.handler.send(Message::GetRunHashes(tx));
-        rx.recv().unwrap()
+    pub async fn get_image_filter(&self) -> ImageFilter {
+        self.settings.read().await.image_filter.clone()
     }
-    pub fn get_run_modifications(&self) -> Vec<usize> {
-        let (tx, rx) = oneshot::channel();
-        self.handler.send(Message::GetRunHashes(tx)).unwrap();
-        rx.recv().unwrap()
+    pub async fn set_duplicate_policy(&self, policy: DuplicatePolicy) -> Result<(), Error> {
+        self.update(|s| s.duplicate_policy = policy).await
     }
-    pub fn get_running_state(&self) -> RunningState {
-        let (tx, rx) = oneshot::channel();
-        self.handler.send(Message::GetRunningState(tx));
-        rx.recv().unwrap()
+    pub async fn get_duplicate_policy(&self) -> DuplicatePolicy {
+        self.settings.read().await.duplicate_policy
     }
-    pub fn set_running_state(&self, state: RunningState) {
-        self.handler.send(Message::SetRunningState(state)).unwrap();
+    pub async fn set_sampling(&self, sampling: Option<Sampling>) -> Result<(), Error> {
+        self.update(|s| s.sampling = sampling).await
     }
-    pub fn set_path(&self, path: impl Into<PathBuf>){
-        self.handler.send(Message::SetPath(path.into())).unwrap();
+    pub async fn get_sampling(&self) -> Option<Sampling> {
+        self.settings.read().await.sampling.clone()
     }
-    pub fn get_path(&self) -> PathBuf{
-        let (tx, rx) = oneshot::channel();
-        self.handler.send(Message::GetPath(tx)).unwrap();
-        rx.recv().unwrap()
+    pub async fn set_split(&self, split: Option<Split>) -> Result<(), Error> {
+        self.update(|s| s.split = split).await
+    }
+    pub async fn get_split(&self) -> Option<Split> {
+        self.settings.read().await.split.clone()
+    }
+    /// Sets how many images are hashed and stored together, at least one.
+    pub async fn set_batch_size(&self, batch_size: usize) -> Result<(), Error> {
+        self.update(|s| s.batch_size = Some(batch_size.max(1)))
+            .await
+    }
+    pub async fn get_batch_size(&self) -> usize {
+        self.settings
+            .read()
+            .await
+            .batch_size
+            .unwrap_or(DEFAULT_BATCH_SIZE)
+    }
+
+    /// Changes the settings unless a run is using them.
+    async fn update(&self, f: impl FnOnce(&mut Settings)) -> Result<(), Error> {
+        let mut settings = self.settings.write().await;
+        if let RunningState::Running = settings.state {
+            return Err(Error::AppAlreadyRunning);
+        }
+        f(&mut settings);
+        Ok(())
+    }
+}
+
+/// Fails unless the image root is a directory that can be read.
+pub async fn check_image_root(path: &Path) -> Result<(), Error> {
+    let res = match tokio::fs::metadata(path).await {
+        Ok(m) if m.is_dir() => tokio::fs::read_dir(path).await.map(|_| ()),
+        Ok(_) => Err(io::Error::from(io::ErrorKind::NotADirectory)),
+        Err(e) => Err(e),
+    };
+    res.map_err(|err| Error::InvalidImageRoot {
+        path: path.to_path_buf(),
+        err,
+    })
+}
+
+/// Fails unless the directories and files the images are read from can be read.
+pub async fn check_image_source(source: &ImageSource) -> Result<(), Error> {
+    match source {
+        ImageSource::Directory(path) => check_image_root(path).await,
+        ImageSource::Manifest { manifest, root } => {
+            check_image_root(root).await?;
+            check_source_file(manifest).await
+        }
+        ImageSource::Archive(path) => check_source_file(path).await,
     }
 }
 
-#[derive(Debug, Default, Clone, Copy)]
+async fn check_source_file(path: &Path) -> Result<(), Error> {
+    let res = match tokio::fs::metadata(path).await {
+        Ok(m) if m.is_file() => tokio::fs::File::open(path).await.map(|_| ()),
+        Ok(_) => Err(io::Error::from(io::ErrorKind::IsADirectory)),
+        Err(e) => Err(e),
+    };
+    res.map_err(|err| Error::InvalidImageSource {
+        path: path.to_path_buf(),
+        err,
+    })
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
 pub enum RunningState {
     Running,
+    /// No run has been started, or the last one finished.
     #[default]
     Stopped,
+    /// The last run stopped early because it was cancelled.
+    Cancelled,
+    /// The last run stopped with an error.
+    Failed {
+        reason: String,
+    },
 }
 
-#[derive(Default)]
-pub struct AppStateBuilder {
-    path: PathBuf,
+/// A run in progress, see `AppState::start_run`. A guard that is dropped before it is finished,
+/// e.g. together with the request that started the run, cancels the run.
+pub struct RunGuard {
+    settings: Arc<RwLock<Settings>>,
+    cancel: CancellationToken,
+    events: EventBus,
+    finished: bool,
+}
+impl RunGuard {
+    /// Token the stages of the run check to see if they should stop.
+    pub fn cancellation(&self) -> &CancellationToken {
+        &self.cancel
+    }
+    /// Records how the run ended, lets a new run start and sends `Event::RunFinished`.
+    pub async fn finish<T>(mut self, res: &Result<T, Error>) {
+        let state = match res {
+            Ok(_) => RunningState::Stopped,
+            Err(Error::Cancelled) => RunningState::Cancelled,
+            Err(e) => RunningState::Failed {
+                reason: e.to_string(),
+            },
+        };
+        if let RunningState::Failed { reason } = &state {
+            tracing::warn!("Run failed: {}", reason);
+        }
+        self.settings.write().await.state = state.clone();
+        self.finished = true;
+        self.events.send(Event::RunFinished { state });
+    }
+}
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        // Stops work still running on other threads.
+        self.cancel.cancel();
+        self.events.send(Event::RunFinished {
+            state: RunningState::Cancelled,
+        });
+        match self.settings.try_write() {
+            Ok(mut settings) => settings.state = RunningState::Cancelled,
+            Err(_) => {
+                if let Ok(handle) = tokio::runtime::Handle::try_current() {
+                    let settings = Arc::clone(&self.settings);
+                    handle.spawn(async move {
+                        settings.write().await.state = RunningState::Cancelled;
+                    });
+                }
+            }
+        }
+    }
+}
 
-    hashes: Arc<HashingMethods>,
-    modifications: Arc<Modifications>,
+#[derive(Default)]
+struct Settings {
+    image_source: ImageSource,
+    image_filter: ImageFilter,
+    duplicate_policy: DuplicatePolicy,
+    sampling: Option<Sampling>,
+    split: Option<Split>,
+    batch_size: Option<usize>,
 
     // Selected hashes
     run_hashes: Vec<usize>,
@@ -88,76 +315,10 @@ pub struct AppStateBuilder {
     run_modifications: Vec<usize>,
 
     state: RunningState,
-}
-impl AppStateBuilder {
-    pub fn new(hashes: HashingMethods, modifications: Modifications) -> Self {
-        Self {
-            hashes: Arc::new(hashes),
-            modifications: Arc::new(modifications),
-            ..Default::default()
-        }
-    }
-
-    /// Spawns app state and returns handler
-    pub fn spawn_app_state(mut self) -> mpsc::Sender<Message> {
-        let (tx, rx) = mpsc::channel();
-        rayon::spawn(move || {
-            while let Ok(m) = rx.recv() {
-                match m {
-                    Message::HashingMethods(r) => {
-                        if r.send(Arc::clone(&self.hashes)).is_err() {
-                            break;
-                        };
-                    }
-                    Message::Modifications(r) => {
-                        if r.send(Arc::clone(&self.modifications)).is_err() {
-                            break;
-                        };
-                    }
-                    Message::SetRunModifications(m) => self.run_modifications = m,
-                    Message::SetRunHashes(m) => self.run_hashes = m,
-                    Message::GetRunHashes(r) => {
-                        if r.send(self.run_hashes.clone()).is_err() {
-                            break;
-                        };
-                    }
-                    Message::GetRunModifications(r) => {
-                        if r.send(self.run_modifications.clone()).is_err() {
-                            break;
-                        };
-                    }
-                    Message::SetRunningState(s) => self.state = s,
-                    Message::GetRunningState(r) => {
-                        if r.send(self.state).is_err() {
-                            break;
-                        }
-                    }
-                    Message::SetPath(p) => self.path = p,
-                    Message::GetPath(r) => {
-                        if r.send(self.path.clone()).is_err(){
-                            break;
-                        }
-                    }
-                }
-            }
-        });
-        tx
-    }
-}
-pub enum Message {
-    HashingMethods(oneshot::Sender<Arc<HashingMethods>>),
-    Modifications(oneshot::Sender<Arc<Modifications>>),
-    SetRunModifications(Vec<usize>),
-    SetRunHashes(Vec<usize>),
-    GetRunModifications(oneshot::Sender<Vec<usize>>),
-    GetRunHashes(oneshot::Sender<Vec<usize>>),
-    GetRunningState(oneshot::Sender<RunningState>),
-    SetRunningState(RunningState),
-    SetPath(PathBuf),
-    GetPath(oneshot::Sender<PathBuf>)
+    cancel: CancellationToken,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Hashes {
     hashes: Vec<Hash>,
 }
@@ -168,11 +329,6 @@ impl Hashes {
         index
     }
 }
-impl Default for Hashes {
-    fn default() -> Self {
-        Self { hashes: Vec::new() }
-    }
-}
 impl<'a> IntoIterator for &'a Hashes {
     type Item = &'a Hash;
     type IntoIter = std::slice::Iter<'a, Hash>;
@@ -204,18 +360,370 @@ impl Hash {
     }
 }
 
+/// Hashes that are already stored for the images of a run. Images, modifications and hashing
+/// methods are given by their ids in the run, i.e. their index in the run.
+#[derive(Debug, Default, Clone)]
+pub struct ExistingHashes {
+    hashes: HashSet<(u32, u16, u16)>,
+}
+impl ExistingHashes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Looks up what hashes are stored for the images. Images are identified by their path and
+    /// content digest, modifications and hashing methods by their name and parameters. Only
+    /// hashes of images decoded with the same load options are reused, or of images without an
+    /// EXIF orientation which decode the same either way.
+    pub async fn fetch(
+        conn: &mut SqliteConnection,
+        images: &[image_parse::Image],
+        modifications: &SelectedModifications<'_>,
+        hashing_methods: &SelectedHashingMethods<'_>,
+        load_options: LoadOptions,
+    ) -> Result<Self, Error> {
+        let mut image_ids = HashMap::new();
+        for (id, img) in images.iter().enumerate() {
+            if let Some(db_id) = db::find_image_id(&mut *conn, img).await? {
+                image_ids.insert(db_id, id as u32);
+            }
+        }
+        let mut mod_ids = HashMap::new();
+        for (id, modification) in modifications.iter().enumerate() {
+            if let Some(db_id) = db::find_modification_id(&mut *conn, *modification).await? {
+                mod_ids.insert(db_id, id as u16);
+            }
+        }
+        let mut hashing_method_ids = HashMap::new();
+        for (id, method) in hashing_methods.iter().enumerate() {
+            if let Some(db_id) = db::find_hashing_method_id(&mut *conn, *method).await? {
+                hashing_method_ids.insert(db_id, id as u16);
+            }
+        }
+
+        let mut existing = Self::default();
+        if image_ids.is_empty() || mod_ids.is_empty() || hashing_method_ids.is_empty() {
+            return Ok(existing);
+        }
+        let mut rows: Vec<(i64, i64, i64)> = Vec::new();
+        let ids: Vec<i64> = image_ids.keys().copied().collect();
+        // Bound parameters are limited, the images are looked up in chunks.
+        for chunk in ids.chunks(1000) {
+            let mut query: QueryBuilder<Sqlite> = QueryBuilder::new(
+                "
+                SELECT mi.image_id, mi.modification_id, h.hashing_method_id
+                FROM hashes h
+                JOIN modified_images mi ON mi.id = h.mod_image_id
+                JOIN images i ON i.id = mi.image_id
+                WHERE (mi.apply_orientation IS ",
+            );
+            query.push_bind(load_options.get_apply_orientation());
+            query.push(" OR i.orientation = ");
+            query.push_bind(Orientation::Normal.tag());
+            query.push(") AND mi.image_id IN (");
+            let mut separated = query.separated(", ");
+            for id in chunk {
+                separated.push_bind(id);
+            }
+            separated.push_unseparated(")");
+            rows.extend(
+                query
+                    .build_query_as::<(i64, i64, i64)>()
+                    .fetch_all(&mut *conn)
+                    .await?,
+            );
+        }
+        for (img, modification, method) in rows {
+            if let (Some(img), Some(modification), Some(method)) = (
+                image_ids.get(&img),
+                mod_ids.get(&modification),
+                hashing_method_ids.get(&method),
+            ) {
+                existing.insert(*img, *modification, *method);
+            }
+        }
+        Ok(existing)
+    }
+    /// Looks up what hashes are stored for the images in a `ResultStore`, see `fetch`.
+    pub fn from_store(
+        store: &ResultStore,
+        images: &[image_parse::Image],
+        modifications: &SelectedModifications<'_>,
+        hashing_methods: &SelectedHashingMethods<'_>,
+        load_options: LoadOptions,
+    ) -> Result<Self, Error> {
+        let (image_ids, mod_ids, hashing_method_ids) = store.read(|t| {
+            let image_ids: HashMap<i64, u32> = images
+                .iter()
+                .enumerate()
+                .filter_map(|(id, img)| {
+                    let path = img.get_path().to_string_lossy();
+                    Some((t.find_image_id(&path, img.get_digest())?, id as u32))
+                })
+                .collect();
+            let mod_ids: HashMap<i64, u16> = modifications
+                .iter()
+                .enumerate()
+                .filter_map(|(id, m)| {
+                    Some((t.find_modification_id(m.name(), &m.params())?, id as u16))
+                })
+                .collect();
+            let hashing_method_ids: HashMap<i64, u16> = hashing_methods
+                .iter()
+                .enumerate()
+                .filter_map(|(id, m)| {
+                    Some((t.find_hashing_method_id(&m.name(), &m.params())?, id as u16))
+                })
+                .collect();
+            (image_ids, mod_ids, hashing_method_ids)
+        })?;
+
+        let mut existing = Self::default();
+        if image_ids.is_empty() || mod_ids.is_empty() || hashing_method_ids.is_empty() {
+            return Ok(existing);
+        }
+        for (img, modification, method) in store.hashed(load_options)? {
+            if let (Some(img), Some(modification), Some(method)) = (
+                image_ids.get(&img),
+                mod_ids.get(&modification),
+                hashing_method_ids.get(&method),
+            ) {
+                existing.insert(*img, *modification, *method);
+            }
+        }
+        Ok(existing)
+    }
+    pub fn insert(&mut self, img_id: u32, mod_id: u16, hashing_method_id: u16) {
+        self.hashes.insert((img_id, mod_id, hashing_method_id));
+    }
+    pub fn contains(&self, img_id: u32, mod_id: u16, hashing_method_id: u16) -> bool {
+        self.hashes.contains(&(img_id, mod_id, hashing_method_id))
+    }
+    /// True if the modified image has hashes for all of the hashing methods.
+    pub fn has_modification(&self, img_id: u32, mod_id: u16, hashing_methods: usize) -> bool {
+        (0..hashing_methods).all(|h| self.contains(img_id, mod_id, h as u16))
+    }
+    /// True if the image has hashes for all combinations of modifications and hashing methods,
+    /// it does not have to be decoded then.
+    pub fn has_image(&self, img_id: u32, modifications: usize, hashing_methods: usize) -> bool {
+        (0..modifications).all(|m| self.has_modification(img_id, m as u16, hashing_methods))
+    }
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+}
+
+/// Database ids of the images, modifications and hashing methods of a run, indexed by their ids
+/// in the run.
+#[derive(Debug, Default, Clone)]
+pub struct RunIds {
+    run: Option<i64>,
+    images: Vec<i64>,
+    modifications: Vec<i64>,
+    hashing_methods: Vec<i64>,
+}
+impl RunIds {
+    pub fn new(images: Vec<i64>, modifications: Vec<i64>, hashing_methods: Vec<i64>) -> Self {
+        Self {
+            run: None,
+            images,
+            modifications,
+            hashing_methods,
+        }
+    }
+    pub fn with_run(mut self, run_id: i64) -> Self {
+        self.run = Some(run_id);
+        self
+    }
+    /// Id of the stored run, `None` for results that are not stored in a database.
+    pub fn run_id(&self) -> Option<i64> {
+        self.run
+    }
+    /// Resolves the database ids of everything in the run, inserting what is not stored yet.
+    pub async fn get_or_insert(
+        conn: &mut SqliteConnection,
+        images: &[image_parse::Image],
+        modifications: &SelectedModifications<'_>,
+        hashing_methods: &SelectedHashingMethods<'_>,
+    ) -> Result<Self, Error> {
+        let mut ids = Self::default();
+        for img in images {
+            ids.images
+                .push(db::get_or_insert_image(&mut *conn, img).await?);
+        }
+        for modification in modifications.iter() {
+            ids.modifications
+                .push(db::get_or_insert_modification(&mut *conn, *modification).await?);
+        }
+        for method in hashing_methods.iter() {
+            ids.hashing_methods
+                .push(db::get_or_insert_hashing_method(&mut *conn, *method).await?);
+        }
+        Ok(ids)
+    }
+    /// Resolves the ids of everything in the run in a `ResultStore`, see `get_or_insert`.
+    pub fn get_or_insert_in_store(
+        store: &ResultStore,
+        images: &[image_parse::Image],
+        modifications: &SelectedModifications<'_>,
+        hashing_methods: &SelectedHashingMethods<'_>,
+    ) -> Result<Self, Error> {
+        Ok(store.write(|t| Self {
+            run: None,
+            images: images
+                .iter()
+                .map(|img| {
+                    t.get_or_insert_image(
+                        &img.get_path().to_string_lossy(),
+                        img.get_user(),
+                        img.get_session(),
+                        img.get_tags(),
+                        img.get_digest(),
+                    )
+                })
+                .collect(),
+            modifications: modifications
+                .iter()
+                .map(|m| t.get_or_insert_modification(m.name(), &m.params()))
+                .collect(),
+            hashing_methods: hashing_methods
+                .iter()
+                .map(|m| t.get_or_insert_hashing_method(&m.name(), &m.params()))
+                .collect(),
+        })?)
+    }
+    /// Ids of the run with the images of a batch in place of its images, inserting the images
+    /// that are not stored yet. Images are then given by their index in the batch.
+    pub async fn with_batch(
+        &self,
+        conn: &mut SqliteConnection,
+        images: &[image_parse::Image],
+    ) -> Result<Self, Error> {
+        let mut ids = Vec::with_capacity(images.len());
+        for img in images {
+            ids.push(db::get_or_insert_image(&mut *conn, img).await?);
+        }
+        Ok(Self {
+            images: ids,
+            ..self.clone()
+        })
+    }
+    /// Ids of the run with the images of a batch in a `ResultStore`, see `with_batch`.
+    pub fn with_batch_in_store(
+        &self,
+        store: &ResultStore,
+        images: &[image_parse::Image],
+    ) -> Result<Self, Error> {
+        let ids = store.write(|t| {
+            images
+                .iter()
+                .map(|img| {
+                    t.get_or_insert_image(
+                        &img.get_path().to_string_lossy(),
+                        img.get_user(),
+                        img.get_session(),
+                        img.get_tags(),
+                        img.get_digest(),
+                    )
+                })
+                .collect()
+        })?;
+        Ok(Self {
+            images: ids,
+            ..self.clone()
+        })
+    }
+    pub fn image_ids(&self) -> &[i64] {
+        &self.images
+    }
+    pub fn modification_ids(&self) -> &[i64] {
+        &self.modifications
+    }
+    pub fn hashing_method_ids(&self) -> &[i64] {
+        &self.hashing_methods
+    }
+    pub fn image(&self, img_id: u32) -> Result<i64, Error> {
+        self.images
+            .get(img_id as usize)
+            .copied()
+            .ok_or(Error::ImageNotFound {
+                id: img_id as usize,
+            })
+    }
+    pub fn modification(&self, mod_id: u16) -> Result<i64, Error> {
+        self.modifications
+            .get(mod_id as usize)
+            .copied()
+            .ok_or(Error::ModificationNotFound {
+                id: mod_id as usize,
+            })
+    }
+    pub fn hashing_method(&self, hashing_method_id: u16) -> Result<i64, Error> {
+        self.hashing_methods
+            .get(hashing_method_id as usize)
+            .copied()
+            .ok_or(Error::HashingMethodNotFound {
+                id: hashing_method_id as usize,
+            })
+    }
+}
+
+/// Images that are hashed and stored together, the images are given by their index in the
+/// batch. The last batch of a run carries the files that were skipped and the duplicates found.
+#[derive(Debug, Default)]
+pub struct ImageBatch {
+    images: Vec<image_parse::Image>,
+    existing: ExistingHashes,
+    ingest_report: IngestReport,
+}
+impl ImageBatch {
+    pub fn new(images: Vec<image_parse::Image>, existing: ExistingHashes) -> Self {
+        Self {
+            images,
+            existing,
+            ingest_report: IngestReport::default(),
+        }
+    }
+    pub fn with_ingest_report(mut self, report: IngestReport) -> Self {
+        self.ingest_report = report;
+        self
+    }
+    pub fn images(&self) -> &[image_parse::Image] {
+        &self.images
+    }
+    /// Hashes of the images that are already stored.
+    pub fn existing(&self) -> &ExistingHashes {
+        &self.existing
+    }
+    pub fn into_parts(self) -> (Vec<image_parse::Image>, ExistingHashes, IngestReport) {
+        (self.images, self.existing, self.ingest_report)
+    }
+}
+
 #[derive(Default)]
 pub struct AppProcessResult {
     imgs: Images,
     phash_results: PHashResults,
+    ingest_report: IngestReport,
 }
 impl AppProcessResult {
     pub fn new(imgs: Images, res: PHashResults) -> Self {
         Self {
             imgs,
             phash_results: res,
+            ingest_report: IngestReport::default(),
         }
     }
+    /// Attaches the files that were skipped before processing.
+    pub fn with_ingest_report(mut self, report: IngestReport) -> Self {
+        self.ingest_report = report;
+        self
+    }
+    pub fn ingest_report(&self) -> &IngestReport {
+        &self.ingest_report
+    }
     pub fn phash_results(&self) -> &PHashResults {
         &self.phash_results
     }
@@ -233,25 +741,30 @@ impl AppProcessResult {
         }
         results
     }
-    pub async fn send_to_db(&self, pool: &SqlitePool) -> Result<(), Error> {
+    pub async fn send_to_db(
+        &self,
+        pool: &SqlitePool,
+        modifications: &SelectedModifications<'_>,
+        hashing_methods: &SelectedHashingMethods<'_>,
+    ) -> Result<(), Error> {
         let mut tx = pool.begin().await?;
-        for (id, img) in self.imgs.iter().enumerate() {
-            let path_str = img.get_path().to_string_lossy().to_string();
-            sqlx::query(
-                "
-            INSERT INTO images (id, path, user) VALUES (?, ?, ?) ON CONFLICT(id) DO NOTHING;
-            ",
-            )
-            .bind(id as u32)
-            .bind(path_str)
-            .bind(img.get_user())
-            .execute(&mut *tx)
-            .await?;
-        }
+        let ids =
+            RunIds::get_or_insert(&mut tx, &self.imgs, modifications, hashing_methods).await?;
+        self.phash_results.send_to_db(&mut tx, &ids).await?;
         tx.commit().await?;
-        self.phash_results.send_to_db(pool).await?;
         Ok(())
     }
+    /// Stores the results in a `ResultStore` instead of a database.
+    pub fn send_to_store(
+        &self,
+        store: &ResultStore,
+        modifications: &SelectedModifications<'_>,
+        hashing_methods: &SelectedHashingMethods<'_>,
+    ) -> Result<(), Error> {
+        let ids =
+            RunIds::get_or_insert_in_store(store, &self.imgs, modifications, hashing_methods)?;
+        self.phash_results.send_to_store(store, &ids)
+    }
 }
 #[derive(Debug, Default)]
 pub struct Images {
@@ -1,171 +1,80 @@
-use std::{path::PathBuf, str::FromStr};
+mod check;
+mod config;
+mod error;
+mod maintenance;
+mod migrations;
+mod plans;
+mod repository;
 
-use sqlx::{SqlitePool, sqlite::{self, SqliteConnectOptions}};
+use std::path::PathBuf;
+
+use sqlx::{SqliteConnection, SqlitePool};
+
+use crate::{image_hash::HashingMethod, image_modify::ImageModification, image_parse::Image};
+
+pub use check::{CheckReport, HashLength, Issue, PathImage, Repairs, Rows, check};
+pub use config::{DB_ENV, DbConfig};
+pub use error::Error;
+pub use maintenance::{CompactReport, DeletedRun};
+pub use migrations::{MIGRATIONS, Migration, latest_version};
+pub use plans::AccessPath;
+pub use repository::{
+    HashFilter, MatchFilter, Repository, Run, RunImage, RunSummary, StoredHash, StoredMatch,
+    StoredMethod, StoredModifiedImage,
+};
+
+#[derive(Default)]
 pub struct DB {}
 impl DB {
     pub fn new() -> Self {
         Self {}
     }
-    pub async fn create_db() -> Result<(), sqlx::Error> {
-        let pool = SqlitePool::connect_with(
-            SqliteConnectOptions::from_str("sqlite:data.db")?.create_if_missing(true),
-        )
-        .await?;
-
-        let mut tx = pool.begin().await?;
-
-        // Current running program. References what run that is currently being processed and
-        // should be used for matching.
-        sqlx::query(
-            "
-            CREATE TABLE IF NOT EXISTS program (
-            id INTEGER PRIMARY KEY CHECK (id = 0),
-            run_id INTEGER NOT NULL,
-            FOREIGN KEY (run_id) REFERENCES runs(id)
-            );
-            ",
-        )
-        .execute(&mut *tx)
-        .await?;
-
-        sqlx::query(
-            "
-            CREATE TABLE IF NOT EXISTS runs (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            timestamp INTEGER NOT NULL
-            );
-            ",
-        )
-        .execute(&mut *tx)
-        .await?;
-
-        sqlx::query(
-            "
-            CREATE TABLE IF NOT EXISTS images (
-            id INTEGER PRIMARY KEY,
-            path TEXT NOT NULL,
-            user TEXT NOT NULL
-            );
-            ",
-        )
-        .execute(&mut *tx)
-        .await?;
-
-        sqlx::query(
-            "
-            CREATE TABLE IF NOT EXISTS run_images(
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            run_id INTEGER NOT NULL,
-            image_id INTEGER NOT NULL,
-            FOREIGN KEY (run_id) REFERENCES runs(id),
-            FOREIGN KEY (image_id) REFERENCES images(id)
-            );
-            ",
-        )
-        .execute(&mut *tx)
-        .await?;
-        sqlx::query(
-            "
-            CREATE TABLE IF NOT EXISTS modifications (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            name TEXT NOT NULL
-            );
-            ",
-        )
-        .execute(&mut *tx)
-        .await?;
-
-        sqlx::query(
-            "
-            CREATE TABLE IF NOT EXISTS hashing_methods (
-            id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
-            name TEXT NOT NULL
-            );
-            ",
-        )
-        .execute(&mut *tx)
-        .await?;
-
-        sqlx::query(
-            "
-            CREATE TABLE IF NOT EXISTS modified_images (
-            id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
-            image_id INTEGER NOT NULL,
-            modification_id INTEGER NOT NULL,
-            FOREIGN KEY (image_id) REFERENCES images(id),
-            FOREIGN KEY (modification_id) REFERENCES modifications(id)
-            UNIQUE (image_id, modification_id)
-            );
-            ",
-        )
-        .execute(&mut *tx)
-        .await?;
-
-        sqlx::query(
-            "
-            CREATE TABLE IF NOT EXISTS hashes (
-            id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
-            hash BLOB NOT NULL,
-            mod_image_id INTEGER NOT NULL,
-            hashing_method_id INTEGER NOT NULL,
-            FOREIGN KEY (mod_image_id) REFERENCES modified_images(id),
-            FOREIGN KEY (hashing_method_id) REFERENCES hashing_methods(id)
-            UNIQUE (mod_image_id, hashing_method_id)
-            );
-            ",
-        )
-        .execute(&mut *tx)
-        .await?;
-
-        sqlx::query(
-            "CREATE TABLE IF NOT EXISTS matches (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                hamming_distance INTEGER,
-                hash_len INTEGER,
-                hash1_id INTEGER,
-                hash2_id INTEGER,
-                FOREIGN KEY (hash1_id) REFERENCES hashes(id),
-                FOREIGN KEY (hash2_id) REFERENCES hashes(id)
-                )",
-        )
-        .execute(&mut *tx)
-        .await?;
-
-        sqlx::query(
-            "
-CREATE VIEW IF NOT EXISTS active_run_hashes AS
-SELECT h.id AS hash_id,
-       h.hash,
-       h.hashing_method_id,
-       mi.id AS modified_image_id,
-       mi.image_id,
-       mi.modification_id
-FROM hashes h
-JOIN modified_images mi ON mi.id = h.mod_image_id
-JOIN images i ON i.id = mi.image_id
-JOIN run_images ri ON ri.image_id = i.id
-JOIN program p ON p.run_id = ri.run_id;
-            ",
-        )
-        .execute(&mut *tx)
-        .await?;
-
-        sqlx::query(
-            "
-CREATE VIEW IF NOT EXISTS active_run_modified_images AS
-SELECT mi.id AS modified_image_id,
-       mi.image_id,
-       mi.modification_id
-FROM modified_images mi
-JOIN images i ON i.id = mi.image_id
-JOIN run_images ri ON ri.image_id = i.id
-JOIN program p ON p.run_id = ri.run_id;
-            ",
-        )
-        .execute(&mut *tx)
-        .await?;
-        tx.commit().await?;
-        Ok(())
+    /// Opens the configured database, creating it if it does not exist, and migrates it to the
+    /// latest schema. The returned pool should be shared by everything using the database.
+    pub async fn create_db(config: &DbConfig) -> Result<SqlitePool, Error> {
+        config.connect().await
+    }
+    /// Upgrades the schema of the database in place. Returns the migrations that were applied.
+    pub async fn migrate(pool: &SqlitePool) -> Result<Vec<&'static Migration>, Error> {
+        migrations::migrate(pool).await
+    }
+    /// Migrations that `migrate` would apply, without changing the database.
+    pub async fn pending_migrations(pool: &SqlitePool) -> Result<Vec<&'static Migration>, Error> {
+        let mut conn = pool.acquire().await?;
+        migrations::pending(&mut conn).await
+    }
+    /// Deletes a run with everything stored only for it, the modified images, hashes and matches
+    /// of images that are not part of another run. The newest remaining run becomes the active
+    /// run if the deleted run was active. Returns `None` if there is no such run.
+    pub async fn delete_run(pool: &SqlitePool, run_id: i64) -> Result<Option<DeletedRun>, Error> {
+        maintenance::delete_run(pool, run_id).await
+    }
+    /// Deletes all but the newest `keep` runs, the active run is always kept.
+    pub async fn prune_runs(pool: &SqlitePool, keep: usize) -> Result<Vec<DeletedRun>, Error> {
+        maintenance::prune_runs(pool, keep).await
+    }
+    /// Releases the space of deleted rows with `VACUUM` and updates the query planner statistics
+    /// with `ANALYZE`.
+    pub async fn compact(pool: &SqlitePool) -> Result<CompactReport, Error> {
+        maintenance::compact(pool).await
+    }
+    /// Validates the stored results and reports rows that break the rules of the schema. With
+    /// `repair` the rows that can not be used are deleted.
+    pub async fn check(pool: &SqlitePool, repair: bool) -> Result<CheckReport, Error> {
+        check::check(pool, repair).await
+    }
+    /// Query plans and timings of the queries the ROC and the matching read the results with,
+    /// for finding reads that scan whole tables.
+    pub async fn benchmark(
+        pool: &SqlitePool,
+        filter: &MatchFilter,
+    ) -> Result<Vec<AccessPath>, Error> {
+        plans::benchmark(pool, filter).await
+    }
+    /// Version of the schema in the database, 0 for databases without any migrations applied.
+    pub async fn schema_version(pool: &SqlitePool) -> Result<u32, Error> {
+        let mut conn = pool.acquire().await?;
+        migrations::current_version(&mut conn).await
     }
 }
 
@@ -175,11 +84,11 @@ pub async fn insert_user_image(pool: SqlitePool, username: &str, images: Vec<Pat
     for image in images {
             sqlx::query(
                 "
-            INSERT INTO images (path, user) VALUES (?, ?) ON CONFLICT(id) DO NOTHING;
+            INSERT INTO images (path, user) VALUES (?, ?) ON CONFLICT DO NOTHING;
             ",
             )
-            .bind(username)
             .bind(image.to_str())
+            .bind(username)
             .execute(&mut *tx)
             .await?;
         }
@@ -187,3 +96,195 @@ pub async fn insert_user_image(pool: SqlitePool, username: &str, images: Vec<Pat
     Ok(())
 }
 
+/// Id of a stored image with the same path and content digest as the image.
+pub async fn find_image_id(
+    conn: &mut SqliteConnection,
+    image: &Image,
+) -> Result<Option<i64>, sqlx::Error> {
+    let id: Option<(i64,)> = sqlx::query_as(
+        "
+        SELECT id FROM images WHERE path = ? AND digest IS ?;
+        ",
+    )
+    .bind(image.get_path().to_string_lossy().to_string())
+    .bind(image.get_digest())
+    .fetch_optional(conn)
+    .await?;
+    Ok(id.map(|(id,)| id))
+}
+
+/// Id of the stored image with the same path and content digest, the image is inserted if
+/// there is none.
+pub async fn get_or_insert_image(
+    conn: &mut SqliteConnection,
+    image: &Image,
+) -> Result<i64, sqlx::Error> {
+    if let Some(id) = find_image_id(&mut *conn, image).await? {
+        return Ok(id);
+    }
+    let id: Option<(i64,)> = sqlx::query_as(
+        "
+        INSERT INTO images (path, user, session, tags, digest) VALUES (?, ?, ?, ?, ?)
+        ON CONFLICT DO NOTHING
+        RETURNING id;
+        ",
+    )
+    .bind(image.get_path().to_string_lossy().to_string())
+    .bind(image.get_user())
+    .bind(image.get_session())
+    .bind(serde_json::Value::from(image.get_tags()).to_string())
+    .bind(image.get_digest())
+    .fetch_optional(&mut *conn)
+    .await?;
+    match id {
+        Some((id,)) => Ok(id),
+        // Inserted by another connection since the lookup.
+        None => find_image_id(conn, image)
+            .await?
+            .ok_or(sqlx::Error::RowNotFound),
+    }
+}
+
+/// Id of the stored modification with the same name and parameters.
+pub async fn find_modification_id(
+    conn: &mut SqliteConnection,
+    modification: &dyn ImageModification,
+) -> Result<Option<i64>, sqlx::Error> {
+    find_modification_id_by_name(conn, modification.name(), &modification.params()).await
+}
+
+/// Id of the stored modification with the same name and parameters, it is inserted if there is
+/// none.
+pub async fn get_or_insert_modification(
+    conn: &mut SqliteConnection,
+    modification: &dyn ImageModification,
+) -> Result<i64, sqlx::Error> {
+    get_or_insert_modification_by_name(conn, modification.name(), &modification.params()).await
+}
+
+/// Id of the stored modification with the name and parameters.
+pub async fn find_modification_id_by_name(
+    conn: &mut SqliteConnection,
+    name: &str,
+    params: &str,
+) -> Result<Option<i64>, sqlx::Error> {
+    let id: Option<(i64,)> =
+        sqlx::query_as("SELECT id FROM modifications WHERE name = ? AND params = ?;")
+            .bind(name)
+            .bind(params)
+            .fetch_optional(conn)
+            .await?;
+    Ok(id.map(|(id,)| id))
+}
+
+/// Id of the stored modification with the name and parameters, it is inserted if there is none.
+pub async fn get_or_insert_modification_by_name(
+    conn: &mut SqliteConnection,
+    name: &str,
+    params: &str,
+) -> Result<i64, sqlx::Error> {
+    if let Some(id) = find_modification_id_by_name(&mut *conn, name, params).await? {
+        return Ok(id);
+    }
+    let id: Option<(i64,)> = sqlx::query_as(
+        "INSERT INTO modifications (name, params) VALUES (?, ?) ON CONFLICT DO NOTHING RETURNING id;",
+    )
+    .bind(name)
+    .bind(params)
+    .fetch_optional(&mut *conn)
+    .await?;
+    match id {
+        Some((id,)) => Ok(id),
+        // Inserted by another connection since the lookup.
+        None => find_modification_id_by_name(conn, name, params)
+            .await?
+            .ok_or(sqlx::Error::RowNotFound),
+    }
+}
+
+/// Id of the stored hashing method with the same name and parameters.
+pub async fn find_hashing_method_id(
+    conn: &mut SqliteConnection,
+    method: &dyn HashingMethod,
+) -> Result<Option<i64>, sqlx::Error> {
+    find_hashing_method_id_by_name(conn, &method.name(), &method.params()).await
+}
+
+/// Id of the stored hashing method with the same name and parameters, it is inserted if there is
+/// none.
+pub async fn get_or_insert_hashing_method(
+    conn: &mut SqliteConnection,
+    method: &dyn HashingMethod,
+) -> Result<i64, sqlx::Error> {
+    get_or_insert_hashing_method_by_name(conn, &method.name(), &method.params()).await
+}
+
+/// Id of the stored hashing method with the name and parameters.
+pub async fn find_hashing_method_id_by_name(
+    conn: &mut SqliteConnection,
+    name: &str,
+    params: &str,
+) -> Result<Option<i64>, sqlx::Error> {
+    let id: Option<(i64,)> =
+        sqlx::query_as("SELECT id FROM hashing_methods WHERE name = ? AND params = ?;")
+            .bind(name)
+            .bind(params)
+            .fetch_optional(conn)
+            .await?;
+    Ok(id.map(|(id,)| id))
+}
+
+/// Id of the stored hashing method with the name and parameters, it is inserted if there is
+/// none.
+pub async fn get_or_insert_hashing_method_by_name(
+    conn: &mut SqliteConnection,
+    name: &str,
+    params: &str,
+) -> Result<i64, sqlx::Error> {
+    if let Some(id) = find_hashing_method_id_by_name(&mut *conn, name, params).await? {
+        return Ok(id);
+    }
+    let id: Option<(i64,)> = sqlx::query_as(
+        "INSERT INTO hashing_methods (name, params) VALUES (?, ?) ON CONFLICT DO NOTHING RETURNING id;",
+    )
+    .bind(name)
+    .bind(params)
+    .fetch_optional(&mut *conn)
+    .await?;
+    match id {
+        Some((id,)) => Ok(id),
+        // Inserted by another connection since the lookup.
+        None => find_hashing_method_id_by_name(conn, name, params)
+            .await?
+            .ok_or(sqlx::Error::RowNotFound),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn user_images_are_stored_by_path() {
+        let pool = DbConfig::in_memory().connect().await.unwrap();
+        let images = vec![PathBuf::from("a.png"), PathBuf::from("b.png")];
+        insert_user_image(pool.clone(), "user", images.clone())
+            .await
+            .unwrap();
+        // Inserting the same images again keeps a single row per image.
+        insert_user_image(pool.clone(), "user", images).await.unwrap();
+
+        let rows: Vec<(String, String)> =
+            sqlx::query_as("SELECT path, user FROM images ORDER BY path;")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                ("a.png".to_string(), "user".to_string()),
+                ("b.png".to_string(), "user".to_string())
+            ]
+        );
+    }
+}
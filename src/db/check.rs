@@ -0,0 +1,385 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use sqlx::{SqliteConnection, SqlitePool, prelude::FromRow};
+
+use crate::db::Error;
+
+/// Most ids listed for a single issue, the count is always complete.
+const SAMPLE_LIMIT: i64 = 100;
+
+/// Length of a hash in bits, hashes stored before the length was recorded count their bytes.
+const HASH_BITS: &str = "COALESCE(hash_bits, length(hash) * 8)";
+
+const DANGLING_MATCHES: &str = "
+    FROM matches
+    WHERE hash1_id IS NULL OR hash2_id IS NULL
+       OR hash1_id NOT IN (SELECT id FROM hashes)
+       OR hash2_id NOT IN (SELECT id FROM hashes)
+    ";
+const ORPHANED_RUN_IMAGES: &str = "
+    FROM run_images WHERE image_id NOT IN (SELECT id FROM images)
+    ";
+const UNHASHED_MODIFIED_IMAGES: &str = "
+    FROM modified_images
+    WHERE NOT EXISTS (SELECT 1 FROM hashes WHERE hashes.mod_image_id = modified_images.id)
+    ";
+
+/// What `check` found in the database, and what it removed if it was asked to repair it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckReport {
+    foreign_keys: bool,
+    issues: Vec<Issue>,
+    repairs: Option<Repairs>,
+}
+impl CheckReport {
+    /// `PRAGMA foreign_keys` is on for the connections of the pool.
+    pub fn get_foreign_keys(&self) -> bool {
+        self.foreign_keys
+    }
+    /// Issues found before anything was repaired.
+    pub fn get_issues(&self) -> &[Issue] {
+        &self.issues
+    }
+    pub fn get_repairs(&self) -> Option<&Repairs> {
+        self.repairs.as_ref()
+    }
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Issue {
+    /// Hashes of a hashing method that are not as long as most of its hashes. They can not be
+    /// matched against the others.
+    InconsistentHashLength {
+        hashing_method_id: i64,
+        expected_bits: u32,
+        lengths: Vec<HashLength>,
+        hashes: Rows,
+    },
+    /// Matches where one of the hashes does not exist.
+    DanglingMatches { matches: Rows },
+    /// Images of runs where the image does not exist.
+    OrphanedRunImages { run_images: Rows },
+    /// Modified images that were stored without any hashes.
+    UnhashedModifiedImages { modified_images: Rows },
+    /// Images stored more than once under the same path. Only an issue if the content is the
+    /// same, images that changed on disk get a new id.
+    DuplicatePath {
+        path: String,
+        images: Vec<PathImage>,
+        same_content: bool,
+    },
+    /// Rows `PRAGMA foreign_key_check` reports that no other check covers.
+    ForeignKeyViolation {
+        table: String,
+        parent: String,
+        rows: Rows,
+    },
+}
+impl Issue {
+    /// `repair` can fix the issue.
+    pub fn is_repairable(&self) -> bool {
+        !matches!(self, Self::DuplicatePath { .. })
+    }
+}
+
+/// Number of affected rows with the ids of the first of them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Rows {
+    count: u64,
+    ids: Vec<i64>,
+}
+impl Rows {
+    pub fn get_count(&self) -> u64 {
+        self.count
+    }
+    /// Ids of at most the first 100 rows.
+    pub fn get_ids(&self) -> &[i64] {
+        &self.ids
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct HashLength {
+    bits: u32,
+    count: u64,
+}
+impl HashLength {
+    pub fn get_bits(&self) -> u32 {
+        self.bits
+    }
+    pub fn get_count(&self) -> u64 {
+        self.count
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct PathImage {
+    id: i64,
+    digest: Option<String>,
+}
+impl PathImage {
+    pub fn get_id(&self) -> i64 {
+        self.id
+    }
+    pub fn get_digest(&self) -> Option<&str> {
+        self.digest.as_deref()
+    }
+}
+
+/// Rows deleted by `repair`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Repairs {
+    matches: u64,
+    hashes: u64,
+    run_images: u64,
+    modified_images: u64,
+    foreign_key_rows: u64,
+}
+impl Repairs {
+    pub fn get_matches(&self) -> u64 {
+        self.matches
+    }
+    pub fn get_hashes(&self) -> u64 {
+        self.hashes
+    }
+    pub fn get_run_images(&self) -> u64 {
+        self.run_images
+    }
+    pub fn get_modified_images(&self) -> u64 {
+        self.modified_images
+    }
+    /// Other rows `PRAGMA foreign_key_check` reported.
+    pub fn get_foreign_key_rows(&self) -> u64 {
+        self.foreign_key_rows
+    }
+}
+
+/// Validates the stored results. With `repair` the rows behind every repairable issue are deleted
+/// in a single transaction, duplicate paths are only reported.
+pub async fn check(pool: &SqlitePool, repair: bool) -> Result<CheckReport, Error> {
+    let mut conn = pool.acquire().await?;
+    let foreign_keys: (bool,) = sqlx::query_as("PRAGMA foreign_keys;")
+        .fetch_one(&mut *conn)
+        .await?;
+    if !foreign_keys.0 {
+        tracing::warn!("Foreign keys are not enforced on this connection");
+    }
+    let issues = find_issues(&mut conn).await?;
+    drop(conn);
+
+    let repairs = match repair && issues.iter().any(|i| i.is_repairable()) {
+        true => {
+            let mut tx = pool.begin().await?;
+            let repairs = repair_issues(&mut tx, &issues).await?;
+            tx.commit().await?;
+            Some(repairs)
+        }
+        false => None,
+    };
+    Ok(CheckReport {
+        foreign_keys: foreign_keys.0,
+        issues,
+        repairs,
+    })
+}
+
+async fn find_issues(conn: &mut SqliteConnection) -> Result<Vec<Issue>, Error> {
+    let mut issues = Vec::new();
+
+    let lengths: Vec<(i64, u32, u64)> = sqlx::query_as(&format!(
+        "
+        SELECT hashing_method_id, {HASH_BITS} AS bits, count(*) FROM hashes
+        GROUP BY hashing_method_id, bits
+        ORDER BY hashing_method_id, count(*) DESC, bits DESC;
+        "
+    ))
+    .fetch_all(&mut *conn)
+    .await?;
+    let mut by_method: BTreeMap<i64, Vec<HashLength>> = BTreeMap::new();
+    for (hashing_method_id, bits, count) in lengths {
+        by_method
+            .entry(hashing_method_id)
+            .or_default()
+            .push(HashLength { bits, count });
+    }
+    for (hashing_method_id, lengths) in by_method {
+        if lengths.len() < 2 {
+            continue;
+        }
+        // The most common length is taken to be the right one.
+        let expected_bits = lengths[0].bits;
+        let hashes = rows(
+            &mut *conn,
+            &format!(
+                "FROM hashes WHERE hashing_method_id = {} AND {HASH_BITS} != {}",
+                hashing_method_id, expected_bits
+            ),
+        )
+        .await?;
+        issues.push(Issue::InconsistentHashLength {
+            hashing_method_id,
+            expected_bits,
+            lengths,
+            hashes,
+        });
+    }
+
+    let matches = rows(&mut *conn, DANGLING_MATCHES).await?;
+    if matches.count > 0 {
+        issues.push(Issue::DanglingMatches { matches });
+    }
+    let run_images = rows(&mut *conn, ORPHANED_RUN_IMAGES).await?;
+    if run_images.count > 0 {
+        issues.push(Issue::OrphanedRunImages { run_images });
+    }
+    let modified_images = rows(&mut *conn, UNHASHED_MODIFIED_IMAGES).await?;
+    if modified_images.count > 0 {
+        issues.push(Issue::UnhashedModifiedImages { modified_images });
+    }
+
+    let duplicates: Vec<(String, i64, Option<String>)> = sqlx::query_as(
+        "
+        SELECT path, id, digest FROM images
+        WHERE path IN (SELECT path FROM images GROUP BY path HAVING count(*) > 1)
+        ORDER BY path, id;
+        ",
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    let mut by_path: BTreeMap<String, Vec<PathImage>> = BTreeMap::new();
+    for (path, id, digest) in duplicates {
+        by_path
+            .entry(path)
+            .or_default()
+            .push(PathImage { id, digest });
+    }
+    for (path, images) in by_path {
+        let same_content = images.windows(2).all(|w| w[0].digest == w[1].digest);
+        issues.push(Issue::DuplicatePath {
+            path,
+            images,
+            same_content,
+        });
+    }
+
+    let mut violations: BTreeMap<(String, String), Rows> = BTreeMap::new();
+    for (table, rowid, parent) in foreign_key_violations(&mut *conn).await? {
+        let rows = violations.entry((table, parent)).or_default();
+        rows.count += 1;
+        if rows.ids.len() < SAMPLE_LIMIT as usize {
+            rows.ids.push(rowid);
+        }
+    }
+    for ((table, parent), rows) in violations {
+        issues.push(Issue::ForeignKeyViolation {
+            table,
+            parent,
+            rows,
+        });
+    }
+    Ok(issues)
+}
+
+async fn repair_issues(conn: &mut SqliteConnection, issues: &[Issue]) -> Result<Repairs, Error> {
+    let mut repairs = Repairs::default();
+
+    // Children before their parents, so nothing is left pointing at a deleted row.
+    for issue in issues {
+        if let Issue::InconsistentHashLength {
+            hashing_method_id,
+            expected_bits,
+            ..
+        } = issue
+        {
+            let wrong = format!(
+                "SELECT id FROM hashes WHERE hashing_method_id = {} AND {HASH_BITS} != {}",
+                hashing_method_id, expected_bits
+            );
+            repairs.matches += sqlx::query(&format!(
+                "DELETE FROM matches WHERE hash1_id IN ({wrong}) OR hash2_id IN ({wrong});"
+            ))
+            .execute(&mut *conn)
+            .await?
+            .rows_affected();
+            repairs.hashes += sqlx::query(&format!("DELETE FROM hashes WHERE id IN ({wrong});"))
+                .execute(&mut *conn)
+                .await?
+                .rows_affected();
+        }
+    }
+    repairs.matches += sqlx::query(&format!("DELETE {DANGLING_MATCHES};"))
+        .execute(&mut *conn)
+        .await?
+        .rows_affected();
+    repairs.run_images += sqlx::query(&format!("DELETE {ORPHANED_RUN_IMAGES};"))
+        .execute(&mut *conn)
+        .await?
+        .rows_affected();
+
+    // Deleting a row can leave its own children without a parent, so this is repeated until
+    // nothing is reported.
+    for _ in 0..8 {
+        let violations = foreign_key_violations(&mut *conn).await?;
+        if violations.is_empty() {
+            break;
+        }
+        for (table, rowid, _) in violations {
+            repairs.foreign_key_rows += sqlx::query(&format!(
+                "DELETE FROM \"{}\" WHERE rowid = ?;",
+                table.replace('"', "\"\"")
+            ))
+            .bind(rowid)
+            .execute(&mut *conn)
+            .await?
+            .rows_affected();
+        }
+    }
+
+    // Last, as the repairs above can leave modified images without hashes.
+    repairs.modified_images += sqlx::query(&format!("DELETE {UNHASHED_MODIFIED_IMAGES};"))
+        .execute(&mut *conn)
+        .await?
+        .rows_affected();
+    Ok(repairs)
+}
+
+/// Count and the first ids of the rows selected by the `FROM ... WHERE ...` clause.
+async fn rows(conn: &mut SqliteConnection, from: &str) -> Result<Rows, Error> {
+    let count: (i64,) = sqlx::query_as(&format!("SELECT count(*) {from};"))
+        .fetch_one(&mut *conn)
+        .await?;
+    let ids: Vec<(i64,)> = sqlx::query_as(&format!("SELECT id {from} ORDER BY id LIMIT ?;"))
+        .bind(SAMPLE_LIMIT)
+        .fetch_all(&mut *conn)
+        .await?;
+    Ok(Rows {
+        count: count.0 as u64,
+        ids: ids.into_iter().map(|(id,)| id).collect(),
+    })
+}
+
+/// Table, rowid and parent table of the rows violating a foreign key, leaving out the ones the
+/// dangling match and orphaned run image checks report.
+async fn foreign_key_violations(
+    conn: &mut SqliteConnection,
+) -> Result<Vec<(String, i64, String)>, Error> {
+    let violations: Vec<(String, Option<i64>, String)> =
+        sqlx::query_as("SELECT \"table\", rowid, parent FROM pragma_foreign_key_check;")
+            .fetch_all(&mut *conn)
+            .await?;
+    Ok(violations
+        .into_iter()
+        .filter(|(table, _, parent)| {
+            !matches!(
+                (table.as_str(), parent.as_str()),
+                ("matches", "hashes") | ("run_images", "images")
+            )
+        })
+        .filter_map(|(table, rowid, parent)| Some((table, rowid?, parent)))
+        .collect())
+}
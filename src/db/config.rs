@@ -0,0 +1,176 @@
+use std::{
+    convert::Infallible,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use sqlx::{
+    SqlitePool,
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions},
+};
+
+use crate::db::{DB, Error};
+
+/// Environment variable `DbConfig::from_env` reads the database location from.
+pub const DB_ENV: &str = "P_HASH_DB";
+
+static IN_MEMORY_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+/// Where the database is and how connections to it are set up. A single configuration is
+/// connected once and the resulting pool is shared by everything that writes to or reads from
+/// the database.
+#[derive(Debug, Clone)]
+pub struct DbConfig {
+    location: Location,
+    wal: bool,
+    cache_size: Option<i64>,
+    busy_timeout: Duration,
+    max_connections: u32,
+    foreign_keys: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Location {
+    File(PathBuf),
+    /// Name of the shared in-memory database, unique per `DbConfig::in_memory` call.
+    Memory(String),
+}
+
+impl Default for DbConfig {
+    fn default() -> Self {
+        Self::file("data.db")
+    }
+}
+impl DbConfig {
+    /// Database stored in the file at path, created if it does not exist.
+    pub fn file(path: impl Into<PathBuf>) -> Self {
+        Self {
+            location: Location::File(path.into()),
+            wal: false,
+            cache_size: Some(200000),
+            busy_timeout: Duration::from_secs(5),
+            max_connections: 10,
+            foreign_keys: true,
+        }
+    }
+    /// Database that only lives as long as the pool connected to it. Every call gives a new
+    /// database, clones of the configuration connect to the same one.
+    pub fn in_memory() -> Self {
+        let seq = IN_MEMORY_SEQ.fetch_add(1, Ordering::Relaxed);
+        Self {
+            location: Location::Memory(format!("p-hash-in-memory-{}", seq)),
+            ..Self::default()
+        }
+    }
+    /// Location from the `P_HASH_DB` environment variable, `data.db` if it is not set.
+    pub fn from_env() -> Self {
+        match std::env::var(DB_ENV) {
+            Ok(location) if !location.is_empty() => location.parse().unwrap(),
+            _ => Self::default(),
+        }
+    }
+    /// Write-ahead logging, lets readers run while a run is written. Has no effect in memory.
+    pub fn wal(mut self, on: bool) -> Self {
+        self.wal = on;
+        self
+    }
+    /// Page cache size as given to `PRAGMA cache_size`, positive values are pages and negative
+    /// values KiB. `None` keeps the SQLite default.
+    pub fn cache_size(mut self, cache_size: Option<i64>) -> Self {
+        self.cache_size = cache_size;
+        self
+    }
+    /// How long a connection waits for a lock held by another connection before failing.
+    pub fn busy_timeout(mut self, timeout: Duration) -> Self {
+        self.busy_timeout = timeout;
+        self
+    }
+    pub fn max_connections(mut self, max: u32) -> Self {
+        self.max_connections = max.max(1);
+        self
+    }
+    /// Enforces the foreign keys of the schema with `PRAGMA foreign_keys`. Databases written
+    /// without it can be checked for rows it would have rejected with `DB::check`.
+    pub fn foreign_keys(mut self, on: bool) -> Self {
+        self.foreign_keys = on;
+        self
+    }
+    /// Path of the database file, `None` for in-memory databases.
+    pub fn get_path(&self) -> Option<&Path> {
+        match &self.location {
+            Location::File(path) => Some(path),
+            Location::Memory(_) => None,
+        }
+    }
+    pub fn is_in_memory(&self) -> bool {
+        matches!(self.location, Location::Memory(_))
+    }
+    pub fn get_wal(&self) -> bool {
+        self.wal
+    }
+    pub fn get_cache_size(&self) -> Option<i64> {
+        self.cache_size
+    }
+    pub fn get_busy_timeout(&self) -> Duration {
+        self.busy_timeout
+    }
+    pub fn get_foreign_keys(&self) -> bool {
+        self.foreign_keys
+    }
+    pub fn connect_options(&self) -> SqliteConnectOptions {
+        let options = match &self.location {
+            Location::File(path) => SqliteConnectOptions::new()
+                .filename(path)
+                .create_if_missing(true)
+                .journal_mode(match self.wal {
+                    true => SqliteJournalMode::Wal,
+                    false => SqliteJournalMode::Delete,
+                }),
+            // Shared cache so every connection of the pool sees the same database.
+            Location::Memory(name) => SqliteConnectOptions::new()
+                .filename(format!("file:{}", name))
+                .in_memory(true)
+                .shared_cache(true),
+        }
+        .busy_timeout(self.busy_timeout)
+        .foreign_keys(self.foreign_keys);
+        match self.cache_size {
+            Some(size) => options.pragma("cache_size", size.to_string()),
+            None => options,
+        }
+    }
+    /// Opens a pool to the database without touching the schema.
+    pub async fn connect_without_migrating(&self) -> Result<SqlitePool, Error> {
+        let mut options = SqlitePoolOptions::new().max_connections(self.max_connections);
+        if self.is_in_memory() {
+            // The database is dropped with its last connection.
+            options = options
+                .min_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None);
+        }
+        Ok(options.connect_with(self.connect_options()).await?)
+    }
+    /// Opens a pool to the database and migrates it to the latest schema.
+    pub async fn connect(&self) -> Result<SqlitePool, Error> {
+        let pool = self.connect_without_migrating().await?;
+        DB::migrate(&pool).await?;
+        Ok(pool)
+    }
+}
+
+/// `:memory:` gives an in-memory database, anything else is a path, optionally prefixed with
+/// `sqlite:`.
+impl FromStr for DbConfig {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let location = s.strip_prefix("sqlite:").unwrap_or(s);
+        Ok(match location {
+            ":memory:" => Self::in_memory(),
+            path => Self::file(path),
+        })
+    }
+}
@@ -0,0 +1,38 @@
+use std::fmt::Display;
+
+#[derive(Debug)]
+pub enum Error {
+    Sqlx {
+        err: sqlx::Error,
+    },
+    /// The database was written by a newer version of the program.
+    UnsupportedVersion {
+        found: u32,
+        supported: u32,
+    },
+    Migration {
+        version: u32,
+        err: sqlx::Error,
+    },
+}
+impl From<sqlx::Error> for Error {
+    fn from(value: sqlx::Error) -> Self {
+        Self::Sqlx { err: value }
+    }
+}
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Sqlx { err } => write!(f, "Sqlx error: {}", err),
+            Self::UnsupportedVersion { found, supported } => write!(
+                f,
+                "Database schema version {} is newer than the supported version {}",
+                found, supported
+            ),
+            Self::Migration { version, err } => {
+                write!(f, "Migration to schema version {} failed: {}", version, err)
+            }
+        }
+    }
+}
+impl std::error::Error for Error {}
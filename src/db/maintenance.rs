@@ -0,0 +1,217 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{SqliteConnection, SqlitePool};
+
+use crate::db::Error;
+
+/// What was removed together with a run.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeletedRun {
+    run_id: i64,
+    run_images: u64,
+    modified_images: u64,
+    hashes: u64,
+    matches: u64,
+}
+impl DeletedRun {
+    pub fn get_run_id(&self) -> i64 {
+        self.run_id
+    }
+    pub fn get_run_images(&self) -> u64 {
+        self.run_images
+    }
+    pub fn get_modified_images(&self) -> u64 {
+        self.modified_images
+    }
+    pub fn get_hashes(&self) -> u64 {
+        self.hashes
+    }
+    pub fn get_matches(&self) -> u64 {
+        self.matches
+    }
+}
+
+/// Size of the database file before and after compacting it.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct CompactReport {
+    size_before: u64,
+    size_after: u64,
+}
+impl CompactReport {
+    pub fn get_size_before(&self) -> u64 {
+        self.size_before
+    }
+    pub fn get_size_after(&self) -> u64 {
+        self.size_after
+    }
+}
+
+/// Tables with rows belonging to a single run, removed with the run.
+const RUN_TABLES: &[&str] = &[
+    "run_images",
+    "skipped_images",
+    "duplicate_images",
+    "run_config",
+    "run_modifications",
+    "run_hashing_methods",
+    "run_stages",
+];
+
+/// Deletes the run in a single transaction. `None` if there is no such run.
+pub async fn delete_run(pool: &SqlitePool, run_id: i64) -> Result<Option<DeletedRun>, Error> {
+    let mut tx = pool.begin().await?;
+    let deleted = delete_run_in(&mut tx, run_id).await?;
+    tx.commit().await?;
+    Ok(deleted)
+}
+
+/// Deletes all but the newest `keep` runs. The active run is never deleted.
+pub async fn prune_runs(pool: &SqlitePool, keep: usize) -> Result<Vec<DeletedRun>, Error> {
+    let run_ids: Vec<(i64,)> = sqlx::query_as(
+        "
+        SELECT id FROM runs
+        WHERE id NOT IN (SELECT run_id FROM program)
+          AND id NOT IN (SELECT id FROM runs ORDER BY id DESC LIMIT ?)
+        ORDER BY id;
+        ",
+    )
+    .bind(keep as i64)
+    .fetch_all(pool)
+    .await?;
+
+    let mut deleted = Vec::with_capacity(run_ids.len());
+    for (run_id,) in run_ids {
+        if let Some(run) = delete_run(pool, run_id).await? {
+            deleted.push(run);
+        }
+    }
+    Ok(deleted)
+}
+
+/// Rebuilds the database file to release the space of deleted rows and refreshes the statistics
+/// the query planner uses.
+pub async fn compact(pool: &SqlitePool) -> Result<CompactReport, Error> {
+    let mut conn = pool.acquire().await?;
+    let size_before = database_size(&mut conn).await?;
+    // Can not run inside a transaction.
+    sqlx::query("VACUUM;").execute(&mut *conn).await?;
+    sqlx::query("ANALYZE;").execute(&mut *conn).await?;
+    let size_after = database_size(&mut conn).await?;
+    Ok(CompactReport {
+        size_before,
+        size_after,
+    })
+}
+
+async fn delete_run_in(
+    conn: &mut SqliteConnection,
+    run_id: i64,
+) -> Result<Option<DeletedRun>, Error> {
+    let exists: (bool,) = sqlx::query_as("SELECT EXISTS (SELECT 1 FROM runs WHERE id = ?);")
+        .bind(run_id)
+        .fetch_one(&mut *conn)
+        .await?;
+    if !exists.0 {
+        return Ok(None);
+    }
+    let mut deleted = DeletedRun {
+        run_id,
+        ..Default::default()
+    };
+
+    // Images of the run that are not part of any other run. Their modified images, hashes and
+    // matches are not used by anything after the run is gone.
+    sqlx::query("CREATE TEMP TABLE IF NOT EXISTS orphaned_images (image_id INTEGER PRIMARY KEY);")
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("DELETE FROM orphaned_images;")
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query(
+        "
+        INSERT OR IGNORE INTO orphaned_images (image_id)
+        SELECT image_id FROM run_images WHERE run_id = ?1
+        EXCEPT
+        SELECT image_id FROM run_images WHERE run_id != ?1;
+        ",
+    )
+    .bind(run_id)
+    .execute(&mut *conn)
+    .await?;
+
+    deleted.matches = sqlx::query(
+        "
+        DELETE FROM matches
+        WHERE hash1_id IN (
+            SELECT h.id FROM hashes h
+            JOIN modified_images mi ON mi.id = h.mod_image_id
+            WHERE mi.image_id IN orphaned_images
+        )
+        OR hash2_id IN (
+            SELECT h.id FROM hashes h
+            JOIN modified_images mi ON mi.id = h.mod_image_id
+            WHERE mi.image_id IN orphaned_images
+        );
+        ",
+    )
+    .execute(&mut *conn)
+    .await?
+    .rows_affected();
+    deleted.hashes = sqlx::query(
+        "
+        DELETE FROM hashes WHERE mod_image_id IN (
+            SELECT id FROM modified_images WHERE image_id IN orphaned_images
+        );
+        ",
+    )
+    .execute(&mut *conn)
+    .await?
+    .rows_affected();
+    deleted.modified_images =
+        sqlx::query("DELETE FROM modified_images WHERE image_id IN orphaned_images;")
+            .execute(&mut *conn)
+            .await?
+            .rows_affected();
+    sqlx::query("DROP TABLE orphaned_images;")
+        .execute(&mut *conn)
+        .await?;
+
+    for table in RUN_TABLES {
+        let res = sqlx::query(&format!("DELETE FROM {} WHERE run_id = ?;", table))
+            .bind(run_id)
+            .execute(&mut *conn)
+            .await?;
+        if *table == "run_images" {
+            deleted.run_images = res.rows_affected();
+        }
+    }
+
+    // The newest remaining run becomes the active run.
+    sqlx::query(
+        "
+        UPDATE program SET run_id = (SELECT max(id) FROM runs WHERE id != ?1)
+        WHERE run_id = ?1 AND EXISTS (SELECT 1 FROM runs WHERE id != ?1);
+        ",
+    )
+    .bind(run_id)
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query("DELETE FROM program WHERE run_id = ?;")
+        .bind(run_id)
+        .execute(&mut *conn)
+        .await?;
+
+    sqlx::query("DELETE FROM runs WHERE id = ?;")
+        .bind(run_id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(Some(deleted))
+}
+
+async fn database_size(conn: &mut SqliteConnection) -> Result<u64, Error> {
+    let size: (i64,) = sqlx::query_as(
+        "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size();",
+    )
+    .fetch_one(conn)
+    .await?;
+    Ok(size.0 as u64)
+}
@@ -0,0 +1,1092 @@
+use bitvec::prelude::*;
+use chrono::Utc;
+use sqlx::{Connection, SqliteConnection, SqlitePool};
+
+use crate::{db::Error, image_hash::Hash};
+
+/// A change of the schema. Migrations are applied in order of their version and every version is
+/// applied once, the applied versions are recorded in `schema_version`.
+#[derive(Debug)]
+pub struct Migration {
+    version: u32,
+    description: &'static str,
+    steps: &'static [Step],
+}
+impl Migration {
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+    pub fn description(&self) -> &'static str {
+        self.description
+    }
+    async fn apply(&self, conn: &mut SqliteConnection) -> Result<(), sqlx::Error> {
+        for step in self.steps {
+            match step {
+                Step::Sql(sql) => {
+                    sqlx::query(sql).execute(&mut *conn).await?;
+                }
+                Step::AddColumn {
+                    table,
+                    column,
+                    definition,
+                } => {
+                    if !has_column(&mut *conn, table, column).await? {
+                        let sql = format!(
+                            "ALTER TABLE {} ADD COLUMN {} {};",
+                            table, column, definition
+                        );
+                        sqlx::query(&sql).execute(&mut *conn).await?;
+                    }
+                }
+                Step::PackBitStrings {
+                    table,
+                    column,
+                    bits_column,
+                } => pack_bit_strings(&mut *conn, table, column, bits_column).await?,
+            }
+        }
+        sqlx::query(
+            "
+            INSERT INTO schema_version (version, description, applied_at) VALUES (?, ?, ?);
+            ",
+        )
+        .bind(self.version)
+        .bind(self.description)
+        .bind(Utc::now().timestamp_millis())
+        .execute(&mut *conn)
+        .await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum Step {
+    Sql(&'static str),
+    /// Adds the column unless it exists. Databases created before migrations were introduced can
+    /// have any of the later columns already.
+    AddColumn {
+        table: &'static str,
+        column: &'static str,
+        definition: &'static str,
+    },
+    /// Rewrites values stored as text of the form `[0, 1, 1, ...]` to the bits packed most
+    /// significant bit first, storing the number of bits in `bits_column`.
+    PackBitStrings {
+        table: &'static str,
+        column: &'static str,
+        bits_column: &'static str,
+    },
+}
+
+/// All migrations, ordered by version. New migrations are only ever appended.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "initial schema",
+        steps: &[
+            // Current running program. References what run that is currently being processed and
+            // should be used for matching.
+            Step::Sql(
+                "
+                CREATE TABLE IF NOT EXISTS program (
+                id INTEGER PRIMARY KEY CHECK (id = 0),
+                run_id INTEGER NOT NULL,
+                FOREIGN KEY (run_id) REFERENCES runs(id)
+                );
+                ",
+            ),
+            Step::Sql(
+                "
+                CREATE TABLE IF NOT EXISTS runs (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp INTEGER NOT NULL
+                );
+                ",
+            ),
+            Step::Sql(
+                "
+                CREATE TABLE IF NOT EXISTS images (
+                id INTEGER PRIMARY KEY,
+                path TEXT NOT NULL,
+                user TEXT NOT NULL
+                );
+                ",
+            ),
+            Step::Sql(
+                "
+                CREATE TABLE IF NOT EXISTS run_images(
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                run_id INTEGER NOT NULL,
+                image_id INTEGER NOT NULL,
+                FOREIGN KEY (run_id) REFERENCES runs(id),
+                FOREIGN KEY (image_id) REFERENCES images(id)
+                );
+                ",
+            ),
+            Step::Sql(
+                "
+                CREATE TABLE IF NOT EXISTS modifications (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT NOT NULL
+                );
+                ",
+            ),
+            Step::Sql(
+                "
+                CREATE TABLE IF NOT EXISTS hashing_methods (
+                id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+                name TEXT NOT NULL
+                );
+                ",
+            ),
+            Step::Sql(
+                "
+                CREATE TABLE IF NOT EXISTS modified_images (
+                id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+                image_id INTEGER NOT NULL,
+                modification_id INTEGER NOT NULL,
+                FOREIGN KEY (image_id) REFERENCES images(id),
+                FOREIGN KEY (modification_id) REFERENCES modifications(id)
+                UNIQUE (image_id, modification_id)
+                );
+                ",
+            ),
+            Step::Sql(
+                "
+                CREATE TABLE IF NOT EXISTS hashes (
+                id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+                hash BLOB NOT NULL,
+                mod_image_id INTEGER NOT NULL,
+                hashing_method_id INTEGER NOT NULL,
+                FOREIGN KEY (mod_image_id) REFERENCES modified_images(id),
+                FOREIGN KEY (hashing_method_id) REFERENCES hashing_methods(id)
+                UNIQUE (mod_image_id, hashing_method_id)
+                );
+                ",
+            ),
+            Step::Sql(
+                "
+                CREATE TABLE IF NOT EXISTS matches (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                hamming_distance INTEGER,
+                hash_len INTEGER,
+                hash1_id INTEGER,
+                hash2_id INTEGER,
+                FOREIGN KEY (hash1_id) REFERENCES hashes(id),
+                FOREIGN KEY (hash2_id) REFERENCES hashes(id)
+                );
+                ",
+            ),
+            Step::Sql(
+                "
+                CREATE VIEW IF NOT EXISTS active_run_hashes AS
+                SELECT h.id AS hash_id,
+                       h.hash,
+                       h.hashing_method_id,
+                       mi.id AS modified_image_id,
+                       mi.image_id,
+                       mi.modification_id
+                FROM hashes h
+                JOIN modified_images mi ON mi.id = h.mod_image_id
+                JOIN images i ON i.id = mi.image_id
+                JOIN run_images ri ON ri.image_id = i.id
+                JOIN program p ON p.run_id = ri.run_id;
+                ",
+            ),
+            Step::Sql(
+                "
+                CREATE VIEW IF NOT EXISTS active_run_modified_images AS
+                SELECT mi.id AS modified_image_id,
+                       mi.image_id,
+                       mi.modification_id
+                FROM modified_images mi
+                JOIN images i ON i.id = mi.image_id
+                JOIN run_images ri ON ri.image_id = i.id
+                JOIN program p ON p.run_id = ri.run_id;
+                ",
+            ),
+        ],
+    },
+    Migration {
+        version: 2,
+        description: "image quality of modified images",
+        steps: &[
+            Step::AddColumn {
+                table: "modified_images",
+                column: "psnr",
+                definition: "REAL",
+            },
+            Step::AddColumn {
+                table: "modified_images",
+                column: "ssim",
+                definition: "REAL",
+            },
+            Step::AddColumn {
+                table: "modified_images",
+                column: "mean_abs_diff",
+                definition: "REAL",
+            },
+        ],
+    },
+    Migration {
+        version: 3,
+        description: "skipped images",
+        // Files found under the image root that were not used in the run.
+        steps: &[Step::Sql(
+            "
+            CREATE TABLE IF NOT EXISTS skipped_images (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            run_id INTEGER NOT NULL,
+            path TEXT NOT NULL,
+            reason TEXT NOT NULL,
+            FOREIGN KEY (run_id) REFERENCES runs(id)
+            );
+            ",
+        )],
+    },
+    Migration {
+        version: 4,
+        description: "image sessions and tags",
+        steps: &[
+            Step::AddColumn {
+                table: "images",
+                column: "session",
+                definition: "TEXT",
+            },
+            Step::AddColumn {
+                table: "images",
+                column: "tags",
+                definition: "TEXT",
+            },
+        ],
+    },
+    Migration {
+        version: 5,
+        description: "content digests and duplicate images",
+        steps: &[
+            Step::AddColumn {
+                table: "images",
+                column: "digest",
+                definition: "TEXT",
+            },
+            // Images with the same content digest as another image in the run. `image_id` is the
+            // image the duplicate was merged into, only set for the merge policy.
+            Step::Sql(
+                "
+                CREATE TABLE IF NOT EXISTS duplicate_images (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                run_id INTEGER NOT NULL,
+                image_id INTEGER,
+                digest TEXT NOT NULL,
+                path TEXT NOT NULL,
+                original_path TEXT NOT NULL,
+                policy TEXT NOT NULL,
+                FOREIGN KEY (run_id) REFERENCES runs(id),
+                FOREIGN KEY (image_id) REFERENCES images(id)
+                );
+                ",
+            ),
+        ],
+    },
+    Migration {
+        version: 6,
+        description: "image metadata",
+        steps: &[
+            Step::AddColumn {
+                table: "images",
+                column: "width",
+                definition: "INTEGER",
+            },
+            Step::AddColumn {
+                table: "images",
+                column: "height",
+                definition: "INTEGER",
+            },
+            Step::AddColumn {
+                table: "images",
+                column: "format",
+                definition: "TEXT",
+            },
+            Step::AddColumn {
+                table: "images",
+                column: "color_type",
+                definition: "TEXT",
+            },
+            Step::AddColumn {
+                table: "images",
+                column: "orientation",
+                definition: "INTEGER",
+            },
+        ],
+    },
+    Migration {
+        version: 7,
+        description: "calibration and evaluation partitions",
+        steps: &[Step::AddColumn {
+            table: "run_images",
+            column: "partition",
+            definition: "TEXT",
+        }],
+    },
+    Migration {
+        version: 8,
+        description: "identity of images, modifications and hashing methods",
+        steps: &[
+            Step::AddColumn {
+                table: "modifications",
+                column: "params",
+                definition: "TEXT NOT NULL DEFAULT ''",
+            },
+            Step::AddColumn {
+                table: "hashing_methods",
+                column: "params",
+                definition: "TEXT NOT NULL DEFAULT ''",
+            },
+            // Not unique, rows from before the ids were stable can share an identity.
+            Step::Sql("CREATE INDEX IF NOT EXISTS images_identity ON images (path, digest);"),
+            Step::Sql(
+                "CREATE INDEX IF NOT EXISTS modifications_identity ON modifications (name, params);",
+            ),
+            Step::Sql(
+                "
+                CREATE INDEX IF NOT EXISTS hashing_methods_identity ON hashing_methods (name, params);
+                ",
+            ),
+        ],
+    },
+    Migration {
+        version: 9,
+        description: "hashes stored as packed bits",
+        steps: &[
+            Step::AddColumn {
+                table: "hashes",
+                column: "hash_bits",
+                definition: "INTEGER",
+            },
+            // Distances of matches between bit-string hashes were computed over the characters of
+            // the strings and can not be trusted.
+            Step::Sql(
+                "
+                DELETE FROM matches
+                WHERE hash1_id IN (SELECT id FROM hashes WHERE typeof(hash) = 'text')
+                   OR hash2_id IN (SELECT id FROM hashes WHERE typeof(hash) = 'text');
+                ",
+            ),
+            Step::PackBitStrings {
+                table: "hashes",
+                column: "hash",
+                bits_column: "hash_bits",
+            },
+        ],
+    },
+    Migration {
+        version: 10,
+        description: "run snapshots",
+        steps: &[
+            Step::Sql(
+                "
+                CREATE TABLE IF NOT EXISTS run_config (
+                run_id INTEGER PRIMARY KEY,
+                image_root TEXT NOT NULL,
+                sampling_seed INTEGER,
+                split_seed INTEGER,
+                crate_version TEXT NOT NULL,
+                threads INTEGER NOT NULL,
+                FOREIGN KEY (run_id) REFERENCES runs(id)
+                );
+                ",
+            ),
+            // Modifications and hashing methods selected for the run, in the order they were
+            // given.
+            Step::Sql(
+                "
+                CREATE TABLE IF NOT EXISTS run_modifications (
+                run_id INTEGER NOT NULL,
+                modification_id INTEGER NOT NULL,
+                position INTEGER NOT NULL,
+                PRIMARY KEY (run_id, modification_id),
+                FOREIGN KEY (run_id) REFERENCES runs(id),
+                FOREIGN KEY (modification_id) REFERENCES modifications(id)
+                );
+                ",
+            ),
+            Step::Sql(
+                "
+                CREATE TABLE IF NOT EXISTS run_hashing_methods (
+                run_id INTEGER NOT NULL,
+                hashing_method_id INTEGER NOT NULL,
+                position INTEGER NOT NULL,
+                PRIMARY KEY (run_id, hashing_method_id),
+                FOREIGN KEY (run_id) REFERENCES runs(id),
+                FOREIGN KEY (hashing_method_id) REFERENCES hashing_methods(id)
+                );
+                ",
+            ),
+            Step::Sql(
+                "
+                CREATE TABLE IF NOT EXISTS run_stages (
+                run_id INTEGER NOT NULL,
+                stage TEXT NOT NULL,
+                started_at INTEGER NOT NULL,
+                duration_ms INTEGER NOT NULL,
+                PRIMARY KEY (run_id, stage),
+                FOREIGN KEY (run_id) REFERENCES runs(id)
+                );
+                ",
+            ),
+        ],
+    },
+    Migration {
+        version: 11,
+        description: "match lookup indexes",
+        steps: &[
+            // Copied from the hashes of a match so the ROC reads matches without joining them.
+            // `modification_id` stays NULL for matches between different modifications, which
+            // the ROC does not use.
+            Step::AddColumn {
+                table: "matches",
+                column: "hashing_method_id",
+                definition: "INTEGER",
+            },
+            Step::AddColumn {
+                table: "matches",
+                column: "modification_id",
+                definition: "INTEGER",
+            },
+            Step::AddColumn {
+                table: "matches",
+                column: "image1_id",
+                definition: "INTEGER",
+            },
+            Step::AddColumn {
+                table: "matches",
+                column: "image2_id",
+                definition: "INTEGER",
+            },
+            Step::Sql(
+                "
+                UPDATE matches
+                SET hashing_method_id = h1.hashing_method_id,
+                    modification_id = CASE
+                        WHEN mi1.modification_id = mi2.modification_id THEN mi1.modification_id
+                    END,
+                    image1_id = mi1.image_id,
+                    image2_id = mi2.image_id
+                FROM hashes h1
+                JOIN modified_images mi1 ON mi1.id = h1.mod_image_id
+                JOIN hashes h2
+                JOIN modified_images mi2 ON mi2.id = h2.mod_image_id
+                WHERE h1.id = matches.hash1_id
+                  AND h2.id = matches.hash2_id
+                  AND h1.hashing_method_id = h2.hashing_method_id;
+                ",
+            ),
+            // Keeps the copied ids up to date for every writer of matches, until version 14 has the
+            // writers store them.
+            Step::Sql(
+                "
+                CREATE TRIGGER IF NOT EXISTS matches_lookup_ids AFTER INSERT ON matches
+                WHEN NEW.hashing_method_id IS NULL
+                BEGIN
+                    UPDATE matches
+                    SET (hashing_method_id, modification_id, image1_id, image2_id) = (
+                        SELECT h1.hashing_method_id,
+                               CASE
+                                   WHEN mi1.modification_id = mi2.modification_id
+                                   THEN mi1.modification_id
+                               END,
+                               mi1.image_id,
+                               mi2.image_id
+                        FROM hashes h1
+                        JOIN modified_images mi1 ON mi1.id = h1.mod_image_id
+                        JOIN hashes h2
+                        JOIN modified_images mi2 ON mi2.id = h2.mod_image_id
+                        WHERE h1.id = NEW.hash1_id
+                          AND h2.id = NEW.hash2_id
+                          AND h1.hashing_method_id = h2.hashing_method_id
+                    )
+                    WHERE id = NEW.id;
+                END;
+                ",
+            ),
+            // The ROC and the match count read all matches of a modification and hashing method.
+            Step::Sql(
+                "
+                CREATE INDEX IF NOT EXISTS matches_roc
+                ON matches (hashing_method_id, modification_id);
+                ",
+            ),
+            // Deleting the matches of a hash and not importing a match twice.
+            Step::Sql("CREATE INDEX IF NOT EXISTS matches_hash1 ON matches (hash1_id, hash2_id);"),
+            Step::Sql("CREATE INDEX IF NOT EXISTS matches_hash2 ON matches (hash2_id);"),
+            // Run and partition filters of the match queries, covering so only the index is read.
+            Step::Sql(
+                "
+                CREATE INDEX IF NOT EXISTS run_images_run
+                ON run_images (run_id, image_id, partition);
+                ",
+            ),
+            // Whether an image is part of another run when deleting a run.
+            Step::Sql("CREATE INDEX IF NOT EXISTS run_images_image ON run_images (image_id);"),
+            Step::Sql(
+                "CREATE INDEX IF NOT EXISTS skipped_images_run ON skipped_images (run_id);",
+            ),
+            Step::Sql(
+                "CREATE INDEX IF NOT EXISTS duplicate_images_run ON duplicate_images (run_id);",
+            ),
+        ],
+    },
+    Migration {
+        version: 12,
+        description: "unique matches",
+        steps: &[
+            // Every run over the same images stored their matches again, in either order of the
+            // hashes. The first match of a pair is kept.
+            Step::Sql(
+                "
+                DELETE FROM matches
+                WHERE id NOT IN (
+                    SELECT MIN(id) FROM matches
+                    GROUP BY MIN(hash1_id, hash2_id), MAX(hash1_id, hash2_id)
+                );
+                ",
+            ),
+            Step::Sql(
+                "
+                UPDATE matches
+                SET hash1_id = hash2_id,
+                    hash2_id = hash1_id,
+                    image1_id = image2_id,
+                    image2_id = image1_id
+                WHERE hash1_id > hash2_id;
+                ",
+            ),
+            Step::Sql("DROP INDEX IF EXISTS matches_hash1;"),
+            Step::Sql(
+                "CREATE UNIQUE INDEX IF NOT EXISTS matches_pair ON matches (hash1_id, hash2_id);",
+            ),
+        ],
+    },
+    Migration {
+        version: 13,
+        description: "unique images, modifications and hashing methods",
+        steps: &[
+            // Rows sharing an identity are merged into the one with the lowest id. Merging them
+            // can make modified images, hashes and matches the same as well, those are merged the
+            // same way. `*_merges` map the id of every merged row to the id it is merged into.
+            Step::Sql(
+                "
+                CREATE TEMP TABLE image_merges AS
+                SELECT id AS old_id, keep AS new_id FROM (
+                    SELECT id, MIN(id) OVER (PARTITION BY path, digest) AS keep FROM images
+                ) WHERE id <> keep;
+                ",
+            ),
+            Step::Sql(
+                "
+                CREATE TEMP TABLE modification_merges AS
+                SELECT id AS old_id, keep AS new_id FROM (
+                    SELECT id, MIN(id) OVER (PARTITION BY name, params) AS keep FROM modifications
+                ) WHERE id <> keep;
+                ",
+            ),
+            Step::Sql(
+                "
+                CREATE TEMP TABLE hashing_method_merges AS
+                SELECT id AS old_id, keep AS new_id FROM (
+                    SELECT id, MIN(id) OVER (PARTITION BY name, params) AS keep FROM hashing_methods
+                ) WHERE id <> keep;
+                ",
+            ),
+            Step::Sql(
+                "
+                CREATE TEMP TABLE modified_image_merges AS
+                SELECT id AS old_id, keep AS new_id FROM (
+                    SELECT mi.id, MIN(mi.id) OVER (
+                        PARTITION BY COALESCE(im.new_id, mi.image_id),
+                                     COALESCE(mm.new_id, mi.modification_id)
+                    ) AS keep
+                    FROM modified_images mi
+                    LEFT JOIN image_merges im ON im.old_id = mi.image_id
+                    LEFT JOIN modification_merges mm ON mm.old_id = mi.modification_id
+                ) WHERE id <> keep;
+                ",
+            ),
+            Step::Sql(
+                "
+                CREATE TEMP TABLE hash_merges AS
+                SELECT id AS old_id, keep AS new_id FROM (
+                    SELECT h.id, MIN(h.id) OVER (
+                        PARTITION BY COALESCE(mim.new_id, h.mod_image_id),
+                                     COALESCE(hmm.new_id, h.hashing_method_id)
+                    ) AS keep
+                    FROM hashes h
+                    LEFT JOIN modified_image_merges mim ON mim.old_id = h.mod_image_id
+                    LEFT JOIN hashing_method_merges hmm ON hmm.old_id = h.hashing_method_id
+                ) WHERE id <> keep;
+                ",
+            ),
+            // Matches that become the same pair, or a hash matched with itself, are deleted
+            // before the rest are moved so the unique pairs are never broken.
+            Step::Sql(
+                "
+                DELETE FROM matches WHERE id IN (
+                    SELECT id FROM (
+                        SELECT id, h1, h2,
+                               MIN(id) OVER (PARTITION BY MIN(h1, h2), MAX(h1, h2)) AS keep
+                        FROM (
+                            SELECT m.id,
+                                   COALESCE(hm1.new_id, m.hash1_id) AS h1,
+                                   COALESCE(hm2.new_id, m.hash2_id) AS h2
+                            FROM matches m
+                            LEFT JOIN hash_merges hm1 ON hm1.old_id = m.hash1_id
+                            LEFT JOIN hash_merges hm2 ON hm2.old_id = m.hash2_id
+                        )
+                    ) WHERE id <> keep OR h1 = h2
+                );
+                ",
+            ),
+            Step::Sql(
+                "
+                UPDATE matches
+                SET hash1_id = COALESCE(
+                        (SELECT new_id FROM hash_merges WHERE old_id = matches.hash1_id), hash1_id
+                    ),
+                    hash2_id = COALESCE(
+                        (SELECT new_id FROM hash_merges WHERE old_id = matches.hash2_id), hash2_id
+                    ),
+                    image1_id = COALESCE(
+                        (SELECT new_id FROM image_merges WHERE old_id = matches.image1_id),
+                        image1_id
+                    ),
+                    image2_id = COALESCE(
+                        (SELECT new_id FROM image_merges WHERE old_id = matches.image2_id),
+                        image2_id
+                    ),
+                    modification_id = COALESCE(
+                        (SELECT new_id FROM modification_merges
+                         WHERE old_id = matches.modification_id),
+                        modification_id
+                    ),
+                    hashing_method_id = COALESCE(
+                        (SELECT new_id FROM hashing_method_merges
+                         WHERE old_id = matches.hashing_method_id),
+                        hashing_method_id
+                    )
+                WHERE hash1_id IN (SELECT old_id FROM hash_merges)
+                   OR hash2_id IN (SELECT old_id FROM hash_merges)
+                   OR image1_id IN (SELECT old_id FROM image_merges)
+                   OR image2_id IN (SELECT old_id FROM image_merges)
+                   OR modification_id IN (SELECT old_id FROM modification_merges)
+                   OR hashing_method_id IN (SELECT old_id FROM hashing_method_merges);
+                ",
+            ),
+            Step::Sql(
+                "
+                UPDATE matches
+                SET hash1_id = hash2_id,
+                    hash2_id = hash1_id,
+                    image1_id = image2_id,
+                    image2_id = image1_id
+                WHERE hash1_id > hash2_id;
+                ",
+            ),
+            Step::Sql("DELETE FROM hashes WHERE id IN (SELECT old_id FROM hash_merges);"),
+            Step::Sql(
+                "
+                UPDATE hashes
+                SET mod_image_id = COALESCE(
+                        (SELECT new_id FROM modified_image_merges
+                         WHERE old_id = hashes.mod_image_id),
+                        mod_image_id
+                    ),
+                    hashing_method_id = COALESCE(
+                        (SELECT new_id FROM hashing_method_merges
+                         WHERE old_id = hashes.hashing_method_id),
+                        hashing_method_id
+                    )
+                WHERE mod_image_id IN (SELECT old_id FROM modified_image_merges)
+                   OR hashing_method_id IN (SELECT old_id FROM hashing_method_merges);
+                ",
+            ),
+            Step::Sql(
+                "DELETE FROM modified_images WHERE id IN (SELECT old_id FROM modified_image_merges);",
+            ),
+            Step::Sql(
+                "
+                UPDATE modified_images
+                SET image_id = COALESCE(
+                        (SELECT new_id FROM image_merges WHERE old_id = modified_images.image_id),
+                        image_id
+                    ),
+                    modification_id = COALESCE(
+                        (SELECT new_id FROM modification_merges
+                         WHERE old_id = modified_images.modification_id),
+                        modification_id
+                    )
+                WHERE image_id IN (SELECT old_id FROM image_merges)
+                   OR modification_id IN (SELECT old_id FROM modification_merges);
+                ",
+            ),
+            Step::Sql(
+                "
+                UPDATE run_images SET image_id = m.new_id
+                FROM image_merges m WHERE m.old_id = run_images.image_id;
+                ",
+            ),
+            Step::Sql(
+                "
+                UPDATE duplicate_images SET image_id = m.new_id
+                FROM image_merges m WHERE m.old_id = duplicate_images.image_id;
+                ",
+            ),
+            // A run can have selected both rows of a merged identity.
+            Step::Sql(
+                "
+                UPDATE OR IGNORE run_modifications SET modification_id = m.new_id
+                FROM modification_merges m WHERE m.old_id = run_modifications.modification_id;
+                ",
+            ),
+            Step::Sql(
+                "
+                DELETE FROM run_modifications
+                WHERE modification_id IN (SELECT old_id FROM modification_merges);
+                ",
+            ),
+            Step::Sql(
+                "
+                UPDATE OR IGNORE run_hashing_methods SET hashing_method_id = m.new_id
+                FROM hashing_method_merges m
+                WHERE m.old_id = run_hashing_methods.hashing_method_id;
+                ",
+            ),
+            Step::Sql(
+                "
+                DELETE FROM run_hashing_methods
+                WHERE hashing_method_id IN (SELECT old_id FROM hashing_method_merges);
+                ",
+            ),
+            Step::Sql("DELETE FROM images WHERE id IN (SELECT old_id FROM image_merges);"),
+            Step::Sql(
+                "DELETE FROM modifications WHERE id IN (SELECT old_id FROM modification_merges);",
+            ),
+            Step::Sql(
+                "
+                DELETE FROM hashing_methods
+                WHERE id IN (SELECT old_id FROM hashing_method_merges);
+                ",
+            ),
+            Step::Sql("DROP TABLE temp.image_merges;"),
+            Step::Sql("DROP TABLE temp.modification_merges;"),
+            Step::Sql("DROP TABLE temp.hashing_method_merges;"),
+            Step::Sql("DROP TABLE temp.modified_image_merges;"),
+            Step::Sql("DROP TABLE temp.hash_merges;"),
+            // Images without a digest are the same image if their paths are.
+            Step::Sql("DROP INDEX IF EXISTS images_identity;"),
+            Step::Sql(
+                "
+                CREATE UNIQUE INDEX IF NOT EXISTS images_identity
+                ON images (path, IFNULL(digest, ''));
+                ",
+            ),
+            Step::Sql("DROP INDEX IF EXISTS modifications_identity;"),
+            Step::Sql(
+                "
+                CREATE UNIQUE INDEX IF NOT EXISTS modifications_identity
+                ON modifications (name, params);
+                ",
+            ),
+            Step::Sql("DROP INDEX IF EXISTS hashing_methods_identity;"),
+            Step::Sql(
+                "
+                CREATE UNIQUE INDEX IF NOT EXISTS hashing_methods_identity
+                ON hashing_methods (name, params);
+                ",
+            ),
+        ],
+    },
+    Migration {
+        version: 14,
+        description: "match ids written with the matches",
+        steps: &[
+            // The writers of matches know the ids from the hashes they matched, looking them up
+            // again for every inserted match is not needed. Matches stored before version 11 were
+            // filled in by its update.
+            Step::Sql("DROP TRIGGER IF EXISTS matches_lookup_ids;"),
+        ],
+    },
+    Migration {
+        version: 15,
+        description: "load options of modified images",
+        steps: &[
+            // If the EXIF orientation was applied when the image was decoded. NULL for modified
+            // images stored before, they are only reused for images without an orientation.
+            Step::AddColumn {
+                table: "modified_images",
+                column: "apply_orientation",
+                definition: "INTEGER",
+            },
+        ],
+    },
+];
+
+/// Version of the schema the program is written for.
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or_default()
+}
+
+/// Version of the schema in the database, 0 if no migrations have been applied.
+pub async fn current_version(conn: &mut SqliteConnection) -> Result<u32, Error> {
+    let exists: (bool,) = sqlx::query_as(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'schema_version');",
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    if !exists.0 {
+        return Ok(0);
+    }
+    let version: (Option<u32>,) = sqlx::query_as("SELECT MAX(version) FROM schema_version;")
+        .fetch_one(&mut *conn)
+        .await?;
+    Ok(version.0.unwrap_or_default())
+}
+
+/// Migrations that are not applied to the database. Databases written by a newer version of the
+/// program are refused.
+pub async fn pending(conn: &mut SqliteConnection) -> Result<Vec<&'static Migration>, Error> {
+    let current = current_version(conn).await?;
+    if current > latest_version() {
+        return Err(Error::UnsupportedVersion {
+            found: current,
+            supported: latest_version(),
+        });
+    }
+    Ok(MIGRATIONS.iter().filter(|m| m.version > current).collect())
+}
+
+/// Applies the pending migrations, every migration in its own transaction. Returns the applied
+/// migrations.
+pub async fn migrate(pool: &SqlitePool) -> Result<Vec<&'static Migration>, Error> {
+    migrate_to(pool, latest_version()).await
+}
+
+/// Applies the pending migrations up to and including the version.
+async fn migrate_to(pool: &SqlitePool, version: u32) -> Result<Vec<&'static Migration>, Error> {
+    let mut conn = pool.acquire().await?;
+    sqlx::query(
+        "
+        CREATE TABLE IF NOT EXISTS schema_version (
+        version INTEGER PRIMARY KEY,
+        description TEXT NOT NULL,
+        applied_at INTEGER NOT NULL
+        );
+        ",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    let mut pending = pending(&mut conn).await?;
+    pending.retain(|m| m.version <= version);
+    for migration in &pending {
+        tracing::info!(
+            "Migrating database to version {}: {}",
+            migration.version,
+            migration.description
+        );
+        let mut tx = conn.begin().await?;
+        migration
+            .apply(&mut tx)
+            .await
+            .map_err(|e| Error::Migration {
+                version: migration.version,
+                err: e,
+            })?;
+        tx.commit().await?;
+    }
+    Ok(pending)
+}
+
+async fn has_column(
+    conn: &mut SqliteConnection,
+    table: &str,
+    column: &str,
+) -> Result<bool, sqlx::Error> {
+    let exists: (bool,) =
+        sqlx::query_as("SELECT EXISTS (SELECT 1 FROM pragma_table_info(?) WHERE name = ?);")
+            .bind(table)
+            .bind(column)
+            .fetch_one(conn)
+            .await?;
+    Ok(exists.0)
+}
+
+async fn pack_bit_strings(
+    conn: &mut SqliteConnection,
+    table: &str,
+    column: &str,
+    bits_column: &str,
+) -> Result<(), sqlx::Error> {
+    let select = format!(
+        "SELECT id, {} FROM {} WHERE typeof({}) = 'text';",
+        column, table, column
+    );
+    let rows: Vec<(i64, String)> = sqlx::query_as(&select).fetch_all(&mut *conn).await?;
+
+    let update = format!(
+        "UPDATE {} SET {} = ?, {} = ? WHERE id = ?;",
+        table, column, bits_column
+    );
+    for (id, text) in rows {
+        let bits = parse_bit_string(&text).ok_or_else(|| {
+            sqlx::Error::Decode(
+                format!("{}.{} of row {} is not a bit string", table, column, id).into(),
+            )
+        })?;
+        let hash = Hash::new(bits);
+        sqlx::query(&update)
+            .bind(hash.to_bytes())
+            .bind(hash.bit_len() as u32)
+            .bind(id)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Parses `[0, 1, 1]`, the `Display` of a bit vector.
+fn parse_bit_string(text: &str) -> Option<BitVec<u8, Msb0>> {
+    let inner = text.trim().strip_prefix('[')?.strip_suffix(']')?;
+    if inner.trim().is_empty() {
+        return Some(BitVec::new());
+    }
+    inner
+        .split(',')
+        .map(|bit| match bit.trim() {
+            "0" => Some(false),
+            "1" => Some(true),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::DbConfig;
+
+    /// Database at version 8 with rows from before hashes were packed, matches were unique and
+    /// identities were unique. Image 2, modification 2 and hashing method 2 are copies of the
+    /// first ones, so modified image 2 and hash 2 are copies as well.
+    async fn legacy_db() -> SqlitePool {
+        let pool = DbConfig::in_memory()
+            .connect_without_migrating()
+            .await
+            .unwrap();
+        migrate_to(&pool, 8).await.unwrap();
+        sqlx::query(
+            "
+            INSERT INTO runs (id, timestamp) VALUES (1, 0);
+            INSERT INTO images (id, path, user) VALUES
+                (1, 'a.png', 'u'), (2, 'a.png', 'u'), (3, 'b.png', 'u'), (4, 'c.png', 'u');
+            INSERT INTO run_images (run_id, image_id) VALUES (1, 2), (1, 3), (1, 4);
+            INSERT INTO modifications (id, name) VALUES (1, 'blur'), (2, 'blur');
+            INSERT INTO hashing_methods (id, name) VALUES (1, 'ahash'), (2, 'ahash');
+            INSERT INTO modified_images (id, image_id, modification_id) VALUES
+                (1, 1, 1), (2, 2, 2), (3, 3, 1), (4, 4, 1);
+            INSERT INTO hashes (id, hash, mod_image_id, hashing_method_id) VALUES
+                (1, x'A0', 1, 1), (2, x'A0', 2, 2), (3, x'B0', 3, 1),
+                (4, '[1, 0, 1, 1, 0, 0, 0, 0, 1]', 4, 1);
+            INSERT INTO matches (id, hamming_distance, hash_len, hash1_id, hash2_id) VALUES
+                (1, 1, 8, 3, 1), (2, 1, 8, 1, 3), (3, 1, 8, 2, 3), (4, 0, 8, 1, 2),
+                (5, 5, 9, 3, 4);
+            ",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        pool
+    }
+
+    async fn ids(pool: &SqlitePool, table: &str) -> Vec<i64> {
+        sqlx::query_as::<_, (i64,)>(&format!("SELECT id FROM {} ORDER BY id;", table))
+            .fetch_all(pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(id,)| id)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn bit_strings_are_packed() {
+        let pool = legacy_db().await;
+        migrate(&pool).await.unwrap();
+
+        let (hash, bits): (Vec<u8>, u32) =
+            sqlx::query_as("SELECT hash, hash_bits FROM hashes WHERE id = 4;")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(hash, [0b1011_0000, 0b1000_0000]);
+        assert_eq!(bits, 9);
+        // Its match was made from the characters of the string.
+        assert!(!ids(&pool, "matches").await.contains(&5));
+    }
+
+    #[tokio::test]
+    async fn pairs_are_matched_once() {
+        let pool = legacy_db().await;
+        migrate(&pool).await.unwrap();
+
+        // The first match of the pair is kept, with the hashes in order.
+        let matches: Vec<(i64, i64, i64, i64, i64)> = sqlx::query_as(
+            "SELECT id, hash1_id, hash2_id, image1_id, image2_id FROM matches ORDER BY id;",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(matches, [(1, 1, 3, 1, 3)]);
+        let ids: (Option<i64>, i64) =
+            sqlx::query_as("SELECT modification_id, hashing_method_id FROM matches;")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(ids, (Some(1), 1));
+
+        let res = sqlx::query(
+            "INSERT INTO matches (hamming_distance, hash_len, hash1_id, hash2_id) VALUES (1, 8, 1, 3);",
+        )
+        .execute(&pool)
+        .await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn identities_are_merged() {
+        let pool = legacy_db().await;
+        migrate(&pool).await.unwrap();
+
+        assert_eq!(ids(&pool, "images").await, [1, 3, 4]);
+        assert_eq!(ids(&pool, "modifications").await, [1]);
+        assert_eq!(ids(&pool, "hashing_methods").await, [1]);
+        assert_eq!(ids(&pool, "modified_images").await, [1, 3, 4]);
+        assert_eq!(ids(&pool, "hashes").await, [1, 3, 4]);
+
+        let run_images: Vec<(i64,)> =
+            sqlx::query_as("SELECT image_id FROM run_images ORDER BY image_id;")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(run_images, [(1,), (3,), (4,)]);
+
+        let res = sqlx::query("INSERT INTO images (path, user) VALUES ('a.png', 'u');")
+            .execute(&pool)
+            .await;
+        assert!(res.is_err());
+        let check: Vec<(String,)> = sqlx::query_as("PRAGMA foreign_key_check;")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert!(check.is_empty());
+    }
+}
@@ -0,0 +1,111 @@
+use std::{collections::HashMap, time::Instant};
+
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    Row, Sqlite, SqliteConnection, SqlitePool,
+    query::Query,
+    sqlite::{SqliteArguments, SqliteRow},
+};
+
+use crate::db::{
+    Error, MatchFilter,
+    repository::{HASHES_QUERY, MATCH_COUNT_QUERY, MATCHES_QUERY},
+};
+
+type SqliteQuery<'q> = Query<'q, Sqlite, SqliteArguments<'q>>;
+
+/// How SQLite executes one of the queries results are read with, and how long reading all of its
+/// rows took.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessPath {
+    name: String,
+    plan: Vec<String>,
+    rows: u64,
+    duration_ms: u64,
+}
+impl AccessPath {
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+    /// Lines of `EXPLAIN QUERY PLAN`, indented by two spaces per level.
+    pub fn get_plan(&self) -> &[String] {
+        &self.plan
+    }
+    pub fn get_rows(&self) -> u64 {
+        self.rows
+    }
+    pub fn get_duration_ms(&self) -> u64 {
+        self.duration_ms
+    }
+}
+
+/// Runs the queries the ROC and the matching read with for the filter.
+pub async fn benchmark(pool: &SqlitePool, filter: &MatchFilter) -> Result<Vec<AccessPath>, Error> {
+    let mut conn = pool.acquire().await?;
+    Ok(vec![
+        measure(
+            &mut conn,
+            "match_count",
+            MATCH_COUNT_QUERY,
+            filter,
+            bind_matches,
+        )
+        .await?,
+        measure(&mut conn, "matches", MATCHES_QUERY, filter, bind_matches).await?,
+        measure(&mut conn, "hashes", HASHES_QUERY, filter, bind_hashes).await?,
+    ])
+}
+
+fn bind_matches<'q>(query: SqliteQuery<'q>, filter: &MatchFilter) -> SqliteQuery<'q> {
+    query
+        .bind(filter.modification_id)
+        .bind(filter.hashing_method_id)
+        .bind(filter.partition.map(|p| p.to_string()))
+        .bind(filter.run)
+}
+
+fn bind_hashes<'q>(query: SqliteQuery<'q>, filter: &MatchFilter) -> SqliteQuery<'q> {
+    query
+        .bind(filter.run)
+        .bind(filter.hashing_method_id)
+        .bind(Some(filter.modification_id))
+}
+
+async fn measure(
+    conn: &mut SqliteConnection,
+    name: &str,
+    sql: &str,
+    filter: &MatchFilter,
+    bind: for<'q> fn(SqliteQuery<'q>, &MatchFilter) -> SqliteQuery<'q>,
+) -> Result<AccessPath, Error> {
+    let explain = format!("EXPLAIN QUERY PLAN {}", sql);
+    let steps: Vec<(i64, i64, String)> = bind(sqlx::query(&explain), filter)
+        .fetch_all(&mut *conn)
+        .await?
+        .into_iter()
+        .map(|row: SqliteRow| Ok((row.try_get(0)?, row.try_get(1)?, row.try_get(3)?)))
+        .collect::<Result<_, sqlx::Error>>()?;
+    let mut depths: HashMap<i64, usize> = HashMap::new();
+    let plan = steps
+        .into_iter()
+        .map(|(id, parent, detail)| {
+            let depth = depths.get(&parent).map(|d| d + 1).unwrap_or_default();
+            depths.insert(id, depth);
+            format!("{}{}", "  ".repeat(depth), detail)
+        })
+        .collect();
+
+    let start = Instant::now();
+    let mut rows = 0;
+    let mut stream = bind(sqlx::query(sql), filter).fetch(&mut *conn);
+    while stream.try_next().await?.is_some() {
+        rows += 1;
+    }
+    Ok(AccessPath {
+        name: name.to_string(),
+        plan,
+        rows,
+        duration_ms: start.elapsed().as_millis() as u64,
+    })
+}
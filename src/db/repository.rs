@@ -0,0 +1,573 @@
+use futures::{StreamExt, TryStreamExt, stream::BoxStream};
+use serde::{Deserialize, Serialize};
+use sqlx::{SqlitePool, prelude::FromRow};
+
+use crate::{
+    db::Error,
+    image_modify::non_finite,
+    image_parse::Partition,
+    matching::state::{HammingDistance, Hash, Match},
+};
+
+/// Filters shared by the match queries. Binds the modification id, the hashing method id, an
+/// optional partition and an optional run. The partition is of the active run unless a run is
+/// given. Reads the ids copied onto `matches` instead of joining the hashes.
+macro_rules! matches_filter {
+    () => {
+        "
+        FROM matches m
+        WHERE m.modification_id = ?1
+          AND m.hashing_method_id = ?2
+          AND (?4 IS NULL OR (
+            EXISTS (
+              SELECT 1 FROM run_images ri WHERE ri.run_id = ?4 AND ri.image_id = m.image1_id
+            )
+            AND EXISTS (
+              SELECT 1 FROM run_images ri WHERE ri.run_id = ?4 AND ri.image_id = m.image2_id
+            )
+          ))
+          AND (?3 IS NULL OR (
+            EXISTS (
+              SELECT 1 FROM run_images ri
+              WHERE ri.run_id = COALESCE(?4, (SELECT run_id FROM program))
+                AND ri.image_id = m.image1_id AND ri.partition = ?3
+            )
+            AND EXISTS (
+              SELECT 1 FROM run_images ri
+              WHERE ri.run_id = COALESCE(?4, (SELECT run_id FROM program))
+                AND ri.image_id = m.image2_id AND ri.partition = ?3
+            )
+          ))
+        "
+    };
+}
+
+pub(super) const MATCHES_QUERY: &str = concat!(
+    "
+    SELECT m.id,
+           m.hamming_distance,
+           m.hash_len,
+           m.hash1_id,
+           m.hash2_id,
+           m.image1_id,
+           m.image2_id,
+           m.modification_id,
+           m.hashing_method_id
+    ",
+    matches_filter!(),
+    ";"
+);
+
+pub(super) const MATCH_COUNT_QUERY: &str = concat!("SELECT count(m.id)", matches_filter!(), ";");
+
+pub(super) const HASHES_QUERY: &str = "
+    SELECT h.id, h.hash, h.hash_bits, mi.image_id, mi.modification_id, h.hashing_method_id
+    FROM hashes h
+    JOIN modified_images mi ON mi.id = h.mod_image_id
+    JOIN run_images ri ON ri.image_id = mi.image_id
+    WHERE ri.run_id = COALESCE(?1, (SELECT run_id FROM program))
+      AND h.hashing_method_id = ?2
+      AND (?3 IS NULL OR mi.modification_id = ?3);
+    ";
+
+/// Typed reads of the results database. Everything reading results goes through here instead of
+/// writing its own joins.
+#[derive(Debug, Clone)]
+pub struct Repository {
+    pool: SqlitePool,
+}
+impl Repository {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+    pub fn pool(&self) -> &SqlitePool {
+        &self.pool
+    }
+    /// All runs, oldest first.
+    pub async fn runs(&self) -> Result<Vec<Run>, Error> {
+        Ok(
+            sqlx::query_as("SELECT id, timestamp FROM runs ORDER BY id;")
+                .fetch_all(&self.pool)
+                .await?,
+        )
+    }
+    pub async fn run(&self, run_id: i64) -> Result<Option<Run>, Error> {
+        Ok(
+            sqlx::query_as("SELECT id, timestamp FROM runs WHERE id = ?;")
+                .bind(run_id)
+                .fetch_optional(&self.pool)
+                .await?,
+        )
+    }
+    /// Id of the run the `program` row points to.
+    pub async fn active_run(&self) -> Result<Option<i64>, Error> {
+        let run: Option<(i64,)> = sqlx::query_as("SELECT run_id FROM program WHERE id = 0;")
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(run.map(|r| r.0))
+    }
+    pub async fn run_images(&self, run_id: i64) -> Result<Vec<RunImage>, Error> {
+        Ok(sqlx::query_as(
+            "
+            SELECT i.id, i.path, i.user, i.session, i.tags, i.digest, i.width, i.height, i.format,
+                   i.color_type, i.orientation, ri.partition
+            FROM run_images ri
+            JOIN images i ON i.id = ri.image_id
+            WHERE ri.run_id = ?
+            ORDER BY i.id;
+            ",
+        )
+        .bind(run_id)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+    /// Modifications the images of a run were stored with.
+    pub async fn run_modifications(&self, run_id: i64) -> Result<Vec<StoredMethod>, Error> {
+        Ok(sqlx::query_as(
+            "
+            SELECT m.id, m.name, m.params FROM modifications m
+            WHERE m.id IN (
+                SELECT mi.modification_id FROM modified_images mi
+                JOIN run_images ri ON ri.image_id = mi.image_id
+                WHERE ri.run_id = ?
+            )
+            ORDER BY m.id;
+            ",
+        )
+        .bind(run_id)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+    /// Hashing methods the images of a run were stored with.
+    pub async fn run_hashing_methods(&self, run_id: i64) -> Result<Vec<StoredMethod>, Error> {
+        Ok(sqlx::query_as(
+            "
+            SELECT hm.id, hm.name, hm.params FROM hashing_methods hm
+            WHERE hm.id IN (
+                SELECT h.hashing_method_id FROM hashes h
+                JOIN modified_images mi ON mi.id = h.mod_image_id
+                JOIN run_images ri ON ri.image_id = mi.image_id
+                WHERE ri.run_id = ?
+            )
+            ORDER BY hm.id;
+            ",
+        )
+        .bind(run_id)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+    pub async fn run_modified_images(
+        &self,
+        run_id: i64,
+    ) -> Result<Vec<StoredModifiedImage>, Error> {
+        Ok(sqlx::query_as(
+            "
+            SELECT mi.id, mi.image_id, mi.modification_id, mi.psnr, mi.ssim, mi.mean_abs_diff
+            FROM modified_images mi
+            JOIN run_images ri ON ri.image_id = mi.image_id
+            WHERE ri.run_id = ?
+            ORDER BY mi.id;
+            ",
+        )
+        .bind(run_id)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+    /// All hashes of the images in a run.
+    pub async fn run_hashes(&self, run_id: i64) -> Result<Vec<StoredHash>, Error> {
+        Ok(sqlx::query_as(
+            "
+            SELECT h.id, h.mod_image_id, h.hashing_method_id, h.hash, h.hash_bits
+            FROM hashes h
+            JOIN modified_images mi ON mi.id = h.mod_image_id
+            JOIN run_images ri ON ri.image_id = mi.image_id
+            WHERE ri.run_id = ?
+            ORDER BY h.id;
+            ",
+        )
+        .bind(run_id)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+    /// Hashes of the images in a run made with a hashing method, optionally of a single
+    /// modification.
+    pub async fn hashes(&self, filter: &HashFilter) -> Result<Vec<Hash>, Error> {
+        Ok(sqlx::query_as(HASHES_QUERY)
+            .bind(filter.run)
+            .bind(filter.hashing_method_id)
+            .bind(filter.modification_id)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+    pub async fn match_count(&self, filter: &MatchFilter) -> Result<u32, Error> {
+        let count: (u32,) = sqlx::query_as(MATCH_COUNT_QUERY)
+            .bind(filter.modification_id)
+            .bind(filter.hashing_method_id)
+            .bind(filter.partition.map(|p| p.to_string()))
+            .bind(filter.run)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(count.0)
+    }
+    /// Streams the matches, with the images and methods they were made from.
+    pub fn matches(&self, filter: &MatchFilter) -> BoxStream<'_, Result<StoredMatch, Error>> {
+        sqlx::query_as(MATCHES_QUERY)
+            .bind(filter.modification_id)
+            .bind(filter.hashing_method_id)
+            .bind(filter.partition.map(|p| p.to_string()))
+            .bind(filter.run)
+            .fetch(&self.pool)
+            .map_err(Error::from)
+            .boxed()
+    }
+    /// Streams every match between images of a run, also the ones between hashes of different
+    /// modifications.
+    pub fn run_matches(&self, run_id: i64) -> BoxStream<'_, Result<StoredMatch, Error>> {
+        sqlx::query_as(
+            "
+            WITH run_image_ids AS (SELECT image_id FROM run_images WHERE run_id = ?1)
+            SELECT m.id,
+                   m.hamming_distance,
+                   m.hash_len,
+                   m.hash1_id,
+                   m.hash2_id,
+                   m.image1_id,
+                   m.image2_id,
+                   m.modification_id,
+                   m.hashing_method_id
+            FROM matches m
+            WHERE m.image1_id IN run_image_ids AND m.image2_id IN run_image_ids
+            ORDER BY m.id;
+            ",
+        )
+        .bind(run_id)
+        .fetch(&self.pool)
+        .map_err(Error::from)
+        .boxed()
+    }
+    /// Counts of what is stored for a run, `None` if there is no such run.
+    pub async fn run_summary(&self, run_id: i64) -> Result<Option<RunSummary>, Error> {
+        Ok(sqlx::query_as(
+            "
+            WITH run_image_ids AS (SELECT image_id FROM run_images WHERE run_id = ?1)
+            SELECT r.id AS run_id,
+                   r.timestamp,
+                   (SELECT count(*) FROM run_images WHERE run_id = r.id) AS images,
+                   (SELECT count(*) FROM skipped_images WHERE run_id = r.id) AS skipped_images,
+                   (SELECT count(*) FROM duplicate_images WHERE run_id = r.id) AS duplicate_images,
+                   (
+                     SELECT count(*) FROM modified_images
+                     WHERE image_id IN run_image_ids
+                   ) AS modified_images,
+                   (
+                     SELECT count(*) FROM hashes h
+                     JOIN modified_images mi ON mi.id = h.mod_image_id
+                     WHERE mi.image_id IN run_image_ids
+                   ) AS hashes,
+                   (
+                     SELECT count(*) FROM matches m
+                     WHERE m.image1_id IN run_image_ids AND m.image2_id IN run_image_ids
+                   ) AS matches
+            FROM runs r
+            WHERE r.id = ?1;
+            ",
+        )
+        .bind(run_id)
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Run {
+    id: i64,
+    /// Milliseconds since the unix epoch.
+    timestamp: i64,
+}
+impl Run {
+    pub fn get_id(&self) -> i64 {
+        self.id
+    }
+    pub fn get_timestamp(&self) -> i64 {
+        self.timestamp
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct RunImage {
+    id: i64,
+    path: String,
+    user: String,
+    session: Option<String>,
+    /// JSON array of the tags.
+    tags: Option<String>,
+    digest: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    format: Option<String>,
+    color_type: Option<String>,
+    orientation: Option<u32>,
+    partition: Option<String>,
+}
+impl RunImage {
+    pub fn get_id(&self) -> i64 {
+        self.id
+    }
+    pub fn get_path(&self) -> &str {
+        &self.path
+    }
+    pub fn get_user(&self) -> &str {
+        &self.user
+    }
+    pub fn get_session(&self) -> Option<&str> {
+        self.session.as_deref()
+    }
+    pub fn get_tags(&self) -> Vec<String> {
+        self.tags
+            .as_deref()
+            .and_then(|t| serde_json::from_str(t).ok())
+            .unwrap_or_default()
+    }
+    pub fn get_digest(&self) -> Option<&str> {
+        self.digest.as_deref()
+    }
+    pub fn get_dimensions(&self) -> Option<(u32, u32)> {
+        self.width.zip(self.height)
+    }
+    pub fn get_format(&self) -> Option<&str> {
+        self.format.as_deref()
+    }
+    pub fn get_color_type(&self) -> Option<&str> {
+        self.color_type.as_deref()
+    }
+    /// EXIF orientation tag.
+    pub fn get_orientation(&self) -> Option<u32> {
+        self.orientation
+    }
+    pub fn get_partition(&self) -> Option<Partition> {
+        self.partition.as_deref().and_then(|p| p.parse().ok())
+    }
+}
+
+/// A modification or hashing method as stored.
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct StoredMethod {
+    id: i64,
+    name: String,
+    params: String,
+}
+impl StoredMethod {
+    pub fn get_id(&self) -> i64 {
+        self.id
+    }
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+    pub fn get_params(&self) -> &str {
+        &self.params
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct StoredModifiedImage {
+    id: i64,
+    image_id: i64,
+    modification_id: i64,
+    #[serde(with = "non_finite")]
+    psnr: Option<f64>,
+    #[serde(with = "non_finite")]
+    ssim: Option<f64>,
+    #[serde(with = "non_finite")]
+    mean_abs_diff: Option<f64>,
+}
+impl StoredModifiedImage {
+    pub fn get_id(&self) -> i64 {
+        self.id
+    }
+    pub fn get_image_id(&self) -> i64 {
+        self.image_id
+    }
+    pub fn get_modification_id(&self) -> i64 {
+        self.modification_id
+    }
+    pub fn get_psnr(&self) -> Option<f64> {
+        self.psnr
+    }
+    pub fn get_ssim(&self) -> Option<f64> {
+        self.ssim
+    }
+    pub fn get_mean_abs_diff(&self) -> Option<f64> {
+        self.mean_abs_diff
+    }
+}
+
+/// A hash with the modified image and hashing method it was made from.
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct StoredHash {
+    id: i64,
+    #[sqlx(rename = "mod_image_id")]
+    modified_image_id: i64,
+    hashing_method_id: i64,
+    /// Hex in serialized form.
+    #[serde(with = "hex")]
+    hash: Vec<u8>,
+    #[sqlx(rename = "hash_bits")]
+    bits: Option<u32>,
+}
+impl StoredHash {
+    pub fn get_id(&self) -> i64 {
+        self.id
+    }
+    pub fn get_modified_image_id(&self) -> i64 {
+        self.modified_image_id
+    }
+    pub fn get_hashing_method_id(&self) -> i64 {
+        self.hashing_method_id
+    }
+    pub fn get_hash(&self) -> &[u8] {
+        &self.hash
+    }
+    /// Number of bits in the hash, `None` for hashes stored before the length was recorded.
+    pub fn get_bits(&self) -> Option<u32> {
+        self.bits
+    }
+}
+
+/// Which hashes `Repository::hashes` returns.
+#[derive(Debug, Clone, Copy)]
+pub struct HashFilter {
+    pub(super) hashing_method_id: i64,
+    pub(super) modification_id: Option<i64>,
+    pub(super) run: Option<i64>,
+}
+impl HashFilter {
+    /// Hashes of the active run made with the hashing method.
+    pub fn new(hashing_method_id: i64) -> Self {
+        Self {
+            hashing_method_id,
+            modification_id: None,
+            run: None,
+        }
+    }
+    pub fn modification(mut self, modification_id: i64) -> Self {
+        self.modification_id = Some(modification_id);
+        self
+    }
+    pub fn run(mut self, run_id: i64) -> Self {
+        self.run = Some(run_id);
+        self
+    }
+}
+
+/// Which matches `Repository::matches` returns.
+#[derive(Debug, Clone, Copy)]
+pub struct MatchFilter {
+    pub(super) modification_id: i64,
+    pub(super) hashing_method_id: i64,
+    pub(super) partition: Option<Partition>,
+    pub(super) run: Option<i64>,
+}
+impl MatchFilter {
+    /// Matches between hashes made with the hashing method of images with the modification, of
+    /// all runs.
+    pub fn new(modification_id: i64, hashing_method_id: i64) -> Self {
+        Self {
+            modification_id,
+            hashing_method_id,
+            partition: None,
+            run: None,
+        }
+    }
+    /// Only matches where both images are in the partition of the run, the active run if no run
+    /// is given.
+    pub fn partition(mut self, partition: Option<Partition>) -> Self {
+        self.partition = partition;
+        self
+    }
+    /// Only matches where both images are part of the run.
+    pub fn run(mut self, run_id: Option<i64>) -> Self {
+        self.run = run_id;
+        self
+    }
+}
+
+/// A match with the images and methods it was made from.
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct StoredMatch {
+    id: i64,
+    hamming_distance: u32,
+    hash_len: u32,
+    hash1_id: u32,
+    hash2_id: u32,
+    image1_id: i64,
+    image2_id: i64,
+    /// `None` if the hashes are of different modifications.
+    modification_id: Option<i64>,
+    hashing_method_id: i64,
+}
+impl StoredMatch {
+    pub fn get_id(&self) -> i64 {
+        self.id
+    }
+    pub fn get_hash_ids(&self) -> (u32, u32) {
+        (self.hash1_id, self.hash2_id)
+    }
+    pub fn get_image_ids(&self) -> (i64, i64) {
+        (self.image1_id, self.image2_id)
+    }
+    pub fn get_modification_id(&self) -> Option<i64> {
+        self.modification_id
+    }
+    pub fn get_hashing_method_id(&self) -> i64 {
+        self.hashing_method_id
+    }
+    /// Both hashes are of the same original image.
+    pub fn is_same_image(&self) -> bool {
+        self.image1_id == self.image2_id
+    }
+    pub fn hamming_distance(&self) -> HammingDistance {
+        HammingDistance::new(self.hamming_distance, self.hash_len)
+    }
+    pub fn to_match(&self) -> Match {
+        Match::from(self)
+    }
+}
+
+/// Counts of what is stored for a run.
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct RunSummary {
+    run_id: i64,
+    timestamp: i64,
+    images: u32,
+    skipped_images: u32,
+    duplicate_images: u32,
+    modified_images: u32,
+    hashes: u32,
+    matches: u32,
+}
+impl RunSummary {
+    pub fn get_run_id(&self) -> i64 {
+        self.run_id
+    }
+    pub fn get_timestamp(&self) -> i64 {
+        self.timestamp
+    }
+    pub fn get_images(&self) -> u32 {
+        self.images
+    }
+    pub fn get_skipped_images(&self) -> u32 {
+        self.skipped_images
+    }
+    pub fn get_duplicate_images(&self) -> u32 {
+        self.duplicate_images
+    }
+    pub fn get_modified_images(&self) -> u32 {
+        self.modified_images
+    }
+    pub fn get_hashes(&self) -> u32 {
+        self.hashes
+    }
+    pub fn get_matches(&self) -> u32 {
+        self.matches
+    }
+}
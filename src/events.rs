@@ -0,0 +1,202 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, Receiver, Sender, error::RecvError};
+
+use crate::{
+    core::{snapshot::Stage, state::RunningState},
+    matching::state::Component,
+};
+
+/// Events a subscriber that falls behind can miss before it misses the oldest ones.
+const CAPACITY: usize = 1024;
+/// Most progress events sent for one total.
+const PROGRESS_STEPS: u64 = 1000;
+const UNKNOWN_TOTAL: u64 = u64::MAX;
+
+/// What happens during a run, sent to everyone subscribed to the `EventBus` of the app.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// The run is stored and its images are being hashed. `run_id` is the id of the stored run,
+    /// `None` if the results are not stored in a database.
+    RunStarted {
+        run_id: Option<i64>,
+    },
+    StageStarted {
+        stage: Stage,
+    },
+    /// Items of the stage that are done. Matching reports each of its components, the fetcher
+    /// counts hashing methods, the processor hashes and the parser stored matches.
+    Progress {
+        stage: Stage,
+        component: Option<Component>,
+        done: u64,
+        total: Option<u64>,
+    },
+    /// Something that went wrong without stopping the run, e.g. an image that could not be read.
+    Warning {
+        stage: Stage,
+        message: String,
+    },
+    StageFinished {
+        stage: Stage,
+        duration_ms: u64,
+    },
+    RunFinished {
+        state: RunningState,
+    },
+}
+
+/// Sends the events of runs to any number of subscribers. Sending never waits, events sent while
+/// nobody is subscribed are dropped. Clones send to the same subscribers.
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    sender: Sender<Event>,
+}
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        Self { sender }
+    }
+    /// Receives the events sent from now on. A receiver that falls more than 1024 events behind
+    /// gets `RecvError::Lagged` and continues with the oldest event still kept.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        self.sender.subscribe()
+    }
+    pub fn send(&self, event: Event) {
+        let _ = self.sender.send(event);
+    }
+    pub fn warn(&self, stage: Stage, message: impl Into<String>) {
+        self.send(Event::Warning {
+            stage,
+            message: message.into(),
+        });
+    }
+    /// Counter for the items of the stage, or of one of its components.
+    pub fn progress(&self, stage: Stage, component: Option<Component>) -> Progress {
+        Progress {
+            events: self.clone(),
+            stage,
+            component,
+            done: AtomicU64::new(0),
+            total: AtomicU64::new(UNKNOWN_TOTAL),
+        }
+    }
+}
+
+/// Counts the items that are done and sends `Event::Progress` as the count grows. At most about a
+/// thousand events are sent for a known total, so items can be counted one by one, also from
+/// several threads.
+#[derive(Debug)]
+pub struct Progress {
+    events: EventBus,
+    stage: Stage,
+    component: Option<Component>,
+    done: AtomicU64,
+    total: AtomicU64,
+}
+impl Progress {
+    /// Starts counting from zero again, towards `total`.
+    pub fn set_total(&self, total: u64) {
+        self.total.store(total, Ordering::Relaxed);
+        self.done.store(0, Ordering::Relaxed);
+        self.send(0, total);
+    }
+    pub fn inc(&self, delta: u64) {
+        let total = self.total.load(Ordering::Relaxed);
+        let done = self.done.fetch_add(delta, Ordering::Relaxed) + delta;
+        let step = match total {
+            UNKNOWN_TOTAL => 1,
+            total => (total / PROGRESS_STEPS).max(1),
+        };
+        if done / step != (done - delta) / step || done == total {
+            self.send(done, total);
+        }
+    }
+    /// Sends a warning about one of the items.
+    pub fn warn(&self, message: impl Into<String>) {
+        self.events.warn(self.stage, message);
+    }
+    fn send(&self, done: u64, total: u64) {
+        self.events.send(Event::Progress {
+            stage: self.stage,
+            component: self.component,
+            done,
+            total: (total != UNKNOWN_TOTAL).then_some(total),
+        });
+    }
+}
+
+/// Draws the progress of runs as indicatif progress bars on a thread of its own, until the bus
+/// is dropped. Nothing is drawn unless this is called.
+pub fn draw_progress(events: &EventBus) {
+    let mut rx = events.subscribe();
+    let style = ProgressStyle::with_template(
+        "[{elapsed_precise} | {eta_precise}] {msg}: {pos:>7}/{len:7} {percent}%",
+    )
+    .unwrap()
+    .progress_chars("##-");
+    // Totals are not known for e.g. the stored matches.
+    let count_style = ProgressStyle::with_template("[{elapsed_precise}] {msg}: {pos:>7}").unwrap();
+
+    thread::spawn(move || {
+        let multi = MultiProgress::new();
+        let mut progressbars: HashMap<(Stage, Option<Component>), ProgressBar> = HashMap::new();
+        loop {
+            let event = match rx.blocking_recv() {
+                Ok(event) => event,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+            match event {
+                Event::Progress {
+                    stage,
+                    component,
+                    done,
+                    total,
+                } => {
+                    let pb = progressbars.entry((stage, component)).or_insert_with(|| {
+                        let msg = match component {
+                            Some(component) => format!("{} {}", stage, component),
+                            None => stage.to_string(),
+                        };
+                        let style = match total {
+                            Some(_) => style.clone(),
+                            None => count_style.clone(),
+                        };
+                        multi.add(ProgressBar::no_length().with_style(style).with_message(msg))
+                    });
+                    if let Some(total) = total {
+                        pb.set_length(total);
+                    }
+                    pb.set_position(done);
+                }
+                Event::StageFinished { stage, .. } => {
+                    progressbars.retain(|(s, _), pb| {
+                        if *s == stage {
+                            pb.finish();
+                        }
+                        *s != stage
+                    });
+                }
+                Event::RunFinished { .. } => {
+                    for (_, pb) in progressbars.drain() {
+                        pb.abandon();
+                    }
+                }
+                Event::RunStarted { .. } | Event::StageStarted { .. } | Event::Warning { .. } => {}
+            }
+        }
+    });
+}
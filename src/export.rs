@@ -0,0 +1,646 @@
+mod error;
+mod record;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use chrono::Utc;
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use futures::TryStreamExt;
+use sqlx::{SqliteConnection, SqlitePool};
+
+use crate::{
+    core::{snapshot::RunSnapshot, state::RunIds},
+    db::{self, DB, Repository},
+    result_calc::{ConfusionMatrix, classify},
+};
+
+pub use error::Error;
+pub use record::{DistanceCount, FORMAT, FORMAT_VERSION, Header, MatchExport, Record, RocPoint};
+
+/// What `export_run` writes besides the stored rows of the run.
+#[derive(Debug, Clone)]
+pub struct ExportOptions {
+    matches: MatchExport,
+    thresholds: Vec<f32>,
+}
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            matches: MatchExport::Full,
+            thresholds: (1..100).map(|i| (i as f32) / 100.).collect(),
+        }
+    }
+}
+impl ExportOptions {
+    pub fn matches(mut self, matches: MatchExport) -> Self {
+        self.matches = matches;
+        self
+    }
+    /// Relative distances the ROC points are calculated at, no ROC is exported if empty.
+    pub fn thresholds(mut self, thresholds: Vec<f32>) -> Self {
+        self.thresholds = thresholds;
+        self
+    }
+}
+
+/// Number of records written for a run.
+#[derive(Debug, Clone, Default)]
+pub struct ExportSummary {
+    run_id: i64,
+    records: usize,
+    images: usize,
+    hashes: usize,
+    matches: usize,
+}
+impl ExportSummary {
+    pub fn get_run_id(&self) -> i64 {
+        self.run_id
+    }
+    pub fn get_records(&self) -> usize {
+        self.records
+    }
+    pub fn get_images(&self) -> usize {
+        self.images
+    }
+    pub fn get_hashes(&self) -> usize {
+        self.hashes
+    }
+    /// Matches of the run, also when they were only exported aggregated.
+    pub fn get_matches(&self) -> usize {
+        self.matches
+    }
+}
+
+/// What an archive was imported as.
+#[derive(Debug, Clone, Default)]
+pub struct ImportSummary {
+    source_run_id: i64,
+    run_id: i64,
+    images: usize,
+    new_images: usize,
+    hashes: usize,
+    matches: usize,
+}
+impl ImportSummary {
+    /// Id of the run in the exporting database.
+    pub fn get_source_run_id(&self) -> i64 {
+        self.source_run_id
+    }
+    /// Id the run was imported as.
+    pub fn get_run_id(&self) -> i64 {
+        self.run_id
+    }
+    pub fn get_images(&self) -> usize {
+        self.images
+    }
+    /// Images that were not stored in the database before.
+    pub fn get_new_images(&self) -> usize {
+        self.new_images
+    }
+    pub fn get_hashes(&self) -> usize {
+        self.hashes
+    }
+    pub fn get_matches(&self) -> usize {
+        self.matches
+    }
+}
+
+/// Writes a run as JSON lines, one `Record` per line. The archive has everything needed to import
+/// the run into another database or to compare it without one.
+pub async fn export_run(
+    repository: &Repository,
+    run_id: i64,
+    writer: impl Write,
+    options: &ExportOptions,
+) -> Result<ExportSummary, Error> {
+    let Some(run) = repository.run(run_id).await? else {
+        return Err(Error::UnknownRun(run_id));
+    };
+    let pool = repository.pool();
+    let mut writer = RecordWriter::new(writer);
+    let mut summary = ExportSummary {
+        run_id,
+        ..Default::default()
+    };
+
+    writer.write(&Record::Header(Header {
+        format: FORMAT.to_string(),
+        version: FORMAT_VERSION,
+        crate_version: env!("CARGO_PKG_VERSION").to_string(),
+        schema_version: DB::schema_version(pool).await?,
+        exported_at: Utc::now().timestamp_millis(),
+        matches: options.matches,
+    }))?;
+    writer.write(&Record::Run(run))?;
+    if let Some(snapshot) = RunSnapshot::fetch(pool, run_id).await? {
+        writer.write(&Record::Config(snapshot))?;
+    }
+
+    let modifications = repository.run_modifications(run_id).await?;
+    for m in &modifications {
+        writer.write(&Record::Modification(m.clone()))?;
+    }
+    let hashing_methods = repository.run_hashing_methods(run_id).await?;
+    for hm in &hashing_methods {
+        writer.write(&Record::HashingMethod(hm.clone()))?;
+    }
+    for image in repository.run_images(run_id).await? {
+        writer.write(&Record::Image(image))?;
+        summary.images += 1;
+    }
+    for modified_image in repository.run_modified_images(run_id).await? {
+        writer.write(&Record::ModifiedImage(modified_image))?;
+    }
+    for hash in repository.run_hashes(run_id).await? {
+        writer.write(&Record::Hash(hash))?;
+        summary.hashes += 1;
+    }
+
+    let mut distances: BTreeMap<(Option<i64>, i64, u32, u32, bool), u32> = BTreeMap::new();
+    let mut matches = repository.run_matches(run_id);
+    while let Some(stored) = matches.try_next().await? {
+        let distance = stored.hamming_distance();
+        *distances
+            .entry((
+                stored.get_modification_id(),
+                stored.get_hashing_method_id(),
+                distance.distance(),
+                distance.entry_length(),
+                stored.is_same_image(),
+            ))
+            .or_default() += 1;
+        summary.matches += 1;
+        if options.matches == MatchExport::Full {
+            writer.write(&Record::Match(stored))?;
+        }
+    }
+    if options.matches == MatchExport::Aggregated {
+        for (
+            &(modification_id, hashing_method_id, hamming_distance, hash_len, same_image),
+            &count,
+        ) in &distances
+        {
+            writer.write(&Record::Distances(DistanceCount {
+                modification_id,
+                hashing_method_id,
+                hamming_distance,
+                hash_len,
+                same_image,
+                count,
+            }))?;
+        }
+    }
+    for point in roc_points(&distances, &options.thresholds) {
+        writer.write(&Record::Roc(point))?;
+    }
+
+    summary.records = writer.finish()?;
+    Ok(summary)
+}
+
+/// Exports a run to a file, gzip compressed if the path ends with `.gz`.
+pub async fn export_run_to_path(
+    repository: &Repository,
+    run_id: i64,
+    path: &Path,
+    options: &ExportOptions,
+) -> Result<ExportSummary, Error> {
+    let file = BufWriter::new(File::create(path)?);
+    match is_gzip(path) {
+        true => {
+            let mut encoder = GzEncoder::new(file, Compression::default());
+            let summary = export_run(repository, run_id, &mut encoder, options).await?;
+            encoder.finish()?.flush()?;
+            Ok(summary)
+        }
+        false => export_run(repository, run_id, file, options).await,
+    }
+}
+
+/// Imports an archive written by `export_run` as a new run. Ids are remapped to the ones of the
+/// database, images, modifications, hashing methods and hashes that are already stored are
+/// reused. Aggregated distances and ROC points are derived data and not imported. The run only
+/// becomes the active run if the database has none. Nothing is imported if the run is already
+/// stored, see `Error::AlreadyImported`.
+pub async fn import_run(pool: &SqlitePool, reader: impl BufRead) -> Result<ImportSummary, Error> {
+    let mut tx = pool.begin().await?;
+    let mut importer = Importer::default();
+    for (i, line) in reader.lines().enumerate() {
+        let line_no = i + 1;
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record =
+            serde_json::from_str(&line).map_err(|err| Error::Json { line: line_no, err })?;
+        importer.import(&mut tx, record, line_no).await?;
+    }
+    let summary = importer.finish(&mut tx).await?;
+    tx.commit().await?;
+    Ok(summary)
+}
+
+/// Imports an archive from a file, see `import_run`. Files ending with `.gz` are decompressed.
+pub async fn import_run_from_path(pool: &SqlitePool, path: &Path) -> Result<ImportSummary, Error> {
+    let file = File::open(path)?;
+    match is_gzip(path) {
+        true => import_run(pool, BufReader::new(GzDecoder::new(file))).await,
+        false => import_run(pool, BufReader::new(file)).await,
+    }
+}
+
+fn is_gzip(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == "gz")
+}
+
+/// Confusion matrices of every modification and hashing method at the thresholds, the same as
+/// `RocProcess` calculates from the stored matches. Matches between different modifications are
+/// not part of any curve.
+fn roc_points(
+    distances: &BTreeMap<(Option<i64>, i64, u32, u32, bool), u32>,
+    thresholds: &[f32],
+) -> Vec<RocPoint> {
+    let mut curves: BTreeMap<(i64, i64), Vec<ConfusionMatrix>> = BTreeMap::new();
+    for (&(m, hm, distance, hash_len, same_image), &count) in distances {
+        let Some(m) = m else {
+            continue;
+        };
+        let curve = curves
+            .entry((m, hm))
+            .or_insert_with(|| vec![ConfusionMatrix::default(); thresholds.len()]);
+        let relative = distance as f32 / hash_len as f32;
+        for (matrix, threshold) in curve.iter_mut().zip(thresholds) {
+            matrix.inc(classify(*threshold, relative, same_image), count);
+        }
+    }
+    curves
+        .into_iter()
+        .flat_map(|((modification_id, hashing_method_id), curve)| {
+            thresholds
+                .iter()
+                .zip(curve)
+                .map(move |(threshold, matrix)| RocPoint {
+                    modification_id,
+                    hashing_method_id,
+                    threshold: *threshold,
+                    matrix,
+                })
+        })
+        .collect()
+}
+
+struct RecordWriter<W: Write> {
+    writer: W,
+    lines: usize,
+}
+impl<W: Write> RecordWriter<W> {
+    fn new(writer: W) -> Self {
+        Self { writer, lines: 0 }
+    }
+    fn write(&mut self, record: &Record) -> Result<(), Error> {
+        self.lines += 1;
+        serde_json::to_writer(&mut self.writer, record).map_err(|err| Error::Json {
+            line: self.lines,
+            err,
+        })?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+    /// Number of records written.
+    fn finish(mut self) -> Result<usize, Error> {
+        self.writer.flush()?;
+        Ok(self.lines)
+    }
+}
+
+/// Ids of the archive mapped to ids of the importing database.
+#[derive(Default)]
+struct Importer {
+    header: bool,
+    run_id: Option<i64>,
+    snapshot: Option<RunSnapshot>,
+    modifications: HashMap<i64, i64>,
+    hashing_methods: HashMap<i64, i64>,
+    images: HashMap<i64, i64>,
+    modified_images: HashMap<i64, i64>,
+    hashes: HashMap<i64, i64>,
+    summary: ImportSummary,
+}
+impl Importer {
+    async fn import(
+        &mut self,
+        conn: &mut SqliteConnection,
+        record: Record,
+        line: usize,
+    ) -> Result<(), Error> {
+        if !self.header {
+            let Record::Header(header) = record else {
+                return Err(format_error(
+                    line,
+                    "the archive does not start with a header",
+                ));
+            };
+            if header.format != FORMAT {
+                return Err(format_error(
+                    line,
+                    format!("unknown format {}", header.format),
+                ));
+            }
+            if header.version > FORMAT_VERSION {
+                return Err(format_error(
+                    line,
+                    format!(
+                        "version {} is newer than the supported version {}",
+                        header.version, FORMAT_VERSION
+                    ),
+                ));
+            }
+            self.header = true;
+            return Ok(());
+        }
+
+        match record {
+            Record::Header(_) => return Err(format_error(line, "more than one header")),
+            Record::Run(run) => {
+                if self.run_id.is_some() {
+                    return Err(format_error(line, "more than one run"));
+                }
+                let run_id: (i64,) =
+                    sqlx::query_as("INSERT INTO runs (timestamp) VALUES (?) RETURNING id;")
+                        .bind(run.get_timestamp())
+                        .fetch_one(&mut *conn)
+                        .await?;
+                self.run_id = Some(run_id.0);
+                self.summary.source_run_id = run.get_id();
+                self.summary.run_id = run_id.0;
+            }
+            Record::Config(snapshot) => self.snapshot = Some(snapshot),
+            Record::Modification(m) => {
+                let id = db::get_or_insert_modification_by_name(
+                    &mut *conn,
+                    m.get_name(),
+                    m.get_params(),
+                )
+                .await?;
+                self.modifications.insert(m.get_id(), id);
+            }
+            Record::HashingMethod(hm) => {
+                let id = db::get_or_insert_hashing_method_by_name(
+                    &mut *conn,
+                    hm.get_name(),
+                    hm.get_params(),
+                )
+                .await?;
+                self.hashing_methods.insert(hm.get_id(), id);
+            }
+            Record::Image(image) => {
+                let Some(run_id) = self.run_id else {
+                    return Err(format_error(line, "image before the run"));
+                };
+                let (width, height) = image.get_dimensions().unzip();
+                let inserted: Option<(i64,)> = sqlx::query_as(
+                    "
+                    INSERT INTO images
+                    (path, user, session, tags, digest, width, height, format, color_type,
+                     orientation)
+                    VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+                    ON CONFLICT DO NOTHING
+                    RETURNING id;
+                    ",
+                )
+                .bind(image.get_path())
+                .bind(image.get_user())
+                .bind(image.get_session())
+                .bind(serde_json::Value::from(image.get_tags()).to_string())
+                .bind(image.get_digest())
+                .bind(width)
+                .bind(height)
+                .bind(image.get_format())
+                .bind(image.get_color_type())
+                .bind(image.get_orientation())
+                .fetch_optional(&mut *conn)
+                .await?;
+                let image_id = match inserted {
+                    Some((id,)) => {
+                        self.summary.new_images += 1;
+                        id
+                    }
+                    None => {
+                        let (id,): (i64,) =
+                            sqlx::query_as("SELECT id FROM images WHERE path = ? AND digest IS ?;")
+                                .bind(image.get_path())
+                                .bind(image.get_digest())
+                                .fetch_one(&mut *conn)
+                                .await?;
+                        id
+                    }
+                };
+                sqlx::query(
+                    "INSERT INTO run_images (run_id, image_id, partition) VALUES (?, ?, ?);",
+                )
+                .bind(run_id)
+                .bind(image_id)
+                .bind(image.get_partition().map(|p| p.to_string()))
+                .execute(&mut *conn)
+                .await?;
+                self.images.insert(image.get_id(), image_id);
+                self.summary.images += 1;
+            }
+            Record::ModifiedImage(mi) => {
+                let image_id = remap(&self.images, mi.get_image_id(), "image", line)?;
+                let modification_id = remap(
+                    &self.modifications,
+                    mi.get_modification_id(),
+                    "modification",
+                    line,
+                )?;
+                sqlx::query(
+                    "
+                    INSERT OR IGNORE INTO modified_images
+                    (image_id, modification_id, psnr, ssim, mean_abs_diff)
+                    VALUES (?, ?, ?, ?, ?);
+                    ",
+                )
+                .bind(image_id)
+                .bind(modification_id)
+                .bind(mi.get_psnr())
+                .bind(mi.get_ssim())
+                .bind(mi.get_mean_abs_diff())
+                .execute(&mut *conn)
+                .await?;
+                let id: (i64,) = sqlx::query_as(
+                    "SELECT id FROM modified_images WHERE image_id = ? AND modification_id = ?;",
+                )
+                .bind(image_id)
+                .bind(modification_id)
+                .fetch_one(&mut *conn)
+                .await?;
+                self.modified_images.insert(mi.get_id(), id.0);
+            }
+            Record::Hash(hash) => {
+                let mod_image_id = remap(
+                    &self.modified_images,
+                    hash.get_modified_image_id(),
+                    "modified image",
+                    line,
+                )?;
+                let hashing_method_id = remap(
+                    &self.hashing_methods,
+                    hash.get_hashing_method_id(),
+                    "hashing method",
+                    line,
+                )?;
+                sqlx::query(
+                    "
+                    INSERT OR IGNORE INTO hashes (hash, hash_bits, mod_image_id, hashing_method_id)
+                    VALUES (?, ?, ?, ?);
+                    ",
+                )
+                .bind(hash.get_hash())
+                .bind(hash.get_bits())
+                .bind(mod_image_id)
+                .bind(hashing_method_id)
+                .execute(&mut *conn)
+                .await?;
+                let id: (i64,) = sqlx::query_as(
+                    "SELECT id FROM hashes WHERE mod_image_id = ? AND hashing_method_id = ?;",
+                )
+                .bind(mod_image_id)
+                .bind(hashing_method_id)
+                .fetch_one(&mut *conn)
+                .await?;
+                self.hashes.insert(hash.get_id(), id.0);
+                self.summary.hashes += 1;
+            }
+            Record::Match(m) => {
+                let (hash1_id, hash2_id) = m.get_hash_ids();
+                let hash1_id = remap(&self.hashes, hash1_id as i64, "hash", line)?;
+                let hash2_id = remap(&self.hashes, hash2_id as i64, "hash", line)?;
+                let (image1_id, image2_id) = m.get_image_ids();
+                let image1_id = remap(&self.images, image1_id, "image", line)?;
+                let image2_id = remap(&self.images, image2_id, "image", line)?;
+                let modification_id = m
+                    .get_modification_id()
+                    .map(|id| remap(&self.modifications, id, "modification", line))
+                    .transpose()?;
+                let hashing_method_id = remap(
+                    &self.hashing_methods,
+                    m.get_hashing_method_id(),
+                    "hashing method",
+                    line,
+                )?;
+                // Importing into the database the run came from must not store its matches twice.
+                // The remapped ids can be in the other order, the images follow their hashes.
+                let (hash1_id, hash2_id, image1_id, image2_id) = match hash1_id <= hash2_id {
+                    true => (hash1_id, hash2_id, image1_id, image2_id),
+                    false => (hash2_id, hash1_id, image2_id, image1_id),
+                };
+                let distance = m.hamming_distance();
+                sqlx::query(
+                    "
+                    INSERT INTO matches
+                    (hamming_distance, hash_len, hash1_id, hash2_id, image1_id, image2_id,
+                     modification_id, hashing_method_id)
+                    VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+                    ON CONFLICT (hash1_id, hash2_id) DO NOTHING;
+                    ",
+                )
+                .bind(distance.distance())
+                .bind(distance.entry_length())
+                .bind(hash1_id)
+                .bind(hash2_id)
+                .bind(image1_id)
+                .bind(image2_id)
+                .bind(modification_id)
+                .bind(hashing_method_id)
+                .execute(&mut *conn)
+                .await?;
+                self.summary.matches += 1;
+            }
+            Record::Distances(_) | Record::Roc(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Stores the configuration of the run once every modification and hashing method is known.
+    /// Fails if a run with the same timestamp and the same images is already stored, which is the
+    /// case when the archive was imported before or comes from this database.
+    async fn finish(self, conn: &mut SqliteConnection) -> Result<ImportSummary, Error> {
+        let Some(run_id) = self.run_id else {
+            return Err(format_error(0, "the archive has no run"));
+        };
+        let stored: Option<(i64,)> = sqlx::query_as(
+            "
+            SELECT r.id FROM runs r
+            WHERE r.id != ?1
+              AND r.timestamp = (SELECT timestamp FROM runs WHERE id = ?1)
+              AND (SELECT count(*) FROM run_images WHERE run_id = r.id)
+                = (SELECT count(*) FROM run_images WHERE run_id = ?1)
+              AND NOT EXISTS (
+                SELECT image_id FROM run_images WHERE run_id = ?1
+                EXCEPT
+                SELECT image_id FROM run_images WHERE run_id = r.id
+              )
+            LIMIT 1;
+            ",
+        )
+        .bind(run_id)
+        .fetch_optional(&mut *conn)
+        .await?;
+        if let Some((stored_run_id,)) = stored {
+            return Err(Error::AlreadyImported {
+                source_run_id: self.summary.source_run_id,
+                run_id: stored_run_id,
+            });
+        }
+        if let Some(snapshot) = self.snapshot {
+            let mut modification_ids = Vec::new();
+            for m in snapshot.get_modifications() {
+                modification_ids.push(
+                    db::get_or_insert_modification_by_name(
+                        &mut *conn,
+                        m.get_name(),
+                        m.get_params(),
+                    )
+                    .await?,
+                );
+            }
+            let mut hashing_method_ids = Vec::new();
+            for hm in snapshot.get_hashing_methods() {
+                hashing_method_ids.push(
+                    db::get_or_insert_hashing_method_by_name(
+                        &mut *conn,
+                        hm.get_name(),
+                        hm.get_params(),
+                    )
+                    .await?,
+                );
+            }
+            let ids = RunIds::new(vec![], modification_ids, hashing_method_ids).with_run(run_id);
+            snapshot.insert(&mut *conn, &ids).await?;
+        }
+        sqlx::query("INSERT OR IGNORE INTO program (id, run_id) VALUES (0, ?);")
+            .bind(run_id)
+            .execute(&mut *conn)
+            .await?;
+        Ok(self.summary)
+    }
+}
+
+fn remap(ids: &HashMap<i64, i64>, id: i64, what: &str, line: usize) -> Result<i64, Error> {
+    ids.get(&id)
+        .copied()
+        .ok_or_else(|| format_error(line, format!("unknown {} {}", what, id)))
+}
+
+fn format_error(line: usize, reason: impl Into<String>) -> Error {
+    Error::Format {
+        line,
+        reason: reason.into(),
+    }
+}
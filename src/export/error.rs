@@ -0,0 +1,77 @@
+use std::fmt::Display;
+
+use crate::{core, db};
+
+#[derive(Debug)]
+pub enum Error {
+    Io {
+        err: std::io::Error,
+    },
+    /// A record could not be written or read, lines start at 1.
+    Json {
+        line: usize,
+        err: serde_json::Error,
+    },
+    Db {
+        err: db::Error,
+    },
+    Core {
+        err: core::Error,
+    },
+    UnknownRun(i64),
+    /// The run of the archive is already stored, as the same timestamp with the same images.
+    AlreadyImported {
+        source_run_id: i64,
+        run_id: i64,
+    },
+    /// The archive is not one this version can import.
+    Format {
+        line: usize,
+        reason: String,
+    },
+}
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io { err: value }
+    }
+}
+impl From<db::Error> for Error {
+    fn from(value: db::Error) -> Self {
+        Self::Db { err: value }
+    }
+}
+impl From<sqlx::Error> for Error {
+    fn from(value: sqlx::Error) -> Self {
+        Self::Db {
+            err: db::Error::from(value),
+        }
+    }
+}
+impl From<core::Error> for Error {
+    fn from(value: core::Error) -> Self {
+        Self::Core { err: value }
+    }
+}
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io { err } => write!(f, "IO error: {}", err),
+            Self::Json { line, err } => write!(f, "Invalid record on line {}: {}", line, err),
+            Self::Db { err } => write!(f, "Database error: {}", err),
+            Self::Core { err } => write!(f, "{}", err),
+            Self::UnknownRun(run_id) => write!(f, "Run {} does not exist", run_id),
+            Self::AlreadyImported {
+                source_run_id,
+                run_id,
+            } => write!(
+                f,
+                "Run {} of the archive is already stored as run {}",
+                source_run_id, run_id
+            ),
+            Self::Format { line, reason } => {
+                write!(f, "Unsupported archive, line {}: {}", line, reason)
+            }
+        }
+    }
+}
+impl std::error::Error for Error {}
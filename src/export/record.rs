@@ -0,0 +1,80 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    core::snapshot::RunSnapshot,
+    db::{Run, RunImage, StoredHash, StoredMatch, StoredMethod, StoredModifiedImage},
+    result_calc::ConfusionMatrix,
+};
+
+/// Name written in the header of every archive.
+pub const FORMAT: &str = "p-hash-run";
+/// Version of the archive layout, bumped when records change incompatibly. Version 2 writes
+/// infinite quality values as `"inf"` instead of `null`, version 3 also has the matches between
+/// hashes of different modifications, their modification is `null`.
+pub const FORMAT_VERSION: u32 = 3;
+
+/// A line of an archive. The header comes first and the run before anything belonging to it,
+/// every other record only refers to ids of records before it. Ids are the ones of the exporting
+/// database.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "record", rename_all = "snake_case")]
+pub enum Record {
+    Header(Header),
+    Run(Run),
+    Config(RunSnapshot),
+    Modification(StoredMethod),
+    HashingMethod(StoredMethod),
+    Image(RunImage),
+    ModifiedImage(StoredModifiedImage),
+    Hash(StoredHash),
+    Match(StoredMatch),
+    Distances(DistanceCount),
+    Roc(RocPoint),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Header {
+    pub format: String,
+    pub version: u32,
+    pub crate_version: String,
+    /// Schema version of the exporting database.
+    pub schema_version: u32,
+    /// Milliseconds since the unix epoch.
+    pub exported_at: i64,
+    pub matches: MatchExport,
+}
+
+/// How the matches of a run are exported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchExport {
+    /// Every match, they are recreated on import.
+    #[default]
+    Full,
+    /// Only the number of matches per distance, enough to calculate ROC curves. Much smaller
+    /// but nothing to import.
+    Aggregated,
+    None,
+}
+
+/// Number of matches with the same distance between images of a modification and hashing method.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DistanceCount {
+    /// `None` for matches between hashes of different modifications.
+    pub modification_id: Option<i64>,
+    pub hashing_method_id: i64,
+    pub hamming_distance: u32,
+    pub hash_len: u32,
+    /// Both hashes are of the same original image.
+    pub same_image: bool,
+    pub count: u32,
+}
+
+/// Confusion matrix of the matches of a modification and hashing method at a threshold.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RocPoint {
+    pub modification_id: i64,
+    pub hashing_method_id: i64,
+    pub threshold: f32,
+    pub matrix: ConfusionMatrix,
+}
@@ -6,4 +6,4 @@ mod interface;
 pub use collection::{HashResult, HashingMethods, SelectedHashingMethods};
 pub use error::Error;
 pub use hashing_methods::*;
-pub use interface::{Hash, HashingMethod, hash_images};
+pub use interface::{Hash, HashingMethod, hash_images, hash_images_where};
@@ -13,7 +13,7 @@ impl HashingMethods {
     pub fn push(&mut self, method: impl HashingMethod + 'static) {
         self.methods.push(Box::new(method));
     }
-    pub fn select(&self, ids: &[usize]) -> SelectedHashingMethods {
+    pub fn select(&self, ids: &[usize]) -> SelectedHashingMethods<'_> {
         let methods = self
             .methods
             .iter()
@@ -19,11 +19,14 @@ impl HashingMethod for AverageHash {
             .hash_size(self.size, self.size)
             .to_hasher();
         let res = hasher.hash_image(img);
-        res.as_bytes().into()
+        Hash::from_bytes(res.as_bytes(), (self.size * self.size) as usize)
     }
     fn name(&self) -> String {
         format!("average_hash{}", self.size)
     }
+    fn params(&self) -> String {
+        format!("size={}", self.size)
+    }
 }
 
 pub struct VertGradient {
@@ -41,12 +44,16 @@ impl HashingMethod for VertGradient {
             .hash_size(self.size, self.size)
             .to_hasher();
         let res = hasher.hash_image(img);
-        res.as_bytes().into()
+        Hash::from_bytes(res.as_bytes(), (self.size * self.size) as usize)
     }
     fn name(&self) -> String {
         format!("vert_gradient{}", self.size)
     }
+    fn params(&self) -> String {
+        format!("size={}", self.size)
+    }
 }
+#[derive(Default)]
 pub struct Gradient {}
 impl Gradient {
     pub fn new() -> Self {
@@ -59,7 +66,8 @@ impl HashingMethod for Gradient {
             .hash_alg(img_hash::HashAlg::Gradient)
             .to_hasher();
         let res = hasher.hash_image(img);
-        res.as_bytes().into()
+        // The default hash size is 8x8.
+        Hash::from_bytes(res.as_bytes(), 64)
     }
     fn name(&self) -> String {
         "vert_gradient".to_string()
@@ -1,19 +1,34 @@
 use std::fmt::Display;
 
+use base64::{Engine, prelude::BASE64_STANDARD};
 use bitvec::prelude::*;
 use image::DynamicImage;
 
-use crate::image_hash::{HashingMethods, SelectedHashingMethods, collection::HashResult};
+use crate::image_hash::{SelectedHashingMethods, collection::HashResult};
 
 pub trait HashingMethod: Send + Sync {
     fn hash(&self, img: &DynamicImage) -> Hash;
     fn name(&self) -> String;
+    /// Parameters that together with the name identifies the hashing method.
+    fn params(&self) -> String {
+        String::new()
+    }
 }
 pub fn hash_images(img: DynamicImage, hashing_methods: &SelectedHashingMethods) -> Vec<HashResult> {
+    hash_images_where(img, hashing_methods, |_| true)
+}
+
+/// Like `hash_images` but only hashes with the methods whose id `include` returns true for.
+pub fn hash_images_where(
+    img: DynamicImage,
+    hashing_methods: &SelectedHashingMethods,
+    include: impl Fn(u16) -> bool,
+) -> Vec<HashResult> {
     // Hashes image with hashing methods that correlate to the given ids
     hashing_methods
         .iter()
         .enumerate()
+        .filter(|(id, _)| include(*id as u16))
         .map(move |(id, method)| HashResult::new(method.hash(&img), id as u16))
         .collect()
 }
@@ -31,17 +46,32 @@ impl From<&[u8]> for Hash {
 }
 
 impl Hash {
-    pub fn new(bits: BitVec<u8, Msb0>) -> Self {
+    pub fn new(mut bits: BitVec<u8, Msb0>) -> Self {
+        bits.set_uninitialized(false);
         Self { bits }
     }
+    /// Hash of the first `bit_len` bits of bytes, most significant bit first.
+    pub fn from_bytes(bytes: &[u8], bit_len: usize) -> Self {
+        let mut bits = BitVec::from_slice(bytes);
+        bits.truncate(bit_len);
+        Self::new(bits)
+    }
+    /// The bits packed most significant bit first, the unused bits of the last byte are zero.
     pub fn to_bytes(&self) -> &[u8] {
         self.bits.as_raw_slice()
     }
+    /// Number of bits in the hash, the last byte of `to_bytes` can be partially used.
+    pub fn bit_len(&self) -> usize {
+        self.bits.len()
+    }
 
-    pub fn to_hex(self) -> String {
+    pub fn to_hex(&self) -> String {
         let bytes = self.to_bytes();
         hex::encode(bytes)
     }
+    pub fn to_base64(&self) -> String {
+        BASE64_STANDARD.encode(self.to_bytes())
+    }
 }
 impl Display for Hash {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -1,8 +1,10 @@
 mod collection;
 mod error;
 mod interface;
+mod metrics;
 mod modifications;
 pub use collection::ModifiedImages;
 pub use error::Error;
 pub use interface::*;
+pub use metrics::{ImageQuality, non_finite};
 pub use modifications::*;
@@ -1,11 +1,7 @@
-use std::{
-    ops::{Deref, DerefMut},
-    path::Path,
-};
+use std::ops::{Deref, DerefMut};
 
-use super::Error;
+use super::ImageQuality;
 use image::DynamicImage;
-use image::io::Reader as ImageReader;
 
 #[derive(Default)]
 pub struct Modifications {
@@ -61,18 +57,25 @@ impl<'a> Deref for SelectedModifications<'a> {
 pub trait ImageModification: Send + Sync {
     fn apply(&self, img: &DynamicImage) -> DynamicImage;
     fn name(&self) -> &str;
+    /// Parameters that together with the name identifies the modification, e.g. two blurs with
+    /// different sigma are different modifications.
+    fn params(&self) -> String {
+        String::new()
+    }
 }
 
 #[derive(Debug)]
 pub struct ModifiedImage {
     mod_id: u16,
     img: Option<image::DynamicImage>,
+    quality: ImageQuality,
 }
 impl ModifiedImage {
-    fn new(mod_img: image::DynamicImage, mod_id: u16) -> Self {
+    fn new(mod_img: image::DynamicImage, mod_id: u16, quality: ImageQuality) -> Self {
         Self {
             img: Some(mod_img),
             mod_id,
+            quality,
         }
     }
     pub fn get_img(&self) -> Option<&DynamicImage> {
@@ -81,25 +84,38 @@ impl ModifiedImage {
     pub fn get_mod_id(&self) -> u16 {
         self.mod_id
     }
+    /// Distortion of the modified image compared to the original.
+    pub fn get_quality(&self) -> &ImageQuality {
+        &self.quality
+    }
     pub fn close_img(&mut self) {
         self.img = None
     }
 }
 
 pub fn modify_image<'a>(
-    path: &Path,
+    img: &DynamicImage,
     modifications: &SelectedModifications<'a>,
-) -> Result<Vec<ModifiedImage>, Error> {
-    // Modifies image with the modifications that matches the ids.
-    let img = ImageReader::open(path)?.decode()?;
+) -> Vec<ModifiedImage> {
+    modify_image_where(img, modifications, |_| true)
+}
 
-    let modified_images = modifications
+/// Like `modify_image` but only applies the modifications whose id `include` returns true for.
+/// The ids of the modified images are still the index of the modification.
+pub fn modify_image_where<'a>(
+    img: &DynamicImage,
+    modifications: &SelectedModifications<'a>,
+    include: impl Fn(u16) -> bool,
+) -> Vec<ModifiedImage> {
+    // Modifies image with the modifications that matches the ids.
+    modifications
         .iter()
         .enumerate()
+        .filter(|(id, _)| include(*id as u16))
         .map(move |(id, modification)| {
-            let mod_img = modification.apply(&img);
-            ModifiedImage::new(mod_img, id as u16)
+            let mod_img = modification.apply(img);
+            let quality = ImageQuality::compare(img, &mod_img);
+            ModifiedImage::new(mod_img, id as u16, quality)
         })
-        .collect();
-    Ok(modified_images)
+        .collect()
 }
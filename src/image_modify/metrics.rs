@@ -0,0 +1,194 @@
+use image::{DynamicImage, GenericImageView, GrayImage, RgbImage, imageops::FilterType};
+
+/// Side length of the windows SSIM is computed over.
+const SSIM_WINDOW: u32 = 8;
+/// Distance between the windows SSIM is computed over.
+const SSIM_STRIDE: u32 = 4;
+const MAX_PIXEL: f64 = 255.0;
+
+/// How visible a modification is, measured between the original and the modified image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImageQuality {
+    psnr: f64,
+    ssim: f64,
+    mean_abs_diff: f64,
+}
+impl ImageQuality {
+    /// Compares the modified image to the original. Modifications that change the dimensions
+    /// of the image (e.g. rotating a non-square image) are resized back to the original
+    /// dimensions before comparing.
+    pub fn compare(original: &DynamicImage, modified: &DynamicImage) -> Self {
+        let modified = if original.dimensions() != modified.dimensions() {
+            let (width, height) = original.dimensions();
+            modified.resize_exact(width, height, FilterType::Triangle)
+        } else {
+            modified.clone()
+        };
+
+        let (mse, mean_abs_diff) = rgb_errors(&original.to_rgb8(), &modified.to_rgb8());
+        let psnr = if mse == 0.0 {
+            f64::INFINITY
+        } else {
+            10.0 * (MAX_PIXEL * MAX_PIXEL / mse).log10()
+        };
+        let ssim = ssim(&original.to_luma8(), &modified.to_luma8());
+
+        Self {
+            psnr,
+            ssim,
+            mean_abs_diff,
+        }
+    }
+    /// Peak signal-to-noise ratio in dB. Identical images gives infinity.
+    pub fn psnr(&self) -> f64 {
+        self.psnr
+    }
+    /// Mean structural similarity of the luma channel, 1.0 for identical images.
+    pub fn ssim(&self) -> f64 {
+        self.ssim
+    }
+    /// Mean absolute difference per channel, in the range 0-255.
+    pub fn mean_abs_diff(&self) -> f64 {
+        self.mean_abs_diff
+    }
+}
+
+/// Serde for quality values, `Option<f64>`, that keeps infinite values. JSON has no number for
+/// them and serde_json writes them as `null`, which reads back as not computed. Non-finite values
+/// are written as the strings `"inf"`, `"-inf"` and `"nan"` instead.
+pub mod non_finite {
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(value: &Option<f64>, serializer: S) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(v) if v.is_nan() => serializer.serialize_str("nan"),
+            Some(v) if *v == f64::INFINITY => serializer.serialize_str("inf"),
+            Some(v) if *v == f64::NEG_INFINITY => serializer.serialize_str("-inf"),
+            Some(v) => serializer.serialize_f64(*v),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<f64>, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Value {
+            Number(f64),
+            Text(String),
+        }
+        match Option::<Value>::deserialize(deserializer)? {
+            Some(Value::Number(v)) => Ok(Some(v)),
+            Some(Value::Text(text)) => match text.as_str() {
+                "inf" => Ok(Some(f64::INFINITY)),
+                "-inf" => Ok(Some(f64::NEG_INFINITY)),
+                "nan" => Ok(Some(f64::NAN)),
+                _ => Err(D::Error::custom(format!("{:?} is not a number", text))),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+/// Returns the mean squared error and mean absolute error over all channels.
+fn rgb_errors(x: &RgbImage, y: &RgbImage) -> (f64, f64) {
+    let len = x.as_raw().len();
+    if len == 0 {
+        return (0.0, 0.0);
+    }
+    let (squared, absolute) =
+        x.as_raw()
+            .iter()
+            .zip(y.as_raw())
+            .fold((0.0, 0.0), |(squared, absolute), (x, y)| {
+                let diff = *x as f64 - *y as f64;
+                (squared + diff * diff, absolute + diff.abs())
+            });
+    (squared / len as f64, absolute / len as f64)
+}
+
+fn ssim(x: &GrayImage, y: &GrayImage) -> f64 {
+    let c1 = (0.01 * MAX_PIXEL).powi(2);
+    let c2 = (0.03 * MAX_PIXEL).powi(2);
+
+    let (width, height) = x.dimensions();
+    let window_width = SSIM_WINDOW.min(width);
+    let window_height = SSIM_WINDOW.min(height);
+    if window_width == 0 || window_height == 0 {
+        return 1.0;
+    }
+
+    let mut total = 0.0;
+    let mut windows = 0;
+    for top in (0..=height - window_height).step_by(SSIM_STRIDE as usize) {
+        for left in (0..=width - window_width).step_by(SSIM_STRIDE as usize) {
+            let pixels = (0..window_height).flat_map(|dy| {
+                (0..window_width).map(move |dx| {
+                    let (px, py) = (left + dx, top + dy);
+                    (x.get_pixel(px, py)[0] as f64, y.get_pixel(px, py)[0] as f64)
+                })
+            });
+
+            let n = (window_width * window_height) as f64;
+            let (sum_x, sum_y) = pixels
+                .clone()
+                .fold((0.0, 0.0), |(sx, sy), (px, py)| (sx + px, sy + py));
+            let (mean_x, mean_y) = (sum_x / n, sum_y / n);
+            let (var_x, var_y, cov) = pixels.fold((0.0, 0.0, 0.0), |(vx, vy, c), (px, py)| {
+                let (dx, dy) = (px - mean_x, py - mean_y);
+                (vx + dx * dx, vy + dy * dy, c + dx * dy)
+            });
+            let (var_x, var_y, cov) = (var_x / n, var_y / n, cov / n);
+
+            total += ((2.0 * mean_x * mean_y + c1) * (2.0 * cov + c2))
+                / ((mean_x * mean_x + mean_y * mean_y + c1) * (var_x + var_y + c2));
+            windows += 1;
+        }
+    }
+    total / windows as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, Rgb, RgbImage};
+
+    use super::ImageQuality;
+
+    fn filled(value: u8) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(8, 8, Rgb([value, value, value])))
+    }
+
+    #[test]
+    fn identical_images() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(16, 16, |x, y| {
+            Rgb([(x * 16) as u8, (y * 16) as u8, 128])
+        }));
+        let quality = ImageQuality::compare(&image, &image);
+        assert_eq!(quality.psnr(), f64::INFINITY);
+        assert_eq!(quality.ssim(), 1.0);
+        assert_eq!(quality.mean_abs_diff(), 0.0);
+    }
+
+    #[test]
+    fn uniform_images_of_different_brightness() {
+        let quality = ImageQuality::compare(&filled(0), &filled(10));
+        // Every channel differs by 10, so the mean squared error is 100.
+        assert!((quality.psnr() - 10.0 * (255.0f64 * 255.0 / 100.0).log10()).abs() < 1e-9);
+        assert!((quality.psnr() - 28.1308).abs() < 1e-4);
+        assert_eq!(quality.mean_abs_diff(), 10.0);
+        // A single window without variance, only the luminance term remains:
+        // c1 / (10^2 + c1) with c1 = (0.01 * 255)^2.
+        let c1 = 6.5025;
+        assert!((quality.ssim() - c1 / (100.0 + c1)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn resized_modifications_are_compared_at_the_original_size() {
+        let original = filled(50);
+        let modified = DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 16, Rgb([50, 50, 50])));
+        let quality = ImageQuality::compare(&original, &modified);
+        assert_eq!(quality.mean_abs_diff(), 0.0);
+        assert_eq!(quality.psnr(), f64::INFINITY);
+    }
+}
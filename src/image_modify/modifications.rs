@@ -7,7 +7,7 @@ pub struct Blur {
 
 impl Blur {
     pub fn new(sigma: f32) -> Self {
-        Self { sigma: sigma }
+        Self { sigma }
     }
 }
 impl Default for Blur {
@@ -23,6 +23,9 @@ impl ImageModification for Blur {
     fn name(&self) -> &str {
         "blur"
     }
+    fn params(&self) -> String {
+        format!("sigma={}", self.sigma)
+    }
 }
 
 //-------------------------------------------------------
@@ -1,2 +1,16 @@
+mod archive;
+mod digest;
+mod filter;
 mod interface;
+mod manifest;
+mod metadata;
+mod sampling;
+mod source;
+pub use archive::{ArchiveFormat, MAX_ENTRIES, MAX_ENTRY_BYTES};
+pub use digest::{Deduplicator, Duplicate, DuplicatePolicy, bytes_digest, file_digest};
+pub use filter::{ImageFilter, IngestReport, SkipReason, SkippedImage};
 pub use interface::{Error, Image, Images};
+pub use manifest::{Manifest, ManifestEntry, ManifestFormat};
+pub use metadata::{ImageMetadata, LoadOptions, Orientation};
+pub use sampling::{Partition, Sampling, Split};
+pub use source::ImageSource;
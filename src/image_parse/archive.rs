@@ -0,0 +1,302 @@
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+    thread,
+};
+
+use crossbeam::channel::{Receiver, Sender, bounded};
+use flate2::read::GzDecoder;
+use zip::ZipArchive;
+
+use crate::image_parse::{Error, Image, SkipReason};
+
+/// How many decompressed entries that can wait in memory before the reader blocks.
+const ENTRY_BUFFER: usize = 16;
+/// Largest entry that is read into memory, larger entries are skipped. The size in the entry
+/// header is not trusted, entries are never read past this.
+pub const MAX_ENTRY_BYTES: u64 = 256 * 1024 * 1024;
+/// Most files that are read from an archive, reading stops with an error after this many.
+pub const MAX_ENTRIES: usize = 1_000_000;
+
+/// Caps on what is read from an archive, `MAX_ENTRIES` and `MAX_ENTRY_BYTES` unless testing.
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    entries: usize,
+    entry_bytes: u64,
+}
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            entries: MAX_ENTRIES,
+            entry_bytes: MAX_ENTRY_BYTES,
+        }
+    }
+}
+
+/// Archive formats images can be read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    Tar,
+    TarGz,
+}
+impl ArchiveFormat {
+    /// Decides the format by the file name of the archive.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.to_lowercase();
+        if name.ends_with(".zip") {
+            Some(Self::Zip)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Self::TarGz)
+        } else if name.ends_with(".tar") {
+            Some(Self::Tar)
+        } else {
+            None
+        }
+    }
+    /// Name of the archive without the archive extensions.
+    fn stem(&self, path: &Path) -> String {
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let extension = match self {
+            Self::Zip => ".zip",
+            Self::Tar => ".tar",
+            Self::TarGz if name.to_lowercase().ends_with(".tgz") => ".tgz",
+            Self::TarGz => ".tar.gz",
+        };
+        name[..name.len() - extension.len()].to_string()
+    }
+}
+
+/// Reads the files of the archive on a separate thread. Entries are decompressed into memory one
+/// at a time and handed over through a bounded channel, so the archive is never extracted to
+/// disk.
+pub(crate) fn read_entries(path: &Path) -> Result<Receiver<Result<Image, Error>>, Error> {
+    read_entries_with(path, Limits::default())
+}
+
+fn read_entries_with(path: &Path, limits: Limits) -> Result<Receiver<Result<Image, Error>>, Error> {
+    let archive_err = |err: String| Error::Archive {
+        path: path.to_path_buf(),
+        err,
+    };
+    let format = ArchiveFormat::from_path(path).ok_or(archive_err(
+        "unknown archive format, expected .zip, .tar, .tar.gz or .tgz".to_string(),
+    ))?;
+    let file = File::open(path).map_err(|e| archive_err(e.to_string()))?;
+
+    let (tx, rx) = bounded(ENTRY_BUFFER);
+    let reader = EntryReader {
+        path: path.to_path_buf(),
+        stem: format.stem(path),
+        limits,
+        tx,
+    };
+    thread::spawn(move || {
+        let res = match format {
+            ArchiveFormat::Zip => reader.read_zip(file),
+            ArchiveFormat::Tar => reader.read_tar(BufReader::new(file)),
+            ArchiveFormat::TarGz => reader.read_tar(GzDecoder::new(BufReader::new(file))),
+        };
+        if let Err(e) = res {
+            // The receiver might have stopped listening, nothing more to do then.
+            let _ = reader.tx.send(Err(e));
+        }
+    });
+    Ok(rx)
+}
+
+struct EntryReader {
+    path: PathBuf,
+    stem: String,
+    limits: Limits,
+    tx: Sender<Result<Image, Error>>,
+}
+impl EntryReader {
+    fn read_zip(&self, file: File) -> Result<(), Error> {
+        let mut archive = ZipArchive::new(file).map_err(|e| self.error(e))?;
+        let mut files = 0;
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i).map_err(|e| self.error(e))?;
+            if entry.is_dir() {
+                continue;
+            }
+            self.count(&mut files)?;
+            let Some(name) = entry.enclosed_name() else {
+                tracing::warn!("Skipping unsafe archive entry {:?}", entry.name());
+                continue;
+            };
+            let sent = match self.read_entry(entry.size(), &mut entry)? {
+                Some(bytes) => self.send(&name, bytes),
+                None => self.skip(&name),
+            };
+            if !sent {
+                break;
+            }
+        }
+        Ok(())
+    }
+    fn read_tar(&self, reader: impl Read) -> Result<(), Error> {
+        let mut archive = tar::Archive::new(reader);
+        let mut files = 0;
+        for entry in archive.entries().map_err(|e| self.error(e))? {
+            let mut entry = entry.map_err(|e| self.error(e))?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            self.count(&mut files)?;
+            let name = entry.path().map_err(|e| self.error(e))?.into_owned();
+            let sent = match self.read_entry(entry.size(), &mut entry)? {
+                Some(bytes) => self.send(&name, bytes),
+                None => self.skip(&name),
+            };
+            if !sent {
+                break;
+            }
+        }
+        Ok(())
+    }
+    /// Counts another file of the archive, fails once there are more than the limit.
+    fn count(&self, files: &mut usize) -> Result<(), Error> {
+        *files += 1;
+        match *files > self.limits.entries {
+            true => Err(self.error(format!(
+                "more than {} files, the rest are not read",
+                self.limits.entries
+            ))),
+            false => Ok(()),
+        }
+    }
+    /// Reads the entry into memory, `None` if it is larger than the limit. `size` is the
+    /// size given by the header, which only decides if the entry is skipped without reading it.
+    fn read_entry(&self, size: u64, entry: &mut impl Read) -> Result<Option<Vec<u8>>, Error> {
+        let max_bytes = self.limits.entry_bytes;
+        if size > max_bytes {
+            return Ok(None);
+        }
+        let mut bytes = Vec::new();
+        entry
+            .take(max_bytes + 1)
+            .read_to_end(&mut bytes)
+            .map_err(|e| self.error(e))?;
+        Ok((bytes.len() as u64 <= max_bytes).then_some(bytes))
+    }
+    /// Reports the entry as skipped for being too large, returns false if the receiver is gone.
+    fn skip(&self, name: &Path) -> bool {
+        let skipped = Error::Skipped {
+            path: self.path.join(name),
+            reason: SkipReason::EntryTooLarge {
+                max_bytes: self.limits.entry_bytes,
+            },
+        };
+        self.tx.send(Err(skipped)).is_ok()
+    }
+    /// Sends the entry as an image, returns false if the receiver is gone.
+    fn send(&self, name: &Path, bytes: Vec<u8>) -> bool {
+        let user = match name.parent().and_then(|p| p.file_name()) {
+            Some(dir) => dir.to_string_lossy().to_string(),
+            None => self.stem.clone(),
+        };
+        let image = Image::from_bytes(self.path.join(name), user, bytes);
+        self.tx.send(Ok(image)).is_ok()
+    }
+    fn error(&self, err: impl ToString) -> Error {
+        Error::Archive {
+            path: self.path.clone(),
+            err: err.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Write};
+
+    use zip::{ZipWriter, write::SimpleFileOptions};
+
+    use super::*;
+
+    fn archive_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("p-hash-{}-{}", std::process::id(), name))
+    }
+
+    fn write_zip(path: &Path, entries: &[(&str, usize)]) {
+        let mut zip = ZipWriter::new(File::create(path).unwrap());
+        for (name, size) in entries {
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(&vec![0; *size]).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    fn write_tar(path: &Path, entries: &[(&str, usize)]) {
+        let mut tar = tar::Builder::new(File::create(path).unwrap());
+        for (name, size) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(*size as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, name, vec![0; *size].as_slice())
+                .unwrap();
+        }
+        tar.finish().unwrap();
+    }
+
+    fn read(path: &Path, limits: Limits) -> Vec<Result<Image, Error>> {
+        let entries = read_entries_with(path, limits)
+            .unwrap()
+            .into_iter()
+            .collect();
+        fs::remove_file(path).unwrap();
+        entries
+    }
+
+    #[test]
+    fn reading_stops_after_the_entry_limit() {
+        let limits = Limits {
+            entries: 2,
+            ..Default::default()
+        };
+        let entries = [("a/1.png", 4), ("a/2.png", 4), ("b/3.png", 4)];
+        let zip = archive_path("limit.zip");
+        write_zip(&zip, &entries);
+        let tar = archive_path("limit.tar");
+        write_tar(&tar, &entries);
+
+        for read in [read(&zip, limits), read(&tar, limits)] {
+            assert_eq!(read.len(), 3);
+            assert!(read[..2].iter().all(|r| r.is_ok()));
+            assert!(matches!(read[2], Err(Error::Archive { .. })));
+        }
+    }
+
+    #[test]
+    fn entries_over_the_size_limit_are_skipped() {
+        let limits = Limits {
+            entry_bytes: 8,
+            ..Default::default()
+        };
+        let entries = [("a/small.png", 8), ("a/large.png", 9)];
+        let zip = archive_path("size.zip");
+        write_zip(&zip, &entries);
+        let tar = archive_path("size.tar");
+        write_tar(&tar, &entries);
+
+        for read in [read(&zip, limits), read(&tar, limits)] {
+            assert_eq!(read.len(), 2);
+            let image = read[0].as_ref().unwrap();
+            assert_eq!(image.get_bytes().map(|b| b.len()), Some(8));
+            assert_eq!(image.get_user(), "a");
+            assert!(matches!(
+                &read[1],
+                Err(Error::Skipped {
+                    reason: SkipReason::EntryTooLarge { max_bytes: 8 },
+                    ..
+                })
+            ));
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::image_parse::Image;
+
+/// SHA-256 of the content of a file, hex encoded.
+pub fn file_digest(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// SHA-256 of the bytes, hex encoded.
+pub fn bytes_digest(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+/// What to do with images that has the exact same content as an image seen earlier in the run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Keep the duplicate as a separate image and log a warning.
+    #[default]
+    Warn,
+    /// Leave the duplicate out of the run, it is only reported.
+    Skip,
+    /// Leave the duplicate out of the run and record its path as an alias of the first image,
+    /// both paths then refer to the same image id.
+    Merge,
+}
+impl Display for DuplicatePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Warn => write!(f, "warn"),
+            Self::Skip => write!(f, "skip"),
+            Self::Merge => write!(f, "merge"),
+        }
+    }
+}
+
+/// An image with the same digest as an image seen earlier in the run.
+#[derive(Debug, Clone)]
+pub struct Duplicate {
+    digest: String,
+    path: PathBuf,
+    original: PathBuf,
+    policy: DuplicatePolicy,
+}
+impl Duplicate {
+    pub fn get_digest(&self) -> &str {
+        &self.digest
+    }
+    /// Path of the duplicate.
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+    /// Path of the first image seen with the digest.
+    pub fn get_original(&self) -> &Path {
+        &self.original
+    }
+    /// The policy that was applied to the duplicate.
+    pub fn get_policy(&self) -> DuplicatePolicy {
+        self.policy
+    }
+    /// If the duplicate is an alias of the original, only with `DuplicatePolicy::Merge`.
+    pub fn is_merged(&self) -> bool {
+        self.policy == DuplicatePolicy::Merge
+    }
+}
+
+/// Finds images with equal content digests and applies a `DuplicatePolicy` to them.
+#[derive(Debug, Default)]
+pub struct Deduplicator {
+    policy: DuplicatePolicy,
+    seen: HashMap<String, PathBuf>,
+    duplicates: Vec<Duplicate>,
+}
+impl Deduplicator {
+    pub fn new(policy: DuplicatePolicy) -> Self {
+        Self {
+            policy,
+            ..Default::default()
+        }
+    }
+    /// Returns the image if it should be used in the run. Images without a digest are always
+    /// kept.
+    pub fn check(&mut self, image: Image) -> Option<Image> {
+        let Some(digest) = image.get_digest() else {
+            return Some(image);
+        };
+        let Some(original) = self.seen.get(digest) else {
+            self.seen
+                .insert(digest.to_string(), image.get_path().to_path_buf());
+            return Some(image);
+        };
+
+        let duplicate = Duplicate {
+            digest: digest.to_string(),
+            path: image.get_path().to_path_buf(),
+            original: original.clone(),
+            policy: self.policy,
+        };
+        tracing::warn!(
+            "{:?} has the same content as {:?}, applying policy {}",
+            duplicate.path,
+            duplicate.original,
+            self.policy
+        );
+        self.duplicates.push(duplicate);
+
+        match self.policy {
+            DuplicatePolicy::Warn => Some(image),
+            DuplicatePolicy::Skip | DuplicatePolicy::Merge => None,
+        }
+    }
+    /// The collisions found so far.
+    pub fn duplicates(&self) -> &[Duplicate] {
+        &self.duplicates
+    }
+    pub fn into_duplicates(self) -> Vec<Duplicate> {
+        self.duplicates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(path: &str, content: &[u8]) -> Image {
+        Image::from_bytes(path, "user", content.to_vec())
+            .with_digest()
+            .unwrap()
+    }
+
+    /// Paths of the images kept by the deduplicator, with the deduplicator.
+    fn check(policy: DuplicatePolicy) -> (Vec<PathBuf>, Deduplicator) {
+        let mut dedup = Deduplicator::new(policy);
+        let kept = [
+            image("a.png", b"first"),
+            image("b.png", b"second"),
+            image("c.png", b"first"),
+            Image::new("d.png", "user"),
+        ]
+        .into_iter()
+        .filter_map(|i| dedup.check(i))
+        .map(|i| i.get_path().to_path_buf())
+        .collect();
+        (kept, dedup)
+    }
+
+    #[test]
+    fn digest_of_bytes() {
+        assert_eq!(
+            bytes_digest(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn warn_keeps_duplicates() {
+        let (kept, dedup) = check(DuplicatePolicy::Warn);
+        assert_eq!(
+            kept,
+            ["a.png", "b.png", "c.png", "d.png"].map(PathBuf::from)
+        );
+        let duplicates = dedup.duplicates();
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates[0].get_path(), Path::new("c.png"));
+        assert_eq!(duplicates[0].get_original(), Path::new("a.png"));
+        assert_eq!(duplicates[0].get_digest(), bytes_digest(b"first"));
+        assert!(!duplicates[0].is_merged());
+    }
+
+    #[test]
+    fn skip_leaves_duplicates_out() {
+        let (kept, dedup) = check(DuplicatePolicy::Skip);
+        assert_eq!(kept, ["a.png", "b.png", "d.png"].map(PathBuf::from));
+        let duplicates = dedup.into_duplicates();
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates[0].get_policy(), DuplicatePolicy::Skip);
+        assert!(!duplicates[0].is_merged());
+    }
+
+    #[test]
+    fn merge_records_duplicates_as_aliases() {
+        let (kept, dedup) = check(DuplicatePolicy::Merge);
+        assert_eq!(kept, ["a.png", "b.png", "d.png"].map(PathBuf::from));
+        let duplicates = dedup.into_duplicates();
+        assert_eq!(duplicates.len(), 1);
+        assert!(duplicates[0].is_merged());
+        assert_eq!(duplicates[0].get_original(), Path::new("a.png"));
+    }
+}
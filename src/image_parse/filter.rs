@@ -0,0 +1,168 @@
+use std::{
+    fmt::Display,
+    io::{BufRead, Cursor, Seek},
+    path::{Path, PathBuf},
+};
+
+use image::{ImageFormat, io::Reader as ImageReader};
+
+use crate::image_parse::Duplicate;
+
+/// Decides what files under the image root are accepted as images. The format is detected from
+/// the magic bytes of the file, not its extension.
+#[derive(Debug, Clone)]
+pub struct ImageFilter {
+    formats: Vec<ImageFormat>,
+    min_dimensions: Option<(u32, u32)>,
+    max_dimensions: Option<(u32, u32)>,
+}
+impl Default for ImageFilter {
+    fn default() -> Self {
+        Self {
+            formats: vec![
+                ImageFormat::Png,
+                ImageFormat::Jpeg,
+                ImageFormat::Gif,
+                ImageFormat::WebP,
+                ImageFormat::Bmp,
+                ImageFormat::Tiff,
+            ],
+            min_dimensions: None,
+            max_dimensions: None,
+        }
+    }
+}
+impl ImageFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Only accept images in one of these formats.
+    pub fn formats(mut self, formats: impl Into<Vec<ImageFormat>>) -> Self {
+        self.formats = formats.into();
+        self
+    }
+    /// Skip images where width or height is smaller than given.
+    pub fn min_dimensions(mut self, width: u32, height: u32) -> Self {
+        self.min_dimensions = Some((width, height));
+        self
+    }
+    /// Skip images where width or height is larger than given.
+    pub fn max_dimensions(mut self, width: u32, height: u32) -> Self {
+        self.max_dimensions = Some((width, height));
+        self
+    }
+    pub fn get_formats(&self) -> &[ImageFormat] {
+        &self.formats
+    }
+    /// Reads the header of the file and checks it against the filter.
+    pub fn check(&self, path: &Path) -> Result<(), SkipReason> {
+        let reader = ImageReader::open(path)
+            .and_then(|r| r.with_guessed_format())
+            .map_err(|e| SkipReason::Unreadable { err: e.to_string() })?;
+        self.check_reader(reader)
+    }
+    /// Checks an image that is already read into memory against the filter.
+    pub fn check_bytes(&self, bytes: &[u8]) -> Result<(), SkipReason> {
+        let reader = ImageReader::new(Cursor::new(bytes))
+            .with_guessed_format()
+            .map_err(|e| SkipReason::Unreadable { err: e.to_string() })?;
+        self.check_reader(reader)
+    }
+    fn check_reader<R: BufRead + Seek>(&self, reader: ImageReader<R>) -> Result<(), SkipReason> {
+        let format = reader.format().ok_or(SkipReason::UnknownFormat)?;
+        if !self.formats.contains(&format) {
+            return Err(SkipReason::FormatNotAllowed { format });
+        }
+
+        let (width, height) = reader
+            .into_dimensions()
+            .map_err(|e| SkipReason::Corrupt { err: e.to_string() })?;
+        if let Some((min_width, min_height)) = self.min_dimensions
+            && (width < min_width || height < min_height)
+        {
+            return Err(SkipReason::TooSmall { width, height });
+        }
+        if let Some((max_width, max_height)) = self.max_dimensions
+            && (width > max_width || height > max_height)
+        {
+            return Err(SkipReason::TooLarge { width, height });
+        }
+        Ok(())
+    }
+}
+
+/// Why a file under the image root was not used in the run.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SkipReason {
+    Unreadable { err: String },
+    UnknownFormat,
+    FormatNotAllowed { format: ImageFormat },
+    Corrupt { err: String },
+    TooSmall { width: u32, height: u32 },
+    TooLarge { width: u32, height: u32 },
+    EntryTooLarge { max_bytes: u64 },
+}
+impl Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unreadable { err } => write!(f, "File could not be read: {}", err),
+            Self::UnknownFormat => write!(f, "Not a known image format"),
+            Self::FormatNotAllowed { format } => write!(f, "Format {:?} is not allowed", format),
+            Self::Corrupt { err } => write!(f, "Image header is corrupt: {}", err),
+            Self::TooSmall { width, height } => {
+                write!(f, "Image is too small: {}x{}", width, height)
+            }
+            Self::TooLarge { width, height } => {
+                write!(f, "Image is too large: {}x{}", width, height)
+            }
+            Self::EntryTooLarge { max_bytes } => {
+                write!(f, "Archive entry is larger than {} bytes", max_bytes)
+            }
+        }
+    }
+}
+
+/// A file that was found under the image root but left out of the run.
+#[derive(Debug, Clone)]
+pub struct SkippedImage {
+    path: PathBuf,
+    reason: SkipReason,
+}
+impl SkippedImage {
+    pub fn new(path: PathBuf, reason: SkipReason) -> Self {
+        Self { path, reason }
+    }
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+    pub fn get_reason(&self) -> &SkipReason {
+        &self.reason
+    }
+}
+
+/// Files skipped and duplicates found while gathering the images for a run.
+#[derive(Debug, Clone, Default)]
+pub struct IngestReport {
+    skipped: Vec<SkippedImage>,
+    duplicates: Vec<Duplicate>,
+}
+impl IngestReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn push(&mut self, skipped: SkippedImage) {
+        self.skipped.push(skipped);
+    }
+    pub fn skipped(&self) -> &[SkippedImage] {
+        &self.skipped
+    }
+    pub fn add_duplicates(&mut self, duplicates: impl IntoIterator<Item = Duplicate>) {
+        self.duplicates.extend(duplicates);
+    }
+    pub fn duplicates(&self) -> &[Duplicate] {
+        &self.duplicates
+    }
+    pub fn is_empty(&self) -> bool {
+        self.skipped.is_empty() && self.duplicates.is_empty()
+    }
+}
@@ -1,20 +1,78 @@
-use sqlx::prelude::FromRow;
+use image::{DynamicImage, ImageResult, io::Reader as ImageReader};
+use sqlx::{
+    Decode, Sqlite, Type,
+    error::BoxDynError,
+    prelude::FromRow,
+    sqlite::{SqliteTypeInfo, SqliteValueRef},
+};
 use std::{
     ffi::OsString,
-    fmt::Display,
+    fmt::{Debug, Display},
+    fs,
+    io::Cursor,
     ops::Deref,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 use tracing::debug;
 
+use crate::image_parse::{
+    ImageFilter, ImageMetadata, LoadOptions, Manifest, Orientation, Partition, SkipReason, archive,
+    bytes_digest, file_digest,
+};
+
 use walkdir::{DirEntry, WalkDir};
 #[derive(Debug, Clone, FromRow)]
 pub struct Image {
     #[sqlx(try_from = "String")]
     path: PathBuf,
     user: String,
+    #[sqlx(default)]
+    session: Option<String>,
+    #[sqlx(default, try_from = "TagsColumn")]
+    tags: Vec<String>,
+    // Hex encoded SHA-256 of the content.
+    #[sqlx(default)]
+    digest: Option<String>,
+    // What part of the run the image belongs to, if the run is split.
+    #[sqlx(skip)]
+    partition: Option<Partition>,
+    // Content of images that does not exist as a file on disk, e.g. entries in an archive.
+    #[sqlx(skip)]
+    bytes: Option<ImageBytes>,
 }
 impl Image {
+    pub fn new(path: impl Into<PathBuf>, user: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            user: user.into(),
+            session: None,
+            tags: Vec::new(),
+            digest: None,
+            partition: None,
+            bytes: None,
+        }
+    }
+    /// An image that is decoded from memory instead of read from its path.
+    pub fn from_bytes(
+        path: impl Into<PathBuf>,
+        user: impl Into<String>,
+        bytes: impl Into<Arc<[u8]>>,
+    ) -> Self {
+        Self {
+            bytes: Some(ImageBytes(bytes.into())),
+            ..Self::new(path, user)
+        }
+    }
+    /// Sets the capture session the image belongs to.
+    pub fn with_session(mut self, session: Option<String>) -> Self {
+        self.session = session;
+        self
+    }
+    pub fn with_tags(mut self, tags: Vec<String>) -> Self {
+        self.tags = tags;
+        self
+    }
     pub fn try_from_dir_entry(entry: DirEntry, root: &Path) -> Result<Self, Error> {
         let root_parent = root.parent().ok_or(Error::ParentNotFound {
             path: root.to_path_buf(),
@@ -37,10 +95,7 @@ impl Image {
                 .to_string()
         };
 
-        Ok(Self {
-            path: path.to_path_buf(),
-            user,
-        })
+        Ok(Self::new(path, user))
     }
     pub fn get_path(&self) -> &Path {
         &self.path
@@ -48,22 +103,175 @@ impl Image {
     pub fn get_user(&self) -> &str {
         &self.user
     }
+    pub fn get_session(&self) -> Option<&str> {
+        self.session.as_deref()
+    }
+    pub fn get_tags(&self) -> &[String] {
+        &self.tags
+    }
+    /// Computes the content digest of the image.
+    pub fn with_digest(mut self) -> Result<Self, Error> {
+        let digest = match self.get_bytes() {
+            Some(bytes) => bytes_digest(bytes),
+            None => file_digest(&self.path).map_err(|e| Error::Digest {
+                path: self.path.clone(),
+                err: e.to_string(),
+            })?,
+        };
+        self.digest = Some(digest);
+        Ok(self)
+    }
+    pub fn get_digest(&self) -> Option<&str> {
+        self.digest.as_deref()
+    }
+    pub fn with_partition(mut self, partition: Option<Partition>) -> Self {
+        self.partition = partition;
+        self
+    }
+    pub fn get_partition(&self) -> Option<Partition> {
+        self.partition
+    }
+    /// Content of the image if it is kept in memory.
+    pub fn get_bytes(&self) -> Option<&[u8]> {
+        self.bytes.as_ref().map(|b| &*b.0)
+    }
+    /// Decodes the image from memory or from its path with the default `LoadOptions`.
+    pub fn load(&self) -> ImageResult<DynamicImage> {
+        self.load_with(LoadOptions::default()).map(|(img, _)| img)
+    }
+    /// Decodes the image from memory or from its path. The format is guessed from the content.
+    pub fn load_with(&self, options: LoadOptions) -> ImageResult<(DynamicImage, ImageMetadata)> {
+        let file;
+        let bytes = match self.get_bytes() {
+            Some(bytes) => bytes,
+            None => {
+                file = fs::read(&self.path)?;
+                &file
+            }
+        };
+        let reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+        let format = reader.format();
+        let img = reader.decode()?;
+
+        let orientation = Orientation::from_bytes(bytes);
+        let img = match options.get_apply_orientation() {
+            true => orientation.apply(img),
+            false => img,
+        };
+        let metadata = ImageMetadata::new(&img, format, orientation).with_load_options(options);
+        Ok((img, metadata))
+    }
+}
+
+/// The `tags` column, a JSON array of the tags or `NULL` for images stored before there were tags.
+struct TagsColumn(Option<String>);
+impl Type<Sqlite> for TagsColumn {
+    fn type_info() -> SqliteTypeInfo {
+        <String as Type<Sqlite>>::type_info()
+    }
+    fn compatible(ty: &SqliteTypeInfo) -> bool {
+        <String as Type<Sqlite>>::compatible(ty)
+    }
+}
+impl<'r> Decode<'r, Sqlite> for TagsColumn {
+    fn decode(value: SqliteValueRef<'r>) -> Result<Self, BoxDynError> {
+        Ok(Self(<Option<String> as Decode<Sqlite>>::decode(value)?))
+    }
+}
+impl TryFrom<TagsColumn> for Vec<String> {
+    type Error = serde_json::Error;
+    fn try_from(value: TagsColumn) -> Result<Self, Self::Error> {
+        match value.0 {
+            Some(tags) => serde_json::from_str(&tags),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct ImageBytes(Arc<[u8]>);
+impl Debug for ImageBytes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ImageBytes({} bytes)", self.0.len())
+    }
 }
 
 pub struct Images {
     images: Box<dyn Iterator<Item = Result<Image, Error>> + Send>,
 }
 impl Images {
+    /// Walks the path and accepts the images that passes the default `ImageFilter`.
     pub fn from_path(path: PathBuf) -> Self {
+        Self::from_path_with_filter(path, ImageFilter::default())
+    }
+    /// Walks the path and accepts the images that passes the filter. Files that does not pass
+    /// are returned as `Error::Skipped`.
+    pub fn from_path_with_filter(path: PathBuf, filter: ImageFilter) -> Self {
         let walker = WalkDir::new(path.clone())
             .into_iter()
             .filter_map(|r| r.ok())
             .filter(|e| e.path().is_file())
-            .map(move |e| Image::try_from_dir_entry(e, &path).map(|i| i));
+            .map(move |e| {
+                filter.check(e.path()).map_err(|reason| Error::Skipped {
+                    path: e.path().to_path_buf(),
+                    reason,
+                })?;
+                Image::try_from_dir_entry(e, &path)?.with_digest()
+            });
         Self {
             images: Box::new(walker),
         }
     }
+    /// Reads the images listed in a manifest, with paths relative to root. Entries are checked
+    /// against the default `ImageFilter`.
+    pub fn from_manifest(manifest: &Path, root: &Path) -> Result<Self, Error> {
+        Self::from_manifest_with_filter(manifest, root, ImageFilter::default())
+    }
+    /// Reads the images listed in a manifest, with paths relative to root. Entries that does
+    /// not pass the filter are returned as `Error::Skipped`.
+    pub fn from_manifest_with_filter(
+        manifest: &Path,
+        root: &Path,
+        filter: ImageFilter,
+    ) -> Result<Self, Error> {
+        let root = root.to_path_buf();
+        let images = Manifest::from_path(manifest)?.into_iter().map(move |e| {
+            let image = e.into_image(&root);
+            filter
+                .check(image.get_path())
+                .map_err(|reason| Error::Skipped {
+                    path: image.get_path().to_path_buf(),
+                    reason,
+                })?;
+            image.with_digest()
+        });
+        Ok(Self {
+            images: Box::new(images),
+        })
+    }
+    /// Reads the images inside a zip or tar archive without extracting it. The user is the
+    /// name of the directory inside the archive that the image is in.
+    pub fn from_archive(path: &Path) -> Result<Self, Error> {
+        Self::from_archive_with_filter(path, ImageFilter::default())
+    }
+    /// Reads the images inside a zip or tar archive. Entries that does not pass the filter are
+    /// returned as `Error::Skipped`.
+    pub fn from_archive_with_filter(path: &Path, filter: ImageFilter) -> Result<Self, Error> {
+        let entries = archive::read_entries(path)?;
+        let images = entries.into_iter().map(move |r| {
+            let image = r?;
+            if let Some(bytes) = image.get_bytes() {
+                filter.check_bytes(bytes).map_err(|reason| Error::Skipped {
+                    path: image.get_path().to_path_buf(),
+                    reason,
+                })?;
+            }
+            image.with_digest()
+        });
+        Ok(Self {
+            images: Box::new(images),
+        })
+    }
     pub fn get_images(&self) -> &dyn Iterator<Item = Result<Image, Error>> {
         &*self.images
     }
@@ -87,6 +295,10 @@ pub enum Error {
     FileNameNotFound { path: PathBuf },
     InvalidUnicode { string: OsString },
     WalkDir { err: String },
+    Skipped { path: PathBuf, reason: SkipReason },
+    Manifest { path: PathBuf, err: String },
+    Archive { path: PathBuf, err: String },
+    Digest { path: PathBuf, err: String },
 }
 impl From<walkdir::Error> for Error {
     fn from(value: walkdir::Error) -> Self {
@@ -104,6 +316,12 @@ impl Display for Error {
                 write!(f, "Invalid unicode found for string {:?}", string)
             }
             Error::WalkDir { err } => write!(f, "WalkDirError: {}", err),
+            Error::Skipped { path, reason } => write!(f, "Skipped file {:?}: {}", path, reason),
+            Error::Manifest { path, err } => write!(f, "Invalid manifest {:?}: {}", path, err),
+            Error::Archive { path, err } => write!(f, "Could not read archive {:?}: {}", path, err),
+            Error::Digest { path, err } => {
+                write!(f, "Could not compute digest of {:?}: {}", path, err)
+            }
         }
     }
 }
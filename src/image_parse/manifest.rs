@@ -0,0 +1,209 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::image_parse::{Error, Image};
+
+/// Tags in CSV manifests are stored in one column, separated by this.
+const CSV_TAG_SEPARATOR: char = ';';
+
+/// Supported manifest file formats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManifestFormat {
+    /// Comma separated with a header row. Tags are separated by `;`.
+    Csv,
+    /// A JSON array of entries.
+    Json,
+}
+impl ManifestFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "csv" => Some(Self::Csv),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+/// One image in a manifest. Paths are relative to the root given when loading the manifest.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ManifestEntry {
+    path: PathBuf,
+    identity: String,
+    #[serde(default)]
+    session: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+impl ManifestEntry {
+    pub fn into_image(self, root: &Path) -> Image {
+        Image::new(root.join(self.path), self.identity)
+            .with_session(self.session)
+            .with_tags(self.tags)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CsvEntry {
+    path: PathBuf,
+    identity: String,
+    #[serde(default)]
+    session: Option<String>,
+    #[serde(default)]
+    tags: Option<String>,
+}
+impl From<CsvEntry> for ManifestEntry {
+    fn from(value: CsvEntry) -> Self {
+        let tags = value
+            .tags
+            .map(|t| {
+                t.split(CSV_TAG_SEPARATOR)
+                    .map(str::trim)
+                    .filter(|t| !t.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self {
+            path: value.path,
+            identity: value.identity,
+            session: value.session.filter(|s| !s.is_empty()),
+            tags,
+        }
+    }
+}
+
+/// A list of labeled images read from a CSV or JSON file.
+#[derive(Debug, Clone)]
+pub struct Manifest {
+    entries: Vec<ManifestEntry>,
+}
+impl Manifest {
+    /// Reads a manifest, the format is decided by the file extension.
+    pub fn from_path(path: &Path) -> Result<Self, Error> {
+        let format = ManifestFormat::from_path(path).ok_or(Error::Manifest {
+            path: path.to_path_buf(),
+            err: "unknown manifest format, expected .csv or .json".to_string(),
+        })?;
+        Self::from_path_with_format(path, format)
+    }
+    pub fn from_path_with_format(path: &Path, format: ManifestFormat) -> Result<Self, Error> {
+        let manifest_err = |err: String| Error::Manifest {
+            path: path.to_path_buf(),
+            err,
+        };
+        let file = File::open(path).map_err(|e| manifest_err(e.to_string()))?;
+
+        let entries = match format {
+            ManifestFormat::Csv => csv::Reader::from_reader(file)
+                .deserialize::<CsvEntry>()
+                .map(|r| r.map(ManifestEntry::from))
+                .collect::<Result<Vec<ManifestEntry>, csv::Error>>()
+                .map_err(|e| manifest_err(e.to_string()))?,
+            ManifestFormat::Json => serde_json::from_reader(BufReader::new(file))
+                .map_err(|e| manifest_err(e.to_string()))?,
+        };
+        Ok(Self { entries })
+    }
+    pub fn entries(&self) -> &[ManifestEntry] {
+        &self.entries
+    }
+}
+impl IntoIterator for Manifest {
+    type Item = ManifestEntry;
+    type IntoIter = std::vec::IntoIter<ManifestEntry>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    /// Writes the manifest to a temporary file and reads it.
+    fn read(name: &str, content: &str) -> Result<Manifest, Error> {
+        let path = std::env::temp_dir().join(format!("p-hash-{}-{}", std::process::id(), name));
+        fs::write(&path, content).unwrap();
+        let manifest = Manifest::from_path(&path);
+        fs::remove_file(path).unwrap();
+        manifest
+    }
+
+    fn images(manifest: Manifest) -> Vec<Image> {
+        manifest
+            .into_iter()
+            .map(|e| e.into_image(Path::new("root")))
+            .collect()
+    }
+
+    #[test]
+    fn csv_entries() {
+        let manifest = read(
+            "entries.csv",
+            "path,identity,session,tags\na.png,alice,,glasses; dark\nb.png,bob,s1,\n",
+        )
+        .unwrap();
+        let images = images(manifest);
+        assert_eq!(images.len(), 2);
+        assert_eq!(images[0].get_path(), Path::new("root/a.png"));
+        assert_eq!(images[0].get_user(), "alice");
+        assert_eq!(images[0].get_session(), None);
+        assert_eq!(images[0].get_tags(), ["glasses", "dark"]);
+        assert_eq!(images[1].get_session(), Some("s1"));
+        assert!(images[1].get_tags().is_empty());
+    }
+
+    #[test]
+    fn json_entries() {
+        let manifest = read(
+            "entries.json",
+            r#"[{"path": "a.png", "identity": "alice", "tags": ["glasses"]},
+                {"path": "b.png", "identity": "bob", "session": "s1"}]"#,
+        )
+        .unwrap();
+        let images = images(manifest);
+        assert_eq!(images.len(), 2);
+        assert_eq!(images[0].get_tags(), ["glasses"]);
+        assert_eq!(images[1].get_session(), Some("s1"));
+    }
+
+    #[test]
+    fn csv_without_identity_fails() {
+        let res = read("no-identity.csv", "path,session\na.png,s1\n");
+        assert!(matches!(res, Err(Error::Manifest { .. })));
+    }
+
+    #[test]
+    fn csv_with_missing_columns_fails() {
+        let res = read("short-row.csv", "path,identity\na.png\n");
+        assert!(matches!(res, Err(Error::Manifest { .. })));
+    }
+
+    #[test]
+    fn invalid_json_fails() {
+        let res = read("invalid.json", r#"[{"path": "a.png", "identity": "alice"}"#);
+        assert!(matches!(res, Err(Error::Manifest { .. })));
+        let res = read("no-identity.json", r#"[{"path": "a.png"}]"#);
+        assert!(matches!(res, Err(Error::Manifest { .. })));
+    }
+
+    #[test]
+    fn unknown_format_fails() {
+        let res = read("entries.txt", "a.png,alice\n");
+        assert!(matches!(res, Err(Error::Manifest { .. })));
+    }
+
+    #[test]
+    fn missing_manifest_fails() {
+        let res = Manifest::from_path(Path::new("missing-manifest.csv"));
+        assert!(matches!(res, Err(Error::Manifest { .. })));
+    }
+}
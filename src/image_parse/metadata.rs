@@ -0,0 +1,147 @@
+use std::{fmt::Display, io::Cursor};
+
+use image::{DynamicImage, GenericImageView, ImageFormat};
+
+/// How images are decoded before they are modified and hashed.
+#[derive(Debug, Clone, Copy)]
+pub struct LoadOptions {
+    apply_orientation: bool,
+}
+impl Default for LoadOptions {
+    fn default() -> Self {
+        Self {
+            apply_orientation: true,
+        }
+    }
+}
+impl LoadOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Rotate and flip the image as told by its EXIF orientation tag, so it is processed the
+    /// way a human would see it. Enabled by default.
+    pub fn apply_orientation(mut self, apply: bool) -> Self {
+        self.apply_orientation = apply;
+        self
+    }
+    pub fn get_apply_orientation(&self) -> bool {
+        self.apply_orientation
+    }
+}
+
+/// The EXIF orientation of an image, the values are those of the EXIF tag.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Orientation {
+    #[default]
+    Normal = 1,
+    FlipHorizontal = 2,
+    Rotate180 = 3,
+    FlipVertical = 4,
+    Transpose = 5,
+    Rotate90 = 6,
+    Transverse = 7,
+    Rotate270 = 8,
+}
+impl Orientation {
+    /// Reads the orientation from the EXIF data of an encoded image. Images without EXIF data
+    /// or with an invalid value are `Normal`.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let exif = match exif::Reader::new().read_from_container(&mut Cursor::new(bytes)) {
+            Ok(e) => e,
+            Err(_) => return Self::Normal,
+        };
+        exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+            .and_then(|f| f.value.get_uint(0))
+            .map(Self::from_tag)
+            .unwrap_or_default()
+    }
+    pub fn from_tag(value: u32) -> Self {
+        match value {
+            2 => Self::FlipHorizontal,
+            3 => Self::Rotate180,
+            4 => Self::FlipVertical,
+            5 => Self::Transpose,
+            6 => Self::Rotate90,
+            7 => Self::Transverse,
+            8 => Self::Rotate270,
+            _ => Self::Normal,
+        }
+    }
+    pub fn tag(&self) -> u16 {
+        *self as u16
+    }
+    /// Transforms the stored image into how it should be displayed.
+    pub fn apply(&self, img: DynamicImage) -> DynamicImage {
+        match self {
+            Self::Normal => img,
+            Self::FlipHorizontal => img.fliph(),
+            Self::Rotate180 => img.rotate180(),
+            Self::FlipVertical => img.flipv(),
+            Self::Transpose => img.rotate90().fliph(),
+            Self::Rotate90 => img.rotate90(),
+            Self::Transverse => img.rotate270().fliph(),
+            Self::Rotate270 => img.rotate270(),
+        }
+    }
+}
+
+/// Properties of a decoded image. Width and height are those of the image after the load
+/// options are applied.
+#[derive(Debug, Clone)]
+pub struct ImageMetadata {
+    width: u32,
+    height: u32,
+    format: Option<ImageFormat>,
+    color_type: image::ColorType,
+    orientation: Orientation,
+    load_options: LoadOptions,
+}
+impl ImageMetadata {
+    pub fn new(img: &DynamicImage, format: Option<ImageFormat>, orientation: Orientation) -> Self {
+        let (width, height) = img.dimensions();
+        Self {
+            width,
+            height,
+            format,
+            color_type: img.color(),
+            orientation,
+            load_options: LoadOptions::default(),
+        }
+    }
+    /// Sets the options the image was decoded with.
+    pub fn with_load_options(mut self, load_options: LoadOptions) -> Self {
+        self.load_options = load_options;
+        self
+    }
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+    pub fn format(&self) -> Option<ImageFormat> {
+        self.format
+    }
+    pub fn color_type(&self) -> image::ColorType {
+        self.color_type
+    }
+    pub fn orientation(&self) -> Orientation {
+        self.orientation
+    }
+    pub fn load_options(&self) -> LoadOptions {
+        self.load_options
+    }
+}
+impl Display for ImageMetadata {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}x{} {:?} {:?} orientation {}",
+            self.width,
+            self.height,
+            self.format,
+            self.color_type,
+            self.orientation.tag()
+        )
+    }
+}
@@ -0,0 +1,253 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fmt::Display,
+    str::FromStr,
+};
+
+use rand::{SeedableRng, seq::SliceRandom};
+use rand_chacha::ChaCha8Rng;
+
+use crate::image_parse::Image;
+
+/// Picks a reproducible subset of the images. The images are shuffled with the seed before the
+/// limits are applied, in the order per identity, fraction and then total limit.
+#[derive(Debug, Clone, Default)]
+pub struct Sampling {
+    seed: u64,
+    limit: Option<usize>,
+    per_identity: Option<usize>,
+    fraction: Option<f64>,
+}
+impl Sampling {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            ..Default::default()
+        }
+    }
+    /// Take at most n images in total.
+    pub fn limit(mut self, n: usize) -> Self {
+        self.limit = Some(n);
+        self
+    }
+    /// Take at most n images of every identity.
+    pub fn per_identity(mut self, n: usize) -> Self {
+        self.per_identity = Some(n);
+        self
+    }
+    /// Take a fraction, between 0 and 1, of the images.
+    pub fn fraction(mut self, fraction: f64) -> Self {
+        self.fraction = Some(fraction.clamp(0.0, 1.0));
+        self
+    }
+    pub fn get_seed(&self) -> u64 {
+        self.seed
+    }
+    /// Returns the sampled images sorted by path.
+    pub fn apply(&self, mut images: Vec<Image>) -> Vec<Image> {
+        let mut rng = ChaCha8Rng::seed_from_u64(self.seed);
+
+        // Sort first so the result does not depend on the order the images were found in.
+        images.sort_by(|a, b| a.get_path().cmp(b.get_path()));
+        images.shuffle(&mut rng);
+
+        if let Some(n) = self.per_identity {
+            let mut taken: BTreeMap<String, usize> = BTreeMap::new();
+            images.retain(|i| {
+                let count = taken.entry(i.get_user().to_string()).or_default();
+                *count += 1;
+                *count <= n
+            });
+        }
+        if let Some(fraction) = self.fraction {
+            let n = (images.len() as f64 * fraction).round() as usize;
+            images.truncate(n);
+        }
+        if let Some(n) = self.limit {
+            images.truncate(n);
+        }
+
+        images.sort_by(|a, b| a.get_path().cmp(b.get_path()));
+        images
+    }
+}
+
+/// Part of the image set an image belongs to within a run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Partition {
+    /// Used to pick thresholds.
+    Calibration,
+    /// Used to report results with thresholds picked on the calibration partition.
+    Evaluation,
+}
+impl Display for Partition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Calibration => write!(f, "calibration"),
+            Self::Evaluation => write!(f, "evaluation"),
+        }
+    }
+}
+impl FromStr for Partition {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "calibration" => Ok(Self::Calibration),
+            "evaluation" => Ok(Self::Evaluation),
+            _ => Err(format!("unknown partition {}", s)),
+        }
+    }
+}
+
+/// Splits the images into a calibration and an evaluation partition.
+#[derive(Debug, Clone)]
+pub struct Split {
+    seed: u64,
+    calibration: f64,
+    by_identity: bool,
+}
+impl Split {
+    /// Puts the fraction, between 0 and 1, of the images in the calibration partition.
+    pub fn new(seed: u64, calibration: f64) -> Self {
+        Self {
+            seed,
+            calibration: calibration.clamp(0.0, 1.0),
+            by_identity: false,
+        }
+    }
+    /// Keep all images of an identity in the same partition, the fraction is then of the
+    /// identities instead of the images.
+    pub fn by_identity(mut self, by_identity: bool) -> Self {
+        self.by_identity = by_identity;
+        self
+    }
+    pub fn get_seed(&self) -> u64 {
+        self.seed
+    }
+    /// Sets the partition of every image.
+    pub fn apply(&self, images: Vec<Image>) -> Vec<Image> {
+        let mut rng = ChaCha8Rng::seed_from_u64(self.seed);
+
+        let mut keys: Vec<String> = images
+            .iter()
+            .map(|i| self.key(i))
+            .collect::<BTreeSet<String>>()
+            .into_iter()
+            .collect();
+        keys.shuffle(&mut rng);
+
+        let n = (keys.len() as f64 * self.calibration).round() as usize;
+        let calibration: HashSet<String> = keys.into_iter().take(n).collect();
+
+        images
+            .into_iter()
+            .map(|i| {
+                let partition = match calibration.contains(&self.key(&i)) {
+                    true => Partition::Calibration,
+                    false => Partition::Evaluation,
+                };
+                i.with_partition(Some(partition))
+            })
+            .collect()
+    }
+    fn key(&self, image: &Image) -> String {
+        match self.by_identity {
+            true => image.get_user().to_string(),
+            false => image.get_path().to_string_lossy().to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// Four images of each of five identities.
+    fn images() -> Vec<Image> {
+        (0..5)
+            .flat_map(|u| {
+                (0..4).map(move |i| Image::new(format!("u{}/{}.png", u, i), format!("u{}", u)))
+            })
+            .collect()
+    }
+
+    fn paths(images: &[Image]) -> Vec<PathBuf> {
+        images.iter().map(|i| i.get_path().to_path_buf()).collect()
+    }
+
+    #[test]
+    fn sampling_is_the_same_for_the_same_seed() {
+        let sampling = Sampling::new(7).limit(6);
+        let first = paths(&sampling.apply(images()));
+        assert_eq!(first.len(), 6);
+
+        // The order the images are found in does not matter.
+        let mut reversed = images();
+        reversed.reverse();
+        assert_eq!(paths(&sampling.apply(reversed)), first);
+
+        let others: Vec<_> = (0..10)
+            .map(|seed| paths(&Sampling::new(seed).limit(6).apply(images())))
+            .collect();
+        assert!(others.iter().any(|o| *o != first));
+    }
+
+    #[test]
+    fn sampling_limits_are_applied() {
+        let sampled = Sampling::new(1).per_identity(2).apply(images());
+        assert_eq!(sampled.len(), 10);
+        let mut users: BTreeMap<&str, usize> = BTreeMap::new();
+        for image in &sampled {
+            *users.entry(image.get_user()).or_default() += 1;
+        }
+        assert!(users.values().all(|n| *n == 2));
+
+        assert_eq!(Sampling::new(1).fraction(0.25).apply(images()).len(), 5);
+        // The fraction is of the images left after the per identity limit.
+        let sampled = Sampling::new(1).per_identity(2).fraction(0.5).limit(3);
+        assert_eq!(sampled.apply(images()).len(), 3);
+    }
+
+    #[test]
+    fn split_is_the_same_for_the_same_seed() {
+        let split = Split::new(3, 0.4);
+        let partitions = |images: Vec<Image>| -> Vec<(PathBuf, Option<Partition>)> {
+            let mut images = split.apply(images);
+            images.sort_by(|a, b| a.get_path().cmp(b.get_path()));
+            images
+                .iter()
+                .map(|i| (i.get_path().to_path_buf(), i.get_partition()))
+                .collect()
+        };
+        let first = partitions(images());
+        let mut reversed = images();
+        reversed.reverse();
+        assert_eq!(partitions(reversed), first);
+
+        let calibration = first
+            .iter()
+            .filter(|(_, p)| *p == Some(Partition::Calibration))
+            .count();
+        assert_eq!(calibration, 8);
+    }
+
+    #[test]
+    fn split_by_identity_keeps_identities_together() {
+        let images = Split::new(3, 0.4).by_identity(true).apply(images());
+        let mut partitions: BTreeMap<&str, HashSet<Option<Partition>>> = BTreeMap::new();
+        for image in &images {
+            partitions
+                .entry(image.get_user())
+                .or_default()
+                .insert(image.get_partition());
+        }
+        assert!(partitions.values().all(|p| p.len() == 1));
+        let calibration = partitions
+            .values()
+            .filter(|p| p.contains(&Some(Partition::Calibration)))
+            .count();
+        assert_eq!(calibration, 2);
+    }
+}
@@ -0,0 +1,39 @@
+use std::path::{Path, PathBuf};
+
+use crate::image_parse::{Error, ImageFilter, Images};
+
+/// Where the images of a run are read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImageSource {
+    /// Every file under the directory, the user is the directory the image is in.
+    Directory(PathBuf),
+    /// The images listed in a CSV or JSON manifest, with paths relative to the root.
+    Manifest { manifest: PathBuf, root: PathBuf },
+    /// The images inside a zip or tar archive, read without extracting it.
+    Archive(PathBuf),
+}
+impl Default for ImageSource {
+    fn default() -> Self {
+        Self::Directory(PathBuf::new())
+    }
+}
+impl ImageSource {
+    /// Directory the images are read from, or the archive for archives.
+    pub fn get_root(&self) -> &Path {
+        match self {
+            Self::Directory(path) => path,
+            Self::Manifest { root, .. } => root,
+            Self::Archive(path) => path,
+        }
+    }
+    /// Reads the images that passes the filter, the others are returned as `Error::Skipped`.
+    pub fn images(&self, filter: ImageFilter) -> Result<Images, Error> {
+        match self {
+            Self::Directory(path) => Ok(Images::from_path_with_filter(path.clone(), filter)),
+            Self::Manifest { manifest, root } => {
+                Images::from_manifest_with_filter(manifest, root, filter)
+            }
+            Self::Archive(path) => Images::from_archive_with_filter(path, filter),
+        }
+    }
+}
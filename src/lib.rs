@@ -1,9 +1,14 @@
 mod macros;
 
+pub mod cancel;
 pub mod core;
 pub mod db;
+pub mod events;
+pub mod export;
 pub mod image_hash;
 pub mod image_modify;
 pub mod image_parse;
 pub mod matching;
 pub mod result_calc;
+pub mod store;
+pub mod synthetic;
@@ -1,20 +1,37 @@
 use std::fmt::Display;
 
+use crate::{db, store};
+
 #[derive(Debug)]
 pub enum Error {
     Sqlx { err: sqlx::Error },
+    Db { err: db::Error },
+    Store { err: store::Error },
     HashesNotEqualLength { l1: u32, l2: u32 },
     NotEnougHashes(usize),
+    Cancelled,
 }
 impl From<sqlx::Error> for Error {
     fn from(value: sqlx::Error) -> Self {
         Self::Sqlx { err: value }
     }
 }
+impl From<db::Error> for Error {
+    fn from(value: db::Error) -> Self {
+        Self::Db { err: value }
+    }
+}
+impl From<store::Error> for Error {
+    fn from(value: store::Error) -> Self {
+        Self::Store { err: value }
+    }
+}
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Sqlx { err } => write!(f, "Sqlx Error: {}", err),
+            Self::Db { err } => write!(f, "Database error: {}", err),
+            Self::Store { err } => write!(f, "Result store error: {}", err),
             Self::HashesNotEqualLength { l1, l2 } => write!(
                 f,
                 "Input hashes does not have equal length: {} != {} ",
@@ -25,6 +42,7 @@ impl Display for Error {
                 "Not enough hashes found to begin matching. Expected len >= 2, found {} ",
                 len
             ),
+            Self::Cancelled => write!(f, "Matching was cancelled"),
         }
     }
 }
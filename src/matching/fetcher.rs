@@ -1,25 +1,31 @@
 use async_trait::async_trait;
 use sqlx::SqlitePool;
 
-use crate::matching::{
-    error::Error,
-    state::{Hash, Hashes, MatchState},
+use crate::{
+    db::{HashFilter, Repository},
+    matching::{
+        error::Error,
+        state::{Hashes, MatchState},
+    },
+    store::ResultStore,
 };
 
-// Fetches hashes from source based on their hashing method used.
+// Fetches hashes from source based on the id of their hashing method used.
 #[async_trait]
 pub trait ResultsFetcher: Send + Sync {
     type Error;
     type Output;
-    async fn fetch(&self, method_id: u16, state: MatchState) -> Result<Self::Output, Self::Error>;
+    async fn fetch(&self, method_id: i64, state: MatchState) -> Result<Self::Output, Self::Error>;
 }
 
 pub struct SqliteFetcher {
-    pool: SqlitePool,
+    repository: Repository,
 }
 impl SqliteFetcher {
     pub fn new(pool: SqlitePool) -> Self {
-        Self { pool }
+        Self {
+            repository: Repository::new(pool),
+        }
     }
 }
 impl ResultsFetcher for SqliteFetcher {
@@ -27,7 +33,7 @@ impl ResultsFetcher for SqliteFetcher {
     type Error = Error;
     fn fetch<'life0, 'async_trait>(
         &'life0 self,
-        method_id: u16,
+        method_id: i64,
         _: MatchState,
     ) -> ::core::pin::Pin<
         Box<
@@ -41,21 +47,39 @@ impl ResultsFetcher for SqliteFetcher {
         Self: 'async_trait,
     {
         Box::pin(async move {
-            let res: Result<Vec<Hash>, sqlx::Error> = sqlx::query_as(
-                "
-                SELECT h.id, h.hash
-                FROM hashes h
-                JOIN modified_images mi ON mi.id = h.mod_image_id
-                JOIN images i ON i.id = mi.image_id
-                JOIN run_images ri ON ri.image_id = i.id
-                JOIN program p ON p.run_id = ri.run_id
-                WHERE h.hashing_method_id = ?;
-                ",
-            )
-            .bind(method_id)
-            .fetch_all(&self.pool)
-            .await;
-            res.map(Hashes::from).map_err(|e| Error::Sqlx { err: e })
+            let hashes = self.repository.hashes(&HashFilter::new(method_id)).await?;
+            Ok(Hashes::from(hashes))
         })
     }
 }
+
+/// Fetches the hashes of the active run from a `ResultStore`.
+pub struct StoreFetcher {
+    store: ResultStore,
+}
+impl StoreFetcher {
+    pub fn new(store: ResultStore) -> Self {
+        Self { store }
+    }
+}
+impl ResultsFetcher for StoreFetcher {
+    type Output = Hashes;
+    type Error = Error;
+    fn fetch<'life0, 'async_trait>(
+        &'life0 self,
+        method_id: i64,
+        _: MatchState,
+    ) -> ::core::pin::Pin<
+        Box<
+            dyn ::core::future::Future<Output = Result<Self::Output, Self::Error>>
+                + ::core::marker::Send
+                + 'async_trait,
+        >,
+    >
+    where
+        'life0: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(async move { Ok(Hashes::from(self.store.run_hashes(method_id)?)) })
+    }
+}
@@ -1,18 +1,17 @@
-use std::{collections::HashMap, thread};
-
 use async_trait::async_trait;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use sqlx::SqlitePool;
 
 use crate::{
-    image_hash::{HashingMethods, SelectedHashingMethods},
+    cancel::CancellationToken,
+    events::EventBus,
     matching::{
         error::Error,
-        fetcher::{ResultsFetcher, SqliteFetcher},
-        processor::{MatchProcessor, MultiThreadedUniquePairMatcher, ThreadedUniquePairMatcher},
-        result_parser::{MatchResultParser, RcSqliteResultParser},
-        state::{Component, MatchState, Message},
+        fetcher::{ResultsFetcher, SqliteFetcher, StoreFetcher},
+        processor::{MatchProcessor, MultiThreadedUniquePairMatcher},
+        result_parser::{MatchResultParser, RcSqliteResultParser, RcStoreResultParser},
+        state::{Component, MatchState},
     },
+    store::ResultStore,
 };
 
 struct MatchPipeline<E, M, R> {
@@ -20,7 +19,10 @@ struct MatchPipeline<E, M, R> {
     processor: Box<dyn MatchProcessor<Input = R, Output = M, Error = E>>,
     parser: Box<dyn MatchResultParser<Result = M, Error = E>>,
 }
-impl<E, M, R> MatchPipeline<E, M, R> {
+impl<E, M, R> MatchPipeline<E, M, R>
+where
+    E: From<Error>,
+{
     pub fn new(
         fetcher: Box<dyn ResultsFetcher<Error = E, Output = R>>,
         processor: Box<dyn MatchProcessor<Input = R, Output = M, Error = E>>,
@@ -32,17 +34,26 @@ impl<E, M, R> MatchPipeline<E, M, R> {
             parser,
         }
     }
-    pub async fn execute<'a>(&self, hashing_methods: &SelectedHashingMethods<'a>) -> Result<(), E> {
-        let state = MatchState::new();
-
-        indicatif_view(state.clone());
+    /// Matches the hashes of every hashing method in turn. Stops with `Error::Cancelled` before
+    /// the next hashing method, or as soon as a component sees it, once the run is cancelled.
+    /// Progress is sent to `events`.
+    pub async fn execute(
+        &self,
+        hashing_method_ids: &[i64],
+        cancel: CancellationToken,
+        events: &EventBus,
+    ) -> Result<(), E> {
+        let state = MatchState::new(events).with_cancellation(cancel);
 
-        state.set(Component::Fetcher, hashing_methods.len() as u32);
+        state.set(Component::Fetcher, hashing_method_ids.len() as u32);
 
-        for (id, _) in hashing_methods.iter().enumerate() {
+        for id in hashing_method_ids {
+            if state.is_cancelled() {
+                return Err(Error::Cancelled.into());
+            }
             state.update(Component::Fetcher, 1);
 
-            let fetch_res = self.fetcher.fetch(id as u16, state.clone()).await?;
+            let fetch_res = self.fetcher.fetch(*id, state.clone()).await?;
             let processor_res = self.processor.process(fetch_res, state.clone())?;
             self.parser.parse(processor_res, state.clone()).await?;
         }
@@ -50,40 +61,19 @@ impl<E, M, R> MatchPipeline<E, M, R> {
     }
 }
 
-/// Spawn a view thread that uses indicatif as frontend to MatchState
-fn indicatif_view(rx_state: MatchState) {
-    let style = ProgressStyle::with_template(
-        "[{elapsed_precise} | {eta_precise}] {msg}: {pos:>7}/{len:7} {percent}%",
-    )
-    .unwrap()
-    .progress_chars("##-");
-
-    thread::spawn(move || {
-        let multi = MultiProgress::new();
-        let mut progressbars = HashMap::new();
-        while let Ok(m) = rx_state.get() {
-            match m {
-                Message::Set { component, total } => {
-                    let pb = ProgressBar::new(total as u64)
-                        .with_style(style.clone())
-                        .with_message(component.to_string());
-                    multi.add(pb.clone());
-                    progressbars.insert(component, pb);
-                }
-                Message::Update { component, delta } => {
-                    progressbars.get(&component).map(|pb| pb.inc(delta as u64));
-                }
-            }
-        }
-    });
-}
 #[derive(Debug)]
 pub struct StateQuit;
 
-/// Pipelinerunner should run for every hashing method. Matching across methods would be useless
+/// Pipelinerunner should run for every hashing method. Matching across methods would be useless.
+/// Hashing methods are given by their database ids.
 #[async_trait]
 pub trait PipelineRunner: Send + Sync {
-    async fn run(&self, hashing_methods: &SelectedHashingMethods) -> Result<(), Error>;
+    async fn run(
+        &self,
+        hashing_method_ids: &[i64],
+        cancel: CancellationToken,
+        events: EventBus,
+    ) -> Result<(), Error>;
 }
 
 pub struct SqliteRunner {
@@ -97,7 +87,9 @@ impl SqliteRunner {
 impl PipelineRunner for SqliteRunner {
     fn run<'life0, 'life1, 'async_trait>(
         &'life0 self,
-        hashing_methods: &'life1 SelectedHashingMethods,
+        hashing_method_ids: &'life1 [i64],
+        cancel: CancellationToken,
+        events: EventBus,
     ) -> ::core::pin::Pin<
         Box<
             dyn ::core::future::Future<Output = Result<(), Error>>
@@ -117,7 +109,51 @@ impl PipelineRunner for SqliteRunner {
 
             let pipeline = MatchPipeline::new(fetcher, processor, parser);
 
-            pipeline.execute(hashing_methods).await?;
+            pipeline
+                .execute(hashing_method_ids, cancel, &events)
+                .await?;
+            Ok(())
+        })
+    }
+}
+
+/// Matches the hashes of the active run in a `ResultStore`.
+pub struct StoreRunner {
+    store: ResultStore,
+}
+impl StoreRunner {
+    pub fn new(store: ResultStore) -> Self {
+        Self { store }
+    }
+}
+impl PipelineRunner for StoreRunner {
+    fn run<'life0, 'life1, 'async_trait>(
+        &'life0 self,
+        hashing_method_ids: &'life1 [i64],
+        cancel: CancellationToken,
+        events: EventBus,
+    ) -> ::core::pin::Pin<
+        Box<
+            dyn ::core::future::Future<Output = Result<(), Error>>
+                + ::core::marker::Send
+                + 'async_trait,
+        >,
+    >
+    where
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(async move {
+            let fetcher = Box::new(StoreFetcher::new(self.store.clone()));
+            let processor = Box::new(MultiThreadedUniquePairMatcher::default());
+            let parser = Box::new(RcStoreResultParser::new(self.store.clone()));
+
+            let pipeline = MatchPipeline::new(fetcher, processor, parser);
+
+            pipeline
+                .execute(hashing_method_ids, cancel, &events)
+                .await?;
             Ok(())
         })
     }
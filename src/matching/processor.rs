@@ -7,7 +7,7 @@ use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIter
 
 use crate::matching::{
     error::Error,
-    state::{Component, HammingDistance, Hashes, Match, MatchState, Matches},
+    state::{Component, HammingDistance, Hash, Hashes, Match, MatchState, Matches},
 };
 
 // Matches hashes and outputs the result
@@ -43,11 +43,14 @@ impl MatchProcessor for UniquePairMatcher {
 
         let mut matches: Matches = Matches::default();
         for (i, input1) in inputs.iter().enumerate() {
+            if state_handle.is_cancelled() {
+                return Err(Error::Cancelled);
+            }
             state_handle.update(Component::Processor, 1);
 
             for input2 in inputs[i + 1..].iter() {
-                let hamming_distance = compute_hamming_distance(input1.hash(), input2.hash())?;
-                let res = Match::new(input1.id(), input2.id(), hamming_distance);
+                let hamming_distance = compute_hamming_distance(input1, input2)?;
+                let res = Match::new(input1, input2, hamming_distance);
                 matches.push(res);
             }
         }
@@ -78,11 +81,11 @@ impl MatchProcessor for ThreadedUniquePairMatcher {
             for (i, input1) in inputs.iter().enumerate() {
                 state_handle.update(Component::Processor, 1);
 
-                if should_quit {
+                if should_quit || state_handle.is_cancelled() {
                     break;
                 }
                 for input2 in inputs[i + 1..].iter() {
-                    let res = compute_hamming_distance(input1.hash(), input2.hash());
+                    let res = compute_hamming_distance(input1, input2);
                     let hamming_distance = match res {
                         Ok(r) => r,
                         Err(e) => {
@@ -92,7 +95,7 @@ impl MatchProcessor for ThreadedUniquePairMatcher {
                         }
                     };
 
-                    let res = Match::new(input1.id(), input2.id(), hamming_distance);
+                    let res = Match::new(input1, input2, hamming_distance);
                     if let Err(e) = tx.send(res) {
                         tracing::warn!("could not send result to channel, err: {}", e);
                         break;
@@ -124,10 +127,13 @@ impl MatchProcessor for MultiThreadedUniquePairMatcher {
 
         rayon::spawn(move || {
             inputs.par_iter().enumerate().for_each(|(i, input1)| {
+                if state_handle.is_cancelled() {
+                    return;
+                }
                 state_handle.update(Component::Processor, 1);
 
                 for input2 in inputs[i + 1..].iter() {
-                    let res = compute_hamming_distance(input1.hash(), input2.hash());
+                    let res = compute_hamming_distance(input1, input2);
                     let hamming_distance = match res {
                         Ok(r) => r,
                         Err(e) => {
@@ -136,7 +142,7 @@ impl MatchProcessor for MultiThreadedUniquePairMatcher {
                         }
                     };
 
-                    let res = Match::new(input1.id(), input2.id(), hamming_distance);
+                    let res = Match::new(input1, input2, hamming_distance);
                     if let Err(e) = tx.send(res) {
                         tracing::warn!("could not send result to channel, err: {}", e);
                         break;
@@ -148,13 +154,19 @@ impl MatchProcessor for MultiThreadedUniquePairMatcher {
     }
 }
 
-fn compute_hamming_distance(x: &[u8], y: &[u8]) -> Result<HammingDistance, Error> {
-    if x.len() != y.len() {
+/// Unused bits of the last byte are zero in stored hashes, so they never add to the distance.
+fn compute_hamming_distance(x: &Hash, y: &Hash) -> Result<HammingDistance, Error> {
+    if x.bits() != y.bits() || x.hash().len() != y.hash().len() {
         return Err(Error::HashesNotEqualLength {
-            l1: x.len() as u32,
-            l2: y.len() as u32,
+            l1: x.bits(),
+            l2: y.bits(),
         });
     }
-    let hamming_distance = x.iter().zip(y).map(|(x, y)| (x ^ y).count_ones()).sum();
-    Ok(HammingDistance::new(hamming_distance, x.len() as u32))
+    let hamming_distance = x
+        .hash()
+        .iter()
+        .zip(y.hash())
+        .map(|(x, y)| (x ^ y).count_ones())
+        .sum();
+    Ok(HammingDistance::new(hamming_distance, x.bits()))
 }
@@ -4,11 +4,20 @@ use async_trait::async_trait;
 use sqlx::{QueryBuilder, Sqlite, SqlitePool};
 use tokio::task::{self};
 
-use crate::matching::{
-    error::Error,
-    state::{Component, Match, MatchState, Matches},
+use crate::{
+    matching::{
+        error::Error,
+        state::{Component, Match, MatchState, Matches},
+    },
+    store::ResultStore,
 };
 
+/// Highest number of bound parameters in a single statement, the compile time default of the
+/// SQLite bundled by sqlx.
+const SQLITE_MAX_VARIABLE_NUMBER: usize = 32_766;
+/// Values bound for every match inserted.
+const MATCH_BINDS: usize = 8;
+
 #[async_trait]
 pub trait MatchResultParser: Sync + Send {
     type Result;
@@ -32,7 +41,7 @@ impl MatchResultParser for RcSqliteResultParser {
     fn parse<'life0, 'async_trait>(
         &'life0 self,
         results: Self::Result,
-        _: MatchState,
+        state: MatchState,
     ) -> ::core::pin::Pin<
         Box<
             dyn ::core::future::Future<Output = Result<(), Self::Error>>
@@ -47,9 +56,13 @@ impl MatchResultParser for RcSqliteResultParser {
         Box::pin(async move {
             tracing::debug!("starting parser");
             let mut stop = false;
-            let batch_size = 8000; // reaching limit for sqlite
+            let batch_size = SQLITE_MAX_VARIABLE_NUMBER / MATCH_BINDS;
             loop {
-                let mut batch = Vec::with_capacity(10_000);
+                // Matches stored before the run was cancelled are kept.
+                if state.is_cancelled() {
+                    return Err(Error::Cancelled);
+                }
+                let mut batch = Vec::with_capacity(batch_size);
                 while let Ok(m) = results.recv() {
                     batch.push(m);
                     if batch.len() >= batch_size {
@@ -59,18 +72,33 @@ impl MatchResultParser for RcSqliteResultParser {
                 if batch.len() < batch_size {
                     stop = true;
                 }
+                // An insert without values is not valid SQL.
+                if batch.is_empty() {
+                    break;
+                }
 
                 let pool = self.pool.clone();
                 let mut query: QueryBuilder<Sqlite> = QueryBuilder::new(
-                    "INSERT INTO matches (hamming_distance, hash_len, hash1_id, hash2_id) ",
+                    "
+                    INSERT INTO matches
+                    (hamming_distance, hash_len, hash1_id, hash2_id, image1_id, image2_id,
+                     modification_id, hashing_method_id)
+                    ",
                 );
                 query.push_values(batch.iter(), |mut b, m| {
                     b.push_bind(m.hamming_distance().distance())
                         .push_bind(m.hamming_distance().entry_length())
                         .push_bind(m.hash_id1())
-                        .push_bind(m.hash_id2());
+                        .push_bind(m.hash_id2())
+                        .push_bind(m.image_ids().0)
+                        .push_bind(m.image_ids().1)
+                        .push_bind(m.modification_id())
+                        .push_bind(m.hashing_method_id());
                 });
+                // Pairs matched by an earlier run over the same images are already stored.
+                query.push(" ON CONFLICT (hash1_id, hash2_id) DO NOTHING");
                 query.build().execute(&pool).await?;
+                state.update(Component::Parser, batch.len() as u32);
 
                 if stop {
                     tracing::debug!("match result parser exiting");
@@ -110,6 +138,9 @@ impl MatchResultParser for SqliteResultParser {
     {
         Box::pin(async move {
             while !results.is_empty() {
+                if s.is_cancelled() {
+                    return Err(Error::Cancelled);
+                }
                 let chunk: Vec<Match> = results.drain(..10_000).collect();
                 s.update(Component::Parser, chunk.len() as u32);
 
@@ -117,15 +148,25 @@ impl MatchResultParser for SqliteResultParser {
 
                 task::spawn(async move {
                     for result in chunk.iter() {
-                        sqlx::query("
-                    INSERT INTO matches (hamming_distance, hash_len, hash1_id, hash2_id) VALUES (?,?,?,?)
-                    ")
-                    .bind(result.hamming_distance().distance())
-                    .bind(result.hamming_distance().entry_length())
-                    .bind(result.hash_id1())
-                    .bind( result.hash_id2())
-                    .execute(&mut *tx)
-                    .await.unwrap();
+                        sqlx::query(
+                            "
+                    INSERT INTO matches (hamming_distance, hash_len, hash1_id, hash2_id, image1_id,
+                                         image2_id, modification_id, hashing_method_id)
+                    VALUES (?,?,?,?,?,?,?,?)
+                    ON CONFLICT (hash1_id, hash2_id) DO NOTHING
+                    ",
+                        )
+                        .bind(result.hamming_distance().distance())
+                        .bind(result.hamming_distance().entry_length())
+                        .bind(result.hash_id1())
+                        .bind(result.hash_id2())
+                        .bind(result.image_ids().0)
+                        .bind(result.image_ids().1)
+                        .bind(result.modification_id())
+                        .bind(result.hashing_method_id())
+                        .execute(&mut *tx)
+                        .await
+                        .unwrap();
                     }
                     tx.commit().await.unwrap();
                 });
@@ -134,3 +175,92 @@ impl MatchResultParser for SqliteResultParser {
         })
     }
 }
+
+/// Takes a Receiver<Match> and stores each entry in a `ResultStore`.
+pub struct RcStoreResultParser {
+    store: ResultStore,
+}
+impl RcStoreResultParser {
+    pub fn new(store: ResultStore) -> Self {
+        Self { store }
+    }
+}
+impl MatchResultParser for RcStoreResultParser {
+    type Error = Error;
+    type Result = Receiver<Match>;
+    fn parse<'life0, 'async_trait>(
+        &'life0 self,
+        results: Self::Result,
+        state: MatchState,
+    ) -> ::core::pin::Pin<
+        Box<
+            dyn ::core::future::Future<Output = Result<(), Self::Error>>
+                + ::core::marker::Send
+                + 'async_trait,
+        >,
+    >
+    where
+        'life0: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(async move {
+            // Stored in batches so files are written while matching instead of all at the end.
+            let batch_size = 8000;
+            let mut batch = Vec::with_capacity(batch_size);
+            loop {
+                let done = match results.recv() {
+                    Ok(m) => {
+                        batch.push(m);
+                        false
+                    }
+                    Err(_) => true,
+                };
+                if batch.len() >= batch_size || (done && !batch.is_empty()) {
+                    if state.is_cancelled() {
+                        return Err(Error::Cancelled);
+                    }
+                    state.update(Component::Parser, batch.len() as u32);
+                    self.store.write(|t| {
+                        for m in batch.drain(..) {
+                            t.insert_match(
+                                m.hamming_distance().distance(),
+                                m.hamming_distance().entry_length(),
+                                m.hash_id1() as i64,
+                                m.hash_id2() as i64,
+                            );
+                        }
+                    })?;
+                }
+                if done {
+                    tracing::debug!("match result parser exiting");
+                    break;
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+
+    use super::*;
+    use crate::db::DbConfig;
+
+    #[tokio::test]
+    async fn no_matches_stores_nothing() {
+        let pool = DbConfig::in_memory().connect().await.unwrap();
+        let (tx, rx) = channel::<Match>();
+        drop(tx);
+        RcSqliteResultParser::from_pool(pool.clone())
+            .parse(rx, MatchState::default())
+            .await
+            .unwrap();
+        let (matches,): (i64,) = sqlx::query_as("SELECT count(*) FROM matches;")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(matches, 0);
+    }
+}
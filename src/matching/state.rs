@@ -1,51 +1,64 @@
 use std::{
+    collections::HashMap,
     fmt::Display,
     ops::{Deref, DerefMut},
+    sync::Arc,
 };
 
-use bitvec::view::BitViewSized;
-use crossbeam::channel::{RecvError, bounded};
-use enum_iterator::Sequence;
+use base64::{Engine, prelude::BASE64_STANDARD};
+use enum_iterator::{Sequence, all};
 use serde::{Deserialize, Serialize};
 use sqlx::prelude::FromRow;
 
-/// Clone only increases reference count of channels(hopefully)
+use crate::{
+    cancel::CancellationToken,
+    core::snapshot::Stage,
+    db::StoredMatch,
+    events::{EventBus, Progress},
+};
+
+/// Progress and cancellation shared by the components of the match pipeline. Clone only
+/// increases reference counts.
 #[derive(Clone)]
 pub struct MatchState {
-    producer: crossbeam::channel::Sender<Message>,
-    consumer: crossbeam::channel::Receiver<Message>,
+    progress: Arc<HashMap<Component, Progress>>,
+    cancel: CancellationToken,
+}
+impl Default for MatchState {
+    fn default() -> Self {
+        Self::new(&EventBus::default())
+    }
 }
 impl MatchState {
-    pub fn new() -> Self {
-        let (state_handle, sub) = bounded(10000);
+    /// Progress of the components is sent to the bus as progress of the matching stage.
+    pub fn new(events: &EventBus) -> Self {
+        let progress = all::<Component>()
+            .map(|c| (c, events.progress(Stage::Matching, Some(c))))
+            .collect();
         Self {
-            consumer: sub,
-            producer: state_handle,
+            progress: Arc::new(progress),
+            cancel: CancellationToken::default(),
         }
     }
+    /// Lets the components of the pipeline see when the run is cancelled.
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+    /// Update the progress of a component
     pub fn update(&self, component: Component, delta: u32) {
-        self.producer
-            .send(Message::Update { component, delta })
-            .unwrap();
+        self.progress[&component].inc(delta as u64);
     }
+    /// Set the total expected progress, the progress starts over from zero.
     pub fn set(&self, component: Component, total: u32) {
-        self.producer
-            .send(Message::Set { component, total })
-            .unwrap();
-    }
-    pub fn get(&self) -> Result<Message, RecvError> {
-        self.consumer.recv()
+        self.progress[&component].set_total(total as u64);
     }
 }
 
-pub enum Message {
-    // Update the progress of a component
-    Update { component: Component, delta: u32 },
-    // Set the total expected progress.
-    Set { component: Component, total: u32 },
-}
-
-#[derive(Debug, Sequence, PartialEq, Eq, Hash, Copy, Clone)]
+#[derive(Debug, Sequence, PartialEq, Eq, Hash, Copy, Clone, Serialize, Deserialize)]
 pub enum Component {
     Fetcher,
     Processor,
@@ -61,18 +74,86 @@ impl Display for Component {
     }
 }
 
+/// The image, modification and hashing method a hash was made from. Copied onto the matches of
+/// the hash so the match queries do not have to join the hashes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, FromRow)]
+pub struct HashSource {
+    image_id: i64,
+    modification_id: i64,
+    hashing_method_id: i64,
+}
+impl HashSource {
+    pub fn new(image_id: i64, modification_id: i64, hashing_method_id: i64) -> Self {
+        Self {
+            image_id,
+            modification_id,
+            hashing_method_id,
+        }
+    }
+    pub fn image_id(&self) -> i64 {
+        self.image_id
+    }
+    pub fn modification_id(&self) -> i64 {
+        self.modification_id
+    }
+    pub fn hashing_method_id(&self) -> i64 {
+        self.hashing_method_id
+    }
+}
+impl From<&StoredMatch> for Match {
+    fn from(value: &StoredMatch) -> Self {
+        let (hash_id1, hash_id2) = value.get_hash_ids();
+        let (image1_id, image2_id) = value.get_image_ids();
+        Self {
+            hash_id1,
+            hash_id2,
+            hamming_distance: value.hamming_distance(),
+            image1_id,
+            image2_id,
+            modification_id: value.get_modification_id(),
+            hashing_method_id: value.get_hashing_method_id(),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, FromRow)]
 pub struct Hash {
     id: u32,
     hash: Vec<u8>,
+    #[sqlx(rename = "hash_bits")]
+    bits: u32,
+    #[sqlx(flatten)]
+    source: HashSource,
 }
 impl Hash {
+    pub fn new(id: u32, hash: Vec<u8>, bits: u32, source: HashSource) -> Self {
+        Self {
+            id,
+            hash,
+            bits,
+            source,
+        }
+    }
     pub fn id(&self) -> u32 {
         self.id
     }
+    /// The bits of the hash packed most significant bit first.
     pub fn hash(&self) -> &[u8] {
         &self.hash
     }
+    /// Number of bits in the hash.
+    pub fn bits(&self) -> u32 {
+        self.bits
+    }
+    pub fn source(&self) -> &HashSource {
+        &self.source
+    }
+    pub fn to_hex(&self) -> String {
+        hex::encode(&self.hash)
+    }
+    pub fn to_base64(&self) -> String {
+        BASE64_STANDARD.encode(&self.hash)
+    }
 }
 pub struct Hashes {
     hashes: Vec<Hash>,
@@ -112,13 +193,30 @@ pub struct Match {
     hash_id2: u32,
     #[sqlx(flatten)]
     hamming_distance: HammingDistance,
+    image1_id: i64,
+    image2_id: i64,
+    modification_id: Option<i64>,
+    hashing_method_id: i64,
 }
 impl Match {
-    pub fn new(hash_id1: u32, hash_id2: u32, hamming_distance: HammingDistance) -> Self {
+    /// The hash with the lower id is always the first, so a pair is stored the same way by every
+    /// run.
+    pub fn new(hash1: &Hash, hash2: &Hash, hamming_distance: HammingDistance) -> Self {
+        let (hash1, hash2) = match hash1.id() <= hash2.id() {
+            true => (hash1, hash2),
+            false => (hash2, hash1),
+        };
+        let (source1, source2) = (hash1.source(), hash2.source());
         Self {
-            hash_id1,
-            hash_id2,
+            hash_id1: hash1.id(),
+            hash_id2: hash2.id(),
             hamming_distance,
+            image1_id: source1.image_id(),
+            image2_id: source2.image_id(),
+            // Matches between different modifications are not part of any modification.
+            modification_id: (source1.modification_id() == source2.modification_id())
+                .then_some(source1.modification_id()),
+            hashing_method_id: source1.hashing_method_id(),
         }
     }
     pub fn hash_id1(&self) -> u32 {
@@ -130,6 +228,15 @@ impl Match {
     pub fn hamming_distance(&self) -> &HammingDistance {
         &self.hamming_distance
     }
+    pub fn image_ids(&self) -> (i64, i64) {
+        (self.image1_id, self.image2_id)
+    }
+    pub fn modification_id(&self) -> Option<i64> {
+        self.modification_id
+    }
+    pub fn hashing_method_id(&self) -> i64 {
+        self.hashing_method_id
+    }
 }
 #[derive(Debug, Clone, Copy, FromRow)]
 pub struct HammingDistance {
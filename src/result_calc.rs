@@ -1,26 +1,27 @@
 use std::{
     fmt::Display,
-    iter::zip,
     ops::{Deref, DerefMut, Div},
     path::Path,
-    sync::{Arc, Mutex},
+    sync::Arc,
 };
 
-use crossbeam::channel::{Receiver, Sender};
-use futures::future::join_all;
-use indicatif::{ParallelProgressIterator, ProgressBar, ProgressStyle};
+use futures::TryStreamExt;
 use plotters::prelude::*;
-use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use serde::{Deserialize, Serialize};
 use sqlx::SqlitePool;
-use tokio_stream::StreamExt;
+use tokio::sync::{mpsc::Sender, oneshot};
 
 use crate::{
+    core::snapshot::{Stage, StageTimer},
+    db::{self, MatchFilter, Repository},
+    events::{Event, EventBus, Progress},
     image_hash::{HashingMethod, HashingMethods},
     image_modify::{ImageModification, Modifications},
+    image_parse::Partition,
     matching::state::Match,
 };
 
-#[derive(Default, Debug, Copy, Clone)]
+#[derive(Default, Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct ConfusionMatrix {
     true_positives: u32,
     true_negatives: u32,
@@ -50,6 +51,10 @@ impl ConfusionMatrix {
     pub fn tp_rate(&self) -> f32 {
         (self.true_positives as f32).div((self.true_positives + self.false_negatives) as f32)
     }
+    /// Number of classified entries.
+    pub fn total(&self) -> u32 {
+        self.true_positives + self.true_negatives + self.false_positives + self.false_negatives
+    }
     pub fn extend(&mut self, other: Self) {
         self.true_positives += other.true_positives;
         self.true_negatives += other.true_negatives;
@@ -71,6 +76,17 @@ impl Roc {
             }
         }
     }
+    /// Picks the threshold that maximizes Youden's J statistic, `tpr - fpr`. The thresholds
+    /// must be the ones the `Roc` was calculated with.
+    pub fn best_threshold(&self, thresholds: &[f32]) -> Option<(f32, ConfusionMatrix)> {
+        thresholds
+            .iter()
+            .zip(self.iter())
+            .map(|(t, c)| (*t, *c, c.tp_rate() - c.fp_rate()))
+            .filter(|(_, _, j)| !j.is_nan())
+            .max_by(|(_, _, a), (_, _, b)| a.total_cmp(b))
+            .map(|(t, c, _)| (t, c))
+    }
 }
 impl IntoIterator for Roc {
     type Item = ConfusionMatrix;
@@ -95,6 +111,9 @@ pub struct RocProcess {
     pool: SqlitePool,
     hashing_methods: HashingMethods,
     modifications: Modifications,
+    partition: Option<Partition>,
+    run: Option<i64>,
+    events: EventBus,
 }
 impl RocProcess {
     pub fn new(
@@ -108,59 +127,69 @@ impl RocProcess {
             pool,
             hashing_methods,
             modifications,
+            partition: None,
+            run: None,
+            events: EventBus::default(),
         }
     }
-    /// Calculates the `ConfusionMatrix` for the given matches.
+    /// Only uses matches where both images are in the given partition of the active run, or of
+    /// the run given with `with_run`.
+    pub fn with_partition(mut self, partition: Partition) -> Self {
+        self.partition = Some(partition);
+        self
+    }
+    /// Only uses matches where both images are part of the given run, without making it the
+    /// active run.
+    pub fn with_run(mut self, run_id: i64) -> Self {
+        self.run = Some(run_id);
+        self
+    }
+    /// Sends the progress of classifying the matches to the bus, as the `Roc` stage.
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = events;
+        self
+    }
+    /// Calculates the `ConfusionMatrix` for the given matches. Fails if any of the matches can not
+    /// be read, instead of returning a ROC of the matches that could.
     pub async fn run(self) -> Result<Roc, Error> {
-        println!("starting");
-        let entries = self.modifications.iter().flat_map(|m| {
-            self.hashing_methods
-                .iter()
-                .map(async |h| match_fetcher(h.as_ref(), m.as_ref(), self.pool.clone()).await)
-        });
-        let entries = join_all(entries).await;
+        self.events.send(Event::StageStarted { stage: Stage::Roc });
+        let timer = StageTimer::start(Stage::Roc);
+        let progress = Arc::new(self.events.progress(Stage::Roc, None));
+        let repository = Repository::new(self.pool.clone());
 
-        println!("starting roc calc");
-        let (tx, rx) = tokio::sync::oneshot::channel();
-        rayon::spawn(move || {
-            let res = entries
-                .into_par_iter()
-                .map(|r| {
-                    let mut roc = Roc::default();
-                    while let Ok(Data { m, is_same_image }) = r.recv() {
-                        for (i, threshold) in self.thresholds.iter().enumerate() {
-                            let class = classify(
-                                *threshold,
-                                m.hamming_distance().relative(),
-                                is_same_image,
-                            );
+        let mut filters = Vec::new();
+        let mut total = 0;
+        for modification in self.modifications.iter() {
+            for hashing_method in self.hashing_methods.iter() {
+                let ids = stored_ids(&self.pool, hashing_method.as_ref(), modification.as_ref());
+                // Nothing is stored for the combination, so there are no matches either.
+                let Some((hashing_method_id, modification_id)) = ids.await? else {
+                    continue;
+                };
+                let filter = MatchFilter::new(modification_id, hashing_method_id)
+                    .partition(self.partition)
+                    .run(self.run);
+                total += u64::from(repository.match_count(&filter).await?);
+                filters.push(filter);
+            }
+        }
+        progress.set_total(total);
 
-                            // Get the entry that corresponds to the threshold if it exists.
-                            match roc.get_mut(i) {
-                                Some(p) => {
-                                    p.increment(class);
-                                }
-                                None => {
-                                    let mut matrix = ConfusionMatrix::default();
-                                    matrix.increment(class);
-                                    roc.push(matrix);
-                                }
-                            }
-                        }
-                    }
-                    roc
-                })
-                .reduce(
-                    || Roc::default(),
-                    |mut acc, other| {
-                        acc.merge(other);
-                        acc
-                    },
-                );
-            tx.send(res).unwrap();
+        // The combinations are read one after another, a stream keeps a connection of the pool
+        // until it is read to the end.
+        let thresholds = Arc::new(self.thresholds);
+        let mut roc = Roc::default();
+        for filter in filters {
+            let matches = Classifier::spawn(thresholds.clone(), progress.clone());
+            roc.merge(matches.read(&repository, &filter).await?);
+        }
+
+        let timing = timer.finish();
+        self.events.send(Event::StageFinished {
+            stage: Stage::Roc,
+            duration_ms: timing.get_duration().as_millis() as u64,
         });
-        let res = rx.await.unwrap();
-        Ok(res)
+        Ok(roc)
     }
 }
 pub struct Data {
@@ -168,116 +197,70 @@ pub struct Data {
     pub is_same_image: bool,
 }
 
-/// Fetches matches for a given hashing_method and modification
-async fn match_fetcher(
-    hashing_method: &dyn HashingMethod,
-    modification: &dyn ImageModification,
-    pool: SqlitePool,
-) -> Receiver<Data> {
-    let hm_name = hashing_method.name();
-    let m_name = modification.name().to_string();
-    let (tx, rx) = crossbeam::channel::bounded(100);
-    get_matches(&pool, &hm_name, &m_name, tx.clone()).await;
-    rx
+/// Classifies the matches sent to it on the rayon pool, while they are read from the database.
+struct Classifier {
+    tx: Sender<Data>,
+    roc: oneshot::Receiver<Roc>,
 }
-async fn get_matches(pool: &SqlitePool, hm: &str, m: &str, tx: Sender<Data>) {
-    println!("finding count");
-    let count: (u32,) = sqlx::query_as(
-        "
-SELECT count(m.id)
-FROM matches m
-JOIN hashes h1 ON m.hash1_id = h1.id
-JOIN hashing_methods ha1 ON h1.hashing_method_id = ha1.id
-JOIN modified_images mi1 ON h1.mod_image_id = mi1.id
-JOIN modifications mod1 ON mi1.modification_id = mod1.id
-
-JOIN hashes h2 ON m.hash2_id = h2.id
-JOIN hashing_methods ha2 ON h2.hashing_method_id = ha2.id
-JOIN modified_images mi2 ON h2.mod_image_id = mi2.id
-JOIN modifications mod2 ON mi2.modification_id = mod2.id
-
-WHERE mod1.name = ?
-  AND mod2.name = ?
-  AND ha1.name = ?
-  AND ha2.name = ?;
-                    ",
-    )
-    .bind(m)
-    .bind(m)
-    .bind(hm)
-    .bind(hm)
-    .fetch_one(pool)
-    .await
-    .unwrap();
-
-    let mut streamer = sqlx::query_as(
-        "
-SELECT 
-    m.hamming_distance, 
-    m.hash_len, 
-    m.hash1_id, 
-    m.hash2_id
-FROM matches m
-JOIN hashes h1 ON m.hash1_id = h1.id
-JOIN hashing_methods ha1 ON h1.hashing_method_id = ha1.id
-JOIN modified_images mi1 ON h1.mod_image_id = mi1.id
-JOIN modifications mod1 ON mi1.modification_id = mod1.id
-
-JOIN hashes h2 ON m.hash2_id = h2.id
-JOIN hashing_methods ha2 ON h2.hashing_method_id = ha2.id
-JOIN modified_images mi2 ON h2.mod_image_id = mi2.id
-JOIN modifications mod2 ON mi2.modification_id = mod2.id
-
-WHERE mod1.name = ?
-  AND mod2.name = ?
-  AND ha1.name = ?
-  AND ha2.name = ?;
-                    ",
-    )
-    .bind(m)
-    .bind(m)
-    .bind(hm)
-    .bind(hm)
-    .fetch(pool);
-
-    println!("len: {}", count.0);
-    let style = ProgressStyle::with_template(
-        "[{elapsed_precise} | {eta_precise}] Sending results to DB: {pos:>7}/{len:7} {percent}%",
-    )
-    .unwrap()
-    .progress_chars("##-");
-    let pb = ProgressBar::new(count.0 as u64).with_style(style);
-    while let Some(m) = streamer.try_next().await.unwrap() {
-        let m: Match = m; // Inferring type
+impl Classifier {
+    /// Starts classifying at the thresholds, counting the classified matches with progress.
+    fn spawn(thresholds: Arc<Vec<f32>>, progress: Arc<Progress>) -> Self {
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<Data>(100);
+        let (roc_tx, roc_rx) = oneshot::channel();
+        rayon::spawn(move || {
+            let mut roc = Roc::default();
+            while let Some(Data { m, is_same_image }) = rx.blocking_recv() {
+                for (i, threshold) in thresholds.iter().enumerate() {
+                    let class =
+                        classify(*threshold, m.hamming_distance().relative(), is_same_image);
 
-        let is_same_image = match is_same_image(&pool, m.hash_id1(), m.hash_id2()).await {
-            Ok(r) => r,
-            Err(_) => break,
-        };
-        if tx.send(Data { m, is_same_image }).is_err() {
-            break;
-        };
-        pb.inc(1);
+                    // Get the entry that corresponds to the threshold if it exists.
+                    match roc.get_mut(i) {
+                        Some(p) => {
+                            p.increment(class);
+                        }
+                        None => {
+                            let mut matrix = ConfusionMatrix::default();
+                            matrix.increment(class);
+                            roc.push(matrix);
+                        }
+                    }
+                }
+                progress.inc(1);
+            }
+            // The reader is gone if reading the matches failed.
+            let _ = roc_tx.send(roc);
+        });
+        Self { tx, roc: roc_rx }
+    }
+    /// Streams the matches of the filter to the classifier and returns their ROC.
+    async fn read(self, repository: &Repository, filter: &MatchFilter) -> Result<Roc, Error> {
+        let Self { tx, roc } = self;
+        let mut streamer = repository.matches(filter);
+        while let Some(m) = streamer.try_next().await? {
+            let data = Data {
+                m: m.to_match(),
+                is_same_image: m.is_same_image(),
+            };
+            if tx.send(data).await.is_err() {
+                break;
+            }
+        }
+        drop(tx);
+        roc.await.map_err(|_| Error::Classification)
     }
 }
 
-async fn is_same_image(pool: &SqlitePool, hash1_id: u32, hash2_id: u32) -> Result<bool, Error> {
-    let is_same: (bool,) = sqlx::query_as(
-        "
-        SELECT (mi1.image_id = mi2.image_id) FROM
-        hashes h1 
-        JOIN modified_images mi1 ON h1.mod_image_id = mi1.id
-        JOIN hashes h2
-        JOIN modified_images mi2 ON h2.mod_image_id = mi2.id
-        WHERE h1.id = ? AND h2.id = ?
-    ",
-    )
-    .bind(hash1_id)
-    .bind(hash2_id)
-    .fetch_one(pool)
-    .await?;
-
-    Ok(is_same.0)
+/// Database ids of the hashing method and the modification.
+async fn stored_ids(
+    pool: &SqlitePool,
+    hashing_method: &dyn HashingMethod,
+    modification: &dyn ImageModification,
+) -> Result<Option<(i64, i64)>, Error> {
+    let mut conn = pool.acquire().await?;
+    let hm = db::find_hashing_method_id(&mut conn, hashing_method).await?;
+    let m = db::find_modification_id(&mut conn, modification).await?;
+    Ok(hm.zip(m))
 }
 
 pub enum Classification {
@@ -288,7 +271,7 @@ pub enum Classification {
 }
 
 /// Classify an entry based a threshold and what its actual condition is.
-fn classify(threshold: f32, entry: f32, actual_positive: bool) -> Classification {
+pub(crate) fn classify(threshold: f32, entry: f32, actual_positive: bool) -> Classification {
     let predicted_same = entry < threshold;
     match (actual_positive, predicted_same) {
         (true, true) => Classification::TruePositive,
@@ -303,7 +286,6 @@ pub fn plot_roc(roc: Roc, path: &Path) -> Result<(), Box<dyn std::error::Error>>
 
     root.fill(&WHITE)?;
 
-    let sd = 0.13;
     let data: Vec<(f64, f64)> = roc
         .into_iter()
         .map(|c| (c.fp_rate() as f64, c.tp_rate() as f64))
@@ -350,16 +332,26 @@ pub fn plot_roc(roc: Roc, path: &Path) -> Result<(), Box<dyn std::error::Error>>
 #[derive(Debug)]
 pub enum Error {
     Sqlx(sqlx::Error),
+    Db(db::Error),
+    /// Classifying the matches stopped before all of them were classified.
+    Classification,
 }
 impl From<sqlx::Error> for Error {
     fn from(value: sqlx::Error) -> Self {
         Self::Sqlx(value)
     }
 }
+impl From<db::Error> for Error {
+    fn from(value: db::Error) -> Self {
+        Self::Db(value)
+    }
+}
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Sqlx(e) => write!(f, "Sqlx error: {}", e),
+            Self::Db(e) => write!(f, "Database error: {}", e),
+            Self::Classification => write!(f, "Classifying the matches stopped"),
         }
     }
 }
@@ -0,0 +1,185 @@
+mod error;
+mod tables;
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+pub use error::Error;
+use tables::Tables;
+pub use tables::{HashRow, ImageRow, MatchRow, MethodRow, ModifiedImageRow, RunImageRow, RunRow};
+
+use crate::{
+    core::snapshot::RunSnapshot,
+    image_parse::{LoadOptions, Orientation},
+    matching::{self, state::HashSource},
+};
+
+/// Results kept without a database, with the same tables, ids and rules for reusing rows as the
+/// SQLite results. Either only in memory, or also written to a directory with one JSON-lines file
+/// per table that other tools can read. Clones share the same results.
+#[derive(Debug, Clone)]
+pub struct ResultStore {
+    tables: Arc<Mutex<Tables>>,
+    dir: Option<PathBuf>,
+}
+impl ResultStore {
+    /// Results that are gone when the last clone of the store is dropped.
+    pub fn in_memory() -> Self {
+        Self {
+            tables: Arc::new(Mutex::new(Tables::default())),
+            dir: None,
+        }
+    }
+    /// Results in the directory, the tables already in it are read and new rows are written to
+    /// it whenever results are stored. The directory is created if it does not exist.
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self, Error> {
+        let dir = dir.into();
+        let tables = Tables::load(&dir)?;
+        Ok(Self {
+            tables: Arc::new(Mutex::new(tables)),
+            dir: Some(dir),
+        })
+    }
+    /// Directory the tables are written to, `None` for in-memory stores.
+    pub fn get_dir(&self) -> Option<&Path> {
+        self.dir.as_deref()
+    }
+
+    /// Runs the closure with the tables and writes what it changed to the directory.
+    pub(crate) fn write<R>(&self, f: impl FnOnce(&mut Tables) -> R) -> Result<R, Error> {
+        let mut tables = self.lock()?;
+        let res = f(&mut tables);
+        if let Some(dir) = &self.dir {
+            tables.save(dir)?;
+        }
+        Ok(res)
+    }
+    pub(crate) fn read<R>(&self, f: impl FnOnce(&Tables) -> R) -> Result<R, Error> {
+        let tables = self.lock()?;
+        Ok(f(&tables))
+    }
+    fn lock(&self) -> Result<MutexGuard<'_, Tables>, Error> {
+        self.tables.lock().map_err(|_| Error::Poisoned)
+    }
+
+    /// All runs, oldest first.
+    pub fn runs(&self) -> Result<Vec<RunRow>, Error> {
+        self.read(|t| t.runs.rows().to_vec())
+    }
+    /// Id of the run matches are made for, the last stored run.
+    pub fn active_run(&self) -> Result<Option<i64>, Error> {
+        self.read(|t| t.active_run())
+    }
+    pub fn images(&self) -> Result<Vec<ImageRow>, Error> {
+        self.read(|t| t.images.rows().to_vec())
+    }
+    pub fn run_images(&self, run_id: i64) -> Result<Vec<RunImageRow>, Error> {
+        self.read(|t| {
+            t.run_images
+                .rows()
+                .iter()
+                .filter(|ri| ri.get_run_id() == run_id)
+                .cloned()
+                .collect()
+        })
+    }
+    pub fn modifications(&self) -> Result<Vec<MethodRow>, Error> {
+        self.read(|t| t.modifications().cloned().collect())
+    }
+    pub fn hashing_methods(&self) -> Result<Vec<MethodRow>, Error> {
+        self.read(|t| t.hashing_methods().cloned().collect())
+    }
+    pub fn modified_images(&self) -> Result<Vec<ModifiedImageRow>, Error> {
+        self.read(|t| t.modified_images.rows().to_vec())
+    }
+    pub fn hashes(&self) -> Result<Vec<HashRow>, Error> {
+        self.read(|t| t.hashes.rows().to_vec())
+    }
+    pub fn matches(&self) -> Result<Vec<MatchRow>, Error> {
+        self.read(|t| t.matches.rows().to_vec())
+    }
+    /// Snapshot of the given run, `None` for runs stored without one.
+    pub fn snapshot(&self, run_id: i64) -> Result<Option<RunSnapshot>, Error> {
+        self.read(|t| {
+            t.run_config
+                .rows()
+                .iter()
+                .find(|s| s.get_run_id() == Some(run_id))
+                .cloned()
+        })
+    }
+
+    /// Hashes of the images in the active run made with the hashing method, what
+    /// `Repository::hashes` returns for SQLite.
+    pub fn run_hashes(&self, hashing_method_id: i64) -> Result<Vec<matching::state::Hash>, Error> {
+        self.read(|t| {
+            let Some(run_id) = t.active_run() else {
+                return Vec::new();
+            };
+            let images: HashSet<i64> = t
+                .run_images
+                .rows()
+                .iter()
+                .filter(|ri| ri.get_run_id() == run_id)
+                .map(|ri| ri.get_image_id())
+                .collect();
+            let modified_images: HashMap<i64, (i64, i64)> = t
+                .modified_images
+                .rows()
+                .iter()
+                .filter(|mi| images.contains(&mi.get_image_id()))
+                .map(|mi| (mi.get_id(), (mi.get_image_id(), mi.get_modification_id())))
+                .collect();
+            t.hashes
+                .rows()
+                .iter()
+                .filter(|h| h.get_hashing_method_id() == hashing_method_id)
+                .filter_map(|h| {
+                    let (image_id, modification_id) =
+                        modified_images.get(&h.get_modified_image_id())?;
+                    Some(matching::state::Hash::new(
+                        h.get_id() as u32,
+                        h.get_hash().to_vec(),
+                        h.get_bits(),
+                        HashSource::new(*image_id, *modification_id, hashing_method_id),
+                    ))
+                })
+                .collect()
+        })
+    }
+    /// Image, modification and hashing method ids of every stored hash that can be reused with the
+    /// load options. Those are the hashes decoded with the same options and the hashes of images
+    /// without an orientation, which decode the same either way.
+    pub(crate) fn hashed(&self, load_options: LoadOptions) -> Result<Vec<(i64, i64, i64)>, Error> {
+        self.read(|t| {
+            let unoriented: HashSet<i64> = t
+                .images
+                .rows()
+                .iter()
+                .filter(|i| i.get_orientation() == Some(Orientation::Normal.tag() as u32))
+                .map(|i| i.get_id())
+                .collect();
+            let modified_images: HashMap<i64, (i64, i64)> = t
+                .modified_images
+                .rows()
+                .iter()
+                .filter(|mi| {
+                    mi.get_apply_orientation() == Some(load_options.get_apply_orientation())
+                        || unoriented.contains(&mi.get_image_id())
+                })
+                .map(|mi| (mi.get_id(), (mi.get_image_id(), mi.get_modification_id())))
+                .collect();
+            t.hashes
+                .rows()
+                .iter()
+                .filter_map(|h| {
+                    let (image, modification) = modified_images.get(&h.get_modified_image_id())?;
+                    Some((*image, *modification, h.get_hashing_method_id()))
+                })
+                .collect()
+        })
+    }
+}
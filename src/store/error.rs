@@ -0,0 +1,29 @@
+use std::{fmt::Display, path::PathBuf};
+
+#[derive(Debug)]
+pub enum Error {
+    Io {
+        path: PathBuf,
+        err: std::io::Error,
+    },
+    /// A row of a table file could not be read or written, lines start at 1.
+    Json {
+        path: PathBuf,
+        line: usize,
+        err: serde_json::Error,
+    },
+    /// The store is used after a thread panicked while writing to it.
+    Poisoned,
+}
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io { path, err } => write!(f, "IO error on {:?}: {}", path, err),
+            Self::Json { path, line, err } => {
+                write!(f, "Invalid row on line {} of {:?}: {}", line, path, err)
+            }
+            Self::Poisoned => write!(f, "Result store poisoned by a panic"),
+        }
+    }
+}
+impl std::error::Error for Error {}
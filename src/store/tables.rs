@@ -0,0 +1,776 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, ErrorKind, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::{
+    core::snapshot::RunSnapshot,
+    image_modify::{ImageQuality, non_finite},
+    store::Error,
+};
+
+/// A row of a table, stored as a line in `<TABLE>.jsonl`.
+pub(crate) trait Row: Serialize + DeserializeOwned {
+    const TABLE: &'static str;
+    fn id(&self) -> i64;
+}
+
+/// Rows of a table in insertion order with what is not written to its file yet.
+#[derive(Debug)]
+pub(crate) struct Table<T> {
+    rows: Vec<T>,
+    seq: i64,
+    /// Rows before this are in the file.
+    saved: usize,
+    /// A saved row was changed, the whole file has to be written again.
+    rewrite: bool,
+}
+impl<T> Default for Table<T> {
+    fn default() -> Self {
+        Self {
+            rows: Vec::new(),
+            seq: 0,
+            saved: 0,
+            rewrite: false,
+        }
+    }
+}
+impl<T: Row> Table<T> {
+    /// Next id of the table, ids are never reused like with `AUTOINCREMENT`.
+    fn next_id(&mut self) -> i64 {
+        self.seq += 1;
+        self.seq
+    }
+    fn push(&mut self, row: T) -> usize {
+        self.seq = self.seq.max(row.id());
+        self.rows.push(row);
+        self.rows.len() - 1
+    }
+    fn get_mut(&mut self, index: usize) -> &mut T {
+        if index < self.saved {
+            self.rewrite = true;
+        }
+        &mut self.rows[index]
+    }
+    fn clear(&mut self) {
+        self.rows.clear();
+        self.rewrite = true;
+    }
+    /// Keeps the rows `f` returns true for, returns the rows that were removed.
+    fn retain(&mut self, mut f: impl FnMut(&T) -> bool) -> Vec<T> {
+        let (kept, removed) = self.rows.drain(..).partition(|row| f(row));
+        self.rows = kept;
+        if !removed.is_empty() {
+            self.rewrite = true;
+        }
+        removed
+    }
+    pub(crate) fn rows(&self) -> &[T] {
+        &self.rows
+    }
+
+    fn load(dir: &Path) -> Result<Self, Error> {
+        let path = dir.join(format!("{}.jsonl", T::TABLE));
+        let mut table = Self::default();
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(table),
+            Err(err) => return Err(Error::Io { path, err }),
+        };
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|err| Error::Io {
+                path: path.clone(),
+                err,
+            })?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let row = serde_json::from_str(&line).map_err(|err| Error::Json {
+                path: path.clone(),
+                line: i + 1,
+                err,
+            })?;
+            table.push(row);
+        }
+        table.saved = table.rows.len();
+        Ok(table)
+    }
+    /// Appends the new rows to the file of the table, or writes it again if saved rows changed.
+    fn save(&mut self, dir: &Path) -> Result<(), Error> {
+        if !self.rewrite && self.saved == self.rows.len() {
+            return Ok(());
+        }
+        let path = dir.join(format!("{}.jsonl", T::TABLE));
+        let io = |err| Error::Io {
+            path: path.clone(),
+            err,
+        };
+        let (file, from) = match self.rewrite {
+            // Replaced in one step so readers never see a half written table.
+            true => {
+                let tmp = path.with_extension("jsonl.tmp");
+                let file = File::create(&tmp).map_err(io)?;
+                (file, 0)
+            }
+            false => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .map_err(io)?;
+                (file, self.saved)
+            }
+        };
+        let mut writer = BufWriter::new(file);
+        for (i, row) in self.rows[from..].iter().enumerate() {
+            serde_json::to_writer(&mut writer, row).map_err(|err| Error::Json {
+                path: path.clone(),
+                line: from + i + 1,
+                err,
+            })?;
+            writer.write_all(b"\n").map_err(io)?;
+        }
+        writer.flush().map_err(io)?;
+        if self.rewrite {
+            fs::rename(path.with_extension("jsonl.tmp"), &path).map_err(io)?;
+        }
+        self.saved = self.rows.len();
+        self.rewrite = false;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunRow {
+    id: i64,
+    /// Milliseconds since the unix epoch.
+    timestamp: i64,
+}
+impl RunRow {
+    pub fn get_id(&self) -> i64 {
+        self.id
+    }
+    pub fn get_timestamp(&self) -> i64 {
+        self.timestamp
+    }
+}
+impl Row for RunRow {
+    const TABLE: &'static str = "runs";
+    fn id(&self) -> i64 {
+        self.id
+    }
+}
+
+/// The active run, there is at most one row.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ProgramRow {
+    id: i64,
+    run_id: i64,
+}
+impl Row for ProgramRow {
+    const TABLE: &'static str = "program";
+    fn id(&self) -> i64 {
+        self.id
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageRow {
+    id: i64,
+    path: String,
+    user: String,
+    session: Option<String>,
+    tags: Vec<String>,
+    digest: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    format: Option<String>,
+    color_type: Option<String>,
+    orientation: Option<u32>,
+}
+impl ImageRow {
+    pub fn get_id(&self) -> i64 {
+        self.id
+    }
+    pub fn get_path(&self) -> &str {
+        &self.path
+    }
+    pub fn get_user(&self) -> &str {
+        &self.user
+    }
+    pub fn get_session(&self) -> Option<&str> {
+        self.session.as_deref()
+    }
+    pub fn get_tags(&self) -> &[String] {
+        &self.tags
+    }
+    pub fn get_digest(&self) -> Option<&str> {
+        self.digest.as_deref()
+    }
+    pub fn get_dimensions(&self) -> Option<(u32, u32)> {
+        self.width.zip(self.height)
+    }
+    pub fn get_format(&self) -> Option<&str> {
+        self.format.as_deref()
+    }
+    pub fn get_color_type(&self) -> Option<&str> {
+        self.color_type.as_deref()
+    }
+    pub fn get_orientation(&self) -> Option<u32> {
+        self.orientation
+    }
+}
+impl Row for ImageRow {
+    const TABLE: &'static str = "images";
+    fn id(&self) -> i64 {
+        self.id
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunImageRow {
+    id: i64,
+    run_id: i64,
+    image_id: i64,
+    partition: Option<String>,
+}
+impl RunImageRow {
+    pub fn get_run_id(&self) -> i64 {
+        self.run_id
+    }
+    pub fn get_image_id(&self) -> i64 {
+        self.image_id
+    }
+    pub fn get_partition(&self) -> Option<&str> {
+        self.partition.as_deref()
+    }
+}
+impl Row for RunImageRow {
+    const TABLE: &'static str = "run_images";
+    fn id(&self) -> i64 {
+        self.id
+    }
+}
+
+/// A modification or hashing method.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MethodRow {
+    id: i64,
+    name: String,
+    params: String,
+}
+impl MethodRow {
+    pub fn get_id(&self) -> i64 {
+        self.id
+    }
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+    pub fn get_params(&self) -> &str {
+        &self.params
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ModificationRow(MethodRow);
+impl Row for ModificationRow {
+    const TABLE: &'static str = "modifications";
+    fn id(&self) -> i64 {
+        self.0.id
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct HashingMethodRow(MethodRow);
+impl Row for HashingMethodRow {
+    const TABLE: &'static str = "hashing_methods";
+    fn id(&self) -> i64 {
+        self.0.id
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModifiedImageRow {
+    id: i64,
+    image_id: i64,
+    modification_id: i64,
+    #[serde(with = "non_finite")]
+    psnr: Option<f64>,
+    #[serde(with = "non_finite")]
+    ssim: Option<f64>,
+    #[serde(with = "non_finite")]
+    mean_abs_diff: Option<f64>,
+    /// If the EXIF orientation was applied when the image was decoded, `None` if unknown.
+    #[serde(default)]
+    apply_orientation: Option<bool>,
+}
+impl ModifiedImageRow {
+    pub fn get_id(&self) -> i64 {
+        self.id
+    }
+    pub fn get_image_id(&self) -> i64 {
+        self.image_id
+    }
+    pub fn get_modification_id(&self) -> i64 {
+        self.modification_id
+    }
+    pub fn get_psnr(&self) -> Option<f64> {
+        self.psnr
+    }
+    pub fn get_ssim(&self) -> Option<f64> {
+        self.ssim
+    }
+    pub fn get_mean_abs_diff(&self) -> Option<f64> {
+        self.mean_abs_diff
+    }
+    pub fn get_apply_orientation(&self) -> Option<bool> {
+        self.apply_orientation
+    }
+}
+impl Row for ModifiedImageRow {
+    const TABLE: &'static str = "modified_images";
+    fn id(&self) -> i64 {
+        self.id
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HashRow {
+    id: i64,
+    mod_image_id: i64,
+    hashing_method_id: i64,
+    /// Hex in the table file.
+    #[serde(with = "hex")]
+    hash: Vec<u8>,
+    hash_bits: u32,
+}
+impl HashRow {
+    pub fn get_id(&self) -> i64 {
+        self.id
+    }
+    pub fn get_modified_image_id(&self) -> i64 {
+        self.mod_image_id
+    }
+    pub fn get_hashing_method_id(&self) -> i64 {
+        self.hashing_method_id
+    }
+    pub fn get_hash(&self) -> &[u8] {
+        &self.hash
+    }
+    pub fn get_bits(&self) -> u32 {
+        self.hash_bits
+    }
+}
+impl Row for HashRow {
+    const TABLE: &'static str = "hashes";
+    fn id(&self) -> i64 {
+        self.id
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MatchRow {
+    id: i64,
+    hamming_distance: u32,
+    hash_len: u32,
+    hash1_id: i64,
+    hash2_id: i64,
+}
+impl MatchRow {
+    pub fn get_id(&self) -> i64 {
+        self.id
+    }
+    pub fn get_hash_ids(&self) -> (i64, i64) {
+        (self.hash1_id, self.hash2_id)
+    }
+    pub fn get_hamming_distance(&self) -> u32 {
+        self.hamming_distance
+    }
+    pub fn get_hash_len(&self) -> u32 {
+        self.hash_len
+    }
+}
+impl Row for MatchRow {
+    const TABLE: &'static str = "matches";
+    fn id(&self) -> i64 {
+        self.id
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct SkippedImageRow {
+    id: i64,
+    run_id: i64,
+    path: String,
+    reason: String,
+}
+impl Row for SkippedImageRow {
+    const TABLE: &'static str = "skipped_images";
+    fn id(&self) -> i64 {
+        self.id
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct DuplicateImageRow {
+    id: i64,
+    run_id: i64,
+    image_id: Option<i64>,
+    digest: String,
+    path: String,
+    original_path: String,
+    policy: String,
+}
+impl Row for DuplicateImageRow {
+    const TABLE: &'static str = "duplicate_images";
+    fn id(&self) -> i64 {
+        self.id
+    }
+}
+
+impl Row for RunSnapshot {
+    const TABLE: &'static str = "run_config";
+    fn id(&self) -> i64 {
+        self.get_run_id().unwrap_or_default()
+    }
+}
+
+/// Every table of the results with the lookups SQLite would use an index for.
+#[derive(Debug, Default)]
+pub(crate) struct Tables {
+    pub(crate) runs: Table<RunRow>,
+    program: Table<ProgramRow>,
+    pub(crate) images: Table<ImageRow>,
+    pub(crate) run_images: Table<RunImageRow>,
+    modifications: Table<ModificationRow>,
+    hashing_methods: Table<HashingMethodRow>,
+    pub(crate) modified_images: Table<ModifiedImageRow>,
+    pub(crate) hashes: Table<HashRow>,
+    pub(crate) matches: Table<MatchRow>,
+    skipped_images: Table<SkippedImageRow>,
+    duplicate_images: Table<DuplicateImageRow>,
+    pub(crate) run_config: Table<RunSnapshot>,
+
+    image_index: HashMap<(String, Option<String>), usize>,
+    image_id_index: HashMap<i64, usize>,
+    modified_image_index: HashMap<(i64, i64), usize>,
+    hash_index: HashMap<(i64, i64), i64>,
+    match_index: HashSet<(i64, i64)>,
+}
+impl Tables {
+    pub(crate) fn load(dir: &Path) -> Result<Self, Error> {
+        let mut tables = Self {
+            runs: Table::load(dir)?,
+            program: Table::load(dir)?,
+            images: Table::load(dir)?,
+            run_images: Table::load(dir)?,
+            modifications: Table::load(dir)?,
+            hashing_methods: Table::load(dir)?,
+            modified_images: Table::load(dir)?,
+            hashes: Table::load(dir)?,
+            matches: Table::load(dir)?,
+            skipped_images: Table::load(dir)?,
+            duplicate_images: Table::load(dir)?,
+            run_config: Table::load(dir)?,
+            ..Default::default()
+        };
+        for (i, image) in tables.images.rows.iter().enumerate() {
+            tables
+                .image_index
+                .insert((image.path.clone(), image.digest.clone()), i);
+            tables.image_id_index.insert(image.id, i);
+        }
+        for (i, mi) in tables.modified_images.rows.iter().enumerate() {
+            tables
+                .modified_image_index
+                .insert((mi.image_id, mi.modification_id), i);
+        }
+        for hash in &tables.hashes.rows {
+            tables
+                .hash_index
+                .insert((hash.mod_image_id, hash.hashing_method_id), hash.id);
+        }
+        for m in &tables.matches.rows {
+            tables.match_index.insert((m.hash1_id, m.hash2_id));
+        }
+        Ok(tables)
+    }
+    pub(crate) fn save(&mut self, dir: &Path) -> Result<(), Error> {
+        fs::create_dir_all(dir).map_err(|err| Error::Io {
+            path: dir.to_path_buf(),
+            err,
+        })?;
+        self.runs.save(dir)?;
+        self.program.save(dir)?;
+        self.images.save(dir)?;
+        self.run_images.save(dir)?;
+        self.modifications.save(dir)?;
+        self.hashing_methods.save(dir)?;
+        self.modified_images.save(dir)?;
+        self.hashes.save(dir)?;
+        self.matches.save(dir)?;
+        self.skipped_images.save(dir)?;
+        self.duplicate_images.save(dir)?;
+        self.run_config.save(dir)?;
+        Ok(())
+    }
+
+    pub(crate) fn insert_run(&mut self, timestamp: i64) -> i64 {
+        let id = self.runs.next_id();
+        self.runs.push(RunRow { id, timestamp });
+        id
+    }
+    pub(crate) fn active_run(&self) -> Option<i64> {
+        self.program.rows.first().map(|p| p.run_id)
+    }
+    pub(crate) fn set_active_run(&mut self, run_id: i64) {
+        self.program.clear();
+        self.program.push(ProgramRow { id: 0, run_id });
+    }
+
+    pub(crate) fn find_image_id(&self, path: &str, digest: Option<&str>) -> Option<i64> {
+        self.image_index
+            .get(&(path.to_string(), digest.map(|d| d.to_string())))
+            .map(|i| self.images.rows[*i].id)
+    }
+    pub(crate) fn get_or_insert_image(
+        &mut self,
+        path: &str,
+        user: &str,
+        session: Option<&str>,
+        tags: &[String],
+        digest: Option<&str>,
+    ) -> i64 {
+        if let Some(id) = self.find_image_id(path, digest) {
+            return id;
+        }
+        let id = self.images.next_id();
+        let index = self.images.push(ImageRow {
+            id,
+            path: path.to_string(),
+            user: user.to_string(),
+            session: session.map(|s| s.to_string()),
+            tags: tags.to_vec(),
+            digest: digest.map(|d| d.to_string()),
+            width: None,
+            height: None,
+            format: None,
+            color_type: None,
+            orientation: None,
+        });
+        self.image_index
+            .insert((path.to_string(), digest.map(|d| d.to_string())), index);
+        self.image_id_index.insert(id, index);
+        id
+    }
+    pub(crate) fn set_image_metadata(
+        &mut self,
+        image_id: i64,
+        (width, height): (u32, u32),
+        format: Option<String>,
+        color_type: String,
+        orientation: u32,
+    ) {
+        let Some(index) = self.image_id_index.get(&image_id) else {
+            return;
+        };
+        let image = self.images.get_mut(*index);
+        image.width = Some(width);
+        image.height = Some(height);
+        image.format = format;
+        image.color_type = Some(color_type);
+        image.orientation = Some(orientation);
+    }
+    pub(crate) fn insert_run_image(
+        &mut self,
+        run_id: i64,
+        image_id: i64,
+        partition: Option<String>,
+    ) {
+        let id = self.run_images.next_id();
+        self.run_images.push(RunImageRow {
+            id,
+            run_id,
+            image_id,
+            partition,
+        });
+    }
+    pub(crate) fn insert_skipped_image(&mut self, run_id: i64, path: String, reason: String) {
+        let id = self.skipped_images.next_id();
+        self.skipped_images.push(SkippedImageRow {
+            id,
+            run_id,
+            path,
+            reason,
+        });
+    }
+    pub(crate) fn insert_duplicate_image(
+        &mut self,
+        run_id: i64,
+        image_id: Option<i64>,
+        digest: String,
+        path: String,
+        original_path: String,
+        policy: String,
+    ) {
+        let id = self.duplicate_images.next_id();
+        self.duplicate_images.push(DuplicateImageRow {
+            id,
+            run_id,
+            image_id,
+            digest,
+            path,
+            original_path,
+            policy,
+        });
+    }
+
+    pub(crate) fn modifications(&self) -> impl Iterator<Item = &MethodRow> {
+        self.modifications.rows.iter().map(|m| &m.0)
+    }
+    pub(crate) fn hashing_methods(&self) -> impl Iterator<Item = &MethodRow> {
+        self.hashing_methods.rows.iter().map(|m| &m.0)
+    }
+    pub(crate) fn find_modification_id(&self, name: &str, params: &str) -> Option<i64> {
+        self.modifications()
+            .find(|m| m.name == name && m.params == params)
+            .map(|m| m.id)
+    }
+    pub(crate) fn get_or_insert_modification(&mut self, name: &str, params: &str) -> i64 {
+        if let Some(id) = self.find_modification_id(name, params) {
+            return id;
+        }
+        let id = self.modifications.next_id();
+        self.modifications.push(ModificationRow(MethodRow {
+            id,
+            name: name.to_string(),
+            params: params.to_string(),
+        }));
+        id
+    }
+    pub(crate) fn find_hashing_method_id(&self, name: &str, params: &str) -> Option<i64> {
+        self.hashing_methods()
+            .find(|m| m.name == name && m.params == params)
+            .map(|m| m.id)
+    }
+    pub(crate) fn get_or_insert_hashing_method(&mut self, name: &str, params: &str) -> i64 {
+        if let Some(id) = self.find_hashing_method_id(name, params) {
+            return id;
+        }
+        let id = self.hashing_methods.next_id();
+        self.hashing_methods.push(HashingMethodRow(MethodRow {
+            id,
+            name: name.to_string(),
+            params: params.to_string(),
+        }));
+        id
+    }
+
+    /// Inserts the modified image, or updates the quality of the stored one.
+    pub(crate) fn upsert_modified_image(
+        &mut self,
+        image_id: i64,
+        modification_id: i64,
+        quality: &ImageQuality,
+        apply_orientation: Option<bool>,
+    ) -> i64 {
+        if let Some(index) = self.modified_image_index.get(&(image_id, modification_id)) {
+            let mi = self.modified_images.get_mut(*index);
+            mi.psnr = Some(quality.psnr());
+            mi.ssim = Some(quality.ssim());
+            mi.mean_abs_diff = Some(quality.mean_abs_diff());
+            mi.apply_orientation = apply_orientation;
+            return mi.id;
+        }
+        let id = self.modified_images.next_id();
+        let index = self.modified_images.push(ModifiedImageRow {
+            id,
+            image_id,
+            modification_id,
+            psnr: Some(quality.psnr()),
+            ssim: Some(quality.ssim()),
+            mean_abs_diff: Some(quality.mean_abs_diff()),
+            apply_orientation,
+        });
+        self.modified_image_index
+            .insert((image_id, modification_id), index);
+        id
+    }
+    /// Inserts the hash of the modified image. A modified image that already has a different hash
+    /// of the hashing method was hashed again with other load options, the hash is replaced and
+    /// its matches are removed.
+    pub(crate) fn insert_hash(
+        &mut self,
+        mod_image_id: i64,
+        hashing_method_id: i64,
+        hash: Vec<u8>,
+        hash_bits: u32,
+    ) {
+        if let Some(id) = self.hash_index.get(&(mod_image_id, hashing_method_id)) {
+            let id = *id;
+            let Some(index) = self.hashes.rows.iter().position(|h| h.id == id) else {
+                return;
+            };
+            if self.hashes.rows[index].hash == hash {
+                return;
+            }
+            let row = self.hashes.get_mut(index);
+            row.hash = hash;
+            row.hash_bits = hash_bits;
+            for m in self
+                .matches
+                .retain(|m| m.hash1_id != id && m.hash2_id != id)
+            {
+                self.match_index.remove(&(m.hash1_id, m.hash2_id));
+            }
+            return;
+        }
+        let id = self.hashes.next_id();
+        self.hashes.push(HashRow {
+            id,
+            mod_image_id,
+            hashing_method_id,
+            hash,
+            hash_bits,
+        });
+        self.hash_index
+            .insert((mod_image_id, hashing_method_id), id);
+    }
+    /// Inserts the match unless the pair is already stored by an earlier run.
+    pub(crate) fn insert_match(
+        &mut self,
+        hamming_distance: u32,
+        hash_len: u32,
+        hash1_id: i64,
+        hash2_id: i64,
+    ) {
+        if !self.match_index.insert((hash1_id, hash2_id)) {
+            return;
+        }
+        let id = self.matches.next_id();
+        self.matches.push(MatchRow {
+            id,
+            hamming_distance,
+            hash_len,
+            hash1_id,
+            hash2_id,
+        });
+    }
+    pub(crate) fn insert_snapshot(&mut self, snapshot: RunSnapshot) {
+        match self
+            .run_config
+            .rows
+            .iter()
+            .position(|s| s.get_run_id() == snapshot.get_run_id())
+        {
+            Some(index) => *self.run_config.get_mut(index) = snapshot,
+            None => {
+                self.run_config.push(snapshot);
+            }
+        }
+    }
+}
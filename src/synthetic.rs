@@ -0,0 +1,259 @@
+//! Generates reproducible datasets of face-like images, for exercising the whole pipeline
+//! without a real dataset.
+
+use std::{
+    f32::consts::PI,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use image::{ImageFormat, Rgb, RgbImage};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+/// Writes `identities` × `images_per_identity` images in the folder-per-user layout, i.e.
+/// `<root>/identity_000/image_000.png`. The same seed and settings always give the same
+/// images.
+///
+/// Every identity gets its own face geometry, colours and background texture. The images of an
+/// identity vary the pose, scale, lighting and noise around it.
+#[derive(Debug, Clone)]
+pub struct SyntheticDataset {
+    seed: u64,
+    identities: usize,
+    images_per_identity: usize,
+    width: u32,
+    height: u32,
+    variation: f32,
+}
+impl Default for SyntheticDataset {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            identities: 10,
+            images_per_identity: 5,
+            width: 128,
+            height: 128,
+            variation: 1.0,
+        }
+    }
+}
+impl SyntheticDataset {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            ..Default::default()
+        }
+    }
+    pub fn identities(mut self, n: usize) -> Self {
+        self.identities = n;
+        self
+    }
+    pub fn images_per_identity(mut self, n: usize) -> Self {
+        self.images_per_identity = n;
+        self
+    }
+    pub fn dimensions(mut self, width: u32, height: u32) -> Self {
+        self.width = width;
+        self.height = height;
+        self
+    }
+    /// Scales how much the images of an identity differ from each other. 0 gives identical
+    /// images per identity, 1 is the default.
+    pub fn variation(mut self, variation: f32) -> Self {
+        self.variation = variation.max(0.0);
+        self
+    }
+    /// Writes the dataset under root and returns the paths of the written images.
+    pub fn generate(&self, root: &Path) -> Result<Vec<PathBuf>, Error> {
+        let mut paths = Vec::with_capacity(self.identities * self.images_per_identity);
+        for identity in 0..self.identities {
+            let dir = root.join(format!("identity_{:03}", identity));
+            fs::create_dir_all(&dir).map_err(|e| Error::Io {
+                path: dir.clone(),
+                err: e.to_string(),
+            })?;
+
+            for index in 0..self.images_per_identity {
+                let path = dir.join(format!("image_{:03}.png", index));
+                self.image(identity, index)
+                    .save_with_format(&path, ImageFormat::Png)
+                    .map_err(|e| Error::Image {
+                        path: path.clone(),
+                        err: e.to_string(),
+                    })?;
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+    /// Draws a single image of the dataset without writing it.
+    pub fn image(&self, identity: usize, index: usize) -> RgbImage {
+        let face = Face::random(&mut self.rng(identity, None));
+        let mut rng = self.rng(identity, Some(index));
+        let pose = Pose::random(&mut rng, self.variation);
+        draw(&face, &pose, self.width, self.height, &mut rng)
+    }
+    /// Every identity and image gets its own stream so changing the size of the dataset does not
+    /// change the images that are already part of it.
+    fn rng(&self, identity: usize, index: Option<usize>) -> ChaCha8Rng {
+        let mut rng = ChaCha8Rng::seed_from_u64(self.seed);
+        rng.set_stream(identity as u64);
+        if let Some(index) = index {
+            rng.set_word_pos((index as u128 + 1) << 40);
+        }
+        rng
+    }
+}
+
+/// What makes an identity, positions and sizes are relative to the image size.
+struct Face {
+    skin: [f32; 3],
+    hair: [f32; 3],
+    background: [[f32; 3]; 2],
+    gradient_angle: f32,
+    texture_frequency: f32,
+    texture_angle: f32,
+    face_width: f32,
+    face_height: f32,
+    hair_line: f32,
+    eye_spacing: f32,
+    eye_height: f32,
+    eye_size: f32,
+    mouth_height: f32,
+    mouth_width: f32,
+}
+impl Face {
+    fn random(rng: &mut impl Rng) -> Self {
+        let skin_tone = rng.gen_range(0.35..0.95);
+        Self {
+            skin: [
+                skin_tone,
+                skin_tone * rng.gen_range(0.7..0.85),
+                skin_tone * rng.gen_range(0.55..0.7),
+            ],
+            hair: random_colour(rng, 0.0..0.5),
+            background: [random_colour(rng, 0.2..1.0), random_colour(rng, 0.2..1.0)],
+            gradient_angle: rng.gen_range(0.0..2.0 * PI),
+            texture_frequency: rng.gen_range(4.0..24.0),
+            texture_angle: rng.gen_range(0.0..PI),
+            face_width: rng.gen_range(0.24..0.34),
+            face_height: rng.gen_range(0.32..0.42),
+            hair_line: rng.gen_range(-0.55..-0.25),
+            eye_spacing: rng.gen_range(0.28..0.45),
+            eye_height: rng.gen_range(-0.25..-0.05),
+            eye_size: rng.gen_range(0.06..0.12),
+            mouth_height: rng.gen_range(0.35..0.55),
+            mouth_width: rng.gen_range(0.25..0.5),
+        }
+    }
+}
+
+/// How a single image of an identity differs from the others.
+struct Pose {
+    offset: (f32, f32),
+    scale: f32,
+    tilt: f32,
+    brightness: f32,
+    noise: f32,
+}
+impl Pose {
+    fn random(rng: &mut impl Rng, variation: f32) -> Self {
+        let mut jitter = |range: f32| rng.gen_range(-1.0..1.0) * range * variation;
+        Self {
+            offset: (jitter(0.06), jitter(0.06)),
+            scale: 1.0 + jitter(0.1),
+            tilt: jitter(0.2),
+            brightness: 1.0 + jitter(0.2),
+            noise: 0.01 + jitter(0.03).abs(),
+        }
+    }
+}
+
+fn draw(face: &Face, pose: &Pose, width: u32, height: u32, rng: &mut impl Rng) -> RgbImage {
+    let size = width.min(height).max(1) as f32;
+    let (sin_tilt, cos_tilt) = pose.tilt.sin_cos();
+    let (sin_gradient, cos_gradient) = face.gradient_angle.sin_cos();
+    let (sin_texture, cos_texture) = face.texture_angle.sin_cos();
+
+    RgbImage::from_fn(width, height, |x, y| {
+        // Coordinates relative to the centre of the image, where 1 is the shortest side.
+        let px = (x as f32 - width as f32 / 2.0) / size;
+        let py = (y as f32 - height as f32 / 2.0) / size;
+
+        let t = (0.5 + px * cos_gradient + py * sin_gradient).clamp(0.0, 1.0);
+        let texture = 0.08
+            * (face.texture_frequency * 2.0 * PI * (px * cos_texture + py * sin_texture)).sin();
+        let mut colour = mix(face.background[0], face.background[1], t).map(|c| c + texture);
+
+        // Coordinates in the frame of the face, where the face ellipse has radius 1.
+        let dx = px - pose.offset.0;
+        let dy = py - pose.offset.1;
+        let fx = (dx * cos_tilt + dy * sin_tilt) / (face.face_width * pose.scale);
+        let fy = (dy * cos_tilt - dx * sin_tilt) / (face.face_height * pose.scale);
+
+        let in_face = fx * fx + fy * fy <= 1.0;
+        let in_hair = fx * fx + (fy + 0.1) * (fy + 0.1) <= 1.25 && fy < face.hair_line;
+        if in_hair {
+            colour = face.hair;
+        } else if in_face {
+            // Simple shading, brighter towards the upper left of the face.
+            let shade = 1.0 - 0.15 * (fx + fy).clamp(-1.0, 1.0);
+            colour = face.skin.map(|c| c * shade);
+
+            let eye_radius = face.eye_size * 2.0;
+            for side in [-1.0, 1.0] {
+                let ex = (fx - side * face.eye_spacing) / eye_radius;
+                let ey = (fy - face.eye_height) / (eye_radius * 0.6);
+                if ex * ex + ey * ey <= 1.0 {
+                    colour = if ex * ex + ey * ey <= 0.25 {
+                        [0.05, 0.05, 0.1]
+                    } else {
+                        [0.95, 0.95, 0.95]
+                    };
+                }
+            }
+
+            let mx = fx / face.mouth_width;
+            let my = (fy - face.mouth_height) / 0.08;
+            if mx * mx + my * my <= 1.0 && my >= -0.2 {
+                colour = [0.55, 0.15, 0.15];
+            }
+        }
+
+        let noise = rng.gen_range(-1.0..1.0) * pose.noise;
+        Rgb(colour.map(|c| ((c * pose.brightness + noise).clamp(0.0, 1.0) * 255.0) as u8))
+    })
+}
+
+fn random_colour(rng: &mut impl Rng, range: std::ops::Range<f32>) -> [f32; 3] {
+    [
+        rng.gen_range(range.clone()),
+        rng.gen_range(range.clone()),
+        rng.gen_range(range),
+    ]
+}
+
+fn mix(a: [f32; 3], b: [f32; 3], t: f32) -> [f32; 3] {
+    [
+        a[0] * (1.0 - t) + b[0] * t,
+        a[1] * (1.0 - t) + b[1] * t,
+        a[2] * (1.0 - t) + b[2] * t,
+    ]
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io { path: PathBuf, err: String },
+    Image { path: PathBuf, err: String },
+}
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io { path, err } => write!(f, "Could not create directory {:?}: {}", path, err),
+            Self::Image { path, err } => write!(f, "Could not write image {:?}: {}", path, err),
+        }
+    }
+}
+impl std::error::Error for Error {}
@@ -0,0 +1,16 @@
+use std::{fs, path::PathBuf};
+
+use p_hash::synthetic::SyntheticDataset;
+
+/// Writes a small synthetic dataset to a directory of its own under the temporary directory.
+pub fn image_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("p-hash-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    SyntheticDataset::new(7)
+        .identities(2)
+        .images_per_identity(2)
+        .dimensions(32, 32)
+        .generate(&dir)
+        .unwrap();
+    dir
+}
@@ -0,0 +1,180 @@
+mod common;
+
+use std::{fs, io::BufReader};
+
+use image::DynamicImage;
+use p_hash::{
+    core::{app::App, images_processor::RayonImagesProcessor, result_parser::SqliteResultParser},
+    db::{DbConfig, Repository},
+    export::{self, ExportOptions, Record},
+    hashing_methods,
+    image_hash::{self, HashingMethods},
+    image_modify::{self, ImageModification, Modifications},
+    matching::match_process::SqliteRunner,
+    modifications,
+};
+use sqlx::SqlitePool;
+
+/// Leaves the image as it is, the quality of the modified image is perfect so its PSNR is
+/// infinite.
+struct Unchanged;
+impl ImageModification for Unchanged {
+    fn apply(&self, img: &DynamicImage) -> DynamicImage {
+        img.clone()
+    }
+    fn name(&self) -> &str {
+        "unchanged"
+    }
+}
+
+#[tokio::test]
+async fn infinite_quality_survives_export_and_import() {
+    let dir = common::image_dir("export");
+    let pool = DbConfig::in_memory().connect().await.unwrap();
+    let app = App::builder()
+        .imgs_path(&dir)
+        .images_processor(Box::new(RayonImagesProcessor::default()))
+        .results_parser(Box::new(SqliteResultParser::new(pool.clone())))
+        .match_process(Box::new(SqliteRunner::new(pool.clone())))
+        .modifications(modifications![Unchanged])
+        .hashing_methods(hashing_methods![image_hash::AverageHash::new(8)])
+        .finish();
+    app.set_selected_modifications(vec![0]).await.unwrap();
+    app.set_selected_hashing_methods(vec![0]).await.unwrap();
+    app.run().await.unwrap();
+
+    let repository = Repository::new(pool.clone());
+    let run_id = repository.runs().await.unwrap()[0].get_id();
+    let exported = repository.run_modified_images(run_id).await.unwrap();
+    assert!(!exported.is_empty());
+    assert!(exported.iter().all(|m| m.get_psnr() == Some(f64::INFINITY)));
+
+    let mut archive = Vec::new();
+    export::export_run(&repository, run_id, &mut archive, &ExportOptions::default())
+        .await
+        .unwrap();
+
+    let imported_pool = DbConfig::in_memory().connect().await.unwrap();
+    export::import_run(&imported_pool, BufReader::new(archive.as_slice()))
+        .await
+        .unwrap();
+    let imported = Repository::new(imported_pool);
+    let run_id = imported.runs().await.unwrap()[0].get_id();
+    let psnr: Vec<_> = imported
+        .run_modified_images(run_id)
+        .await
+        .unwrap()
+        .iter()
+        .map(|m| m.get_psnr())
+        .collect();
+    assert_eq!(psnr.len(), exported.len());
+    assert!(psnr.iter().all(|psnr| *psnr == Some(f64::INFINITY)));
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+/// Runs the images with an unchanged and a blurred modification, so there are matches between
+/// hashes of different modifications. Returns the id of the run.
+async fn store_run(pool: &SqlitePool, name: &str) -> i64 {
+    let dir = common::image_dir(name);
+    let app = App::builder()
+        .imgs_path(&dir)
+        .images_processor(Box::new(RayonImagesProcessor::default()))
+        .results_parser(Box::new(SqliteResultParser::new(pool.clone())))
+        .match_process(Box::new(SqliteRunner::new(pool.clone())))
+        .modifications(modifications![Unchanged, image_modify::Blur::new(0.5)])
+        .hashing_methods(hashing_methods![image_hash::AverageHash::new(8)])
+        .finish();
+    app.set_selected_modifications(vec![0, 1]).await.unwrap();
+    app.set_selected_hashing_methods(vec![0]).await.unwrap();
+    app.run().await.unwrap();
+    fs::remove_dir_all(dir).unwrap();
+    Repository::new(pool.clone()).runs().await.unwrap()[0].get_id()
+}
+
+async fn run_match_count(pool: &SqlitePool, run_id: i64) -> usize {
+    let (count,): (i64,) = sqlx::query_as(
+        "
+        SELECT count(*) FROM matches m
+        WHERE m.image1_id IN (SELECT image_id FROM run_images WHERE run_id = ?1)
+          AND m.image2_id IN (SELECT image_id FROM run_images WHERE run_id = ?1);
+        ",
+    )
+    .bind(run_id)
+    .fetch_one(pool)
+    .await
+    .unwrap();
+    count as usize
+}
+
+#[tokio::test]
+async fn export_has_every_match_of_the_run() {
+    let pool = DbConfig::in_memory().connect().await.unwrap();
+    let run_id = store_run(&pool, "export-matches").await;
+    let stored = run_match_count(&pool, run_id).await;
+
+    let mut archive = Vec::new();
+    let summary = export::export_run(
+        &Repository::new(pool.clone()),
+        run_id,
+        &mut archive,
+        &ExportOptions::default(),
+    )
+    .await
+    .unwrap();
+    let matches: Vec<_> = archive
+        .split(|b| *b == b'\n')
+        .filter(|line| !line.is_empty())
+        .filter_map(|line| match serde_json::from_slice(line).unwrap() {
+            Record::Match(m) => Some(m),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(summary.get_matches(), stored);
+    assert_eq!(matches.len(), stored);
+    assert!(matches.iter().any(|m| m.get_modification_id().is_none()));
+
+    let imported_pool = DbConfig::in_memory().connect().await.unwrap();
+    let imported = export::import_run(&imported_pool, BufReader::new(archive.as_slice()))
+        .await
+        .unwrap();
+    assert_eq!(imported.get_matches(), stored);
+    assert_eq!(
+        run_match_count(&imported_pool, imported.get_run_id()).await,
+        stored
+    );
+}
+
+#[tokio::test]
+async fn importing_a_stored_run_fails() {
+    let pool = DbConfig::in_memory().connect().await.unwrap();
+    let run_id = store_run(&pool, "export-twice").await;
+    let mut archive = Vec::new();
+    export::export_run(
+        &Repository::new(pool.clone()),
+        run_id,
+        &mut archive,
+        &ExportOptions::default(),
+    )
+    .await
+    .unwrap();
+
+    // The database the run came from already has it.
+    let err = export::import_run(&pool, BufReader::new(archive.as_slice()))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, export::Error::AlreadyImported { run_id: id, .. } if id == run_id));
+
+    let imported_pool = DbConfig::in_memory().connect().await.unwrap();
+    let imported = export::import_run(&imported_pool, BufReader::new(archive.as_slice()))
+        .await
+        .unwrap();
+    let err = export::import_run(&imported_pool, BufReader::new(archive.as_slice()))
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, export::Error::AlreadyImported { run_id, .. } if run_id == imported.get_run_id())
+    );
+    let runs = Repository::new(imported_pool).runs().await.unwrap();
+    assert_eq!(runs.len(), 1);
+}
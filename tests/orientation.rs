@@ -0,0 +1,113 @@
+use std::{fs, path::Path};
+
+use image::{DynamicImage, ImageOutputFormat, RgbImage};
+use p_hash::{
+    core::{
+        app::App, image_parser::AppProcParser, images_processor::RayonImagesProcessor,
+        result_parser::SqliteResultParser,
+    },
+    db::DbConfig,
+    hashing_methods,
+    image_hash::{self, HashingMethods},
+    image_modify::{self, Modifications},
+    image_parse::LoadOptions,
+    matching::match_process::SqliteRunner,
+    modifications,
+};
+use sqlx::SqlitePool;
+
+/// EXIF APP1 segment with the orientation tag set to 6, rotate 90 degrees to display.
+const EXIF_ROTATE_90: &[u8] = &[
+    0xFF, 0xE1, 0x00, 0x22, b'E', b'x', b'i', b'f', 0x00, 0x00, b'I', b'I', 0x2A, 0x00, 0x08, 0x00,
+    0x00, 0x00, 0x01, 0x00, 0x12, 0x01, 0x03, 0x00, 0x01, 0x00, 0x00, 0x00, 0x06, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00,
+];
+
+/// Dark to the left and bright to the right, so rotating it changes its hash.
+fn gradient() -> DynamicImage {
+    DynamicImage::ImageRgb8(RgbImage::from_fn(32, 32, |x, _| {
+        let v = (x * 8) as u8;
+        image::Rgb([v, v, v])
+    }))
+}
+
+/// Writes a JPEG with an EXIF orientation and a PNG without one.
+fn write_images(dir: &Path) {
+    let user = dir.join("user");
+    fs::create_dir_all(&user).unwrap();
+    let mut jpeg = Vec::new();
+    gradient()
+        .write_to(&mut jpeg, ImageOutputFormat::Jpeg(95))
+        .unwrap();
+    // The segment goes right after the start of image marker.
+    jpeg.splice(2..2, EXIF_ROTATE_90.iter().copied());
+    fs::write(user.join("rotated.jpg"), jpeg).unwrap();
+    gradient().save(user.join("plain.png")).unwrap();
+}
+
+async fn run(pool: &SqlitePool, dir: &Path, load_options: LoadOptions) {
+    let app = App::builder()
+        .imgs_path(dir)
+        .images_processor(Box::new(RayonImagesProcessor::new(Box::new(
+            AppProcParser::new(load_options),
+        ))))
+        .results_parser(Box::new(SqliteResultParser::new(pool.clone())))
+        .match_process(Box::new(SqliteRunner::new(pool.clone())))
+        .modifications(modifications![image_modify::Blur::new(0.5)])
+        .hashing_methods(hashing_methods![image_hash::AverageHash::new(8)])
+        .finish();
+    app.set_selected_modifications(vec![0]).await.unwrap();
+    app.set_selected_hashing_methods(vec![0]).await.unwrap();
+    app.run().await.unwrap();
+}
+
+/// Hash of the image and whether it was decoded with its orientation applied.
+async fn stored_hash(pool: &SqlitePool, name: &str) -> (i64, Vec<u8>, Option<bool>) {
+    sqlx::query_as(
+        "
+        SELECT h.id, h.hash, mi.apply_orientation
+        FROM hashes h
+        JOIN modified_images mi ON mi.id = h.mod_image_id
+        JOIN images i ON i.id = mi.image_id
+        WHERE i.path LIKE ?;
+        ",
+    )
+    .bind(format!("%{}", name))
+    .fetch_one(pool)
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn changing_the_orientation_option_hashes_oriented_images_again() {
+    let dir = std::env::temp_dir().join(format!("p-hash-orientation-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    write_images(&dir);
+    let pool = DbConfig::in_memory().connect().await.unwrap();
+
+    run(&pool, &dir, LoadOptions::default()).await;
+    let (rotated_id, rotated, applied) = stored_hash(&pool, "rotated.jpg").await;
+    assert_eq!(applied, Some(true));
+    let plain = stored_hash(&pool, "plain.png").await;
+
+    run(&pool, &dir, LoadOptions::new().apply_orientation(false)).await;
+    let (id, unrotated, applied) = stored_hash(&pool, "rotated.jpg").await;
+    assert_eq!(id, rotated_id);
+    assert_eq!(applied, Some(false));
+    assert_ne!(unrotated, rotated);
+    // The image without an orientation decodes the same and is reused.
+    assert_eq!(stored_hash(&pool, "plain.png").await, plain);
+
+    run(&pool, &dir, LoadOptions::default()).await;
+    let (_, hash, applied) = stored_hash(&pool, "rotated.jpg").await;
+    assert_eq!(applied, Some(true));
+    assert_eq!(hash, rotated);
+    // Matches are made from the current hashes, none are left from the replaced ones.
+    let (matches,): (i64,) = sqlx::query_as("SELECT count(*) FROM matches;")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(matches, 1);
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,123 @@
+use std::{fs, time::Duration};
+
+use p_hash::{
+    core::{
+        app::App, images_processor::RayonImagesProcessor, result_parser::SqliteResultParser,
+        snapshot::Stage,
+    },
+    db::DbConfig,
+    events::{Event, EventBus},
+    hashing_methods,
+    image_hash::{self, HashingMethods},
+    image_modify::{self, Modifications},
+    matching::match_process::SqliteRunner,
+    modifications,
+    result_calc::RocProcess,
+    synthetic::SyntheticDataset,
+};
+use sqlx::SqlitePool;
+
+fn modifications() -> Modifications {
+    modifications![image_modify::Blur::new(0.5), image_modify::Angle::Rot180]
+}
+
+fn hashing_methods() -> HashingMethods {
+    hashing_methods![
+        image_hash::AverageHash::new(8),
+        image_hash::VertGradient::new(8)
+    ]
+}
+
+/// Runs 16 images with every modification and hashing method, giving 120 matches per
+/// modification and hashing method. Returns the number of matches the ROC reads.
+async fn store_run(pool: &SqlitePool, name: &str) -> u64 {
+    let dir = std::env::temp_dir().join(format!("p-hash-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    SyntheticDataset::new(7)
+        .identities(4)
+        .images_per_identity(4)
+        .dimensions(32, 32)
+        .generate(&dir)
+        .unwrap();
+
+    let app = App::builder()
+        .imgs_path(&dir)
+        .images_processor(Box::new(RayonImagesProcessor::default()))
+        .results_parser(Box::new(SqliteResultParser::new(pool.clone())))
+        .match_process(Box::new(SqliteRunner::new(pool.clone())))
+        .modifications(modifications())
+        .hashing_methods(hashing_methods())
+        .finish();
+    app.set_selected_modifications(vec![0, 1]).await.unwrap();
+    app.set_selected_hashing_methods(vec![0, 1]).await.unwrap();
+    app.run().await.unwrap();
+    fs::remove_dir_all(dir).unwrap();
+
+    // Matches between different modifications are not part of any ROC.
+    let (count,): (i64,) =
+        sqlx::query_as("SELECT count(*) FROM matches WHERE modification_id IS NOT NULL;")
+            .fetch_one(pool)
+            .await
+            .unwrap();
+    count as u64
+}
+
+fn roc_process(pool: SqlitePool) -> RocProcess {
+    RocProcess::new(vec![0.25, 0.5], pool, modifications(), hashing_methods())
+}
+
+#[tokio::test]
+async fn roc_reads_more_matches_than_the_channel_holds() {
+    let pool = DbConfig::in_memory().connect().await.unwrap();
+    let count = store_run(&pool, "roc").await;
+    assert!(count > 100);
+
+    let events = EventBus::new();
+    let mut rx = events.subscribe();
+    let roc = roc_process(pool).with_events(events).run().await.unwrap();
+    assert_eq!(roc.len(), 2);
+    for matrix in roc.iter() {
+        assert_eq!(matrix.total() as u64, count);
+    }
+
+    let mut classified = None;
+    while let Ok(event) = rx.try_recv() {
+        if let Event::Progress {
+            stage: Stage::Roc,
+            done,
+            total,
+            ..
+        } = event
+        {
+            classified = Some((done, total));
+        }
+    }
+    assert_eq!(classified, Some((count, Some(count))));
+}
+
+#[tokio::test]
+async fn roc_reads_more_combinations_than_the_pool_has_connections() {
+    let pool = DbConfig::in_memory()
+        .max_connections(2)
+        .connect()
+        .await
+        .unwrap();
+    let count = store_run(&pool, "roc-pool").await;
+
+    let roc = tokio::time::timeout(Duration::from_secs(20), roc_process(pool).run())
+        .await
+        .expect("the ROC waited for a connection")
+        .unwrap();
+    for matrix in roc.iter() {
+        assert_eq!(matrix.total() as u64, count);
+    }
+}
+
+#[tokio::test]
+async fn roc_fails_when_the_matches_can_not_be_read() {
+    let pool = DbConfig::in_memory().connect().await.unwrap();
+    store_run(&pool, "roc-closed").await;
+    pool.close().await;
+
+    assert!(roc_process(pool).run().await.is_err());
+}
@@ -0,0 +1,154 @@
+mod common;
+
+use std::{fs, fs::File, io::Write, path::Path};
+
+use p_hash::{
+    core::{app::App, images_processor::RayonImagesProcessor, result_parser::SqliteResultParser},
+    db::DbConfig,
+    hashing_methods,
+    image_hash::{self, HashingMethods},
+    image_modify::{self, Modifications},
+    image_parse::{Image, ImageSource},
+    matching::match_process::SqliteRunner,
+    modifications,
+};
+use sqlx::SqlitePool;
+use walkdir::WalkDir;
+use zip::{ZipWriter, write::SimpleFileOptions};
+
+fn app(pool: &SqlitePool, path: &Path) -> App {
+    App::builder()
+        .imgs_path(path)
+        .images_processor(Box::new(RayonImagesProcessor::default()))
+        .results_parser(Box::new(SqliteResultParser::new(pool.clone())))
+        .match_process(Box::new(SqliteRunner::new(pool.clone())))
+        .modifications(modifications![image_modify::Blur::new(0.5)])
+        .hashing_methods(hashing_methods![image_hash::AverageHash::new(8)])
+        .finish()
+}
+
+/// Image files under the directory relative to it, in a stable order.
+fn files(dir: &Path) -> Vec<String> {
+    let mut files: Vec<_> = WalkDir::new(dir)
+        .into_iter()
+        .map(|e| e.unwrap())
+        .filter(|e| e.path().is_file())
+        .map(|e| {
+            e.path()
+                .strip_prefix(dir)
+                .unwrap()
+                .to_string_lossy()
+                .to_string()
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+async fn stored_images(pool: &SqlitePool) -> Vec<Image> {
+    sqlx::query_as("SELECT path, user, session, tags, digest FROM images ORDER BY path;")
+        .fetch_all(pool)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn run_reads_the_images_of_a_manifest() {
+    let dir = common::image_dir("source-manifest");
+    let files = files(&dir);
+    // Only the first three images are listed, each as an identity of its own.
+    let mut csv = "path,identity,session,tags\n".to_string();
+    for (i, file) in files.iter().take(3).enumerate() {
+        csv += &format!("{},person{},s{},listed;n{}\n", file, i, i, i);
+    }
+    let manifest = dir.with_extension("csv");
+    fs::write(&manifest, csv).unwrap();
+
+    let pool = DbConfig::in_memory().connect().await.unwrap();
+    let app = app(&pool, &dir);
+    app.set_image_source(ImageSource::Manifest {
+        manifest: manifest.clone(),
+        root: dir.clone(),
+    })
+    .await
+    .unwrap();
+    app.set_selected_modifications(vec![0]).await.unwrap();
+    app.set_selected_hashing_methods(vec![0]).await.unwrap();
+    app.run().await.unwrap();
+
+    let images = stored_images(&pool).await;
+    assert_eq!(images.len(), 3);
+    for (i, image) in images.iter().enumerate() {
+        assert_eq!(image.get_path(), dir.join(&files[i]));
+        assert_eq!(image.get_user(), format!("person{}", i));
+        assert_eq!(image.get_session(), Some(format!("s{}", i).as_str()));
+        assert_eq!(image.get_tags(), ["listed".to_string(), format!("n{}", i)]);
+    }
+    let (hashes,): (i64,) = sqlx::query_as("SELECT count(*) FROM hashes;")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(hashes, 3);
+
+    fs::remove_file(manifest).unwrap();
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn run_reads_the_images_of_an_archive() {
+    let dir = common::image_dir("source-archive");
+    let files = files(&dir);
+    let archive = dir.with_extension("zip");
+    let mut zip = ZipWriter::new(File::create(&archive).unwrap());
+    for file in &files {
+        zip.start_file(file.as_str(), SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(&fs::read(dir.join(file)).unwrap()).unwrap();
+    }
+    zip.finish().unwrap();
+
+    let pool = DbConfig::in_memory().connect().await.unwrap();
+    let app = app(&pool, &dir);
+    app.set_image_source(ImageSource::Archive(archive.clone()))
+        .await
+        .unwrap();
+    app.set_selected_modifications(vec![0]).await.unwrap();
+    app.set_selected_hashing_methods(vec![0]).await.unwrap();
+    app.run().await.unwrap();
+
+    let images = stored_images(&pool).await;
+    assert_eq!(images.len(), files.len());
+    for (image, file) in images.iter().zip(&files) {
+        assert_eq!(image.get_path(), archive.join(file));
+        let user = Path::new(file).parent().unwrap().to_string_lossy();
+        assert_eq!(image.get_user(), user);
+    }
+    let (hashes,): (i64,) = sqlx::query_as("SELECT count(*) FROM hashes;")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(hashes as usize, files.len());
+
+    fs::remove_file(archive).unwrap();
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn image_source_must_exist() {
+    let dir = common::image_dir("source-missing");
+    let pool = DbConfig::in_memory().connect().await.unwrap();
+    let app = app(&pool, &dir);
+    let res = app
+        .set_image_source(ImageSource::Manifest {
+            manifest: dir.join("missing.csv"),
+            root: dir.clone(),
+        })
+        .await;
+    assert!(res.is_err());
+    assert_eq!(
+        app.get_image_source().await,
+        ImageSource::Directory(dir.clone())
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,245 @@
+mod common;
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs,
+    path::Path,
+};
+
+use futures::TryStreamExt;
+use p_hash::{
+    core::{
+        app::App,
+        images_processor::RayonImagesProcessor,
+        result_parser::{ResultParser, SqliteResultParser, StoreResultParser},
+    },
+    db::{DbConfig, MatchFilter, Repository},
+    hashing_methods,
+    image_hash::{self, HashingMethods},
+    image_modify::{self, Modifications},
+    matching::match_process::{PipelineRunner, SqliteRunner, StoreRunner},
+    modifications,
+    store::ResultStore,
+};
+
+/// Image path, modification and hashing method a hash was made from.
+type HashKey = (String, String, String);
+/// Content digest and dimensions of an image.
+type ImageInfo = (Option<String>, Option<(u32, u32)>);
+
+/// What a run stored, keyed by names and paths instead of the ids the two backends give out.
+#[derive(Debug, Default, PartialEq)]
+struct Stored {
+    images: BTreeMap<String, ImageInfo>,
+    quality: BTreeMap<(String, String), (Option<f64>, Option<f64>)>,
+    hashes: BTreeMap<HashKey, Vec<u8>>,
+    matches: BTreeSet<(HashKey, HashKey, u32)>,
+}
+
+fn method(name: &str, params: &str) -> String {
+    format!("{}({})", name, params)
+}
+
+fn pair(a: HashKey, b: HashKey, distance: u32) -> (HashKey, HashKey, u32) {
+    match a <= b {
+        true => (a, b, distance),
+        false => (b, a, distance),
+    }
+}
+
+async fn run(
+    dir: &Path,
+    results_parser: Box<dyn ResultParser>,
+    match_process: Box<dyn PipelineRunner>,
+) {
+    let app = App::builder()
+        .imgs_path(dir)
+        .images_processor(Box::new(RayonImagesProcessor::default()))
+        .results_parser(results_parser)
+        .match_process(match_process)
+        .modifications(modifications![
+            image_modify::Angle::Rot180,
+            image_modify::Blur::new(0.5)
+        ])
+        .hashing_methods(hashing_methods![
+            image_hash::AverageHash::new(8),
+            image_hash::VertGradient::new(8)
+        ])
+        .finish();
+    app.set_selected_modifications(vec![0, 1]).await.unwrap();
+    app.set_selected_hashing_methods(vec![0, 1]).await.unwrap();
+    app.run().await.unwrap();
+}
+
+async fn stored_in_sqlite(repository: &Repository) -> Stored {
+    let run_id = repository.runs().await.unwrap()[0].get_id();
+    let images: HashMap<_, _> = repository
+        .run_images(run_id)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|i| (i.get_id(), i))
+        .collect();
+    let modifications: HashMap<_, _> = repository
+        .run_modifications(run_id)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|m| (m.get_id(), method(m.get_name(), m.get_params())))
+        .collect();
+    let hashing_methods: HashMap<_, _> = repository
+        .run_hashing_methods(run_id)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|m| (m.get_id(), method(m.get_name(), m.get_params())))
+        .collect();
+    let modified_images: HashMap<_, _> = repository
+        .run_modified_images(run_id)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|mi| (mi.get_id(), mi))
+        .collect();
+
+    let mut stored = Stored::default();
+    for image in images.values() {
+        stored.images.insert(
+            image.get_path().to_string(),
+            (
+                image.get_digest().map(|d| d.to_string()),
+                image.get_dimensions(),
+            ),
+        );
+    }
+    for mi in modified_images.values() {
+        stored.quality.insert(
+            (
+                images[&mi.get_image_id()].get_path().to_string(),
+                modifications[&mi.get_modification_id()].clone(),
+            ),
+            (mi.get_psnr(), mi.get_ssim()),
+        );
+    }
+    let mut hash_keys = HashMap::new();
+    for hash in repository.run_hashes(run_id).await.unwrap() {
+        let mi = &modified_images[&hash.get_modified_image_id()];
+        let key = (
+            images[&mi.get_image_id()].get_path().to_string(),
+            modifications[&mi.get_modification_id()].clone(),
+            hashing_methods[&hash.get_hashing_method_id()].clone(),
+        );
+        hash_keys.insert(hash.get_id(), key.clone());
+        stored.hashes.insert(key, hash.get_hash().to_vec());
+    }
+    for modification_id in modifications.keys() {
+        for hashing_method_id in hashing_methods.keys() {
+            let filter = MatchFilter::new(*modification_id, *hashing_method_id);
+            let matches: Vec<_> = repository.matches(&filter).try_collect().await.unwrap();
+            for m in matches {
+                let (hash1, hash2) = m.get_hash_ids();
+                stored.matches.insert(pair(
+                    hash_keys[&(hash1 as i64)].clone(),
+                    hash_keys[&(hash2 as i64)].clone(),
+                    m.hamming_distance().distance(),
+                ));
+            }
+        }
+    }
+    stored
+}
+
+fn stored_in_store(store: &ResultStore) -> Stored {
+    let images: HashMap<_, _> = store
+        .images()
+        .unwrap()
+        .into_iter()
+        .map(|i| (i.get_id(), i))
+        .collect();
+    let modifications: HashMap<_, _> = store
+        .modifications()
+        .unwrap()
+        .into_iter()
+        .map(|m| (m.get_id(), method(m.get_name(), m.get_params())))
+        .collect();
+    let hashing_methods: HashMap<_, _> = store
+        .hashing_methods()
+        .unwrap()
+        .into_iter()
+        .map(|m| (m.get_id(), method(m.get_name(), m.get_params())))
+        .collect();
+    let modified_images: HashMap<_, _> = store
+        .modified_images()
+        .unwrap()
+        .into_iter()
+        .map(|mi| (mi.get_id(), mi))
+        .collect();
+
+    let mut stored = Stored::default();
+    for image in images.values() {
+        stored.images.insert(
+            image.get_path().to_string(),
+            (
+                image.get_digest().map(|d| d.to_string()),
+                image.get_dimensions(),
+            ),
+        );
+    }
+    for mi in modified_images.values() {
+        stored.quality.insert(
+            (
+                images[&mi.get_image_id()].get_path().to_string(),
+                modifications[&mi.get_modification_id()].clone(),
+            ),
+            (mi.get_psnr(), mi.get_ssim()),
+        );
+    }
+    let mut hash_keys = HashMap::new();
+    for hash in store.hashes().unwrap() {
+        let mi = &modified_images[&hash.get_modified_image_id()];
+        let key = (
+            images[&mi.get_image_id()].get_path().to_string(),
+            modifications[&mi.get_modification_id()].clone(),
+            hashing_methods[&hash.get_hashing_method_id()].clone(),
+        );
+        hash_keys.insert(hash.get_id(), key.clone());
+        stored.hashes.insert(key, hash.get_hash().to_vec());
+    }
+    // The match queries of SQLite only return matches of hashes with the same modification.
+    for m in store.matches().unwrap() {
+        let (hash1, hash2) = m.get_hash_ids();
+        let (key1, key2) = (&hash_keys[&hash1], &hash_keys[&hash2]);
+        if key1.1 == key2.1 {
+            stored
+                .matches
+                .insert(pair(key1.clone(), key2.clone(), m.get_hamming_distance()));
+        }
+    }
+    stored
+}
+
+#[tokio::test]
+async fn store_and_sqlite_store_the_same_run() {
+    let dir = common::image_dir("store");
+    let pool = DbConfig::in_memory().connect().await.unwrap();
+    run(
+        &dir,
+        Box::new(SqliteResultParser::new(pool.clone())),
+        Box::new(SqliteRunner::new(pool.clone())),
+    )
+    .await;
+    let store = ResultStore::in_memory();
+    run(
+        &dir,
+        Box::new(StoreResultParser::new(store.clone())),
+        Box::new(StoreRunner::new(store.clone())),
+    )
+    .await;
+    fs::remove_dir_all(dir).unwrap();
+
+    let sqlite = stored_in_sqlite(&Repository::new(pool)).await;
+    let jsonl = stored_in_store(&store);
+    assert!(!sqlite.hashes.is_empty());
+    assert!(!sqlite.matches.is_empty());
+    assert_eq!(sqlite, jsonl);
+}